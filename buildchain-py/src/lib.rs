@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Python bindings for buildchain
+
+use std::collections::BTreeMap;
+
+use base32::Alphabet;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use buildchain::verify::{self, BLOCK_SIZE};
+
+create_exception!(buildchain, BuildchainError, PyException);
+
+const B32_ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+fn err<E: ToString>(err: E) -> PyErr {
+    BuildchainError::new_err(err.to_string())
+}
+
+fn decode<const N: usize>(name: &str, txt: &str) -> PyResult<[u8; N]> {
+    base32::decode(B32_ALPHABET, txt)
+        .and_then(|bin| bin.try_into().ok())
+        .ok_or_else(|| err(format!("{} is not valid base32", name)))
+}
+
+/// A verified block, with base32 encoded signatures and digest
+#[pyclass(frozen, get_all, module = "buildchain")]
+#[derive(Clone)]
+struct Block {
+    signature: String,
+    public_key: String,
+    previous_signature: String,
+    counter: u64,
+    timestamp: u64,
+    digest: String,
+}
+
+#[pymethods]
+impl Block {
+    fn __repr__(&self) -> String {
+        format!(
+            "Block(counter={}, timestamp={}, digest={:?})",
+            self.counter, self.timestamp, self.digest
+        )
+    }
+}
+
+impl From<buildchain::Block> for Block {
+    fn from(block: buildchain::Block) -> Block {
+        Block {
+            signature: block.signature,
+            public_key: block.public_key,
+            previous_signature: block.previous_signature,
+            counter: block.counter,
+            timestamp: block.timestamp,
+            digest: block.digest,
+        }
+    }
+}
+
+/// A manifest of build artifacts
+#[pyclass(frozen, module = "buildchain")]
+struct Manifest {
+    inner: buildchain::Manifest,
+}
+
+#[pymethods]
+impl Manifest {
+    /// Parse a manifest from JSON
+    #[staticmethod]
+    fn from_json(data: &[u8]) -> PyResult<Manifest> {
+        let inner = serde_json::from_slice(data).map_err(err)?;
+        Ok(Manifest { inner })
+    }
+
+    /// Serialize the manifest to JSON
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string(&self.inner).map_err(err)
+    }
+
+    /// The timestamp of the source control revision that was built
+    #[getter]
+    fn time(&self) -> u64 {
+        self.inner.time
+    }
+
+    /// A dictionary of filenames and their base32 sha384 digests
+    #[getter]
+    fn files(&self) -> BTreeMap<String, String> {
+        self.inner.files.clone()
+    }
+
+    /// Compare this manifest to an older one, returning the added, changed, and removed files
+    #[allow(clippy::type_complexity)]
+    fn diff(
+        &self,
+        old: &Manifest,
+    ) -> (
+        BTreeMap<String, String>,
+        BTreeMap<String, String>,
+        Vec<String>,
+    ) {
+        let diff = self.inner.diff(&old.inner);
+        (diff.added, diff.changed, diff.removed)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Manifest(time={}, files={})",
+            self.inner.time,
+            self.inner.files.len()
+        )
+    }
+}
+
+/// Downloads and verifies tails and objects from a buildchain mirror
+#[pyclass(frozen, module = "buildchain")]
+struct Downloader {
+    inner: buildchain::Downloader,
+}
+
+#[pymethods]
+impl Downloader {
+    #[new]
+    #[pyo3(signature = (key, url, project = "default", branch = "master", cert = None))]
+    fn new(
+        key: &str,
+        url: &str,
+        project: &str,
+        branch: &str,
+        cert: Option<&[u8]>,
+    ) -> PyResult<Downloader> {
+        let inner = buildchain::Downloader::new(key, url, project, branch, cert).map_err(err)?;
+        Ok(Downloader { inner })
+    }
+
+    /// Download and verify the tail block
+    fn tail(&self, py: Python) -> PyResult<Block> {
+        py.allow_threads(|| self.inner.tail())
+            .map(Block::from)
+            .map_err(err)
+    }
+
+    /// Download and verify the block with the base32 `signature`
+    fn block(&self, py: Python, signature: &str) -> PyResult<Block> {
+        py.allow_threads(|| self.inner.block(signature))
+            .map(Block::from)
+            .map_err(err)
+    }
+
+    /// Download and verify the object with the base32 sha384 `digest`
+    fn object<'py>(&self, py: Python<'py>, digest: &str) -> PyResult<Bound<'py, PyBytes>> {
+        let data = py
+            .allow_threads(|| self.inner.object(digest))
+            .map_err(err)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Download and verify the manifest referenced by `block`
+    fn manifest(&self, py: Python, block: &Block) -> PyResult<Manifest> {
+        let data = py
+            .allow_threads(|| self.inner.object(&block.digest))
+            .map_err(err)?;
+        Manifest::from_json(&data)
+    }
+
+    /// List the projects on the mirror
+    fn projects(&self, py: Python) -> PyResult<Vec<String>> {
+        py.allow_threads(|| self.inner.projects()).map_err(err)
+    }
+
+    /// List the branches of the project on the mirror
+    fn branches(&self, py: Python) -> PyResult<Vec<String>> {
+        py.allow_threads(|| self.inner.branches()).map_err(err)
+    }
+}
+
+/// Verify that `data` is a block signed by the base32 public `key`
+#[pyfunction]
+fn verify_block(data: &[u8], key: &str) -> PyResult<Block> {
+    let data: &[u8; BLOCK_SIZE] = data
+        .try_into()
+        .map_err(|_| err(format!("block is not {} bytes", BLOCK_SIZE)))?;
+    let key = decode::<32>("key", key)?;
+    let block = verify::verify_block(data, &key).map_err(err)?;
+    Ok(block.to_block().into())
+}
+
+/// Verify that `data` has the base32 sha384 `digest`
+#[pyfunction]
+fn verify_object(data: &[u8], digest: &str) -> PyResult<()> {
+    let digest = decode::<48>("digest", digest)?;
+    verify::verify_object(data, &digest).map_err(err)
+}
+
+#[pymodule]
+#[pyo3(name = "buildchain")]
+fn buildchain_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("BuildchainError", m.py().get_type::<BuildchainError>())?;
+    m.add_class::<Block>()?;
+    m.add_class::<Downloader>()?;
+    m.add_class::<Manifest>()?;
+    m.add_function(wrap_pyfunction!(verify_block, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_object, m)?)?;
+    Ok(())
+}
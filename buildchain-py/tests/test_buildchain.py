@@ -0,0 +1,41 @@
+# SPDX-License-Identifier: GPL-3.0-only
+
+import base64
+import hashlib
+import unittest
+
+import buildchain
+
+
+def b32(data):
+    return base64.b32encode(data).decode().rstrip("=")
+
+
+class TestBuildchain(unittest.TestCase):
+    def test_manifest(self):
+        digest = b32(hashlib.sha384(b"data").digest())
+        manifest = buildchain.Manifest.from_json(
+            ('{"time": 1, "files": {"a.bin": "%s"}}' % digest).encode()
+        )
+        self.assertEqual(manifest.time, 1)
+        self.assertEqual(manifest.files, {"a.bin": digest})
+
+        old = buildchain.Manifest.from_json(b'{"time": 0, "files": {"b.bin": ""}}')
+        self.assertEqual(manifest.diff(old), ({"a.bin": digest}, {}, ["b.bin"]))
+
+        with self.assertRaises(buildchain.BuildchainError):
+            buildchain.Manifest.from_json(b"{")
+
+    def test_verify_object(self):
+        digest = b32(hashlib.sha384(b"data").digest())
+        buildchain.verify_object(b"data", digest)
+        with self.assertRaises(buildchain.BuildchainError):
+            buildchain.verify_object(b"atad", digest)
+
+    def test_verify_block(self):
+        with self.assertRaises(buildchain.BuildchainError):
+            buildchain.verify_block(bytes(400), b32(bytes(32)))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,153 @@
+/* SPDX-License-Identifier: GPL-3.0-only */
+
+#ifndef BUILDCHAIN_H
+#define BUILDCHAIN_H
+
+/* Generated by cbindgen, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define BUILDCHAIN_OK 0
+
+#define BUILDCHAIN_ERROR_NULL -1
+
+#define BUILDCHAIN_ERROR_LENGTH -2
+
+#define BUILDCHAIN_ERROR_PUBLIC_KEY_MISMATCH -3
+
+#define BUILDCHAIN_ERROR_SIGNATURE_INVALID -4
+
+#define BUILDCHAIN_ERROR_MESSAGE_INVALID -5
+
+#define BUILDCHAIN_ERROR_DIGEST_MISMATCH -6
+
+#define BUILDCHAIN_ERROR_NOT_FOUND -7
+
+/**
+ * The size of a signed block
+ */
+#define BUILDCHAIN_BLOCK_SIZE 400
+
+/**
+ * A parsed manifest, created by `buildchain_manifest_parse`
+ */
+typedef struct BuildchainManifest BuildchainManifest;
+
+/**
+ * The fields of a verified block
+ */
+typedef struct {
+  uint8_t signature[64];
+  uint8_t public_key[32];
+  uint8_t previous_signature[64];
+  uint64_t counter;
+  uint64_t timestamp;
+  /**
+   * The sha384 of the manifest
+   */
+  uint8_t digest[48];
+} BuildchainBlock;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Verify that `data` is a block signed by `key`, filling `out` if it is not null
+ *
+ * Returns `BUILDCHAIN_OK` or a negative error code
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes, `key` to 32 readable bytes, and `out` must be
+ * null or point to a writable `BuildchainBlock`
+ */
+int buildchain_verify_block(const uint8_t *data,
+                            size_t len,
+                            const uint8_t *key,
+                            BuildchainBlock *out);
+
+/**
+ * Verify that `data` has the sha384 `digest`
+ *
+ * Returns `BUILDCHAIN_OK` or a negative error code
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes and `digest` to 48 readable bytes
+ */
+int buildchain_verify_object(const uint8_t *data, size_t len, const uint8_t *digest);
+
+/**
+ * Parse the JSON manifest in `data`, returning null if it is invalid
+ *
+ * The result must be freed with `buildchain_manifest_free`
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes
+ */
+BuildchainManifest *buildchain_manifest_parse(const uint8_t *data, size_t len);
+
+/**
+ * Free a manifest returned by `buildchain_manifest_parse`
+ *
+ * # Safety
+ *
+ * `manifest` must be null or a pointer returned by `buildchain_manifest_parse` that has not
+ * already been freed
+ */
+void buildchain_manifest_free(BuildchainManifest *manifest);
+
+/**
+ * The timestamp of the source control revision that was built
+ *
+ * # Safety
+ *
+ * `manifest` must be a valid pointer returned by `buildchain_manifest_parse`
+ */
+uint64_t buildchain_manifest_time(const BuildchainManifest *manifest);
+
+/**
+ * The number of files in the manifest
+ *
+ * # Safety
+ *
+ * `manifest` must be a valid pointer returned by `buildchain_manifest_parse`
+ */
+size_t buildchain_manifest_len(const BuildchainManifest *manifest);
+
+/**
+ * The name of the file at `index`, or null if `index` is out of range
+ *
+ * The name is owned by the manifest and is valid until it is freed
+ *
+ * # Safety
+ *
+ * `manifest` must be a valid pointer returned by `buildchain_manifest_parse`
+ */
+const char *buildchain_manifest_name(const BuildchainManifest *manifest, size_t index);
+
+/**
+ * Copy the sha384 of the file `name` into `digest`
+ *
+ * Returns `BUILDCHAIN_OK` or a negative error code
+ *
+ * # Safety
+ *
+ * `manifest` must be a valid pointer returned by `buildchain_manifest_parse`, `name` a
+ * NUL-terminated string, and `digest` must point to 48 writable bytes
+ */
+int buildchain_manifest_digest(const BuildchainManifest *manifest,
+                               const char *name,
+                               uint8_t *digest);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* BUILDCHAIN_H */
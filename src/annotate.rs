@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::io;
+
+use crate::id::b32dec;
+use crate::verify::verify_block;
+use crate::{sign_manifest, Annotation, BlockPin, Downloader, Error, Store, Urgency};
+
+pub struct AnnotateArguments<'a> {
+    pub store_path: &'a str,
+    pub key: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// The block to annotate, the tail if not set
+    pub pin_opt: Option<BlockPin>,
+    pub changelog_opt: Option<String>,
+    pub urgency_opt: Option<Urgency>,
+    pub hardware: Vec<String>,
+}
+
+/// Attach an [`Annotation`] to a block of `args.branch`, using `sign` to produce the block that
+/// refers to it
+///
+/// The annotated block must be signed by `args.key`, and so must the annotation.
+pub(crate) fn annotate_store<F>(
+    store: &Store,
+    args: &AnnotateArguments,
+    sign: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&[u8]) -> io::Result<[u8; 400]>,
+{
+    let key: [u8; 32] = b32dec(args.key)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::Config("key is not a base32 public key".to_string()))?;
+
+    let dl = Downloader::from_store(args.key, args.project, args.branch, store)?;
+    let block = match &args.pin_opt {
+        Some(pin) => dl.find_block(pin)?,
+        None => dl.tail()?,
+    };
+
+    let annotation = Annotation {
+        block: block.signature,
+        changelog: args.changelog_opt.clone(),
+        urgency: args.urgency_opt,
+        hardware: args.hardware.clone(),
+    };
+    let annotation_json = serde_json::to_vec_pretty(&annotation).map_err(io::Error::from)?;
+    let digest = store.write_object(&annotation_json)?;
+
+    let response = sign(&annotation_json).map_err(Error::Sign)?;
+    let verified = verify_block(&response, &key)
+        .map_err(|err| Error::Verify(format!("annotation: {}", err)))?;
+    if *verified.digest() != *digest {
+        return Err(Error::Verify(
+            "annotation block does not refer to the annotation".to_string(),
+        ));
+    }
+
+    store.write_annotation(&block.signature, &response)?;
+    println!(
+        "buildchain: annotated block {} with counter {}",
+        block.signature, block.counter
+    );
+    Ok(())
+}
+
+/// Sign an annotation of a published build with PiHSM, without publishing a new build
+pub fn annotate(args: AnnotateArguments) -> Result<(), Error> {
+    let store = Store::open(args.store_path)?;
+    annotate_store(&store, &args, sign_manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tempfile::TempDir;
+
+    use super::{annotate_store, AnnotateArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{
+        Annotation, BlockPin, Downloader, Error, LocalTransport, Manifest, Store, Urgency,
+    };
+
+    #[test]
+    fn test_annotate() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let manifest = serde_json::to_vec(&Manifest::default()).unwrap();
+        let digest = store.write_manifest(&manifest).unwrap();
+        let (public_key, first) = signed_block(1, &[0; 64], 0, &digest);
+        store.write_tail("default", "stable", &first).unwrap();
+        let (_, second) = signed_block(1, first[..64].try_into().unwrap(), 1, &digest);
+        store.write_tail("default", "stable", &second).unwrap();
+
+        let key = b32enc(&public_key);
+        let args = AnnotateArguments {
+            store_path: "",
+            key: &key,
+            project: "default",
+            branch: "stable",
+            pin_opt: Some(BlockPin::Counter(0)),
+            changelog_opt: Some("Fix suspend".to_string()),
+            urgency_opt: Some(Urgency::High),
+            hardware: vec!["galp5".to_string()],
+        };
+
+        let sign = |data: &[u8]| -> io::Result<[u8; 400]> {
+            let annotation: Annotation = serde_json::from_slice(data).unwrap();
+            assert_eq!(annotation.block.0[..], first[..64]);
+            let digest = store.write_object(data).unwrap();
+            Ok(signed_block(1, &[0; 64], 0, &digest).1)
+        };
+        annotate_store(&store, &args, sign).unwrap();
+
+        let dl = Downloader::from_transport(
+            &key,
+            "default",
+            "stable",
+            Box::new(LocalTransport::new(temp_dir.path())),
+        )
+        .unwrap();
+        let tail = dl.tail().unwrap();
+        assert!(dl.annotation(&tail).unwrap().is_none());
+        let block = dl.find_block(&BlockPin::Counter(0)).unwrap();
+        let annotation = dl.annotation(&block).unwrap().unwrap();
+        assert_eq!(annotation.changelog.as_deref(), Some("Fix suspend"));
+        assert_eq!(annotation.urgency, Some(Urgency::High));
+        assert!(annotation.supports("galp5"));
+
+        // An annotation moved to another block is rejected
+        std::fs::copy(
+            store.annotation_path(&block.signature),
+            store.annotation_path(&tail.signature),
+        )
+        .unwrap();
+        assert!(matches!(dl.annotation(&tail), Err(Error::Verify(_))));
+
+        // The annotation must be signed by the key of the branch
+        let sign = |data: &[u8]| {
+            let digest = store.write_object(data).unwrap();
+            Ok(signed_block(2, &[0; 64], 0, &digest).1)
+        };
+        assert!(matches!(
+            annotate_store(&store, &args, sign),
+            Err(Error::Verify(_))
+        ));
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::BlockSig;
+
+/// How urgently devices should install a build
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Urgency {
+    Low,
+    Medium,
+    High,
+    /// The build fixes a security or data loss problem
+    Critical,
+}
+
+impl fmt::Display for Urgency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Urgency::Low => write!(f, "low"),
+            Urgency::Medium => write!(f, "medium"),
+            Urgency::High => write!(f, "high"),
+            Urgency::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// Metadata attached to a block after it is published, for updaters to show before installing
+///
+/// The annotation is stored as an object, and signed as a block that refers to it, which is
+/// written to `annotation/SIGNATURE` next to the block it annotates. Unlike the manifest, it
+/// can be replaced without publishing a new build.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Annotation {
+    /// The signature of the annotated block
+    pub block: BlockSig,
+    /// Release notes of the build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changelog: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<Urgency>,
+    /// Hardware identifiers of the devices the build supports, such as DMI product names, or
+    /// empty if it supports every device
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hardware: Vec<String>,
+}
+
+impl Annotation {
+    /// True if the build supports the device with `hardware_id`
+    pub fn supports(&self, hardware_id: &str) -> bool {
+        self.hardware.is_empty() || self.hardware.iter().any(|id| id == hardware_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Annotation, Urgency};
+    use crate::BlockSig;
+
+    #[test]
+    fn test_annotation() {
+        let annotation = Annotation {
+            block: BlockSig([1; 64]),
+            urgency: Some(Urgency::Critical),
+            hardware: vec!["galp5".to_string()],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&annotation).unwrap();
+        assert!(json.contains(r#""urgency":"critical""#));
+        assert!(!json.contains("changelog"));
+        assert_eq!(
+            serde_json::from_str::<Annotation>(&json).unwrap(),
+            annotation
+        );
+
+        assert!(annotation.supports("galp5"));
+        assert!(!annotation.supports("oryp6"));
+        assert!(Annotation::default().supports("oryp6"));
+        assert!(Urgency::Critical > Urgency::Low);
+    }
+}
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+
+use crate::{err_str, Store};
+
+pub struct AptArguments<'a> {
+    pub store_path: &'a str,
+    pub dest: &'a str,
+    pub suite: &'a str,
+    pub component: &'a str,
+    pub origin_opt: Option<&'a str>,
+    pub gpg_key_opt: Option<&'a str>,
+}
+
+/// A `.deb` artifact and its control stanza
+struct Package {
+    control: String,
+    architecture: String,
+    filename: String,
+    size: u64,
+    sha256: String,
+}
+
+impl Package {
+    /// The entry for this package in a `Packages` index
+    fn entry(&self) -> String {
+        format!(
+            "{}\nFilename: {}\nSize: {}\nSHA256: {}\n",
+            self.control.trim_end(),
+            self.filename,
+            self.size,
+            self.sha256
+        )
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Read the control stanza of a `.deb` with `dpkg-deb`
+fn deb_control(path: &Path) -> Result<String, String> {
+    let output = Command::new("dpkg-deb")
+        .arg("--field")
+        .arg(path)
+        .output()
+        .map_err(err_str)?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "dpkg-deb failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    String::from_utf8(output.stdout).map_err(err_str)
+}
+
+/// Find the value of `field` in a control stanza
+fn control_field<'a>(control: &'a str, field: &str) -> Option<&'a str> {
+    control.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case(field) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Format a unix timestamp as an RFC 2822 date in UTC, as used in `Release` files
+fn rfc2822(time: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let days = time / 86400;
+    let secs = time % 86400;
+
+    // Convert days since the epoch to a civil date
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} UTC",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Generate a `Release` file listing the `Packages` indexes in `indexes`
+fn release(
+    origin: &str,
+    suite: &str,
+    component: &str,
+    time: u64,
+    indexes: &BTreeMap<String, String>,
+) -> String {
+    let architectures: Vec<&str> = indexes
+        .keys()
+        .filter_map(|path| path.split('/').nth(1)?.strip_prefix("binary-"))
+        .collect();
+
+    let mut release = format!(
+        "Origin: {}\nLabel: {}\nSuite: {}\nCodename: {}\nDate: {}\nArchitectures: {}\nComponents: {}\nSHA256:\n",
+        origin,
+        origin,
+        suite,
+        suite,
+        rfc2822(time),
+        architectures.join(" "),
+        component
+    );
+    for (path, data) in indexes.iter() {
+        release.push_str(&format!(
+            " {} {} {}\n",
+            sha256_hex(data.as_bytes()),
+            data.len(),
+            path
+        ));
+    }
+    release
+}
+
+/// Sign `release` with gpg, writing `InRelease` and `Release.gpg` beside it
+fn sign_release(release: &Path, key: &str) -> Result<(), String> {
+    let dir = release.parent().unwrap();
+    for (args, output) in [
+        (&["--clearsign"][..], "InRelease"),
+        (&["--detach-sign", "--armor"][..], "Release.gpg"),
+    ] {
+        let status = Command::new("gpg")
+            .arg("--batch")
+            .arg("--yes")
+            .arg("--local-user")
+            .arg(key)
+            .args(args)
+            .arg("--output")
+            .arg(dir.join(output))
+            .arg(release)
+            .status()
+            .map_err(err_str)?;
+
+        if !status.success() {
+            return Err(format!("gpg failed with status: {}", status));
+        }
+    }
+
+    Ok(())
+}
+
+/// Generate a Debian repository in `dest` from the `.deb` artifacts of the manifest in a store
+///
+/// Packages are copied to `pool/<component>`, and `dists/<suite>` is regenerated. The
+/// `Date` of the release is the time of the build, so the output is reproducible.
+pub fn apt_repo(args: AptArguments) -> Result<(), String> {
+    let store = Store::new(args.store_path);
+    let manifest = store
+        .read_manifest()
+        .map_err(err_str)?
+        .ok_or_else(|| format!("{} has no manifest.json", args.store_path))?;
+
+    let dest = Path::new(args.dest);
+    let pool = dest.join("pool").join(args.component);
+    fs::create_dir_all(&pool).map_err(err_str)?;
+
+    let mut packages = Vec::new();
+    for (name, digest) in manifest.files.iter() {
+        if !name.ends_with(".deb") {
+            continue;
+        }
+
+        let data = fs::read(store.object_path(digest)).map_err(err_str)?;
+        let file_name = Path::new(name)
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .ok_or_else(|| format!("invalid artifact name {}", name))?;
+
+        let path = pool.join(file_name);
+        fs::write(&path, &data).map_err(err_str)?;
+
+        let control = deb_control(&path)?;
+        let architecture = control_field(&control, "Architecture")
+            .ok_or_else(|| format!("{} has no Architecture", name))?
+            .to_string();
+
+        println!("Add {}", name);
+        packages.push(Package {
+            control,
+            architecture,
+            filename: format!("pool/{}/{}", args.component, file_name),
+            size: data.len() as u64,
+            sha256: sha256_hex(&data),
+        });
+    }
+
+    if packages.is_empty() {
+        return Err("no .deb artifacts in manifest".to_string());
+    }
+
+    // Architecture independent packages are listed for every architecture
+    let mut architectures: BTreeSet<&str> = packages
+        .iter()
+        .map(|package| package.architecture.as_str())
+        .filter(|architecture| *architecture != "all")
+        .collect();
+    if architectures.is_empty() {
+        architectures.insert("all");
+    }
+
+    let mut indexes = BTreeMap::new();
+    for architecture in architectures {
+        let entries: Vec<String> = packages
+            .iter()
+            .filter(|package| package.architecture == architecture || package.architecture == "all")
+            .map(|package| package.entry())
+            .collect();
+        indexes.insert(
+            format!("{}/binary-{}/Packages", args.component, architecture),
+            entries.join("\n"),
+        );
+    }
+
+    let dist = dest.join("dists").join(args.suite);
+    if dist.is_dir() {
+        fs::remove_dir_all(&dist).map_err(err_str)?;
+    }
+    for (path, data) in indexes.iter() {
+        let path = dist.join(path);
+        fs::create_dir_all(path.parent().unwrap()).map_err(err_str)?;
+        fs::write(path, data).map_err(err_str)?;
+    }
+
+    let release_path = dist.join("Release");
+    let release = release(
+        args.origin_opt.unwrap_or("buildchain"),
+        args.suite,
+        args.component,
+        manifest.time,
+        &indexes,
+    );
+    fs::write(&release_path, release).map_err(err_str)?;
+
+    if let Some(key) = args.gpg_key_opt {
+        sign_release(&release_path, key)?;
+    }
+
+    println!(
+        "buildchain: generated {} with {} packages",
+        args.dest,
+        packages.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{control_field, release, rfc2822, Package};
+
+    #[test]
+    fn test_rfc2822() {
+        assert_eq!(rfc2822(0), "Thu, 01 Jan 1970 00:00:00 UTC");
+        assert_eq!(rfc2822(951782400), "Tue, 29 Feb 2000 00:00:00 UTC");
+        assert_eq!(rfc2822(1_500_000_000), "Fri, 14 Jul 2017 02:40:00 UTC");
+    }
+
+    #[test]
+    fn test_packages() {
+        let control = "Package: firmware\nVersion: 1.0\nArchitecture: amd64\n";
+        assert_eq!(control_field(control, "architecture"), Some("amd64"));
+        assert_eq!(control_field(control, "Depends"), None);
+
+        let package = Package {
+            control: control.to_string(),
+            architecture: "amd64".to_string(),
+            filename: "pool/main/firmware_1.0_amd64.deb".to_string(),
+            size: 3,
+            sha256: "abc".to_string(),
+        };
+        assert_eq!(
+            package.entry(),
+            "Package: firmware\nVersion: 1.0\nArchitecture: amd64\n\
+             Filename: pool/main/firmware_1.0_amd64.deb\nSize: 3\nSHA256: abc\n"
+        );
+
+        let indexes: BTreeMap<String, String> =
+            [("main/binary-amd64/Packages".to_string(), package.entry())].into();
+        let release = release("buildchain", "stable", "main", 0, &indexes);
+        assert!(release.contains("Architectures: amd64\n"));
+        assert!(release.contains("Date: Thu, 01 Jan 1970 00:00:00 UTC\n"));
+        assert!(release.ends_with(&format!(
+            " {} main/binary-amd64/Packages\n",
+            package.entry().len()
+        )));
+    }
+}
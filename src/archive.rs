@@ -0,0 +1,268 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use tar::{Builder, Header, HeaderMode};
+
+/// Names that are not archived, as with `tar --exclude-vcs`
+pub(crate) const VCS_NAMES: &[&str] = &[
+    ".bzr",
+    ".bzrignore",
+    ".bzrtags",
+    ".git",
+    ".gitattributes",
+    ".gitignore",
+    ".gitmodules",
+    ".hg",
+    ".hgignore",
+    ".hgtags",
+    ".svn",
+    "CVS",
+    "_darcs",
+];
+
+/// True if `path` in the build directory `base` is left out of its archive or copies
+fn is_excluded(base: &Path, path: &Path, exclude_source: bool) -> bool {
+    let vcs = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| VCS_NAMES.contains(&name));
+    vcs || (exclude_source && path == base.join("source"))
+}
+
+/// Copy the build directory `base` to `dest`, with the same contents as its archive
+///
+/// The copy is made next to `dest` and renamed into place, so `dest` is either complete or
+/// missing. It is an error if `dest` already exists.
+pub(crate) fn copy_build_dir<P: AsRef<Path>, Q: AsRef<Path>>(
+    base: P,
+    dest: Q,
+    exclude_source: bool,
+) -> io::Result<()> {
+    fn copy_dir(base: &Path, dir: &Path, dest: &Path, exclude_source: bool) -> io::Result<()> {
+        fs::create_dir(dest)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if is_excluded(base, &path, exclude_source) {
+                continue;
+            }
+
+            let target = dest.join(path.file_name().unwrap());
+            let file_type = fs::symlink_metadata(&path)?.file_type();
+            if file_type.is_symlink() {
+                symlink(fs::read_link(&path)?, target)?;
+            } else if file_type.is_dir() {
+                copy_dir(base, &path, &target, exclude_source)?;
+            } else {
+                fs::copy(&path, target)?;
+            }
+        }
+        Ok(())
+    }
+
+    let (base, dest) = (base.as_ref(), dest.as_ref());
+    if fs::symlink_metadata(dest).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", dest.display()),
+        ));
+    }
+    let mut partial = OsString::from(dest.as_os_str());
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    if partial.exists() {
+        fs::remove_dir_all(&partial)?;
+    }
+
+    if let Err(err) = copy_dir(base, base, &partial, exclude_source) {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(err);
+    }
+    fs::rename(partial, dest)
+}
+
+/// Writes the archive of a build directory while the build is still producing it
+///
+/// Objects are appended and removed as they are imported, so that the build directory and the
+/// archive never both hold a full copy of the artifacts. The rest of the directory is appended
+/// in name order by [`ArchiveWriter::finish`]. Owners are recorded as root, as with
+/// `tar --owner=0 --group=0 --numeric-owner`.
+pub(crate) struct ArchiveWriter {
+    base: PathBuf,
+    dest: PathBuf,
+    partial: PathBuf,
+    exclude_source: bool,
+    builder: Builder<BufWriter<File>>,
+}
+
+impl ArchiveWriter {
+    /// Start an archive of `base`, which is written to `dest` when finished
+    pub fn create<P: AsRef<Path>, Q: AsRef<Path>>(
+        base: P,
+        dest: Q,
+        exclude_source: bool,
+    ) -> io::Result<ArchiveWriter> {
+        let dest = dest.as_ref().to_path_buf();
+        let mut partial = OsString::from(dest.as_os_str());
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+
+        let mut builder = Builder::new(BufWriter::new(File::create(&partial)?));
+        builder.follow_symlinks(false);
+        Ok(ArchiveWriter {
+            base: base.as_ref().to_path_buf(),
+            dest,
+            partial,
+            exclude_source,
+            builder,
+        })
+    }
+
+    fn append(&mut self, path: &Path) -> io::Result<()> {
+        let name = path.strip_prefix(&self.base).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not in the archived directory", path.display()),
+            )
+        })?;
+
+        let metadata = fs::symlink_metadata(path)?;
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Complete);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("")?;
+        header.set_groupname("")?;
+
+        if metadata.file_type().is_symlink() {
+            let target = fs::read_link(path)?;
+            self.builder.append_link(&mut header, name, target)
+        } else if metadata.is_file() {
+            self.builder
+                .append_data(&mut header, name, File::open(path)?)
+        } else {
+            self.builder.append_data(&mut header, name, io::empty())
+        }
+    }
+
+    /// Append the object at `path`, removing it from the build directory
+    pub fn append_object<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.append(path.as_ref())?;
+        fs::remove_file(path)
+    }
+
+    fn append_dir(&mut self, dir: &Path) -> io::Result<()> {
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry_res| entry_res.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+
+        for path in paths {
+            if is_excluded(&self.base, &path, self.exclude_source) {
+                continue;
+            }
+
+            self.append(&path)?;
+            if fs::symlink_metadata(&path)?.is_dir() {
+                self.append_dir(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the finished archive to `dest` instead of the path it was created with
+    pub fn set_dest<P: AsRef<Path>>(&mut self, dest: P) {
+        self.dest = dest.as_ref().to_path_buf();
+    }
+
+    /// Append the rest of the build directory and move the archive into place
+    pub fn finish(mut self) -> io::Result<()> {
+        let base = self.base.clone();
+        self.append_dir(&base)?;
+
+        self.builder.finish()?;
+        self.builder.get_mut().flush()?;
+        self.builder.get_ref().get_ref().sync_all()?;
+        fs::rename(&self.partial, &self.dest)
+    }
+}
+
+impl Drop for ArchiveWriter {
+    fn drop(&mut self) {
+        // An archive that was not finished is incomplete, and is not left behind
+        let _ = fs::remove_file(&self.partial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+
+    use tempfile::TempDir;
+
+    use super::{copy_build_dir, ArchiveWriter};
+
+    #[test]
+    fn test_archive() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let base = temp_dir.path().join("build");
+        fs::create_dir_all(base.join("object/ab")).unwrap();
+        fs::create_dir_all(base.join("artifacts")).unwrap();
+        fs::create_dir_all(base.join("source/.git")).unwrap();
+        fs::write(base.join("object/ab/cd"), "object").unwrap();
+        symlink("../object/ab/cd", base.join("artifacts/file")).unwrap();
+        fs::write(base.join("source/buildchain.json"), "{}").unwrap();
+        fs::write(base.join("source/.git/HEAD"), "ref").unwrap();
+
+        let dest = temp_dir.path().join("buildchain.tar");
+        let mut archive = ArchiveWriter::create(&base, &dest, false).unwrap();
+        archive.append_object(base.join("object/ab/cd")).unwrap();
+        assert!(!base.join("object/ab/cd").exists());
+        assert!(!dest.exists());
+        archive.finish().unwrap();
+
+        let output = Command::new("tar")
+            .arg("--list")
+            .arg("--file")
+            .arg(&dest)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let list = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(
+            list.lines().collect::<Vec<_>>(),
+            [
+                "object/ab/cd",
+                "artifacts",
+                "artifacts/file",
+                "object",
+                "object/ab",
+                "source",
+                "source/buildchain.json",
+            ]
+        );
+
+        let copy = temp_dir.path().join("copy");
+        copy_build_dir(&base, &copy, true).unwrap();
+        assert_eq!(
+            fs::read_link(copy.join("artifacts/file")).unwrap(),
+            fs::read_link(base.join("artifacts/file")).unwrap()
+        );
+        assert!(copy.join("object/ab").is_dir());
+        assert!(!copy.join("source").exists());
+        assert!(copy_build_dir(&base, &copy, false).is_err());
+
+        // Unfinished archives are removed
+        let other = temp_dir.path().join("other.tar");
+        drop(ArchiveWriter::create(&base, &other, true).unwrap());
+        assert!(!temp_dir.path().join("other.tar.partial").exists());
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,2205 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Non-blocking download API, for use from async applications
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::task::Poll;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::block::PackedBlock;
+use crate::id::{b32dec, b32enc};
+use crate::keyring::in_window;
+use crate::verify::{PublicKey, VerifyError};
+use crate::{
+    err_str, Annotation, Block, BlockIndex, BlockPin, BlockSig, Cache, CacheState, CasTransport,
+    Clock, DeltaSignature, Device, Error, Fetched, FileInfo, Genesis, HttpTransport, IndexedBlock,
+    Keyring, LocalTransport, Manifest, ManifestDiff, ObjectId, Policy, PolicyFile, Sha384, Store,
+    SystemClock, TorrentTransport, Transport, TransportFuture, Validators,
+};
+
+/// The number of objects [`Downloader::objects`] downloads at the same time
+const MAX_CONCURRENT_OBJECTS: usize = 8;
+
+/// How often [`Downloader::wait_for_update`] checks the tail
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The last verified tail response, used to make conditional requests
+struct TailCache {
+    validators: Validators,
+    data: Vec<u8>,
+}
+
+/// Credentials sent to HTTP(S) mirrors
+#[derive(Clone)]
+pub enum Auth {
+    /// An `Authorization: Bearer` token
+    Bearer(String),
+    /// HTTP basic authentication
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Credentials are never printed
+        match self {
+            Auth::Bearer(_) => write!(f, "Bearer"),
+            Auth::Basic { username, .. } => write!(f, "Basic({})", username),
+        }
+    }
+}
+
+/// A client certificate presented to HTTPS mirrors that require mutual TLS
+#[derive(Clone)]
+pub enum Identity {
+    /// A PEM encoded certificate chain and PKCS#8 private key
+    Pem { cert: Vec<u8>, key: Vec<u8> },
+    /// A DER encoded PKCS#12 archive and its password
+    Pkcs12 { der: Vec<u8>, password: String },
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keys are never printed
+        match self {
+            Identity::Pem { .. } => write!(f, "Pem"),
+            Identity::Pkcs12 { .. } => write!(f, "Pkcs12"),
+        }
+    }
+}
+
+/// A verified block in the history of a branch, and the branch it was signed for
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub branch: String,
+    pub block: Block,
+}
+
+/// The outcome of a check made by [`Downloader::probe`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeStatus {
+    Ok,
+    /// Downloads work, but some requests are slower or less robust than they could be
+    Warning,
+    /// Downloads from the mirror fail
+    Failed,
+}
+
+impl fmt::Display for ProbeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeStatus::Ok => write!(f, "ok"),
+            ProbeStatus::Warning => write!(f, "warning"),
+            ProbeStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A check of a file on the mirror, made by [`Downloader::probe`]
+#[derive(Debug, Serialize)]
+pub struct ProbeCheck {
+    pub path: String,
+    pub status: ProbeStatus,
+    pub message: String,
+}
+
+impl ProbeCheck {
+    fn new<S: Into<String>>(path: &str, status: ProbeStatus, message: S) -> ProbeCheck {
+        ProbeCheck {
+            path: path.to_string(),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+/// Decode the base32 public `key`
+pub(crate) fn public_key(key: &str) -> Result<PublicKey, Error> {
+    let key = b32dec(key).ok_or_else(|| Error::Config("key not in base32 format".to_string()))?;
+    PublicKey::try_from(key.as_slice())
+        .map_err(|_| Error::Config(format!("key is {} bytes, expected 32 bytes", key.len())))
+}
+
+/// A key that blocks may be signed by, and the block timestamps it is valid for
+struct TrustedKey {
+    key: PublicKey,
+    not_before: Option<u64>,
+    not_after: Option<u64>,
+}
+
+/// Downloads and verifies tails and objects from a buildchain mirror without blocking
+pub struct Downloader {
+    keys: Vec<TrustedKey>,
+    transport: Box<dyn Transport>,
+    project: String,
+    branch: String,
+    tail_cache: Mutex<Option<TailCache>>,
+    cache_opt: Option<Cache>,
+    policy_opt: Option<Box<dyn Policy>>,
+    index_opt: Option<BlockIndex>,
+}
+
+/// Configures and creates a [`Downloader`]
+#[derive(Clone, Debug)]
+pub struct DownloaderBuilder {
+    key: String,
+    extra_keys: Vec<String>,
+    keyring_opt: Option<Keyring>,
+    url: String,
+    project: String,
+    branch: String,
+    cert_opt: Option<Vec<u8>>,
+    proxy_opt: Option<String>,
+    system_proxy: bool,
+    auth_opt: Option<Auth>,
+    identity_opt: Option<Identity>,
+    connect_timeout: Duration,
+    read_timeout_opt: Option<Duration>,
+    pool_max_idle: usize,
+    http2_prior_knowledge: bool,
+    compression: bool,
+    cache_root_opt: Option<PathBuf>,
+    gateway_opt: Option<String>,
+    torrent_command_opt: Option<Vec<String>>,
+}
+
+impl DownloaderBuilder {
+    /// Create a builder for the mirror at `url`, verified with the base32 public `key`
+    ///
+    /// The `url` may be an `http://` or `https://` URL, a `file://` URL, or a plain local
+    /// path. Local mirrors are read directly from the filesystem.
+    pub fn new(key: &str, url: &str) -> DownloaderBuilder {
+        DownloaderBuilder {
+            key: key.to_string(),
+            extra_keys: Vec::new(),
+            keyring_opt: None,
+            url: url.to_string(),
+            project: "default".to_string(),
+            branch: "master".to_string(),
+            cert_opt: None,
+            proxy_opt: None,
+            system_proxy: true,
+            auth_opt: None,
+            identity_opt: None,
+            connect_timeout: Duration::from_secs(30),
+            read_timeout_opt: Some(Duration::from_secs(60)),
+            pool_max_idle: 8,
+            http2_prior_knowledge: false,
+            compression: true,
+            cache_root_opt: None,
+            gateway_opt: None,
+            torrent_command_opt: None,
+        }
+    }
+
+    /// Set the tail signature project name, `default` if not set
+    pub fn project(mut self, project: &str) -> DownloaderBuilder {
+        self.project = project.to_string();
+        self
+    }
+
+    /// Set the tail signature branch name, `master` if not set
+    pub fn branch(mut self, branch: &str) -> DownloaderBuilder {
+        self.branch = branch.to_string();
+        self
+    }
+
+    /// Also accept blocks signed by the base32 public `key`, such as during a key rotation
+    pub fn key(mut self, key: &str) -> DownloaderBuilder {
+        self.extra_keys.push(key.to_string());
+        self
+    }
+
+    /// Also accept blocks signed by the signer keys of the verified `keyring`
+    pub fn keyring(mut self, keyring: Keyring) -> DownloaderBuilder {
+        self.keyring_opt = Some(keyring);
+        self
+    }
+
+    /// Trust the PEM encoded root certificate `cert` for HTTPS mirrors
+    pub fn cert(mut self, cert: &[u8]) -> DownloaderBuilder {
+        self.cert_opt = Some(cert.to_vec());
+        self
+    }
+
+    /// Send all HTTP(S) requests through the proxy at `proxy`
+    ///
+    /// Hosts listed in the `no_proxy` environment variable are still accessed directly.
+    pub fn proxy(mut self, proxy: &str) -> DownloaderBuilder {
+        self.proxy_opt = Some(proxy.to_string());
+        self
+    }
+
+    /// Honor the `http_proxy`, `https_proxy`, and `no_proxy` environment variables,
+    /// enabled by default
+    pub fn system_proxy(mut self, system_proxy: bool) -> DownloaderBuilder {
+        self.system_proxy = system_proxy;
+        self
+    }
+
+    /// Authenticate to HTTP(S) mirrors with `auth`
+    ///
+    /// This only grants access to the mirror, signatures are verified as usual.
+    pub fn auth(mut self, auth: Auth) -> DownloaderBuilder {
+        self.auth_opt = Some(auth);
+        self
+    }
+
+    /// Present the client certificate `identity` to HTTPS mirrors
+    pub fn identity(mut self, identity: Identity) -> DownloaderBuilder {
+        self.identity_opt = Some(identity);
+        self
+    }
+
+    /// Give up connecting to HTTP(S) mirrors after `timeout`, 30 seconds if not set
+    pub fn connect_timeout(mut self, timeout: Duration) -> DownloaderBuilder {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Give up on HTTP(S) requests when no data is received for `timeout_opt`, 60 seconds if
+    /// not set
+    ///
+    /// This limits stalls rather than the total time, so large objects may still take longer.
+    pub fn read_timeout(mut self, timeout_opt: Option<Duration>) -> DownloaderBuilder {
+        self.read_timeout_opt = timeout_opt;
+        self
+    }
+
+    /// Keep up to `max` idle connections to the mirror for reuse, 8 if not set
+    pub fn pool_max_idle(mut self, max: usize) -> DownloaderBuilder {
+        self.pool_max_idle = max;
+        self
+    }
+
+    /// Speak HTTP/2 to `http://` mirrors without negotiating it first
+    ///
+    /// HTTPS mirrors negotiate HTTP/2 with ALPN, and multiplex requests over one connection
+    /// when they support it.
+    pub fn http2_prior_knowledge(mut self, http2_prior_knowledge: bool) -> DownloaderBuilder {
+        self.http2_prior_knowledge = http2_prior_knowledge;
+        self
+    }
+
+    /// Ask HTTP(S) mirrors to compress responses with gzip or brotli, enabled by default
+    ///
+    /// Responses are decoded as they are received, so digests are always checked against the
+    /// decoded bytes. Disable this for mirrors of artifacts that are already compressed, where
+    /// compressing them again only costs time on the server.
+    pub fn compression(mut self, compression: bool) -> DownloaderBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Cache tails and objects of the mirror under `root`, see [`Cache`]
+    ///
+    /// [`Cache::default_root`] is shared by the command line and other clients.
+    pub fn cache<P: AsRef<Path>>(mut self, root: P) -> DownloaderBuilder {
+        self.cache_root_opt = Some(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Fetch objects published to IPFS from the HTTP(S) gateway at `url`, see [`CasTransport`]
+    pub fn gateway(mut self, url: &str) -> DownloaderBuilder {
+        self.gateway_opt = Some(url.to_string());
+        self
+    }
+
+    /// Download large objects from peers with the BitTorrent client `command`, see
+    /// [`TorrentTransport`]
+    pub fn torrent(mut self, command: Vec<String>) -> DownloaderBuilder {
+        self.torrent_command_opt = Some(command);
+        self
+    }
+
+    fn client(&self) -> Result<reqwest::Client, Error> {
+        let config = |err| Error::Config(err_str(err));
+
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .tcp_keepalive(Duration::from_secs(60))
+            .http2_adaptive_window(true)
+            .http2_keep_alive_interval(Duration::from_secs(30))
+            .http2_keep_alive_while_idle(false)
+            .gzip(self.compression)
+            .brotli(self.compression);
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+
+        if let Some(cert) = &self.cert_opt {
+            builder =
+                builder.add_root_certificate(reqwest::Certificate::from_pem(cert).map_err(config)?);
+        }
+
+        if let Some(identity) = &self.identity_opt {
+            let identity = match identity {
+                Identity::Pem { cert, key } => reqwest::Identity::from_pkcs8_pem(cert, key),
+                Identity::Pkcs12 { der, password } => {
+                    reqwest::Identity::from_pkcs12_der(der, password)
+                }
+            };
+            builder = builder.identity(identity.map_err(config)?);
+        }
+
+        if !self.system_proxy {
+            builder = builder.no_proxy();
+        }
+
+        if let Some(proxy) = &self.proxy_opt {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(config)?
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+
+        builder.build().map_err(config)
+    }
+
+    /// Create the [`Downloader`]
+    pub fn build(self) -> Result<Downloader, Error> {
+        let mut local_opt = if !self.url.contains("://") {
+            Some(PathBuf::from(&self.url))
+        } else {
+            None
+        };
+        let mut transport: Box<dyn Transport> = if let Some(path) = &local_opt {
+            Box::new(LocalTransport::new(path))
+        } else {
+            let url = reqwest::Url::parse(&self.url).map_err(|err| Error::Config(err_str(err)))?;
+            match url.scheme() {
+                "http" | "https" => Box::new(
+                    HttpTransport::new(url, self.client()?, self.auth_opt.clone())
+                        .read_timeout(self.read_timeout_opt),
+                ),
+                "file" => {
+                    let path = url
+                        .to_file_path()
+                        .map_err(|()| Error::Config(format!("{} is not a valid file URL", url)))?;
+                    local_opt = Some(path.clone());
+                    Box::new(LocalTransport::new(path))
+                }
+                scheme => return Err(Error::Config(format!("unsupported URL scheme: {}", scheme))),
+            }
+        };
+        if let Some(gateway) = &self.gateway_opt {
+            // The gateway URL is a base, so `ipfs/<cid>` is joined below its path
+            let mut url =
+                reqwest::Url::parse(gateway).map_err(|err| Error::Config(err_str(err)))?;
+            if !url.path().ends_with('/') {
+                url.set_path(&format!("{}/", url.path()));
+            }
+            let gateway =
+                HttpTransport::new(url, self.client()?, None).read_timeout(self.read_timeout_opt);
+            transport = Box::new(CasTransport::new(transport, Box::new(gateway)));
+        }
+        if let Some(command) = &self.torrent_command_opt {
+            transport = Box::new(TorrentTransport::new(transport, command.clone()));
+        }
+
+        let mut downloader =
+            Downloader::from_transport(&self.key, &self.project, &self.branch, transport)?;
+        for key in self.extra_keys.iter() {
+            downloader.add_key(key)?;
+        }
+        if let Some(keyring) = &self.keyring_opt {
+            downloader.add_keyring(keyring)?;
+        }
+        if let Some(root) = &self.cache_root_opt {
+            downloader.set_cache(Cache::open(root, &self.url)?);
+        }
+        // A local mirror is a store, so its block index can be used to find blocks
+        if let Some(path) = local_opt {
+            downloader.set_index(BlockIndex::read(&Store::new(path))?);
+        }
+        Ok(downloader)
+    }
+
+    /// Create a blocking [`crate::Downloader`]
+    pub fn build_blocking(self) -> Result<crate::Downloader, Error> {
+        crate::Downloader::from_async(self.build()?)
+    }
+}
+
+impl Downloader {
+    /// Create a new Downloader
+    ///
+    /// See [`DownloaderBuilder`] for more options.
+    pub fn new(
+        key: &str,
+        url: &str,
+        project: &str,
+        branch: &str,
+        cert_opt: Option<&[u8]>,
+    ) -> Result<Downloader, Error> {
+        let mut builder = DownloaderBuilder::new(key, url)
+            .project(project)
+            .branch(branch);
+        if let Some(cert) = cert_opt {
+            builder = builder.cert(cert);
+        }
+        builder.build()
+    }
+
+    /// Create a Downloader that fetches files using `transport`
+    pub fn from_transport(
+        key: &str,
+        project: &str,
+        branch: &str,
+        transport: Box<dyn Transport>,
+    ) -> Result<Downloader, Error> {
+        let key = public_key(key)?;
+
+        Ok(Downloader {
+            keys: vec![TrustedKey {
+                key,
+                not_before: None,
+                not_after: None,
+            }],
+            transport,
+            project: project.to_string(),
+            branch: branch.to_string(),
+            tail_cache: Mutex::new(None),
+            cache_opt: None,
+            policy_opt: None,
+            index_opt: None,
+        })
+    }
+
+    /// Cache tails and objects in `cache`, which is read before the mirror
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache_opt = Some(cache);
+    }
+
+    /// The cache of this Downloader, if it has one
+    pub fn cache(&self) -> Option<&Cache> {
+        self.cache_opt.as_ref()
+    }
+
+    /// Look up blocks of the branch in `index`, such as the [`BlockIndex`] of a local store
+    ///
+    /// [`Downloader::find_block`] then downloads an indexed block directly, instead of walking
+    /// back from the tail. The block is still verified, and must have its indexed counter.
+    pub fn set_index(&mut self, index: BlockIndex) {
+        self.index_opt = Some(index);
+    }
+
+    /// Check builds and their files with `policy` before downloading them
+    pub fn set_policy(&mut self, policy: Box<dyn Policy>) {
+        self.policy_opt = Some(policy);
+    }
+
+    /// Check the verified `block` and `manifest`, and the files `names` of it, against the
+    /// policy set with [`Downloader::set_policy`]
+    ///
+    /// The length of each file is requested from the mirror, so that the policy can limit
+    /// sizes. Nothing is requested without a policy.
+    pub async fn check_policy(
+        &self,
+        block: &Block,
+        manifest: &Manifest,
+        names: &[&str],
+    ) -> Result<(), Error> {
+        let policy = match &self.policy_opt {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        policy.check_build(block, manifest).map_err(|reason| {
+            Error::Denied(format!("build {} denied: {}", block.counter, reason))
+        })?;
+
+        for name in names.iter() {
+            let digest = manifest
+                .files
+                .get(*name)
+                .ok_or_else(|| Error::NotFound(format!("{} not found", name)))?;
+            let length = match self.transport.head(&format!("object/{}", digest)).await {
+                Ok(info) => info.length,
+                Err(_) => None,
+            };
+            let file = PolicyFile {
+                name,
+                digest,
+                length,
+                mode: manifest.modes.get(*name).copied(),
+            };
+            policy
+                .check_file(block, manifest, &file)
+                .map_err(|reason| Error::Denied(format!("{} denied: {}", name, reason)))?;
+        }
+        Ok(())
+    }
+
+    /// Also accept blocks signed by the base32 public `key`
+    ///
+    /// The key that signed a block is recorded in [`Block::public_key`].
+    pub fn add_key(&mut self, key: &str) -> Result<(), Error> {
+        let key = public_key(key)?;
+        self.keys.push(TrustedKey {
+            key,
+            not_before: None,
+            not_after: None,
+        });
+        Ok(())
+    }
+
+    /// Also accept blocks signed by the signer keys of `keyring`, within their validity windows
+    ///
+    /// The keyring should be verified with [`Keyring::open`] first.
+    pub fn add_keyring(&mut self, keyring: &Keyring) -> Result<(), Error> {
+        for entry in keyring.signers() {
+            let key = public_key(&entry.key)?;
+            self.keys.push(TrustedKey {
+                key,
+                not_before: entry.not_before,
+                not_after: entry.not_after,
+            });
+        }
+        Ok(())
+    }
+
+    async fn download(&self, path: &str) -> Result<Vec<u8>, Error> {
+        self.transport.get(path).await
+    }
+
+    /// Parse and verify a block downloaded from `path`
+    fn verify(&self, data: &[u8]) -> Result<Block, Error> {
+        let b = PackedBlock::from_bytes(data).map_err(Error::Verify)?;
+        // Blocks carry their public key, which selects the key to verify with
+        let trusted = self
+            .keys
+            .iter()
+            .find(|trusted| trusted.key.matches(b.public_key()))
+            .unwrap_or(&self.keys[0]);
+        let block = b.verify(&trusted.key).map_err(|err| match err {
+            VerifyError::PublicKeyMismatch => Error::Verify(format!(
+                "block is signed by {}, which is not trusted",
+                b32enc(b.public_key())
+            )),
+            err => Error::Verify(err.to_string()),
+        })?;
+
+        if !in_window(block.timestamp, trusted.not_before, trusted.not_after) {
+            return Err(Error::Verify(format!(
+                "key {} is not valid at {}",
+                block.public_key, block.timestamp
+            )));
+        }
+        Ok(block)
+    }
+
+    /// Download and verify an object, using the [`Cache`] if this Downloader has one
+    pub async fn object(&self, digest: &ObjectId) -> Result<Vec<u8>, Error> {
+        match &self.cache_opt {
+            Some(cache) => self.object_cached(digest, cache.store()).await,
+            None => self.fetch_object(digest).await,
+        }
+    }
+
+    async fn fetch_object(&self, digest: &ObjectId) -> Result<Vec<u8>, Error> {
+        let path = format!("object/{}", digest);
+        let data = self.download(&path).await?;
+
+        let sha = Sha384::new(data.as_slice())?;
+        if sha.to_id() != *digest {
+            return Err(Error::Verify("sha384 mismatch".to_string()));
+        }
+
+        Ok(data)
+    }
+
+    /// Download and verify the object with `digest`, reusing the blocks of `basis`
+    ///
+    /// `basis` is a previous version of the object, such as the file of the same name in the
+    /// last build. If the mirror has a [`DeltaSignature`] of the object, only the parts missing
+    /// from `basis` are downloaded, with ranged requests. Otherwise, or if the rebuilt object
+    /// does not verify, the whole object is downloaded.
+    pub async fn object_delta(&self, digest: &ObjectId, basis: &[u8]) -> Result<Vec<u8>, Error> {
+        match self.rebuild_object(digest, basis).await {
+            Ok(data) => Ok(data),
+            Err(_) => self.fetch_object(digest).await,
+        }
+    }
+
+    async fn rebuild_object(&self, digest: &ObjectId, basis: &[u8]) -> Result<Vec<u8>, Error> {
+        let signature_data = self.download(&format!("zsync/{}", digest)).await?;
+        let signature = DeltaSignature::from_bytes(&signature_data).map_err(Error::Verify)?;
+        // The signature is not trusted, so its length is checked before it is allocated
+        let path = format!("object/{}", digest);
+        if self.transport.head(&path).await?.length != Some(signature.length) {
+            return Err(Error::Verify(format!(
+                "{} does not match its delta signature",
+                path
+            )));
+        }
+        let (mut data, missing) = signature.rebuild(basis);
+
+        for range in missing {
+            let part = self.transport.get_range(&path, range.clone()).await?;
+            data.get_mut(range.start as usize..range.end as usize)
+                .filter(|dest| dest.len() == part.len())
+                .ok_or_else(|| {
+                    Error::Http(format!("{} sent the wrong size for {:?}", path, range))
+                })?
+                .copy_from_slice(&part);
+        }
+
+        let sha = Sha384::new(data.as_slice())?;
+        if sha.to_id() != *digest {
+            return Err(Error::Verify("sha384 mismatch".to_string()));
+        }
+        Ok(data)
+    }
+
+    /// Download and verify the objects with `digests` into `dir`, returning their paths
+    ///
+    /// Up to 8 objects are downloaded at the same time, so that clients that only need some
+    /// files of a build do not download the rest. Each object is written to `dir/DIGEST`, and
+    /// the paths are returned in the order of `digests`.
+    pub async fn objects(&self, digests: &[ObjectId], dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        let unique: BTreeSet<&ObjectId> = digests.iter().collect();
+        let mut queue = unique.into_iter();
+        let mut running: Vec<TransportFuture<'_, ()>> = Vec::new();
+        loop {
+            while running.len() < MAX_CONCURRENT_OBJECTS {
+                match queue.next() {
+                    Some(digest) => running.push(Box::pin(self.object_to(digest, dir))),
+                    None => break,
+                }
+            }
+            if running.is_empty() {
+                break;
+            }
+
+            let (index, result) = future::poll_fn(|cx| {
+                for (index, object) in running.iter_mut().enumerate() {
+                    if let Poll::Ready(result) = object.as_mut().poll(cx) {
+                        return Poll::Ready((index, result));
+                    }
+                }
+                Poll::Pending
+            })
+            .await;
+            drop(running.swap_remove(index));
+            result?;
+        }
+
+        Ok(digests
+            .iter()
+            .map(|digest| dir.join(digest.to_string()))
+            .collect())
+    }
+
+    /// Download and verify the object with `digest` into `dir/DIGEST`
+    async fn object_to(&self, digest: &ObjectId, dir: &Path) -> Result<(), Error> {
+        let data = self.object(digest).await?;
+        let path = dir.join(digest.to_string());
+        let tmp = dir.join(format!(".{}.partial", digest));
+        tokio::fs::write(&tmp, data).await?;
+        Ok(tokio::fs::rename(tmp, path).await?)
+    }
+
+    /// Download the attestations of the build of `block` by the base32 public keys `attestors`
+    ///
+    /// An attestation is a block signed by an independent rebuilder that refers to the same
+    /// manifest, stored on the mirror as `attestation/DIGEST/KEY`. Attestations that are
+    /// missing or do not verify are skipped, and each key is counted once, so the result has
+    /// one verified block for each attestor that reproduced the build.
+    pub async fn attestations(
+        &self,
+        block: &Block,
+        attestors: &[String],
+    ) -> Result<Vec<Block>, Error> {
+        let mut keys: Vec<PublicKey> = Vec::new();
+        for attestor in attestors.iter() {
+            let key = public_key(attestor)?;
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+
+        let mut attestations = Vec::new();
+        for key in keys.iter() {
+            let path = format!("attestation/{}/{}", block.digest, b32enc(key.as_bytes()));
+            let Ok(data) = self.download(&path).await else {
+                continue;
+            };
+            let verified = PackedBlock::from_bytes(&data)
+                .ok()
+                .and_then(|packed| packed.verify(key).ok());
+            if let Some(attestation) = verified {
+                if attestation.digest == block.digest {
+                    attestations.push(attestation);
+                }
+            }
+        }
+        Ok(attestations)
+    }
+
+    /// Download the [`Annotation`] of `block`, if it has one
+    ///
+    /// The annotation block at `annotation/SIGNATURE` must be signed by a trusted key, and the
+    /// annotation it refers to must name `block`, so an annotation cannot be moved to another
+    /// build.
+    pub async fn annotation(&self, block: &Block) -> Result<Option<Annotation>, Error> {
+        let data = match self
+            .download(&format!("annotation/{}", block.signature))
+            .await
+        {
+            Ok(data) => data,
+            Err(Error::NotFound(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let signed = self.verify(&data)?;
+        let annotation: Annotation = serde_json::from_slice(&self.object(&signed.digest).await?)
+            .map_err(|err| Error::Verify(err_str(err)))?;
+        if annotation.block != block.signature {
+            return Err(Error::Verify(format!(
+                "annotation of block {} is for block {}",
+                block.signature, annotation.block
+            )));
+        }
+        Ok(Some(annotation))
+    }
+
+    /// Download and verify an object, using the copy in `cache` if it has one
+    ///
+    /// Downloaded objects are written to `cache`, so each object is only downloaded once.
+    pub async fn object_cached(&self, digest: &ObjectId, cache: &Store) -> Result<Vec<u8>, Error> {
+        if let Ok(data) = tokio::fs::read(cache.object_path(digest)).await {
+            let sha = Sha384::new(data.as_slice())?;
+            if sha.to_id() == *digest {
+                return Ok(data);
+            }
+        }
+
+        let data = self.fetch_object(digest).await?;
+        cache.write_object(&data)?;
+        Ok(data)
+    }
+
+    /// Update `cache` to the build referenced by `block`
+    ///
+    /// Only objects missing from `cache` are downloaded, so unchanged files are not
+    /// downloaded again, and changed files are rebuilt from their previous version with
+    /// [`Downloader::object_delta`]. The cache's `manifest.json` is then pointed at the new
+    /// manifest.
+    ///
+    /// # Return
+    ///
+    /// The differences from the manifest previously in `cache`
+    pub async fn update(&self, block: &Block, cache: &Store) -> Result<ManifestDiff, Error> {
+        let manifest_json = self.object_cached(&block.digest, cache).await?;
+        let manifest = serde_json::from_slice::<Manifest>(&manifest_json)
+            .map_err(|err| Error::Verify(err_str(err)))?;
+
+        let old = cache.read_manifest()?.unwrap_or_default();
+        let diff = manifest.diff(&old);
+        let names: Vec<&str> = manifest.files.keys().map(String::as_str).collect();
+        self.check_policy(block, &manifest, &names).await?;
+
+        for (name, digest) in manifest.files.iter() {
+            // Changed files are rebuilt from their previous version, if it is cached
+            let basis_opt = match old.files.get(name) {
+                Some(old_digest) if !cache.contains(digest) => {
+                    tokio::fs::read(cache.object_path(old_digest)).await.ok()
+                }
+                _ => None,
+            };
+            let data = match basis_opt {
+                Some(basis) => {
+                    let data = self.object_delta(digest, &basis).await?;
+                    cache.write_object(&data)?;
+                    data
+                }
+                None => self.object_cached(digest, cache).await?,
+            };
+            manifest.verify_file(name, &data).map_err(Error::Verify)?;
+        }
+
+        cache.write_manifest(&manifest_json)?;
+
+        Ok(diff)
+    }
+
+    /// Download and verify the tail block
+    ///
+    /// The last verified tail is cached, and later calls send `If-None-Match` and
+    /// `If-Modified-Since` so that an unchanged tail is answered with `304 Not Modified`. With
+    /// a [`Cache`], the tail and its validators are also kept between Downloaders, and a tail
+    /// older than the cached one, or a different block with the same counter, is refused so a
+    /// mirror cannot roll clients back.
+    pub async fn tail(&self) -> Result<Block, Error> {
+        let path = format!("tail/{}/{}", self.project, self.branch);
+
+        let validators = {
+            let mut tail_cache = self.tail_cache.lock().unwrap();
+            if let (None, Some(cache)) = (tail_cache.as_ref(), &self.cache_opt) {
+                if let Some((data, state)) = cache.read_tail(&self.project, &self.branch)? {
+                    *tail_cache = Some(TailCache {
+                        validators: Validators {
+                            etag: state.etag,
+                            last_modified: state.last_modified,
+                        },
+                        data: data.to_vec(),
+                    });
+                }
+            }
+            match tail_cache.as_ref() {
+                Some(cache) => cache.validators.clone(),
+                None => Validators::default(),
+            }
+        };
+
+        let fetched = self.transport.get_conditional(&path, &validators).await?;
+        let (data, validators) = match fetched {
+            Fetched::NotModified => {
+                let data = match self.tail_cache.lock().unwrap().as_ref() {
+                    Some(cache) => cache.data.clone(),
+                    None => {
+                        return Err(Error::Http(format!(
+                            "{} not modified, but not cached",
+                            path
+                        )))
+                    }
+                };
+                (data, validators)
+            }
+            Fetched::Modified(data, validators) => (data, validators),
+        };
+
+        let block = self.verify(&data)?;
+
+        if let Some(cache) = &self.cache_opt {
+            if let Some((_data, cached)) = cache.read_tail(&self.project, &self.branch)? {
+                if block.counter < cached.counter
+                    || (block.counter == cached.counter && block.signature != cached.signature)
+                {
+                    return Err(Error::Verify(format!(
+                        "{} counter {} does not follow the cached tail, counter {}",
+                        path, block.counter, cached.counter
+                    )));
+                }
+            }
+
+            let state = CacheState {
+                etag: validators.etag.clone(),
+                last_modified: validators.last_modified.clone(),
+                counter: block.counter,
+                signature: block.signature,
+                verified_at: SystemClock.now(),
+            };
+            let packed: &[u8; 400] = data
+                .as_slice()
+                .try_into()
+                .map_err(|_| Error::Verify(format!("{} is not a block", path)))?;
+            cache.write_tail(&self.project, &self.branch, packed, &state)?;
+        }
+        *self.tail_cache.lock().unwrap() = Some(TailCache { validators, data });
+
+        Ok(block)
+    }
+
+    /// Wait up to `timeout` for a tail with a counter greater than `current_counter`
+    ///
+    /// The tail is checked every 30 seconds with conditional requests, so an unchanged tail
+    /// only costs a `304 Not Modified` response. Network errors are retried until the timeout,
+    /// and returned if the last check failed. Returns `None` if the tail was not updated in
+    /// time.
+    pub async fn wait_for_update(
+        &self,
+        current_counter: u64,
+        timeout: Duration,
+    ) -> Result<Option<Block>, Error> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let last_err_opt = match self.tail().await {
+                Ok(block) if block.counter > current_counter => return Ok(Some(block)),
+                Ok(_) => None,
+                Err(Error::Http(err)) => Some(Error::Http(err)),
+                Err(err) => return Err(err),
+            };
+
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return match last_err_opt {
+                    Some(err) => Err(err),
+                    None => Ok(None),
+                };
+            }
+            tokio::time::sleep(UPDATE_POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    /// Download and verify the block with the given signature
+    pub async fn block(&self, signature: &BlockSig) -> Result<Block, Error> {
+        self.block_data(signature).await.map(|(_data, block)| block)
+    }
+
+    /// Download and verify the block with the given signature, returning its data as well
+    async fn block_data(&self, signature: &BlockSig) -> Result<([u8; 400], Block), Error> {
+        let path = format!("block/{}", signature);
+        let data = self.download(&path).await?;
+
+        let block = self.verify(&data)?;
+        if block.signature != *signature {
+            return Err(Error::Verify(format!(
+                "block {} has signature {}",
+                signature, block.signature
+            )));
+        }
+
+        // Verification checks the length
+        Ok((data.as_slice().try_into().unwrap(), block))
+    }
+
+    /// Download the block before `block`, verifying that it is linked by counter
+    async fn previous(&self, block: &Block) -> Result<Block, Error> {
+        self.previous_data(block)
+            .await
+            .map(|(_data, previous)| previous)
+    }
+
+    /// Download the block before `block` like [`Downloader::previous`], returning its data too
+    async fn previous_data(&self, block: &Block) -> Result<([u8; 400], Block), Error> {
+        let (data, previous) = self.block_data(&block.previous_signature).await?;
+        if previous.counter + 1 != block.counter {
+            return Err(Error::Verify(format!(
+                "block {} has counter {}, but previous block {} has counter {}",
+                block.signature, block.counter, previous.signature, previous.counter
+            )));
+        }
+        Ok((data, previous))
+    }
+
+    /// Find a block by walking back from the tail, verifying the linkage of each block
+    ///
+    /// With [`Downloader::set_index`], blocks in the index are downloaded without the walk.
+    pub async fn find_block(&self, pin: &BlockPin) -> Result<Block, Error> {
+        if let Some(indexed) = self.find_indexed(pin) {
+            let block = self.block(&indexed.signature).await?;
+            if block.counter != indexed.counter {
+                return Err(Error::Verify(format!(
+                    "block {} has counter {}, but is indexed with counter {}",
+                    block.signature, block.counter, indexed.counter
+                )));
+            }
+            return Ok(block);
+        }
+
+        let mut block = self.tail().await?;
+        loop {
+            match pin {
+                BlockPin::Counter(counter) => {
+                    if block.counter == *counter {
+                        return Ok(block);
+                    } else if block.counter < *counter {
+                        break;
+                    }
+                }
+                BlockPin::Signature(signature) => {
+                    if block.signature == *signature {
+                        return Ok(block);
+                    }
+                }
+            }
+
+            if block.counter == 0 {
+                break;
+            }
+
+            block = self.previous(&block).await?;
+        }
+
+        Err(Error::NotFound(format!(
+            "{} not found in tail/{}/{}",
+            pin, self.project, self.branch
+        )))
+    }
+
+    /// The entry of the block of `pin` in the index of this Downloader, if it has one
+    fn find_indexed(&self, pin: &BlockPin) -> Option<IndexedBlock> {
+        let index = self.index_opt.as_ref()?;
+        match pin {
+            BlockPin::Counter(counter) => index.find(&self.project, &self.branch, *counter),
+            BlockPin::Signature(signature) => index
+                .blocks(&self.project, &self.branch)
+                .iter()
+                .rev()
+                .find(|block| block.signature == *signature),
+        }
+        .cloned()
+    }
+
+    /// Find the newest build that the device with `seed` should install
+    ///
+    /// Walks back from the tail, skipping builds whose manifest [`crate::Channel`] does not
+    /// include the device. Builds without a channel are available to every device.
+    pub async fn tail_for_device(
+        &self,
+        seed: &[u8],
+        cohort_opt: Option<&str>,
+    ) -> Result<Block, Error> {
+        let mut block = self.tail().await?;
+        loop {
+            let manifest = self.manifest(&block).await?;
+            match &manifest.channel {
+                Some(channel) if !channel.includes(seed, cohort_opt) => (),
+                _ => return Ok(block),
+            }
+
+            if block.counter == 0 || manifest.genesis.is_some() {
+                break;
+            }
+
+            block = self.previous(&block).await?;
+        }
+
+        Err(Error::NotFound(format!(
+            "no build in tail/{}/{} is rolled out to this device",
+            self.project, self.branch
+        )))
+    }
+
+    /// The files of the build referenced by `block` that apply to `device`, and their hashes
+    ///
+    /// Files are matched against the [`Manifest::devices`] of the build, and files it does not
+    /// list apply to every device, so builds without device targets select every file.
+    pub async fn select_for_device(
+        &self,
+        block: &Block,
+        device: &Device,
+    ) -> Result<BTreeMap<String, ObjectId>, Error> {
+        Ok(self.manifest(block).await?.files_for_device(device))
+    }
+
+    /// Copy the verified chain of this branch and its builds into `store`, returning the tail
+    ///
+    /// Blocks are walked back from the tail until one that is already in `store`, the first
+    /// block, or a genesis block, so later syncs only download what is new. The manifest and
+    /// files of each new block are written before the block, and the tail is written last, so
+    /// `store` can be served to clients while it is synced.
+    pub async fn sync_to_store(&self, store: &Store) -> Result<Block, Error> {
+        let tail = self.tail().await?;
+        let tail_data = match self.tail_cache.lock().unwrap().as_ref() {
+            Some(cache) => cache.data.clone(),
+            None => return Err(Error::Verify("tail was not cached".to_string())),
+        };
+        let tail_data: [u8; 400] = tail_data.as_slice().try_into().unwrap();
+
+        let mut blocks = Vec::new();
+        let mut next_opt = Some((tail_data, tail.clone()));
+        while let Some((data, block)) = next_opt.take() {
+            if store.block_path(&block.signature).is_file() {
+                break;
+            }
+
+            let manifest = self.synced_manifest(&block, store).await?;
+            if block.counter > 0 && manifest.genesis.is_none() {
+                next_opt = Some(self.previous_data(&block).await?);
+            }
+            blocks.push(data);
+        }
+
+        // Oldest first, so that every block in the store has its previous block
+        for data in blocks.iter().rev() {
+            store.write_block(data)?;
+        }
+        store.write_tail(&self.project, &self.branch, &tail_data)?;
+
+        Ok(tail)
+    }
+
+    /// Write the manifest of `block` and its files to `store`, verifying each of them
+    async fn synced_manifest(&self, block: &Block, store: &Store) -> Result<Manifest, Error> {
+        let manifest_json = self.object_cached(&block.digest, store).await?;
+        let manifest = serde_json::from_slice::<Manifest>(&manifest_json)
+            .map_err(|err| Error::Verify(err_str(err)))?;
+        let names: Vec<&str> = manifest.files.keys().map(String::as_str).collect();
+        self.check_policy(block, &manifest, &names).await?;
+        for (name, digest) in manifest.files.iter() {
+            let data = self.object_cached(digest, store).await?;
+            manifest.verify_file(name, &data).map_err(Error::Verify)?;
+        }
+        Ok(manifest)
+    }
+
+    /// Download and parse the manifest referenced by `block`
+    async fn manifest(&self, block: &Block) -> Result<Manifest, Error> {
+        let manifest_json = self.object(&block.digest).await?;
+        serde_json::from_slice::<Manifest>(&manifest_json)
+            .map_err(|err| Error::Verify(err_str(err)))
+    }
+
+    /// Walk back from the tail to the [`crate::Genesis`] block of the branch
+    ///
+    /// See [`Downloader::genesis_from`].
+    pub async fn genesis(&self) -> Result<(Block, Genesis), Error> {
+        let tail = self.tail().await?;
+        self.genesis_from(&tail).await
+    }
+
+    /// Walk back from `block` to the [`crate::Genesis`] block of the branch
+    ///
+    /// The genesis must be the first block of the branch, with counter 0 and no previous
+    /// block, be for this project and branch, and use a supported hash. Every block from
+    /// `block` back to the genesis must be signed by one of the keys it lists, so that the
+    /// whole branch is anchored on its recorded policy.
+    pub async fn genesis_from(&self, block: &Block) -> Result<(Block, Genesis), Error> {
+        let mut signers = BTreeSet::new();
+        let mut block = block.clone();
+        loop {
+            signers.insert(block.public_key.clone());
+            if let Some(genesis) = self.manifest(&block).await?.genesis {
+                if block.counter != 0 || block.previous_signature.0 != [0; 64] {
+                    return Err(Error::Verify(format!(
+                        "genesis block {} is not the first block of the branch",
+                        block.signature
+                    )));
+                }
+                if genesis.project != self.project || genesis.branch != self.branch {
+                    return Err(Error::Verify(format!(
+                        "genesis block {} is for {}/{}",
+                        block.signature, genesis.project, genesis.branch
+                    )));
+                }
+                if genesis.hash != "sha384" {
+                    return Err(Error::Verify(format!(
+                        "genesis block {} uses unsupported hash {}",
+                        block.signature, genesis.hash
+                    )));
+                }
+                if let Some(key) = signers.iter().find(|key| !genesis.keys.contains(key)) {
+                    return Err(Error::Verify(format!(
+                        "genesis block {} does not allow key {}",
+                        block.signature, key
+                    )));
+                }
+                return Ok((block, genesis));
+            }
+
+            if block.counter == 0 {
+                break;
+            }
+            block = self.previous(&block).await?;
+        }
+
+        Err(Error::NotFound(format!(
+            "no genesis block in tail/{}/{}",
+            self.project, self.branch
+        )))
+    }
+
+    /// Walk back from the tail, returning up to `limit` verified blocks, newest first
+    ///
+    /// When a manifest records a [`crate::Fork`], the walk continues on the branch it was cut
+    /// from, so the history of a release branch includes the history it was cut from. The walk
+    /// stops at a [`crate::Genesis`] block.
+    pub async fn history(&self, limit: usize) -> Result<Vec<HistoryEntry>, Error> {
+        let mut history = Vec::new();
+        let mut branch = self.branch.clone();
+        let mut block = self.tail().await?;
+        while history.len() < limit {
+            let manifest = self.manifest(&block).await?;
+            history.push(HistoryEntry {
+                branch: branch.clone(),
+                block: block.clone(),
+            });
+            if history.len() == limit || manifest.genesis.is_some() {
+                break;
+            }
+
+            block = match manifest.fork {
+                Some(fork) => {
+                    let parent = self.block(&fork.signature).await?;
+                    if parent.counter >= block.counter {
+                        return Err(Error::Verify(format!(
+                            "block {} has counter {}, but fork block {} has counter {}",
+                            block.signature, block.counter, parent.signature, parent.counter
+                        )));
+                    }
+                    branch = fork.branch;
+                    parent
+                }
+                None if block.counter == 0 => break,
+                None => self.previous(&block).await?,
+            };
+        }
+        Ok(history)
+    }
+
+    /// Download the index of projects and their branches
+    ///
+    /// HTTP mirrors serve this as `tail/index.json`, local mirrors are scanned directly. The
+    /// index is only used for discovery, tails are verified when they are downloaded.
+    pub async fn index(&self) -> Result<BTreeMap<String, Vec<String>>, Error> {
+        self.transport.index().await
+    }
+
+    /// Check that the mirror serves this branch correctly, without caching anything
+    ///
+    /// Misconfigured mirrors otherwise fail deep inside verification with unhelpful errors.
+    /// The tail and its manifest are requested with `HEAD` and `GET`, to compare the length the
+    /// mirror reports with the data it sends, and verified. Part of the manifest is then
+    /// requested with a range request. Checks stop at the first file that cannot be verified.
+    pub async fn probe(&self) -> Vec<ProbeCheck> {
+        let mut checks = Vec::new();
+        match self.transport.index().await {
+            Ok(index) => checks.push(ProbeCheck::new(
+                "tail/index.json",
+                ProbeStatus::Ok,
+                format!("lists {} projects", index.len()),
+            )),
+            Err(err) => checks.push(ProbeCheck::new(
+                "tail/index.json",
+                ProbeStatus::Warning,
+                format!("projects cannot be listed: {}", err),
+            )),
+        }
+
+        let path = format!("tail/{}/{}", self.project, self.branch);
+        let block = match self.probe_file(&path, &mut checks).await {
+            Some((_info, data)) => match self.verify(&data) {
+                Ok(block) => {
+                    checks.push(ProbeCheck::new(
+                        &path,
+                        ProbeStatus::Ok,
+                        format!("verified block with counter {}", block.counter),
+                    ));
+                    block
+                }
+                Err(err) => {
+                    checks.push(ProbeCheck::new(&path, ProbeStatus::Failed, err.to_string()));
+                    return checks;
+                }
+            },
+            None => return checks,
+        };
+
+        let path = format!("object/{}", block.digest);
+        let (info, data) = match self.probe_file(&path, &mut checks).await {
+            Some(some) => some,
+            None => return checks,
+        };
+        if Sha384::new(data.as_slice()).map(|sha| sha.to_id()).ok() != Some(block.digest) {
+            checks.push(ProbeCheck::new(
+                &path,
+                ProbeStatus::Failed,
+                "sha384 mismatch, the mirror may be changing files, such as by compressing them",
+            ));
+            return checks;
+        }
+        checks.push(ProbeCheck::new(
+            &path,
+            ProbeStatus::Ok,
+            format!("verified {} bytes", data.len()),
+        ));
+
+        // Skip the first byte, so that a range starting at 0 is not mistaken for the whole file
+        let range = 1..(data.len() as u64).min(4097);
+        if range.is_empty() {
+            return checks;
+        }
+        let expected = &data[range.start as usize..range.end as usize];
+        checks.push(match self.transport.get_range(&path, range.clone()).await {
+            Ok(part) if part != expected => ProbeCheck::new(
+                &path,
+                ProbeStatus::Failed,
+                format!("range request for {:?} sent the wrong bytes", range),
+            ),
+            Ok(_) if !info.accept_ranges => ProbeCheck::new(
+                &path,
+                ProbeStatus::Warning,
+                "range requests are not supported, delta downloads fetch whole objects",
+            ),
+            Ok(_) => ProbeCheck::new(&path, ProbeStatus::Ok, "range requests are supported"),
+            Err(err) => ProbeCheck::new(
+                &path,
+                ProbeStatus::Failed,
+                format!("range request for {:?} failed: {}", range, err),
+            ),
+        });
+        checks
+    }
+
+    /// Request the file at `path` with `HEAD` and `GET`, recording problems in `checks`
+    ///
+    /// Returns `None` if the file could not be downloaded.
+    async fn probe_file(
+        &self,
+        path: &str,
+        checks: &mut Vec<ProbeCheck>,
+    ) -> Option<(FileInfo, Vec<u8>)> {
+        let head = self.transport.head(path).await;
+        if let Err(err) = &head {
+            checks.push(ProbeCheck::new(
+                path,
+                ProbeStatus::Warning,
+                format!("HEAD request failed: {}", err),
+            ));
+        }
+
+        let data = match self.download(path).await {
+            Ok(data) => data,
+            Err(err) => {
+                checks.push(ProbeCheck::new(path, ProbeStatus::Failed, err.to_string()));
+                return None;
+            }
+        };
+
+        let info = match head {
+            Ok(info) => info,
+            Err(_) => return Some((FileInfo::default(), data)),
+        };
+        match info.length {
+            Some(length) if length != data.len() as u64 => checks.push(ProbeCheck::new(
+                path,
+                ProbeStatus::Failed,
+                format!(
+                    "HEAD reports {} bytes, but {} bytes were sent, the mirror or a proxy may be \
+                     changing files",
+                    length,
+                    data.len()
+                ),
+            )),
+            Some(_) => (),
+            None => checks.push(ProbeCheck::new(
+                path,
+                ProbeStatus::Warning,
+                "no Content-Length is reported, so truncated responses are only found by verification",
+            )),
+        }
+        Some((info, data))
+    }
+
+    /// List the projects available on the mirror
+    pub async fn projects(&self) -> Result<Vec<String>, Error> {
+        Ok(self.index().await?.into_keys().collect())
+    }
+
+    /// List the branches of this project available on the mirror
+    pub async fn branches(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .index()
+            .await?
+            .remove(&self.project)
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::ops::Range;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempfile::TempDir;
+
+    use super::DownloaderBuilder;
+    use crate::block::tests::{publish_chain, signed_block};
+    use crate::id::b32enc;
+    use crate::{
+        BlockPin, BlockSig, Cache, CasTransport, Channel, DeltaSignature, Downloader, Error,
+        FileInfo, Fork, Genesis, Keyring, KeyringEntry, LocalTransport, Manifest, MemoryTransport,
+        ObjectId, ProbeStatus, Role, Sha384, Store, TorrentTransport, Transport, TransportFuture,
+        CAS_INDEX_PATH, TORRENT_INDEX_PATH,
+    };
+
+    /// Publish a chain of `count` blocks to a MemoryTransport, returning the key and signatures
+    fn chain(count: u64) -> (String, Vec<BlockSig>, MemoryTransport) {
+        let transport = MemoryTransport::new();
+        let mut signatures = Vec::new();
+        let mut previous = [0u8; 64];
+        let mut public_key = [0u8; 32];
+        for counter in 0..count {
+            let (key, block) = signed_block(1, &previous, counter, &[counter as u8; 48]);
+            previous.copy_from_slice(&block[..64]);
+            public_key = key;
+
+            let signature = BlockSig(previous);
+            transport.insert(&format!("block/{}", signature), &block);
+            transport.insert("tail/default/master", &block);
+            signatures.push(signature);
+        }
+        (b32enc(&public_key), signatures, transport)
+    }
+
+    #[test]
+    fn test_cache() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let (key, _signatures, transport) = chain(2);
+        let data = b"object";
+        let digest = Sha384::new(&data[..]).unwrap().to_id();
+        transport.insert(&format!("object/{}", digest), data);
+
+        let open = |transport: MemoryTransport| {
+            let mut dl =
+                super::Downloader::from_transport(&key, "default", "master", Box::new(transport))
+                    .unwrap();
+            dl.set_cache(Cache::open(temp_dir.path(), "memory").unwrap());
+            Downloader::from_async(dl).unwrap()
+        };
+        let dl = open(transport);
+        assert_eq!(dl.tail().unwrap().counter, 1);
+        assert_eq!(dl.object(&digest).unwrap(), data);
+        let (_block, state) = dl
+            .cache()
+            .unwrap()
+            .read_tail("default", "master")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.counter, 1);
+
+        // Cached objects are read from the cache by later Downloaders
+        let (_key, _signatures, transport) = chain(2);
+        let dl = open(transport);
+        assert_eq!(dl.object(&digest).unwrap(), data);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_cache_downgrade() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let (key, _signatures, transport) = chain(3);
+        let open = |transport: MemoryTransport| {
+            let mut dl =
+                super::Downloader::from_transport(&key, "default", "master", Box::new(transport))
+                    .unwrap();
+            dl.set_cache(Cache::open(temp_dir.path(), "memory").unwrap());
+            Downloader::from_async(dl).unwrap()
+        };
+        assert_eq!(open(transport).tail().unwrap().counter, 2);
+
+        // An older tail, although validly signed, is refused and not cached
+        let (_key, _signatures, transport) = chain(2);
+        let dl = open(transport);
+        assert!(matches!(dl.tail(), Err(Error::Verify(_))));
+        let (_block, state) = dl
+            .cache()
+            .unwrap()
+            .read_tail("default", "master")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.counter, 2);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_objects() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let (key, _signatures, transport) = chain(1);
+        let mut digests = Vec::new();
+        for index in 0..20 {
+            let data = format!("object {}", index);
+            let digest = Sha384::new(data.as_bytes()).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), data.as_bytes());
+            digests.push(digest);
+        }
+        let missing = Sha384::new(&b"missing"[..]).unwrap().to_id();
+        let corrupt = Sha384::new(&b"corrupt"[..]).unwrap().to_id();
+        transport.insert(&format!("object/{}", corrupt), b"changed");
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        // Duplicates are downloaded once, and returned in order
+        let mut selected = digests.clone();
+        selected.push(digests[0]);
+        let paths = dl.objects(&selected, temp_dir.path()).unwrap();
+        assert_eq!(paths.len(), 21);
+        assert_eq!(paths[20], paths[0]);
+        for (index, path) in paths.iter().take(20).enumerate() {
+            assert_eq!(
+                fs::read_to_string(path).unwrap(),
+                format!("object {}", index)
+            );
+        }
+
+        assert!(matches!(
+            dl.objects(&[digests[0], missing], temp_dir.path()),
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            dl.objects(&[corrupt], temp_dir.path()),
+            Err(Error::Verify(_))
+        ));
+        assert!(!temp_dir.path().join(corrupt.to_string()).exists());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_cas_transport() {
+        let (key, _signatures, mirror) = chain(1);
+        let gateway = MemoryTransport::new();
+        let mut index = BTreeMap::new();
+        let digest = |data: &str| Sha384::new(data.as_bytes()).unwrap().to_id();
+
+        // Only published to the gateway
+        index.insert(digest("gateway"), "QmGateway".to_string());
+        gateway.insert("ipfs/QmGateway", b"gateway");
+        // Only on the mirror
+        mirror.insert(&format!("object/{}", digest("mirror")), b"mirror");
+        // Corrupted by the gateway, so fetched from the mirror
+        index.insert(digest("corrupt"), "QmCorrupt".to_string());
+        gateway.insert("ipfs/QmCorrupt", b"changed");
+        mirror.insert(&format!("object/{}", digest("corrupt")), b"corrupt");
+        mirror.insert(CAS_INDEX_PATH, &serde_json::to_vec(&index).unwrap());
+
+        let transport = CasTransport::new(Box::new(mirror), Box::new(gateway));
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        assert_eq!(dl.tail().unwrap().counter, 0);
+        for data in ["gateway", "mirror", "corrupt"] {
+            assert_eq!(dl.object(&digest(data)).unwrap(), data.as_bytes());
+        }
+        assert!(matches!(
+            dl.object(&digest("missing")),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_torrent_transport() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let (key, _signatures, mirror) = chain(1);
+        let data = b"large object";
+        let digest = Sha384::new(&data[..]).unwrap().to_id();
+        mirror.insert(&format!("torrent/{}.torrent", digest), b"torrent");
+        mirror.insert(
+            TORRENT_INDEX_PATH,
+            &serde_json::to_vec(&BTreeMap::from([(digest, "magnet")])).unwrap(),
+        );
+
+        // The "client" copies the object from a peer
+        let peer = temp_dir.path().join(digest.to_string());
+        fs::write(&peer, data).unwrap();
+        let command = vec![
+            "cp".to_string(),
+            peer.to_string_lossy().into_owned(),
+            "{dir}".to_string(),
+        ];
+        let transport = TorrentTransport::new(Box::new(mirror), command);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        assert_eq!(dl.object(&digest).unwrap(), data);
+
+        // Failed downloads from peers fall back to the mirror
+        let (_key, _signatures, mirror) = chain(1);
+        mirror.insert(&format!("object/{}", digest), data);
+        mirror.insert(&format!("torrent/{}.torrent", digest), b"torrent");
+        mirror.insert(
+            TORRENT_INDEX_PATH,
+            &serde_json::to_vec(&BTreeMap::from([(digest, "magnet")])).unwrap(),
+        );
+        let transport = TorrentTransport::new(Box::new(mirror), vec!["false".to_string()]);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        assert_eq!(dl.object(&digest).unwrap(), data);
+
+        temp_dir.close().unwrap();
+    }
+
+    /// A mirror in memory that records ranged requests
+    struct RangeTransport {
+        inner: MemoryTransport,
+        ranges: Arc<Mutex<Vec<Range<u64>>>>,
+    }
+
+    impl Transport for RangeTransport {
+        fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>> {
+            self.inner.get(path)
+        }
+
+        fn get_range<'a>(
+            &'a self,
+            path: &'a str,
+            range: Range<u64>,
+        ) -> TransportFuture<'a, Vec<u8>> {
+            self.ranges.lock().unwrap().push(range.clone());
+            self.inner.get_range(path, range)
+        }
+
+        fn head<'a>(&'a self, path: &'a str) -> TransportFuture<'a, FileInfo> {
+            self.inner.head(path)
+        }
+    }
+
+    #[test]
+    fn test_object_delta() {
+        let (key, _signatures, mirror) = chain(1);
+        let old: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new[40_000] ^= 1;
+        let digest = Sha384::new(new.as_slice()).unwrap().to_id();
+        mirror.insert(&format!("object/{}", digest), &new);
+        let signature = DeltaSignature::new(new.len() as u64, new.as_slice()).unwrap();
+        mirror.insert(&format!("zsync/{}", digest), &signature.to_bytes());
+
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let transport = RangeTransport {
+            inner: mirror,
+            ranges: ranges.clone(),
+        };
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        // Only the changed block is downloaded
+        assert_eq!(dl.object_delta(&digest, &old).unwrap(), new);
+        let block_size = signature.block_size as u64;
+        let start = 40_000 / block_size * block_size;
+        let ranges = ranges.lock().unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], start..start + block_size);
+
+        // Objects without a signature are downloaded whole
+        let data = b"unsigned";
+        let unsigned = Sha384::new(&data[..]).unwrap().to_id();
+        let (_key, _signatures, mirror) = chain(1);
+        mirror.insert(&format!("object/{}", unsigned), data);
+        let dl = Downloader::from_transport(&key, "default", "master", Box::new(mirror)).unwrap();
+        assert_eq!(dl.object_delta(&unsigned, &old).unwrap(), data);
+
+        // Signatures with the wrong length are not used
+        let (_key, _signatures, mirror) = chain(1);
+        mirror.insert(&format!("object/{}", digest), &new);
+        let long = [new.as_slice(), new.as_slice()].concat();
+        let signature = DeltaSignature::new(long.len() as u64, long.as_slice()).unwrap();
+        mirror.insert(&format!("zsync/{}", digest), &signature.to_bytes());
+        let dl = Downloader::from_transport(&key, "default", "master", Box::new(mirror)).unwrap();
+        assert_eq!(dl.object_delta(&digest, &old).unwrap(), new);
+    }
+
+    #[test]
+    fn test_probe() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let manifest = serde_json::to_vec(&Manifest::default()).unwrap();
+        let manifest_key = store.write_object(&manifest).unwrap();
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &manifest_key);
+        store.write_tail("default", "master", &block).unwrap();
+
+        let dl = Downloader::new(
+            &b32enc(&public_key),
+            temp_dir.path().to_str().unwrap(),
+            "default",
+            "master",
+            None,
+        )
+        .unwrap();
+        let checks = dl.probe();
+        assert_eq!(checks.len(), 4);
+        assert!(checks.iter().all(|check| check.status == ProbeStatus::Ok));
+
+        // A changed object is reported, and stops the checks
+        let path = store.object_path(&manifest_key);
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, b"{}").unwrap();
+        let checks = dl.probe();
+        assert_eq!(checks.len(), 3);
+        assert_eq!(checks[2].status, ProbeStatus::Failed);
+        assert!(checks[2].message.starts_with("sha384 mismatch"));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_update() {
+        let (key, _signatures, transport) = chain(3);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        let block = dl.wait_for_update(1, Duration::from_secs(60)).unwrap();
+        assert_eq!(block.map(|block| block.counter), Some(2));
+        assert!(dl
+            .wait_for_update(2, Duration::from_millis(10))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_attestations() {
+        let (key, _signatures, transport) = chain(2);
+        let (first, attestation) = signed_block(2, &[0; 64], 0, &[1; 48]);
+        let (second, _) = signed_block(3, &[0; 64], 0, &[1; 48]);
+        let (third, stale) = signed_block(4, &[0; 64], 0, &[0; 48]);
+        let tail_digest = b32enc(&[1; 48]);
+        transport.insert(
+            &format!("attestation/{}/{}", tail_digest, b32enc(&first)),
+            &attestation,
+        );
+        // Attestations of another manifest, or by another key, do not count
+        transport.insert(
+            &format!("attestation/{}/{}", tail_digest, b32enc(&third)),
+            &stale,
+        );
+        transport.insert(
+            &format!("attestation/{}/{}", tail_digest, b32enc(&second)),
+            &attestation,
+        );
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        let tail = dl.tail().unwrap();
+        let attestors = [first, first, second, third].map(|key| b32enc(&key));
+        let attestations = dl.attestations(&tail, &attestors).unwrap();
+        assert_eq!(attestations.len(), 1);
+        assert_eq!(attestations[0].public_key, b32enc(&first));
+        assert!(matches!(
+            dl.attestations(&tail, &["invalid".to_string()]),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_tail() {
+        let (key, signatures, transport) = chain(3);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        let tail = dl.tail().unwrap();
+        assert_eq!(tail.counter, 2);
+        assert_eq!(tail.signature, signatures[2]);
+        assert_eq!(tail.previous_signature, signatures[1]);
+    }
+
+    #[test]
+    fn test_tail_wrong_key() {
+        let (key, _signatures, transport) = chain(1);
+        let (other_key, _block) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        let dl = Downloader::from_transport(
+            &b32enc(&other_key),
+            "default",
+            "master",
+            Box::new(transport),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            dl.tail(),
+            Err(Error::Verify(message))
+                if message == format!("block is signed by {}, which is not trusted", key)
+        ));
+
+        // Keys must be 32 bytes
+        assert!(matches!(
+            Downloader::from_transport(
+                &b32enc(&[0; 31]),
+                "default",
+                "master",
+                Box::new(MemoryTransport::new()),
+            ),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_find_block() {
+        let (key, signatures, transport) = chain(4);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        let block = dl.find_block(&BlockPin::Counter(1)).unwrap();
+        assert_eq!(block.signature, signatures[1]);
+
+        let block = dl.find_block(&BlockPin::Signature(signatures[0])).unwrap();
+        assert_eq!(block.counter, 0);
+
+        assert!(dl.find_block(&BlockPin::Counter(7)).is_err());
+        assert!(dl
+            .find_block(&BlockPin::Signature(BlockSig([1; 64])))
+            .is_err());
+    }
+
+    #[test]
+    fn test_find_block_broken_link() {
+        let (key, signatures, transport) = chain(3);
+        transport.remove(&format!("block/{}", signatures[1]));
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        assert_eq!(dl.find_block(&BlockPin::Counter(2)).unwrap().counter, 2);
+        assert!(dl.find_block(&BlockPin::Counter(0)).is_err());
+    }
+
+    #[test]
+    fn test_tail_for_device() {
+        let transport = MemoryTransport::new();
+        let mut previous = [0u8; 64];
+        let mut public_key = [0u8; 32];
+        for (counter, rollout) in [(0, 100), (1, 0)] {
+            let manifest = Manifest {
+                time: counter,
+                channel: Some(Channel {
+                    rollout,
+                    cohorts: vec!["beta".to_string()],
+                }),
+                ..Default::default()
+            };
+            let json = serde_json::to_vec(&manifest).unwrap();
+            let digest = Sha384::new(json.as_slice()).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), &json);
+
+            let (key, block) = signed_block(1, &previous, counter, &digest);
+            previous.copy_from_slice(&block[..64]);
+            public_key = key;
+            transport.insert(&format!("block/{}", b32enc(&previous)), &block);
+            transport.insert("tail/default/master", &block);
+        }
+        let dl = Downloader::from_transport(
+            &b32enc(&public_key),
+            "default",
+            "master",
+            Box::new(transport),
+        )
+        .unwrap();
+
+        assert_eq!(dl.tail_for_device(b"device", None).unwrap().counter, 0);
+        assert_eq!(
+            dl.tail_for_device(b"device", Some("beta")).unwrap().counter,
+            1
+        );
+    }
+
+    #[test]
+    fn test_select_for_device() {
+        let transport = MemoryTransport::new();
+        let manifest = Manifest {
+            files: BTreeMap::from([
+                ("common.txt".to_string(), ObjectId([1; 48])),
+                ("galp5.rom".to_string(), ObjectId([2; 48])),
+            ]),
+            devices: BTreeMap::from([("galp5.rom".to_string(), vec!["galp5".parse().unwrap()])]),
+            ..Default::default()
+        };
+        let json = serde_json::to_vec(&manifest).unwrap();
+        let digest = Sha384::new(json.as_slice()).unwrap().to_id();
+        transport.insert(&format!("object/{}", digest), &json);
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &digest);
+        transport.insert("tail/default/master", &block);
+        let dl = Downloader::from_transport(
+            &b32enc(&public_key),
+            "default",
+            "master",
+            Box::new(transport),
+        )
+        .unwrap();
+
+        let tail = dl.tail().unwrap();
+        let names = |device: &str| -> Vec<String> {
+            dl.select_for_device(&tail, &device.parse().unwrap())
+                .unwrap()
+                .into_keys()
+                .collect()
+        };
+        assert_eq!(names("galp5:A1"), ["common.txt", "galp5.rom"]);
+        assert_eq!(names("oryp6"), ["common.txt"]);
+    }
+
+    #[test]
+    fn test_history_fork() {
+        let transport = MemoryTransport::new();
+        let mut previous = [0u8; 64];
+        let mut public_key = [0u8; 32];
+        let mut signatures: Vec<BlockSig> = Vec::new();
+        for counter in 0..4 {
+            let (branch, fork) = if counter == 3 {
+                let fork = Fork {
+                    branch: "master".to_string(),
+                    signature: signatures[1],
+                };
+                ("release", Some(fork))
+            } else {
+                ("master", None)
+            };
+            let manifest = Manifest {
+                time: counter,
+                fork,
+                ..Default::default()
+            };
+            let json = serde_json::to_vec(&manifest).unwrap();
+            let digest = Sha384::new(json.as_slice()).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), &json);
+
+            let (key, block) = signed_block(1, &previous, counter, &digest);
+            previous.copy_from_slice(&block[..64]);
+            public_key = key;
+            signatures.push(BlockSig(previous));
+            transport.insert(&format!("block/{}", b32enc(&previous)), &block);
+            transport.insert(&format!("tail/default/{}", branch), &block);
+        }
+        let dl = Downloader::from_transport(
+            &b32enc(&public_key),
+            "default",
+            "release",
+            Box::new(transport),
+        )
+        .unwrap();
+
+        let history: Vec<_> = dl
+            .history(10)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.branch, entry.block.counter))
+            .collect();
+        assert_eq!(
+            history,
+            [
+                ("release".to_string(), 3),
+                ("master".to_string(), 1),
+                ("master".to_string(), 0)
+            ]
+        );
+        assert_eq!(dl.history(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_genesis() {
+        let (first, _block) = signed_block(1, &[0; 64], 0, &[0; 48]);
+        let (second, _block) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        let genesis = |time| Manifest {
+            time,
+            genesis: Some(Genesis {
+                project: "default".to_string(),
+                branch: "master".to_string(),
+                hash: "sha384".to_string(),
+                keys: vec![b32enc(&first)],
+            }),
+            ..Default::default()
+        };
+        let open = |key: &str, transport: MemoryTransport| {
+            Downloader::from_transport(key, "default", "master", Box::new(transport)).unwrap()
+        };
+
+        let transport = MemoryTransport::new();
+        let key = publish_chain(&transport, &[genesis(0), Manifest::default()]);
+        let dl = open(&key, transport);
+        let (block, genesis_manifest) = dl.genesis().unwrap();
+        assert_eq!(block.counter, 0);
+        assert_eq!(genesis_manifest.keys, [key]);
+        let tail = dl.tail().unwrap();
+        assert_eq!(dl.genesis_from(&tail).unwrap().0.signature, block.signature);
+
+        // History is not followed past the genesis block
+        assert_eq!(dl.history(10).unwrap().len(), 2);
+
+        // A genesis block must be the first block of the branch
+        let transport = MemoryTransport::new();
+        let key = publish_chain(&transport, &[Manifest::default(), genesis(1)]);
+        assert!(matches!(
+            open(&key, transport).genesis(),
+            Err(Error::Verify(_))
+        ));
+
+        // Every block must be signed by a key the genesis allows
+        let transport = MemoryTransport::new();
+        let mut previous = [0u8; 64];
+        for (counter, (seed, manifest)) in [(1, genesis(0)), (2, Manifest::default())]
+            .into_iter()
+            .enumerate()
+        {
+            let json = serde_json::to_vec(&manifest).unwrap();
+            let digest = Sha384::new(json.as_slice()).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), &json);
+            let (_key, block) = signed_block(seed, &previous, counter as u64, &digest);
+            previous.copy_from_slice(&block[..64]);
+            transport.insert(&format!("block/{}", b32enc(&previous)), &block);
+            transport.insert("tail/default/master", &block);
+        }
+        let mut dl = open(&b32enc(&first), transport);
+        dl.add_key(&b32enc(&second)).unwrap();
+        assert_eq!(dl.tail().unwrap().public_key, b32enc(&second));
+        assert!(matches!(dl.genesis(), Err(Error::Verify(_))));
+    }
+
+    #[test]
+    fn test_tail_rotated_key() {
+        let (key, _signatures, transport) = chain(2);
+        let (other_key, _block) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        let mut dl = Downloader::from_transport(
+            &b32enc(&other_key),
+            "default",
+            "master",
+            Box::new(transport),
+        )
+        .unwrap();
+        assert!(dl.tail().is_err());
+
+        dl.add_key(&key).unwrap();
+        let tail = dl.tail().unwrap();
+        assert_eq!(tail.public_key, key);
+        assert_eq!(
+            dl.find_block(&BlockPin::Counter(0)).unwrap().public_key,
+            key
+        );
+    }
+
+    #[test]
+    fn test_tail_keyring() {
+        let (key, _signatures, transport) = chain(2);
+        let (other_key, _block) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        let mut dl = Downloader::from_transport(
+            &b32enc(&other_key),
+            "default",
+            "master",
+            Box::new(transport),
+        )
+        .unwrap();
+
+        // The tail has timestamp 1_500_000_001, after the key expired
+        let mut keyring = Keyring::default();
+        keyring.add(KeyringEntry {
+            key: key.clone(),
+            role: Role::Signer,
+            not_before: None,
+            not_after: Some(1_500_000_000),
+        });
+        dl.add_keyring(&keyring).unwrap();
+        assert!(matches!(
+            dl.tail(),
+            Err(Error::Verify(message)) if message.starts_with("key")
+        ));
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        // Connections are queued by the kernel, but the server never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let dl = DownloaderBuilder::new(&b32enc(&[0; 32]), &url)
+            .read_timeout(Some(Duration::from_millis(100)))
+            .build_blocking()
+            .unwrap();
+        assert!(matches!(
+            dl.tail(),
+            Err(Error::Http(message)) if message.contains("timed out")
+        ));
+    }
+
+    #[test]
+    fn test_compression() {
+        let data = vec![b'x'; 4096];
+        let digest = Sha384::new(data.as_slice()).unwrap().to_id();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzip = encoder.finish().unwrap();
+
+        // Answer each request with gzip if it is accepted, recording the Accept-Encoding
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let plain = data.clone();
+        let server = thread::spawn(move || {
+            let mut accepted = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _address) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                let gzip_accepted = String::from_utf8(request)
+                    .unwrap()
+                    .lines()
+                    .any(|line| line.starts_with("accept-encoding:") && line.contains("gzip"));
+                let (encoding, body) = if gzip_accepted {
+                    ("Content-Encoding: gzip\r\n", &gzip)
+                } else {
+                    ("", &plain)
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    encoding,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+                accepted.push(gzip_accepted);
+            }
+            accepted
+        });
+
+        // The digest is checked against the decoded bytes
+        for compression in [true, false] {
+            let dl = DownloaderBuilder::new(&b32enc(&[0; 32]), &url)
+                .compression(compression)
+                .build_blocking()
+                .unwrap();
+            assert_eq!(dl.object(&digest).unwrap(), data);
+        }
+        assert_eq!(server.join().unwrap(), [true, false]);
+    }
+
+    #[test]
+    fn test_sync_to_store() {
+        // Publish builds with one file each, returning the key and a downloader
+        let publish = |count: u64| {
+            let transport = MemoryTransport::new();
+            let manifests: Vec<Manifest> = (0..count)
+                .map(|counter| {
+                    let file = format!("file {}", counter);
+                    let digest = Sha384::new(file.as_bytes()).unwrap().to_id();
+                    transport.insert(&format!("object/{}", digest), file.as_bytes());
+                    let mut manifest = Manifest::default();
+                    manifest.files.insert("file".to_string(), digest);
+                    manifest
+                })
+                .collect();
+            let key = publish_chain(&transport, &manifests);
+            let dl =
+                Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+            (key, dl)
+        };
+
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let (key, dl) = publish(2);
+        assert_eq!(dl.sync_to_store(&store).unwrap().counter, 1);
+
+        // The store serves the chain and its builds
+        let mirror = Downloader::from_transport(
+            &key,
+            "default",
+            "master",
+            Box::new(LocalTransport::new(temp_dir.path())),
+        )
+        .unwrap();
+        let tail = mirror.tail().unwrap();
+        assert_eq!(tail.counter, 1);
+        let first = mirror.block(&tail.previous_signature).unwrap();
+        let manifest: Manifest =
+            serde_json::from_slice(&mirror.object(&first.digest).unwrap()).unwrap();
+        assert_eq!(mirror.object(&manifest.files["file"]).unwrap(), b"file 0");
+
+        // Later syncs stop at the blocks already in the store
+        let (_key, dl) = publish(3);
+        fs::remove_file(store.block_path(&first.signature)).unwrap();
+        assert_eq!(dl.sync_to_store(&store).unwrap().counter, 2);
+        assert_eq!(mirror.tail().unwrap().counter, 2);
+        assert!(mirror.block(&first.signature).is_err());
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fs;
+use std::io;
+
+use crate::id::{b32dec, b32enc};
+use crate::verify::verify_block;
+use crate::{sign_manifest, Error, Sha384, Store};
+
+pub struct AttestArguments<'a> {
+    pub store_path: &'a str,
+    /// The base32 public key of the rebuilder, which must sign the attestation
+    pub key: &'a str,
+}
+
+/// Attest the manifest of an independent rebuild in `store`, using `sign` to produce the block
+///
+/// The attestation is written to `attestation/DIGEST/KEY`. Copied to the mirror of the
+/// original build, it lets clients require that the build was reproduced, see
+/// [`crate::DownloadOptions::attestor`].
+pub(crate) fn attest_store<F>(store: &Store, args: &AttestArguments, sign: F) -> Result<(), Error>
+where
+    F: FnOnce(&[u8]) -> io::Result<[u8; 400]>,
+{
+    let key: [u8; 32] = b32dec(args.key)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::Config("key is not a base32 public key".to_string()))?;
+
+    let manifest_json = fs::read(store.path().join("manifest.json")).map_err(|err| {
+        Error::NotFound(format!(
+            "manifest.json of {}: {}",
+            store.path().display(),
+            err
+        ))
+    })?;
+    let digest = Sha384::new(manifest_json.as_slice())?.to_base32();
+
+    let response = sign(&manifest_json).map_err(Error::Sign)?;
+    let verified = verify_block(&response, &key)
+        .map_err(|err| Error::Verify(format!("attestation: {}", err)))?;
+    if b32enc(verified.digest()) != digest {
+        return Err(Error::Verify(
+            "attestation does not refer to the manifest".to_string(),
+        ));
+    }
+
+    store.write_attestation(&response)?;
+    println!(
+        "buildchain: attested manifest {} with key {}",
+        digest, args.key
+    );
+    Ok(())
+}
+
+/// Sign the manifest of a rebuild with PiHSM, attesting that the build was reproduced
+pub fn attest(args: AttestArguments) -> Result<(), Error> {
+    let store = Store::open(args.store_path)?;
+    attest_store(&store, &args, sign_manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{attest_store, AttestArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::verify::verify_block;
+    use crate::{Error, Manifest, Store};
+
+    #[test]
+    fn test_attest() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let json = serde_json::to_vec_pretty(&Manifest::default()).unwrap();
+        let digest = store.write_manifest(&json).unwrap();
+        let (public_key, _block) = signed_block(1, &[0; 64], 0, &[0; 48]);
+        let key = b32enc(&public_key);
+        let args = AttestArguments {
+            store_path: "",
+            key: &key,
+        };
+
+        let sign = |_: &[u8]| Ok(signed_block(1, &[0; 64], 0, &digest).1);
+        attest_store(&store, &args, sign).unwrap();
+        let data = std::fs::read(store.attestation_path(&digest, &public_key)).unwrap();
+        let verified = verify_block(data.as_slice().try_into().unwrap(), &public_key).unwrap();
+        assert_eq!(*verified.digest(), *digest);
+
+        // The attestation must refer to the manifest of the store
+        let sign = |_: &[u8]| Ok(signed_block(1, &[0; 64], 0, &[1; 48]).1);
+        assert!(matches!(
+            attest_store(&store, &args, sign),
+            Err(Error::Verify(_))
+        ));
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -1,73 +1,235 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
-use plain::{self, Plain};
 use serde::{Deserialize, Serialize};
-use sodalite::sign_attached_open;
-
-use crate::store::b32enc;
-
-#[allow(dead_code)]
-#[repr(packed)]
-pub(crate) struct PackedBlockRequest {
-    signature: [u8; 64],
-    public_key: [u8; 32],
-    previous_signature: [u8; 64],
-    counter: [u8; 8],
-    timestamp: [u8; 8],
-    digest: [u8; 48],
-}
 
-#[repr(packed)]
-pub(crate) struct PackedBlock {
-    signature: [u8; 64],
-    public_key: [u8; 32],
-    previous_signature: [u8; 64],
-    counter: u64,
-    timestamp: u64,
-    request: PackedBlockRequest,
+use crate::id::b32enc;
+use crate::verify::{
+    u64_le, verify_block, PublicKey, VerifyError, BLOCK_SIZE, COUNTER, DIGEST, PREVIOUS_SIGNATURE,
+    PUBLIC_KEY, SIGNATURE, TIMESTAMP,
+};
+use crate::{BlockSig, ObjectId};
+
+/// A block in its wire format, checked to be exactly [`BLOCK_SIZE`] bytes
+///
+/// A block is the signature, followed by the signed message: the public key, the previous
+/// signature, the little-endian counter and timestamp, and the request from the builder, which
+/// ends with the digest of the manifest.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PackedBlock<'a> {
+    data: &'a [u8; BLOCK_SIZE],
 }
 
-unsafe impl Plain for PackedBlock {}
+#[cfg_attr(not(feature = "download"), allow(dead_code))]
+impl<'a> PackedBlock<'a> {
+    /// Check the length of `data`, which may come from an untrusted mirror or signer
+    pub(crate) fn from_bytes(data: &'a [u8]) -> Result<PackedBlock<'a>, String> {
+        let data = data.try_into().map_err(|_| {
+            format!(
+                "block is {} bytes, expected {} bytes",
+                data.len(),
+                BLOCK_SIZE
+            )
+        })?;
+        Ok(PackedBlock { data })
+    }
+
+    /// The public key the block claims to be signed by, used to select the key to verify with
+    pub(crate) fn public_key(&self) -> &'a [u8] {
+        &self.data[PUBLIC_KEY]
+    }
 
-impl PackedBlock {
     // Convert to a usable struct through verification
-    pub(crate) fn verify(&self, key: &[u8]) -> Result<Block, String> {
-        if self.public_key != key {
-            return Err("public key mismatch".to_string());
+    pub(crate) fn verify(&self, key: &PublicKey) -> Result<Block, VerifyError> {
+        verify_block(self.data, key.as_bytes()).map(|block| block.to_block())
+    }
+}
+
+/// The fields of a signed block
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Block {
+    /// The signature over the rest of the block, which names the block
+    pub signature: BlockSig,
+    /// The base32 public key that signed the block
+    pub public_key: String,
+    /// The signature of the previous block in the chain
+    pub previous_signature: BlockSig,
+    /// The position of the block in the chain
+    pub counter: u64,
+    /// The time the block was signed, in seconds since the Unix epoch
+    pub timestamp: u64,
+    /// The digest of the manifest
+    pub digest: ObjectId,
+}
+
+impl Block {
+    /// Decode the fields of a signed block without verifying its signature
+    pub(crate) fn from_unverified(data: &[u8; BLOCK_SIZE]) -> Block {
+        Block {
+            signature: BlockSig::try_from(&data[SIGNATURE]).unwrap(),
+            public_key: b32enc(&data[PUBLIC_KEY]),
+            previous_signature: BlockSig::try_from(&data[PREVIOUS_SIGNATURE]).unwrap(),
+            counter: u64_le(data, COUNTER),
+            timestamp: u64_le(data, TIMESTAMP),
+            digest: ObjectId::try_from(&data[DIGEST]).unwrap(),
         }
+    }
+}
 
-        {
-            let sm = unsafe { plain::as_bytes(self) };
+#[cfg(test)]
+#[cfg_attr(not(feature = "download"), allow(dead_code))]
+pub(crate) mod tests {
+    use sodalite::{sign_attached, sign_keypair_seed};
 
-            let mut m = vec![0; sm.len()];
-            match sign_attached_open(&mut m, sm, &self.public_key) {
-                Ok(count) => m.truncate(count),
-                Err(()) => return Err("signature invalid".to_string()),
-            }
+    use super::{Block, PackedBlock};
+    use crate::id::b32enc;
+    use crate::verify::{
+        PublicKey, VerifyError, BLOCK_SIZE, COUNTER, DIGEST, PREVIOUS_SIGNATURE, PUBLIC_KEY,
+        SIGNATURE, TIMESTAMP,
+    };
+    use crate::{BlockSig, ObjectId};
+    #[cfg(feature = "download")]
+    use crate::{Manifest, MemoryTransport, Sha384};
 
-            // Check that message matches signed message after skipping the signature
-            if m != sm[64..] {
-                return Err("message data invalid".to_string());
-            }
+    /// Sign a block with the key generated from `seed`, returning the public key and block
+    pub(crate) fn signed_block(
+        seed: u8,
+        previous: &[u8; 64],
+        counter: u64,
+        digest: &[u8; 48],
+    ) -> ([u8; 32], [u8; 400]) {
+        let mut public_key = [0u8; 32];
+        let mut secret_key = [0u8; 64];
+        sign_keypair_seed(&mut public_key, &mut secret_key, &[seed; 32]);
+
+        let mut message = [0u8; 336];
+        message[..32].copy_from_slice(&public_key);
+        message[32..96].copy_from_slice(previous);
+        message[96..104].copy_from_slice(&counter.to_le_bytes());
+        message[104..112].copy_from_slice(&(1_500_000_000 + counter).to_le_bytes());
+        message[288..].copy_from_slice(digest);
+
+        let mut block = [0u8; 400];
+        sign_attached(&mut block, &message, &secret_key);
+        (public_key, block)
+    }
+
+    /// Pseudo-random data from a seeded LCG, for chunking and delta tests
+    pub(crate) fn random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    /// Publish `manifests` to `transport` as a chain of tails of `default/master` counting from
+    /// 0, signed with the key generated from seed 1, returning the base32 public key
+    #[cfg(feature = "download")]
+    pub(crate) fn publish_chain(transport: &MemoryTransport, manifests: &[Manifest]) -> String {
+        let mut previous = [0u8; 64];
+        let mut public_key = [0u8; 32];
+        for (counter, manifest) in manifests.iter().enumerate() {
+            let json = serde_json::to_vec_pretty(manifest).unwrap();
+            let digest = Sha384::new(json.as_slice()).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), &json);
+
+            let (key, block) = signed_block(1, &previous, counter as u64, &digest);
+            previous.copy_from_slice(&block[..64]);
+            public_key = key;
+            transport.insert(&format!("block/{}", b32enc(&previous)), &block);
+            transport.insert("tail/default/master", &block);
         }
+        b32enc(&public_key)
+    }
 
-        Ok(Block {
-            signature: b32enc(&self.signature),
-            public_key: b32enc(&self.public_key),
-            previous_signature: b32enc(&self.previous_signature),
-            counter: u64::from_le(self.counter),
-            timestamp: u64::from_le(self.timestamp),
-            digest: b32enc(&self.request.digest),
-        })
+    #[test]
+    fn test_packed_block_length() {
+        let (key, block) = signed_block(1, &[0; 64], 0, &[0; 48]);
+        let packed = PackedBlock::from_bytes(&block).unwrap();
+        assert!(packed.verify(&PublicKey::from(key)).is_ok());
+
+        let mut long = block.to_vec();
+        long.push(0);
+        for data in [&[][..], &block[..1], &block[..BLOCK_SIZE - 1], &long[..]] {
+            assert_eq!(
+                PackedBlock::from_bytes(data).unwrap_err(),
+                format!("block is {} bytes, expected 400 bytes", data.len())
+            );
+        }
     }
-}
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Block {
-    pub signature: String,
-    pub public_key: String,
-    pub previous_signature: String,
-    pub counter: u64,
-    pub timestamp: u64,
-    pub digest: String,
+    #[test]
+    fn test_packed_block_fields() {
+        let counter = 0x0102_0304_0506_0708;
+        let (key, block) = signed_block(1, &[2; 64], counter, &[3; 48]);
+        let key = PublicKey::from(key);
+
+        // Integers are little-endian
+        assert_eq!(block[COUNTER], [8, 7, 6, 5, 4, 3, 2, 1]);
+
+        let verified = PackedBlock::from_bytes(&block)
+            .unwrap()
+            .verify(&key)
+            .unwrap();
+        assert_eq!(verified.signature.as_ref(), &block[SIGNATURE]);
+        assert_eq!(verified.public_key, b32enc(key.as_bytes()));
+        assert_eq!(verified.previous_signature, BlockSig([2; 64]));
+        assert_eq!(verified.counter, counter);
+        assert_eq!(verified.timestamp, 1_500_000_000 + counter);
+        assert_eq!(verified.digest, ObjectId([3; 48]));
+
+        let unverified = Block::from_unverified(&block);
+        assert_eq!(unverified.counter, verified.counter);
+        assert_eq!(unverified.digest, verified.digest);
+    }
+
+    #[test]
+    fn test_packed_block_corrupted() {
+        let (key, block) = signed_block(1, &[2; 64], 3, &[4; 48]);
+        let key = PublicKey::from(key);
+
+        // Corrupting the first or last byte of any field fails verification
+        let request = TIMESTAMP.end..DIGEST.start;
+        for field in [
+            SIGNATURE,
+            PUBLIC_KEY,
+            PREVIOUS_SIGNATURE,
+            COUNTER,
+            TIMESTAMP,
+            request,
+            DIGEST,
+        ] {
+            for offset in [field.start, field.end - 1] {
+                let mut corrupted = block;
+                corrupted[offset] ^= 0x80;
+                let packed = PackedBlock::from_bytes(&corrupted).unwrap();
+                let err = packed.verify(&key).unwrap_err();
+                if PUBLIC_KEY.contains(&offset) {
+                    assert_eq!(err, VerifyError::PublicKeyMismatch);
+                } else {
+                    assert_eq!(err, VerifyError::SignatureInvalid, "byte {}", offset);
+                }
+            }
+        }
+
+        // A block that is entirely zeroes or ones is rejected
+        for fill in [0, 0xff] {
+            let packed_data = [fill; BLOCK_SIZE];
+            let packed = PackedBlock::from_bytes(&packed_data).unwrap();
+            assert!(packed.verify(&key).is_err());
+        }
+
+        // A valid block is not accepted for another key
+        let (other_key, _block) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        let packed = PackedBlock::from_bytes(&block).unwrap();
+        assert_eq!(
+            packed.verify(&PublicKey::from(other_key)).unwrap_err(),
+            VerifyError::PublicKeyMismatch
+        );
+    }
 }
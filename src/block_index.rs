@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! An index of the blocks of each branch in a store
+//!
+//! Blocks only link to their previous block, so listing the history of a branch otherwise
+//! means reading every block back from its tail. [`Store::write_tail`] appends the blocks after
+//! the previous tail to `index/blocks.jsonl`, one JSON line per block, and the index can be
+//! rebuilt from the tails and blocks with [`BlockIndex::rebuild`] if it is lost or stale.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::verify::BLOCK_SIZE;
+use crate::{BlockSig, Error, Store};
+
+/// A block of a branch, as recorded in the index
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IndexedBlock {
+    pub signature: BlockSig,
+    /// The position of the block in the chain
+    pub counter: u64,
+}
+
+/// One line of `index/blocks.jsonl`
+#[derive(Deserialize, Serialize)]
+struct IndexLine {
+    project: String,
+    branch: String,
+    #[serde(flatten)]
+    block: IndexedBlock,
+}
+
+/// The indexed blocks of one branch, oldest first, and their signatures
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+struct IndexedBranch {
+    blocks: Vec<IndexedBlock>,
+    signatures: BTreeSet<BlockSig>,
+}
+
+/// The blocks of each project and branch of a store, oldest first
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlockIndex {
+    branches: BTreeMap<(String, String), IndexedBranch>,
+}
+
+fn index_path(store: &Store) -> PathBuf {
+    store.path().join("index").join("blocks.jsonl")
+}
+
+/// Read the block with `sig` from `store`, if it is there
+fn read_block(store: &Store, sig: &BlockSig) -> Result<Option<Block>, Error> {
+    let data = match fs::read(store.block_path(sig)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let data: &[u8; BLOCK_SIZE] = data.as_slice().try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("block {} is not {} bytes", sig, BLOCK_SIZE),
+        )
+    })?;
+    Ok(Some(Block::from_unverified(data)))
+}
+
+/// Walk back from `tail` until a block in `known`, the first block, or a block missing from
+/// `store`, returning the blocks walked oldest first
+fn walk_back(
+    store: &Store,
+    tail: &BlockSig,
+    known: &BTreeSet<BlockSig>,
+) -> Result<Vec<IndexedBlock>, Error> {
+    let mut blocks = Vec::new();
+    let mut next_opt = Some(*tail);
+    while let Some(sig) = next_opt.take() {
+        if known.contains(&sig) || blocks.iter().any(|b: &IndexedBlock| b.signature == sig) {
+            break;
+        }
+        let Some(block) = read_block(store, &sig)? else {
+            break;
+        };
+        if block.counter > 0 {
+            next_opt = Some(block.previous_signature);
+        }
+        blocks.push(IndexedBlock {
+            signature: sig,
+            counter: block.counter,
+        });
+    }
+    blocks.reverse();
+    Ok(blocks)
+}
+
+impl BlockIndex {
+    /// Read the index of `store`, which is empty if the store has none
+    ///
+    /// Lines that cannot be parsed, such as one cut short by a crash, are skipped.
+    pub fn read(store: &Store) -> Result<BlockIndex, Error> {
+        let mut index = BlockIndex::default();
+        let file = match File::open(index_path(store)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(err) => return Err(err.into()),
+        };
+
+        for line in BufReader::new(file).lines() {
+            if let Ok(line) = serde_json::from_str::<IndexLine>(&line?) {
+                index.insert(line.project, line.branch, line.block);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Rebuild the index of `store` from its tails and blocks, replacing the previous index
+    pub fn rebuild(store: &Store) -> Result<BlockIndex, Error> {
+        store.check_writable()?;
+        let mut index = BlockIndex::default();
+        for (project, branches) in store.tail_index()? {
+            for branch in branches {
+                let Some(data) = store.read_tail(&project, &branch)? else {
+                    continue;
+                };
+                let tail = BlockSig::try_from(&data[..64]).unwrap();
+                for block in walk_back(store, &tail, &BTreeSet::new())? {
+                    index.insert(project.clone(), branch.clone(), block);
+                }
+            }
+        }
+
+        let path = index_path(store);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("jsonl.partial");
+        {
+            let mut file = File::create(&tmp)?;
+            for ((project, branch), indexed) in index.branches.iter() {
+                for block in indexed.blocks.iter() {
+                    write_line(&mut file, project, branch, block)?;
+                }
+            }
+            file.sync_all()?;
+        }
+        fs::rename(tmp, path)?;
+        Ok(index)
+    }
+
+    /// Append the blocks of `project` and `branch` up to `tail`, after the `previous` tail
+    ///
+    /// The previous tail was indexed when it was written, so only the new blocks are read,
+    /// rather than the whole index. Blocks indexed twice, such as when a tail is replaced by
+    /// one that does not follow it, are only listed once when the index is read.
+    pub(crate) fn append(
+        store: &Store,
+        project: &str,
+        branch: &str,
+        previous_opt: Option<&BlockSig>,
+        tail: &BlockSig,
+    ) -> Result<(), Error> {
+        let known: BTreeSet<BlockSig> = previous_opt.into_iter().copied().collect();
+        let blocks = walk_back(store, tail, &known)?;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        let path = index_path(store);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        for block in blocks.iter() {
+            write_line(&mut file, project, branch, block)?;
+        }
+        Ok(file.sync_all()?)
+    }
+
+    fn insert(&mut self, project: String, branch: String, block: IndexedBlock) {
+        let indexed = self.branches.entry((project, branch)).or_default();
+        if indexed.signatures.insert(block.signature) {
+            indexed.blocks.push(block);
+        }
+    }
+
+    /// The indexed blocks of `project` and `branch`, oldest first
+    pub fn blocks(&self, project: &str, branch: &str) -> &[IndexedBlock] {
+        self.branches
+            .get(&(project.to_string(), branch.to_string()))
+            .map_or(&[], |indexed| indexed.blocks.as_slice())
+    }
+
+    /// The newest indexed block of `project` and `branch`
+    pub fn latest(&self, project: &str, branch: &str) -> Option<&IndexedBlock> {
+        self.blocks(project, branch).last()
+    }
+
+    /// The indexed block of `project` and `branch` with `counter`
+    pub fn find(&self, project: &str, branch: &str, counter: u64) -> Option<&IndexedBlock> {
+        self.blocks(project, branch)
+            .iter()
+            .rev()
+            .find(|block| block.counter == counter)
+    }
+
+    /// The indexed projects and branches
+    pub fn branches(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.branches
+            .keys()
+            .map(|(project, branch)| (project.as_str(), branch.as_str()))
+    }
+}
+
+fn write_line<W: Write>(
+    writer: &mut W,
+    project: &str,
+    branch: &str,
+    block: &IndexedBlock,
+) -> io::Result<()> {
+    let line = IndexLine {
+        project: project.to_string(),
+        branch: branch.to_string(),
+        block: block.clone(),
+    };
+    serde_json::to_writer(&mut *writer, &line)?;
+    writer.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::BlockIndex;
+    use crate::block::tests::signed_block;
+    use crate::{BlockSig, Store};
+
+    #[test]
+    fn test_block_index() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        assert_eq!(BlockIndex::read(&store).unwrap(), BlockIndex::default());
+
+        let (_, first) = signed_block(1, &[0; 64], 0, &[1; 48]);
+        let (_, second) = signed_block(1, first[..64].try_into().unwrap(), 1, &[2; 48]);
+        let (_, third) = signed_block(1, second[..64].try_into().unwrap(), 2, &[3; 48]);
+        store.write_tail("default", "master", &first).unwrap();
+        // Blocks synced before their tail are indexed with it
+        store.write_block(&second).unwrap();
+        store.write_tail("default", "master", &third).unwrap();
+        store.write_tail("default", "stable", &second).unwrap();
+
+        let sig = |block: &[u8; 400]| BlockSig::try_from(&block[..64]).unwrap();
+        let index = BlockIndex::read(&store).unwrap();
+        let counters: Vec<u64> = index
+            .blocks("default", "master")
+            .iter()
+            .map(|block| block.counter)
+            .collect();
+        assert_eq!(counters, vec![0, 1, 2]);
+        assert_eq!(index.blocks("default", "stable").len(), 2);
+        assert_eq!(
+            index.latest("default", "master").unwrap().signature,
+            sig(&third)
+        );
+        assert_eq!(
+            index.find("default", "master", 1).unwrap().signature,
+            sig(&second)
+        );
+        assert!(index.find("default", "master", 3).is_none());
+        assert!(index.blocks("default", "beta").is_empty());
+
+        fs::remove_file(temp_dir.path().join("index/blocks.jsonl")).unwrap();
+        assert_eq!(BlockIndex::rebuild(&store).unwrap(), index);
+        assert_eq!(BlockIndex::read(&store).unwrap(), index);
+
+        // Writing the same tail again indexes nothing new
+        store.write_tail("default", "master", &third).unwrap();
+        assert_eq!(BlockIndex::read(&store).unwrap(), index);
+
+        // Stores without indexes, such as caches, only write the tail
+        let cache = Store::new(temp_dir.path().join("cache")).with_indexes(false);
+        fs::create_dir(cache.path()).unwrap();
+        cache.write_tail("default", "master", &first).unwrap();
+        assert!(cache.read_tail("default", "master").unwrap().is_some());
+        assert!(!cache.path().join("index/blocks.jsonl").exists());
+        assert!(!cache.path().join("tail/index.json").exists());
+        temp_dir.close().unwrap();
+    }
+}
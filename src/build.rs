@@ -1,15 +1,29 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CString;
 use std::fs;
 use std::io;
-use std::path::Path;
-use std::process::Command;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
 use lxd::{Container, Image, Location};
 use tempfile::TempDir;
 
-use crate::{sign_manifest, Config, Sha384, Source, Store};
+use crate::archive::{copy_build_dir, ArchiveWriter};
+use crate::manifest::file_digest;
+use crate::normalize::normalize_dir;
+use crate::{
+    sign_manifest, Block, BuildInfo, BuildRecord, BuildReport, Clock, CommandRecord, Config,
+    Environment, EnvironmentInfo, Error, Event, Format, HostInfo, Log, Manifest, OsRng,
+    OutputTarget, OutputTemplate, OutputVars, Provenance, Reproduction, Rng, ScanVerdict, Scanner,
+    Sha384, Source, StageStatus, Store,
+};
 
 /// A temporary structure used to generate a unique build environment
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
@@ -20,7 +34,7 @@ struct BuildEnvironmentConfig {
     pub prepare: Vec<Vec<String>>,
 }
 
-fn prepare(config: &Config, location: &Location) -> io::Result<String> {
+fn prepare(config: &Config, location: &Location, log: Log) -> io::Result<String> {
     let build_json = serde_json::to_string(&BuildEnvironmentConfig {
         base: config.base.clone(),
         prepare: config.prepare.clone(),
@@ -41,16 +55,19 @@ fn prepare(config: &Config, location: &Location) -> io::Result<String> {
     );
 
     if Image::new(location.clone(), &build_image).is_ok() {
-        println!("Build environment cached as {}", build_image);
+        log.message(&format!("Build environment cached as {}", build_image));
     } else {
         let mut container = if config.privileged {
-            println!(
+            log.message(&format!(
                 "Create privileged container {} from {}",
                 container_name, &config.base
-            );
+            ));
             unsafe { Container::new_privileged(location.clone(), &container_name, &config.base)? }
         } else {
-            println!("Create container {} from {}", container_name, &config.base);
+            log.message(&format!(
+                "Create container {} from {}",
+                container_name, &config.base
+            ));
             Container::new(location.clone(), &container_name, &config.base)?
         };
 
@@ -60,177 +77,1056 @@ fn prepare(config: &Config, location: &Location) -> io::Result<String> {
                 args.push(arg.as_str());
             }
 
-            println!("Prepare command {:?}", args);
+            log.command(&args);
             container.exec(&args)?;
         }
 
-        println!("Snapshot build environment as {}", build_image);
+        log.message(&format!("Snapshot build environment as {}", build_image));
         let snapshot = container.snapshot(&build_image)?;
 
-        println!("Publish build environment as {}", build_image);
+        log.message(&format!("Publish build environment as {}", build_image));
         snapshot.publish(&build_image)?;
     }
 
     Ok(build_image)
 }
 
+/// The path the source is checked out to in the container, which is the same on every host
+pub(crate) const SOURCE_PATH: &str = "/root/source";
+
+/// Set the umask given as the first argument, then run the rest of the arguments
+const UMASK_SCRIPT: &str = "umask \"$1\" && shift && exec \"$@\"";
+
+/// The environment variables set for build and publish commands, as `NAME=VALUE`
+pub(crate) fn command_env(config: &Config, source_time: u64) -> Vec<String> {
+    let mut env = vec![
+        format!("HOME={}", config.process.home),
+        format!("LC_ALL={}", config.process.locale),
+        format!("TZ={}", config.process.timezone),
+        format!("SOURCE_DATE_EPOCH={}", source_time),
+    ];
+    if let Some(prefix) = &config.prefix_map {
+        let map = format!("{}={}", SOURCE_PATH, prefix);
+        env.push(format!("CFLAGS=-ffile-prefix-map={}", map));
+        env.push(format!("CXXFLAGS=-ffile-prefix-map={}", map));
+        env.push(format!("RUSTFLAGS=--remap-path-prefix={}", map));
+    }
+    env
+}
+
+/// The arguments that run a command as the user, and with the environment, umask, and clock
+/// of the configuration, which are followed by the command
+pub(crate) fn command_prefix(config: &Config, source_time: u64) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(user) = &config.user {
+        args.extend([
+            "setpriv".to_string(),
+            format!("--reuid={}", user.uid),
+            format!("--regid={}", user.gid),
+            "--clear-groups".to_string(),
+        ]);
+    }
+    args.push("env".to_string());
+    args.extend(command_env(config, source_time));
+    args.extend(["sh", "-c", UMASK_SCRIPT, "sh"].map(String::from));
+    args.push(config.process.umask.clone());
+    if config.faketime {
+        args.extend(["faketime".to_string(), format!("@{}", source_time)]);
+    }
+    args
+}
+
+/// Prefix `command` with `prefix` from [`command_prefix`], in the pinned environment if there
+/// is one
+pub(crate) fn in_environment<'a>(
+    config: &'a Config,
+    prefix: &'a [String],
+    command: &'a [String],
+) -> Vec<&'a str> {
+    let mut args = Vec::new();
+    if let Some(environment) = &config.environment {
+        args.extend([
+            "nix",
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "develop",
+            environment.nix.as_str(),
+            "--command",
+        ]);
+    }
+    // The prefix runs inside the pinned environment, so its shell cannot replace variables
+    args.extend(prefix.iter().map(|arg| arg.as_str()));
+    args.extend(command.iter().map(|arg| arg.as_str()));
+    args
+}
+
+/// Hash a list of store paths, ignoring their order
+fn closure_hash(paths: &str) -> io::Result<String> {
+    let mut paths: Vec<&str> = paths
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+    paths.sort_unstable();
+    paths.dedup();
+
+    let sha = Sha384::new(paths.join("\n").as_bytes())?;
+    Ok(sha.to_base32())
+}
+
+/// Record the closure of the pinned environment's development shell
+fn environment_info<P: AsRef<Path>>(
+    container: &mut Container,
+    environment: &Environment,
+    temp_path: P,
+    log: Log,
+) -> io::Result<EnvironmentInfo> {
+    // The derivation closure pins every input of the development shell
+    let script = format!(
+        "nix --extra-experimental-features 'nix-command flakes' path-info --derivation --recursive '{}' > /root/closure",
+        environment.nix.replace('\'', "'\\''")
+    );
+
+    log.message(&format!(
+        "Record environment closure of {}",
+        environment.nix
+    ));
+    container.exec(&["sh", "-c", &script])?;
+
+    let closure_path = temp_path.as_ref().join("closure");
+    container.pull("/root/closure", &closure_path, false)?;
+    let closure = fs::read_to_string(&closure_path)?;
+    fs::remove_file(&closure_path)?;
+
+    Ok(EnvironmentInfo {
+        nix: environment.nix.clone(),
+        closure: closure_hash(&closure)?,
+    })
+}
+
 fn run<P: AsRef<Path>, Q: AsRef<Path>>(
     config: &Config,
     location: &Location,
     build_image: &str,
     source_path: P,
+    source_time: u64,
     temp_path: Q,
-) -> io::Result<()> {
+    log: Log,
+) -> io::Result<(Option<EnvironmentInfo>, Vec<CommandRecord>)> {
     let source_path = source_path.as_ref();
     let temp_path = temp_path.as_ref();
 
     let container_name = format!("buildchain-{}-build", config.name);
 
     let mut container = if config.privileged {
-        println!(
+        log.message(&format!(
             "Create privileged container {} from {}",
             container_name, build_image
-        );
+        ));
         unsafe { Container::new_privileged(location.clone(), &container_name, build_image)? }
     } else {
-        println!("Create container {} from {}", container_name, build_image);
+        log.message(&format!(
+            "Create container {} from {}",
+            container_name, build_image
+        ));
         Container::new(location.clone(), &container_name, build_image)?
     };
 
-    println!("Push source");
+    log.message("Push source");
     container.push(source_path, "/root", true)?;
 
-    for command in config.build.iter() {
-        let mut args = Vec::new();
-        for arg in command.iter() {
-            args.push(arg.as_str());
-        }
+    // The commands run as the configured user, who needs to reach and write the source
+    let owner_opt = config
+        .user
+        .as_ref()
+        .map(|user| format!("{}:{}", user.uid, user.gid));
+    if let Some(owner) = &owner_opt {
+        let args = ["chmod", "0755", "/root"];
+        log.command(&args);
+        container.exec(&args)?;
 
-        println!("Build command {:?}", args);
+        let args = ["chown", "--recursive", owner, SOURCE_PATH];
+        log.command(&args);
         container.exec(&args)?;
     }
 
-    println!("Create artifact directory");
-    container.exec(&["mkdir", "/root/artifacts"])?;
+    let prefix = command_prefix(config, source_time);
+    let mut commands = Vec::new();
+    let mut exec_command = |container: &mut Container, command: &[String]| -> io::Result<()> {
+        let args = in_environment(config, &prefix, command);
 
-    for command in config.publish.iter() {
-        let mut args = Vec::new();
-        for arg in command.iter() {
-            args.push(arg.as_str());
-        }
+        log.command(&args);
+        let start = Instant::now();
+        container.exec(&args)?;
+        commands.push(CommandRecord {
+            command: command.to_vec(),
+            exit_code: 0,
+            duration: start.elapsed().as_secs_f64(),
+        });
+        Ok(())
+    };
+
+    for command in config.build.iter() {
+        exec_command(&mut container, command)?;
+    }
 
-        println!("Publish command {:?}", args);
+    let args = ["mkdir", "/root/artifacts"];
+    log.command(&args);
+    container.exec(&args)?;
+    if let Some(owner) = &owner_opt {
+        let args = ["chown", owner, "/root/artifacts"];
+        log.command(&args);
         container.exec(&args)?;
     }
 
-    println!("Pull artifacts");
+    for command in config.publish.iter() {
+        exec_command(&mut container, command)?;
+    }
+
+    let environment_info_opt = match &config.environment {
+        Some(environment) => Some(environment_info(
+            &mut container,
+            environment,
+            temp_path,
+            log,
+        )?),
+        None => None,
+    };
+
+    log.message("Pull artifacts");
     container.pull("/root/artifacts", temp_path, true)?;
 
+    Ok((environment_info_opt, commands))
+}
+
+/// Move the artifacts in `temp_path` that [`Config::is_unsigned`] matches to `unsigned/`
+///
+/// They are archived with the build, but are not in the manifest, so they cannot change its
+/// digest between otherwise reproducible builds.
+fn move_unsigned<P: AsRef<Path>>(config: &Config, temp_path: P, log: Log) -> io::Result<()> {
+    fn walk(dir: &Path, prefix: &str, names: &mut Vec<String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &format!("{}/", name), names)?;
+            } else {
+                names.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    if config.unsigned.is_empty() {
+        return Ok(());
+    }
+
+    let artifacts = temp_path.as_ref().join("artifacts");
+    let unsigned = temp_path.as_ref().join("unsigned");
+    let mut names = Vec::new();
+    walk(&artifacts, "", &mut names)?;
+    names.sort();
+    for name in names.iter().filter(|name| config.is_unsigned(name)) {
+        log.message(&format!("Exclude unsigned {}", name));
+        let dest = unsigned.join(name);
+        fs::create_dir_all(dest.parent().unwrap())?;
+        fs::rename(artifacts.join(name), dest)?;
+    }
     Ok(())
 }
 
-fn archive<P: AsRef<Path>, Q: AsRef<Path>>(
-    source_path: P,
-    dest_path: Q,
+/// Configures a call to [`build`]
+#[derive(Clone, Debug)]
+pub struct BuildOptions {
+    config_path: String,
+    output_opt: Option<String>,
+    project: String,
+    branch: String,
+    remote_opt: Option<String>,
+    source_url: String,
+    source_kind: String,
+    use_pihsm: bool,
     exclude_source: bool,
-) -> io::Result<()> {
-    let source_path = source_path.as_ref();
-    let dest_path = dest_path.as_ref();
-
-    let mut args = vec![
-        "--create",
-        "--verbose",
-        "--sort=name",
-        "--owner=0",
-        "--group=0",
-        "--numeric-owner",
-        "--exclude-vcs",
-    ];
+    store_report: bool,
+    report_opt: Option<String>,
+    log_format: Format,
+    clock_opt: Option<Arc<dyn Clock>>,
+    rng: Arc<dyn Rng>,
+    tmpdir_opt: Option<String>,
+    min_free_space: u64,
+    strict: bool,
+    previous_opt: Option<String>,
+    scanners: Vec<Arc<dyn Scanner>>,
+    skip_unchanged: bool,
+}
 
-    if exclude_source {
-        args.push("--exclude=./source")
+impl BuildOptions {
+    /// Build with the configuration file at `config_path`, relative to the source
+    pub fn new(config_path: &str) -> BuildOptions {
+        BuildOptions {
+            config_path: config_path.to_string(),
+            output_opt: None,
+            project: "default".to_string(),
+            branch: "master".to_string(),
+            remote_opt: None,
+            source_url: ".".to_string(),
+            source_kind: "dir".to_string(),
+            use_pihsm: false,
+            exclude_source: false,
+            store_report: false,
+            report_opt: None,
+            log_format: Format::Text,
+            clock_opt: None,
+            rng: Arc::new(OsRng),
+            tmpdir_opt: None,
+            min_free_space: DEFAULT_MIN_FREE_SPACE,
+            strict: false,
+            previous_opt: None,
+            scanners: Vec::new(),
+            skip_unchanged: false,
+        }
     }
 
-    let status = Command::new("tar")
-        .args(args)
-        .arg("--file")
-        .arg(dest_path)
-        .arg("--directory")
-        .arg(source_path)
-        .arg(".")
-        .status()?;
+    /// Set the path of the output archive, as an [`OutputTemplate`]
+    ///
+    /// This replaces the outputs of the configuration, see [`Config::output_targets`]. If
+    /// neither is set, the build is archived to `buildchain.tar`.
+    pub fn output(mut self, output: &str) -> BuildOptions {
+        self.output_opt = Some(output.to_string());
+        self
+    }
 
-    if status.success() {
-        Ok(())
-    } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("tar failed with status: {}", status),
-        ))
+    /// Set the tail signature project name, `default` if not set
+    pub fn project(mut self, project: &str) -> BuildOptions {
+        self.project = project.to_string();
+        self
+    }
+
+    /// Set the tail signature branch name, `master` if not set
+    pub fn branch(mut self, branch: &str) -> BuildOptions {
+        self.branch = branch.to_string();
+        self
+    }
+
+    /// Build on the remote LXD server `remote` instead of locally
+    pub fn remote(mut self, remote: &str) -> BuildOptions {
+        self.remote_opt = Some(remote.to_string());
+        self
     }
+
+    /// Set the source URL and kind (`dir` or `git`), `.` and `dir` if not set
+    pub fn source(mut self, url: &str, kind: &str) -> BuildOptions {
+        self.source_url = url.to_string();
+        self.source_kind = kind.to_string();
+        self
+    }
+
+    /// Sign the manifest with a PiHSM
+    pub fn pihsm(mut self, use_pihsm: bool) -> BuildOptions {
+        self.use_pihsm = use_pihsm;
+        self
+    }
+
+    /// Exclude the source checkout from the archive
+    pub fn exclude_source(mut self, exclude_source: bool) -> BuildOptions {
+        self.exclude_source = exclude_source;
+        self
+    }
+
+    /// Store a [`BuildRecord`] in the chain, referenced from the manifest, false if not set
+    ///
+    /// The record differs between builds, so the manifest is no longer reproducible.
+    pub fn store_report(mut self, store_report: bool) -> BuildOptions {
+        self.store_report = store_report;
+        self
+    }
+
+    /// Write a report of the build stages to `report_path`, see [`BuildReport::write`]
+    pub fn report(mut self, report_path: &str) -> BuildOptions {
+        self.report_opt = Some(report_path.to_string());
+        self
+    }
+
+    /// Print progress as human readable text, the default, or as one JSON [`Event`] per line
+    pub fn log_format(mut self, log_format: Format) -> BuildOptions {
+        self.log_format = log_format;
+        self
+    }
+
+    /// Take the manifest time from `clock`, instead of the newest source file or commit
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> BuildOptions {
+        self.clock_opt = Some(clock);
+        self
+    }
+
+    /// Name temporary files in the build store using `rng`, see [`Store::with_rng`]
+    pub fn rng(mut self, rng: Arc<dyn Rng>) -> BuildOptions {
+        self.rng = rng;
+        self
+    }
+
+    /// Create the temporary build directory in `tmpdir`, instead of the system default
+    pub fn tmpdir(mut self, tmpdir: &str) -> BuildOptions {
+        self.tmpdir_opt = Some(tmpdir.to_string());
+        self
+    }
+
+    /// Require `bytes` of free space for the temporary build directory, 1 GiB if not set
+    pub fn min_free_space(mut self, bytes: u64) -> BuildOptions {
+        self.min_free_space = bytes;
+        self
+    }
+
+    /// Refuse a configuration with unknown keys, instead of warning about them, false if not set
+    pub fn strict(mut self, strict: bool) -> BuildOptions {
+        self.strict = strict;
+        self
+    }
+
+    /// Compare artifact sizes with the build in the store directory `previous` when a size
+    /// budget is exceeded, see [`crate::Budget`], and the source with it when unchanged builds
+    /// are skipped
+    pub fn previous(mut self, previous: &str) -> BuildOptions {
+        self.previous_opt = Some(previous.to_string());
+        self
+    }
+
+    /// Skip the build if the source tree and configuration are the same as those of the tail
+    /// of the branch in the [`BuildOptions::previous`] store, false if not set
+    ///
+    /// The build succeeds without writing any outputs, so no duplicate block is published.
+    pub fn skip_unchanged(mut self, skip_unchanged: bool) -> BuildOptions {
+        self.skip_unchanged = skip_unchanged;
+        self
+    }
+
+    /// Check the artifacts with `scanner` before they are signed, which may veto the build
+    ///
+    /// Scanners run in the order they are added, and the verdicts are recorded in the report.
+    pub fn scanner(mut self, scanner: Arc<dyn Scanner>) -> BuildOptions {
+        self.scanners.push(scanner);
+        self
+    }
+}
+
+/// The sizes of the artifacts of the build in the store directory `path`
+fn previous_sizes(path: &str) -> Result<BTreeMap<String, u64>, Error> {
+    let store = Store::open(path)?;
+    let manifest = store
+        .read_manifest()?
+        .ok_or_else(|| Error::NotFound(format!("{} has no manifest", path)))?;
+    let mut sizes = BTreeMap::new();
+    for (name, digest) in manifest.files.iter() {
+        if let Some(size) = store.object_size(digest)? {
+            sizes.insert(name.clone(), size);
+        }
+    }
+    Ok(sizes)
 }
 
-pub struct BuildArguments<'a> {
-    pub config_path: &'a str,
-    pub output_path: &'a str,
-    pub project_name: &'a str,
-    pub branch_name: &'a str,
-    pub remote_opt: Option<&'a str>,
-    pub source_url: &'a str,
-    pub source_kind: &'a str,
-    pub use_pihsm: bool,
-    pub exclude_source: bool,
+/// The manifest of the tail of `project` and `branch` in the store directory `path`, or its
+/// `manifest.json` if it has no tail for the branch
+fn previous_manifest(path: &str, project: &str, branch: &str) -> Result<Option<Manifest>, Error> {
+    let store = Store::open(path)?;
+    let Some(tail) = store.read_tail(project, branch)? else {
+        return store.read_manifest();
+    };
+    let block = Block::from_unverified(&tail);
+    let data = fs::read(store.object_path(&block.digest))?;
+    let manifest = serde_json::from_slice(&data).map_err(io::Error::from)?;
+    Ok(Some(manifest))
 }
 
-pub fn build(args: BuildArguments) -> io::Result<()> {
-    let config_path = args.config_path;
+/// The path of the output archive if neither the build nor the configuration sets one
+const DEFAULT_OUTPUT: &str = "buildchain.tar";
+
+/// The free space required for the temporary build directory if not configured
+const DEFAULT_MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
 
-    let temp_dir = TempDir::with_prefix("buildchain.")?;
+/// The space available to unprivileged users on the filesystem of `path`
+fn free_space(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is NUL terminated, and statvfs initializes stat when it succeeds
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Check that `dir` has at least `required` bytes free, before a build can run out of space
+fn check_free_space(dir: &Path, required: u64) -> Result<(), Error> {
+    let free = free_space(dir)?;
+    if free < required {
+        return Err(Error::Config(format!(
+            "{} has {} MiB free, {} MiB are required for the build, use --tmpdir to build \
+             elsewhere or --min-free-space to change the requirement",
+            dir.display(),
+            free / 1024 / 1024,
+            required.div_ceil(1024 * 1024)
+        )));
+    }
+    Ok(())
+}
+
+pub fn build(options: &BuildOptions) -> Result<(), Error> {
+    let mut report = BuildReport::new(&options.project, &options.branch);
+
+    let log = Log::new(options.log_format);
+
+    let result = build_stages(options, &mut report, log);
+    if let Err(err) = &result {
+        log.event(&Event::Error {
+            message: &err.to_string(),
+        });
+    }
+
+    if let Some(report_path) = &options.report_opt {
+        report.write(report_path)?;
+        log.message(&format!("buildchain: wrote report to {}", report_path));
+    }
+
+    result
+}
+
+/// Run the stage `name`, recording it in `report` and logging the result
+fn stage<T, F: FnOnce() -> Result<T, Error>>(
+    report: &mut BuildReport,
+    log: Log,
+    name: &str,
+    f: F,
+) -> Result<T, Error> {
+    let result = report.stage(name, f);
+    if let Some(stage) = report.stages.last() {
+        log.event(&Event::Stage(stage));
+    }
+    result
+}
+
+fn build_stages(args: &BuildOptions, report: &mut BuildReport, log: Log) -> Result<(), Error> {
+    let config_path = &args.config_path;
+
+    let tmpdir = match &args.tmpdir_opt {
+        Some(tmpdir) => PathBuf::from(tmpdir),
+        None => env::temp_dir(),
+    };
+    check_free_space(&tmpdir, args.min_free_space)?;
+    let temp_dir = TempDir::with_prefix_in("buildchain.", &tmpdir)?;
 
     let source = Source {
-        kind: args.source_kind.to_string(),
-        url: args.source_url.to_string(),
+        kind: args.source_kind.clone(),
+        url: args.source_url.clone(),
     };
 
+    // Pushed to SOURCE_PATH in the container
     let source_path = temp_dir.path().join("source");
 
-    let source_time = source.download(&source_path)?;
+    let mut source_time = stage(report, log, "source", || {
+        source.download(&source_path).map_err(Error::Source)
+    })?;
+    if let Some(clock) = &args.clock_opt {
+        source_time = clock.now();
+    }
 
-    let string = fs::read_to_string(source_path.join(config_path))?;
-    let config = serde_json::from_str::<Config>(&string)?;
+    let string = fs::read_to_string(source_path.join(config_path))
+        .map_err(|err| Error::Config(format!("failed to read {}: {}", config_path, err)))?;
+    let (config, ignored) = Config::parse(&string)
+        .map_err(|err| Error::Config(format!("failed to parse {}: {}", config_path, err)))?;
+    if !ignored.is_empty() {
+        if args.strict {
+            return Err(Error::Config(format!(
+                "unknown keys in {}: {}",
+                config_path,
+                ignored.join(", ")
+            )));
+        }
+        for key in ignored.iter() {
+            log.message(&format!(
+                "buildchain: warning: ignoring unknown key {} in {}",
+                key, config_path
+            ));
+        }
+    }
+    report.name = config.name.clone();
+
+    let source_state = source
+        .state(&source_path, string.as_bytes())
+        .map_err(Error::Source)?;
+    if args.skip_unchanged {
+        let previous = args.previous_opt.as_deref().ok_or_else(|| {
+            Error::Config("skipping unchanged builds requires the previous build".to_string())
+        })?;
+        let previous_state_opt = previous_manifest(previous, &args.project, &args.branch)?
+            .and_then(|manifest| manifest.source);
+        if previous_state_opt.is_some_and(|state| state.is_unchanged(&source_state)) {
+            log.message(&format!(
+                "buildchain: source and configuration of {} are unchanged since the build in {}, \
+                 skipping",
+                config.name, previous
+            ));
+            return Ok(());
+        }
+    }
+
+    let targets = match &args.output_opt {
+        Some(path) => vec![OutputTarget::Archive { path: path.clone() }],
+        None => config.output_targets(DEFAULT_OUTPUT),
+    };
+    let mut outputs = targets
+        .iter()
+        .map(|target| Output::new(target, args.use_pihsm))
+        .collect::<Result<Vec<_>, Error>>()?;
 
-    let location = if let Some(remote) = args.remote_opt {
-        println!("buildchain: building {} on {}", config.name, remote);
-        Location::Remote(remote.to_string())
+    let location = if let Some(remote) = &args.remote_opt {
+        log.message(&format!(
+            "buildchain: building {} on {}",
+            config.name, remote
+        ));
+        Location::Remote(remote.clone())
     } else {
-        println!("buildchain: building {} locally", config.name);
+        log.message(&format!("buildchain: building {} locally", config.name));
         Location::Local
     };
 
-    let build_image = prepare(&config, &location)?;
+    let build_image = stage(report, log, "prepare", || {
+        prepare(&config, &location, log).map_err(Error::Exec)
+    })?;
 
-    run(
-        &config,
-        &location,
-        &build_image,
-        &source_path,
-        temp_dir.path(),
-    )?;
+    let (environment_info_opt, commands) = stage(report, log, "build", || {
+        run(
+            &config,
+            &location,
+            &build_image,
+            &source_path,
+            source_time,
+            temp_dir.path(),
+            log,
+        )
+        .map_err(Error::Exec)
+    })?;
 
-    let store = Store::new(&temp_dir);
-    let manifest = store.import_artifacts(source_time)?;
-    let manifest_bytes = serde_json::to_vec_pretty(&manifest)?;
+    move_unsigned(&config, &temp_dir, log)?;
+    let normalized = if config.normalize {
+        stage(report, log, "normalize", || {
+            Ok(normalize_dir(temp_dir.path().join("artifacts"))?)
+        })?
+    } else {
+        BTreeMap::new()
+    };
+    for (name, format) in normalized.iter() {
+        log.message(&format!("Normalized {} {}", format, name));
+    }
+
+    if !args.scanners.is_empty() {
+        let artifacts = temp_dir.path().join("artifacts");
+        let mut verdicts = Vec::new();
+        let result = stage(report, log, "scan", || {
+            for scanner in args.scanners.iter() {
+                let name = scanner.name();
+                log.message(&format!("Scan artifacts with {}", name));
+                let result = scanner.scan(&artifacts);
+                verdicts.push(ScanVerdict {
+                    scanner: name.clone(),
+                    passed: result.is_ok(),
+                    message: result.clone().unwrap_or_else(|err| err),
+                });
+                if let Err(err) = result {
+                    return Err(Error::Denied(format!("{} vetoed signing: {}", name, err)));
+                }
+            }
+            Ok(())
+        });
+        report.scans = verdicts;
+        result?;
+    }
 
-    store.write_manifest(&manifest_bytes)?;
+    // Until the output names are rendered, archives are written next to their templates
+    for output in outputs.iter_mut() {
+        if let Output::Archive(template, archive_opt) = output {
+            *archive_opt = Some(ArchiveWriter::create(
+                &temp_dir,
+                template.to_string(),
+                args.exclude_source,
+            )?);
+        }
+    }
+    // With a single archive, objects are archived as they are imported, so the artifacts are
+    // not stored twice. Other outputs need the objects in the build directory.
+    let mut streamed_opt = match outputs.as_mut_slice() {
+        [Output::Archive(_, archive_opt)] => archive_opt.as_mut(),
+        _ => None,
+    };
+
+    let store = Store::with_rng(&temp_dir, args.rng.clone());
+    let mut sizes = BTreeMap::new();
+    let mut manifest = stage(report, log, "import", || {
+        let mut digests = BTreeMap::new();
+        for algorithm in config.digests.iter() {
+            digests.insert(algorithm.clone(), BTreeMap::new());
+        }
+        let mut manifest =
+            store.import_artifacts_with(source_time, |name, object, index, total| {
+                log.event(&Event::Import { name, index, total });
+                for (algorithm, files) in digests.iter_mut() {
+                    let digest = file_digest(algorithm, fs::File::open(object)?)?;
+                    files.insert(name.to_string(), digest);
+                }
+                sizes.insert(name.to_string(), fs::metadata(object)?.len());
+                match streamed_opt.as_mut() {
+                    Some(archive) => archive.append_object(object),
+                    None => Ok(()),
+                }
+            })?;
+        manifest.digests = digests;
+        manifest.devices = config.device_targets(manifest.files.keys());
+        manifest.source = Some(source_state);
+        Ok(manifest)
+    })?;
+    if !config.required.is_empty() {
+        stage(report, log, "require", || {
+            let missing = config.missing_artifacts(&sizes, &manifest.modes);
+            if missing.is_empty() {
+                Ok(())
+            } else {
+                Err(Error::Exec(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("required artifacts: {}", missing.join(", ")),
+                )))
+            }
+        })?;
+    }
+    if let Some(budget) = &config.budget {
+        stage(report, log, "budget", || {
+            let previous_opt = match &args.previous_opt {
+                Some(previous) => Some(previous_sizes(previous)?),
+                None => None,
+            };
+            let exceeded = budget.check(&sizes, previous_opt.as_ref());
+            if exceeded.is_empty() || budget.warn {
+                for message in exceeded.iter() {
+                    log.message(&format!("buildchain: warning: {}", message));
+                }
+                Ok(())
+            } else {
+                Err(Error::Denied(format!(
+                    "size budget exceeded: {}",
+                    exceeded.join(", ")
+                )))
+            }
+        })?;
+    }
+    if args.store_report {
+        let record = BuildRecord {
+            name: config.name.clone(),
+            stages: report
+                .stages
+                .iter()
+                .filter(|stage| stage.status == StageStatus::Passed)
+                .map(|stage| (stage.name.clone(), stage.duration))
+                .collect(),
+            commands,
+            artifacts_size: Some(sizes.values().sum()),
+            host: HostInfo::current(),
+            executor: "lxd".to_string(),
+            remote: args.remote_opt.clone(),
+        };
+        let record_bytes = serde_json::to_vec_pretty(&record).map_err(io::Error::from)?;
+        manifest.report = Some(store.write_record(&record_bytes)?);
+    }
+    let manifest_bytes = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+
+    let manifest_key = store.write_manifest(&manifest_bytes)?;
+
+    let buildinfo = BuildInfo {
+        name: config.name.clone(),
+        source_time,
+        environment: environment_info_opt,
+        process: Some(config.process.clone()),
+        faketime: config.faketime,
+        user: config.user.clone(),
+        normalized,
+    };
+    fs::write(
+        temp_dir.path().join("buildinfo.json"),
+        serde_json::to_vec_pretty(&buildinfo).map_err(io::Error::from)?,
+    )?;
+    Reproduction::capture(&config, source_time).write(&temp_dir)?;
+    for (name, digest) in manifest.files.iter() {
+        log.event(&Event::Artifact { name, digest });
+    }
+    report.artifacts = manifest.files.clone();
+    log.event(&Event::Manifest {
+        digest: &manifest_key,
+    });
+    report.manifest = Some(manifest_key);
+
+    let mut counter_opt = None;
     if args.use_pihsm {
-        let response = sign_manifest(&manifest_bytes)?;
-        store.write_tail(args.project_name, args.branch_name, &response)?;
+        let response = stage(report, log, "sign", || {
+            sign_manifest(&manifest_bytes).map_err(Error::Sign)
+        })?;
+        store.write_tail(&args.project, &args.branch, &response)?;
+        Provenance::new(&args.project, &args.branch, &response)?.write(&temp_dir)?;
+        counter_opt = Some(Block::from_unverified(&response).counter);
     }
     store.remove_tmp_dir()?;
 
-    archive(&temp_dir, args.output_path, args.exclude_source)?;
-
-    println!("buildchain: placed results in {}", args.output_path);
+    let vars = OutputVars {
+        name: &config.name,
+        project: &args.project,
+        branch: &args.branch,
+        counter_opt,
+        time: source_time,
+        manifest: &manifest_key,
+    };
+    for output in outputs {
+        output.write(&temp_dir, &vars, args.exclude_source, report, log)?;
+    }
 
     Ok(())
 }
+
+/// A result of the build, from an [`OutputTarget`] that was checked before building
+enum Output {
+    Archive(OutputTemplate, Option<ArchiveWriter>),
+    Directory(OutputTemplate),
+    Push { url: String, token: String },
+}
+
+impl Output {
+    /// Check `target` before the build, so that a build is not lost to a bad output
+    fn new(target: &OutputTarget, signed: bool) -> Result<Output, Error> {
+        let parse = |path: &str| -> Result<OutputTemplate, Error> {
+            let template: OutputTemplate = path
+                .parse()
+                .map_err(|err| Error::Config(format!("invalid output {:?}: {}", path, err)))?;
+            if template.uses_counter() && !signed {
+                return Err(Error::Config(format!(
+                    "output {:?} uses {{counter}}, which requires the manifest to be signed",
+                    path
+                )));
+            }
+            Ok(template)
+        };
+
+        match target {
+            OutputTarget::Archive { path } => Ok(Output::Archive(parse(path)?, None)),
+            OutputTarget::Directory { path } => Ok(Output::Directory(parse(path)?)),
+            OutputTarget::Push { url, token_env } => {
+                if !cfg!(feature = "serve") {
+                    return Err(Error::Config(format!(
+                        "pushing to {} requires the serve feature",
+                        url
+                    )));
+                }
+                if !signed {
+                    return Err(Error::Config(format!(
+                        "pushing to {} requires the manifest to be signed",
+                        url
+                    )));
+                }
+                let token = env::var(token_env).map_err(|_| {
+                    Error::Config(format!(
+                        "pushing to {} requires {} to be set",
+                        url, token_env
+                    ))
+                })?;
+                Ok(Output::Push {
+                    url: url.clone(),
+                    token,
+                })
+            }
+        }
+    }
+
+    /// Write the output from the complete build directory `temp_dir`
+    fn write(
+        self,
+        temp_dir: &TempDir,
+        vars: &OutputVars,
+        exclude_source: bool,
+        report: &mut BuildReport,
+        log: Log,
+    ) -> Result<(), Error> {
+        match self {
+            Output::Archive(template, archive_opt) => {
+                let path = template.render(vars).map_err(Error::Config)?;
+                if let Some(mut archive) = archive_opt {
+                    archive.set_dest(&path);
+                    stage(report, log, "archive", || Ok(archive.finish()?))?;
+                }
+                log.message(&format!("buildchain: placed results in {}", path));
+            }
+            Output::Directory(template) => {
+                let path = template.render(vars).map_err(Error::Config)?;
+                stage(report, log, "export", || {
+                    Ok(copy_build_dir(temp_dir.path(), &path, exclude_source)?)
+                })?;
+                log.message(&format!("buildchain: exported results to {}", path));
+            }
+            Output::Push { url, token } => {
+                stage(report, log, "push", || push(temp_dir.path(), &url, &token))?;
+                log.message(&format!("buildchain: pushed results to {}", url));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Upload the store of the build directory `dir` to the server at `url`
+#[cfg(feature = "serve")]
+fn push(dir: &Path, url: &str, token: &str) -> Result<(), Error> {
+    let publisher = crate::Publisher::new(url, token, None).map_err(Error::Http)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime
+        .block_on(publisher.publish(&Store::new(dir)))
+        .map_err(Error::Http)
+}
+
+#[cfg(not(feature = "serve"))]
+fn push(_dir: &Path, url: &str, _token: &str) -> Result<(), Error> {
+    Err(Error::Config(format!(
+        "pushing to {} requires the serve feature",
+        url
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{
+        check_free_space, closure_hash, command_env, command_prefix, in_environment, move_unsigned,
+        UMASK_SCRIPT,
+    };
+    use crate::{Config, Environment, Error, Format, Log, User};
+
+    #[test]
+    fn test_in_environment() {
+        let mut config: Config = serde_json::from_str(
+            r#"{"name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": []}"#,
+        )
+        .unwrap();
+        let command = vec!["make".to_string(), "all".to_string()];
+        let prefix = command_prefix(&config, 42);
+        assert_eq!(
+            in_environment(&config, &prefix, &command),
+            [
+                "env",
+                "HOME=/root",
+                "LC_ALL=C.UTF-8",
+                "TZ=UTC",
+                "SOURCE_DATE_EPOCH=42",
+                "sh",
+                "-c",
+                UMASK_SCRIPT,
+                "sh",
+                "022",
+                "make",
+                "all"
+            ]
+        );
+
+        config.environment = Some(Environment {
+            nix: "./source#default".to_string(),
+        });
+        assert_eq!(
+            &in_environment(&config, &prefix, &command)[..7],
+            [
+                "nix",
+                "--extra-experimental-features",
+                "nix-command flakes",
+                "develop",
+                "./source#default",
+                "--command",
+                "env",
+            ]
+        );
+
+        config.prefix_map = Some("/usr/src/test".to_string());
+        config.process.timezone = "Europe/Berlin".to_string();
+        let env = command_env(&config, 42);
+        assert_eq!(env[2], "TZ=Europe/Berlin");
+        assert_eq!(
+            env[4],
+            "CFLAGS=-ffile-prefix-map=/root/source=/usr/src/test"
+        );
+
+        config.faketime = true;
+        let prefix = command_prefix(&config, 42);
+        assert_eq!(&prefix[prefix.len() - 2..], ["faketime", "@42"]);
+
+        config.user = Some(User {
+            uid: 1000,
+            gid: 100,
+        });
+        assert_eq!(
+            &command_prefix(&config, 42)[..5],
+            [
+                "setpriv",
+                "--reuid=1000",
+                "--regid=100",
+                "--clear-groups",
+                "env"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_closure_hash() {
+        assert_eq!(
+            closure_hash("/nix/store/b\n/nix/store/a\n").unwrap(),
+            closure_hash("/nix/store/a\n\n/nix/store/b\n/nix/store/a").unwrap()
+        );
+        assert_ne!(
+            closure_hash("/nix/store/a").unwrap(),
+            closure_hash("/nix/store/b").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        check_free_space(temp_dir.path(), 0).unwrap();
+        assert!(matches!(
+            check_free_space(temp_dir.path(), u64::MAX),
+            Err(Error::Config(_))
+        ));
+        assert!(check_free_space(&temp_dir.path().join("missing"), 0).is_err());
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_move_unsigned() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let artifacts = temp_dir.path().join("artifacts");
+        fs::create_dir_all(artifacts.join("logs")).unwrap();
+        fs::write(artifacts.join("app.bin"), "app").unwrap();
+        fs::write(artifacts.join("logs/build.log"), "log").unwrap();
+
+        let mut config: Config = serde_json::from_str(
+            r#"{"name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": []}"#,
+        )
+        .unwrap();
+        config.unsigned = vec!["logs/*.log".to_string()];
+        move_unsigned(&config, temp_dir.path(), Log::new(Format::Json)).unwrap();
+
+        assert!(artifacts.join("app.bin").is_file());
+        assert!(!artifacts.join("logs/build.log").exists());
+        assert_eq!(
+            fs::read(temp_dir.path().join("unsigned/logs/build.log")).unwrap(),
+            b"log"
+        );
+
+        temp_dir.close().unwrap();
+    }
+}
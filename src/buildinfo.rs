@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{Process, User};
+
+/// The pinned environment a build ran in
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct EnvironmentInfo {
+    /// The nix flake output from the build configuration
+    pub nix: String,
+    /// The sha384 of the sorted store paths in the closure of the development shell
+    pub closure: String,
+}
+
+/// Information about how a build was produced, stored as `buildinfo.json` in the archive
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct BuildInfo {
+    /// The name of the build project
+    pub name: String,
+    /// The timestamp of the source control revision
+    pub source_time: u64,
+    /// The pinned environment, if the build configuration has one
+    pub environment: Option<EnvironmentInfo>,
+    /// The process settings of the build and publish commands
+    #[serde(default)]
+    pub process: Option<Process>,
+    /// Whether the commands ran under `faketime`, starting at the source time
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub faketime: bool,
+    /// The user the commands ran as, if not root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    /// The artifacts that were normalized before signing, and their formats
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub normalized: BTreeMap<String, String>,
+}
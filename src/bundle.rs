@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::block::PackedBlock;
+use crate::format::print_json;
+use crate::id::{b32dec, b32enc};
+use crate::r#async::public_key;
+use crate::{Block, BlockPin, Downloader, Error, Format, Manifest, Store};
+
+/// The version of the bundle format written by [`bundle`]
+pub const BUNDLE_VERSION: u32 = 1;
+
+const INSTRUCTIONS: &str = "\
+This bundle verifies one build without network access. To verify it with buildchain, run \
+`buildchain verify-bundle --key KEY BUNDLE`, with KEY obtained from the publisher separately. \
+To verify it by hand:
+1. Check that public_key is the key of the publisher. Strings are base32 (RFC 4648, without \
+padding).
+2. Decode block. It is 400 bytes: a 64 byte Ed25519 signature of the 336 bytes that follow it. \
+Those are the 32 byte public key, the 64 byte signature of the previous block, the \
+little-endian 8 byte counter and 8 byte timestamp, and the 48 byte SHA-384 digest of the \
+manifest in the last 48 bytes.
+3. Check that the public key in the block is public_key, and that the signature is valid.
+4. Check that the SHA-384 digest of manifest, encoded as UTF-8, is the digest in the block.
+5. Check the SHA-384 digest of each artifact against files in the manifest, and any other \
+digests in the manifest.
+";
+
+/// Everything needed to verify one build offline
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Bundle {
+    pub version: u32,
+    pub project: String,
+    pub branch: String,
+    /// The base32 public key that signed the block
+    pub public_key: String,
+    /// The base32 signed block
+    pub block: String,
+    /// The manifest exactly as signed, so that its digest can be checked
+    pub manifest: String,
+    pub instructions: String,
+}
+
+impl Bundle {
+    /// Create a bundle of the block `data` and the `manifest` it refers to
+    pub fn new(
+        project: &str,
+        branch: &str,
+        data: &[u8],
+        manifest: String,
+    ) -> Result<Bundle, Error> {
+        let packed = PackedBlock::from_bytes(data).map_err(Error::Verify)?;
+        Ok(Bundle {
+            version: BUNDLE_VERSION,
+            project: project.to_string(),
+            branch: branch.to_string(),
+            public_key: b32enc(packed.public_key()),
+            block: b32enc(data),
+            manifest,
+            instructions: INSTRUCTIONS.to_string(),
+        })
+    }
+
+    /// Verify the block and manifest of the bundle
+    ///
+    /// If `key_opt` is set, the bundle must be signed by that key rather than only by the key
+    /// it records, which anyone could replace.
+    pub fn verify(&self, key_opt: Option<&str>) -> Result<(Block, Manifest), Error> {
+        if self.version != BUNDLE_VERSION {
+            return Err(Error::Config(format!(
+                "bundle version {} is not supported",
+                self.version
+            )));
+        }
+
+        let key = public_key(&self.public_key)?;
+        if let Some(expected) = key_opt {
+            if public_key(expected)? != key {
+                return Err(Error::Verify(format!(
+                    "bundle is signed by {}, not {}",
+                    self.public_key, expected
+                )));
+            }
+        }
+
+        let data = b32dec(&self.block)
+            .ok_or_else(|| Error::Verify("block not in base32 format".to_string()))?;
+        let block = PackedBlock::from_bytes(&data)
+            .map_err(Error::Verify)?
+            .verify(&key)
+            .map_err(|err| Error::Verify(format!("block: {}", err)))?;
+        let manifest =
+            Manifest::from_signed(self.manifest.as_bytes(), &block).map_err(Error::Verify)?;
+        Ok((block, manifest))
+    }
+}
+
+pub struct BundleArguments<'a> {
+    pub store_path: &'a str,
+    pub key: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// Bundle this block instead of the tail
+    pub pin_opt: Option<BlockPin>,
+    pub output: &'a str,
+}
+
+/// Write a [`Bundle`] of the tail of `args.branch`, or of `args.pin_opt`, to `args.output`
+///
+/// The block is found and verified in the store before it is bundled.
+pub fn bundle(args: BundleArguments) -> Result<(), Error> {
+    let store = Store::open_read_only(args.store_path)?;
+    let dl = Downloader::from_store(args.key, args.project, args.branch, &store)?;
+    let block = match &args.pin_opt {
+        Some(pin) => dl.find_block(pin)?,
+        None => dl.tail()?,
+    };
+
+    let mut data = Vec::new();
+    store.open_block(&block.signature)?.read_to_end(&mut data)?;
+
+    let manifest = String::from_utf8(dl.object(&block.digest)?)
+        .map_err(|_| Error::Verify("manifest is not UTF-8".to_string()))?;
+    let bundle = Bundle::new(args.project, args.branch, &data, manifest)?;
+    bundle.verify(Some(args.key))?;
+
+    let json = serde_json::to_vec_pretty(&bundle).map_err(io::Error::from)?;
+    fs::write(args.output, json)?;
+    println!(
+        "buildchain: bundled block {} of tail/{}/{} with counter {} to {}",
+        block.signature, args.project, args.branch, block.counter, args.output
+    );
+    Ok(())
+}
+
+/// The result of [`verify_bundle`]
+#[derive(Debug, Serialize)]
+pub struct BundleVerification {
+    pub project: String,
+    pub branch: String,
+    pub block: Block,
+    pub manifest: Manifest,
+    /// Artifacts that were checked against the manifest
+    pub artifacts_verified: Vec<String>,
+}
+
+pub struct VerifyBundleArguments<'a> {
+    pub path: &'a str,
+    /// The key the bundle must be signed by
+    pub key_opt: Option<&'a str>,
+    /// Check the files of the manifest in this directory
+    pub artifacts_opt: Option<&'a str>,
+    pub format: Format,
+}
+
+/// Verify the bundle at `args.path`, and optionally the artifacts it describes
+pub fn verify_bundle(args: VerifyBundleArguments) -> Result<(), Error> {
+    let data = fs::read(args.path)?;
+    let bundle: Bundle = serde_json::from_slice(&data)
+        .map_err(|err| Error::Config(format!("invalid bundle: {}", err)))?;
+    let (block, manifest) = bundle.verify(args.key_opt)?;
+
+    let mut artifacts_verified = Vec::new();
+    if let Some(artifacts) = args.artifacts_opt {
+        for name in manifest.files.keys() {
+            let data = fs::read(Path::new(artifacts).join(name))
+                .map_err(|err| Error::NotFound(format!("artifact {}: {}", name, err)))?;
+            manifest.verify_file(name, &data).map_err(Error::Verify)?;
+            artifacts_verified.push(name.clone());
+        }
+    }
+
+    let verification = BundleVerification {
+        project: bundle.project,
+        branch: bundle.branch,
+        block,
+        manifest,
+        artifacts_verified,
+    };
+    match args.format {
+        Format::Text => {
+            if args.key_opt.is_none() {
+                eprintln!(
+                    "buildchain: warning: the bundle was verified with the key it contains, \
+                     use --key to verify it with a trusted key"
+                );
+            }
+            println!(
+                "buildchain: verified block {} of tail/{}/{} with counter {}, signed at {}",
+                verification.block.signature,
+                verification.project,
+                verification.branch,
+                verification.block.counter,
+                verification.block.timestamp
+            );
+            for name in verification.artifacts_verified.iter() {
+                println!("{}: OK", name);
+            }
+        }
+        Format::Json => print_json(&verification)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{bundle, verify_bundle, Bundle, BundleArguments, VerifyBundleArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{Error, Format, Manifest, Sha384, Store};
+
+    #[test]
+    fn test_bundle() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path().join("store"));
+        let artifacts = temp_dir.path().join("artifacts");
+        fs::create_dir_all(store.path()).unwrap();
+        fs::create_dir_all(&artifacts).unwrap();
+        fs::write(artifacts.join("file"), "data").unwrap();
+
+        let mut manifest = Manifest::default();
+        manifest.files.insert(
+            "file".to_string(),
+            Sha384::new("data".as_bytes()).unwrap().to_id(),
+        );
+        let json = serde_json::to_vec_pretty(&manifest).unwrap();
+        let digest = store.write_manifest(&json).unwrap();
+        let (key, block) = signed_block(1, &[0; 64], 0, &digest);
+        store.write_block(&block).unwrap();
+        store.write_tail("default", "master", &block).unwrap();
+        let key = b32enc(&key);
+
+        let output = temp_dir.path().join("bundle.json");
+        bundle(BundleArguments {
+            store_path: store.path().to_str().unwrap(),
+            key: &key,
+            project: "default",
+            branch: "master",
+            pin_opt: None,
+            output: output.to_str().unwrap(),
+        })
+        .unwrap();
+
+        let verify = |key_opt| {
+            verify_bundle(VerifyBundleArguments {
+                path: output.to_str().unwrap(),
+                key_opt,
+                artifacts_opt: Some(artifacts.to_str().unwrap()),
+                format: Format::Json,
+            })
+        };
+        verify(Some(&key)).unwrap();
+        verify(None).unwrap();
+
+        // The bundle must be signed by the expected key, not only by the key it records
+        let (other, _) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        let other = b32enc(&other);
+        assert!(matches!(verify(Some(&other)), Err(Error::Verify(_))));
+
+        // A changed manifest no longer matches the block
+        let data = fs::read(&output).unwrap();
+        let mut tampered: Bundle = serde_json::from_slice(&data).unwrap();
+        tampered.manifest = tampered.manifest.replace("\"time\": 0", "\"time\": 1");
+        assert!(matches!(tampered.verify(Some(&key)), Err(Error::Verify(_))));
+
+        // Artifacts are checked against the manifest
+        fs::write(artifacts.join("file"), "changed").unwrap();
+        assert!(matches!(verify(Some(&key)), Err(Error::Verify(_))));
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! The on-disk cache of download clients, shared by the command line and the library
+//!
+//! Each mirror has a directory under the cache root, named by the digest of its URL:
+//!
+//! ```text
+//! <root>/<url digest>/
+//!     lock                           locked while the cache is read or written
+//!     url                            the URL of the mirror
+//!     object/, block/                verified objects and blocks, as in a Store
+//!     tail/<project>/<branch>        link to the last verified tail block
+//!     state/<project>/<branch>.json  validators of the tail response, see CacheState
+//!     manifest.json                  link to the manifest of the last update
+//! ```
+//!
+//! Everything read from the cache is verified again before it is used, so the cache never has
+//! to be trusted.
+
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{BlockSig, Error, Sha384, Store};
+
+/// The state of the tail of a branch, as last verified
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CacheState {
+    /// The `ETag` of the tail response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    /// The `Last-Modified` time of the tail response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// The counter of the tail block
+    pub counter: u64,
+    /// The signature of the tail block
+    pub signature: BlockSig,
+    /// When the tail was verified, in seconds since the Unix epoch
+    pub verified_at: u64,
+}
+
+/// An advisory lock of the cache directory, released when dropped
+struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    fn new(path: &Path, exclusive: bool) -> io::Result<CacheLock> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // SAFETY: the descriptor is owned by file, which outlives the call
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(CacheLock { _file: file })
+    }
+}
+
+/// The cache directory of one mirror
+pub struct Cache {
+    store: Store,
+}
+
+impl Cache {
+    /// The default cache root, `$XDG_CACHE_HOME/buildchain` or `~/.cache/buildchain`
+    pub fn default_root() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join("buildchain"))
+    }
+
+    /// Open the cache of the mirror at `url` under `root`, creating it if needed
+    pub fn open<P: AsRef<Path>>(root: P, url: &str) -> Result<Cache, Error> {
+        let digest = Sha384::new(url.as_bytes())?.to_base32();
+        let dir = root.as_ref().join(&digest[..32]);
+        fs::create_dir_all(&dir)?;
+
+        let cache = Cache {
+            store: Store::new(&dir).with_indexes(false),
+        };
+        let _lock = cache.lock(true)?;
+        if !dir.join("url").exists() {
+            fs::write(dir.join("url"), url)?;
+        }
+        Ok(cache)
+    }
+
+    /// Open the cache of the mirror at `url` under [`Cache::default_root`]
+    pub fn open_default(url: &str) -> Result<Cache, Error> {
+        let root = Cache::default_root().ok_or_else(|| {
+            Error::Config("no cache directory, set XDG_CACHE_HOME or HOME".to_string())
+        })?;
+        Cache::open(root, url)
+    }
+
+    fn lock(&self, exclusive: bool) -> io::Result<CacheLock> {
+        CacheLock::new(&self.store.path().join("lock"), exclusive)
+    }
+
+    /// The directory of the cache
+    pub fn path(&self) -> &Path {
+        self.store.path()
+    }
+
+    /// The store of cached objects and blocks
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    fn state_path(&self, project: &str, branch: &str) -> PathBuf {
+        self.path()
+            .join("state")
+            .join(project)
+            .join(format!("{}.json", branch))
+    }
+
+    /// Read the last verified tail block of `project` and `branch` and its state, if cached
+    ///
+    /// The block must be verified again before it is used.
+    pub fn read_tail(
+        &self,
+        project: &str,
+        branch: &str,
+    ) -> Result<Option<([u8; 400], CacheState)>, Error> {
+        let _lock = self.lock(false)?;
+        let block = match self.store.read_tail(project, branch)? {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let state = match fs::read(self.state_path(project, branch)) {
+            Ok(data) => serde_json::from_slice(&data).map_err(io::Error::from)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Some((block, state)))
+    }
+
+    /// Record the verified tail `block` of `project` and `branch` and its state
+    pub fn write_tail(
+        &self,
+        project: &str,
+        branch: &str,
+        block: &[u8; 400],
+        state: &CacheState,
+    ) -> Result<(), Error> {
+        let _lock = self.lock(true)?;
+        self.store.write_tail(project, branch, block)?;
+
+        let path = self.state_path(project, branch);
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("json.partial");
+        fs::write(
+            &tmp,
+            serde_json::to_vec_pretty(state).map_err(io::Error::from)?,
+        )?;
+        Ok(fs::rename(tmp, path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{Cache, CacheState};
+    use crate::block::tests::signed_block;
+
+    #[test]
+    fn test_cache() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let cache = Cache::open(temp_dir.path(), "https://example.com/").unwrap();
+        let other = Cache::open(temp_dir.path(), "https://example.org/").unwrap();
+        assert_ne!(cache.path(), other.path());
+        assert_eq!(
+            std::fs::read_to_string(cache.path().join("url")).unwrap(),
+            "https://example.com/"
+        );
+        assert!(cache.read_tail("default", "master").unwrap().is_none());
+
+        let (_key, block) = signed_block(1, &[0; 64], 3, &[0; 48]);
+        let state = CacheState {
+            etag: Some("\"1\"".to_string()),
+            counter: 3,
+            verified_at: 1_500_000_003,
+            ..Default::default()
+        };
+        cache
+            .write_tail("default", "master", &block, &state)
+            .unwrap();
+        let (read, read_state) = cache.read_tail("default", "master").unwrap().unwrap();
+        assert_eq!(read, block);
+        assert_eq!(read_state, state);
+
+        // Opening the cache again finds the same directory
+        let again = Cache::open(temp_dir.path(), "https://example.com/").unwrap();
+        assert!(again.read_tail("default", "master").unwrap().is_some());
+
+        temp_dir.close().unwrap();
+    }
+}
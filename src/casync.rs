@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Export of verified artifacts as casync chunk stores and blob indexes
+//!
+//! Each artifact of the tail of every branch is split into content-defined chunks, which are
+//! written to `<dest>/default.castr/<first 4 hex digits>/<chunk id>.cacnk`, shared by all
+//! branches. The artifact is described by `<dest>/<project>/<branch>/<artifact>.caibx`, which
+//! lists its chunks, so `casync extract` or `desync extract` can rebuild it while reusing the
+//! chunks of a previous version.
+//!
+//! Chunk IDs are the SHA-512/256 of the chunk, as in casync. Chunks are stored as zstd frames
+//! of raw blocks, so no compressor is needed, and clients decompress them as usual. Boundaries
+//! are found with a gear hash rather than the buzhash of casync, so chunks are shared between
+//! exports, but not with chunk stores that casync made from the same data.
+
+use sha2::{Digest, Sha256, Sha512_256};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{err_str, Downloader, LocalTransport, Manifest, Store};
+
+const CA_FORMAT_INDEX: u64 = 0x96824d9c7b129ff9;
+const CA_FORMAT_TABLE: u64 = 0xe75b9e112f17417d;
+const CA_FORMAT_TABLE_TAIL_MARKER: u64 = 0x4b4f050e5549ecd1;
+const CA_FORMAT_SHA512_256: u64 = 0x2000000000000000;
+
+/// The chunk sizes of casync, recorded in each index
+const CHUNK_SIZE_MIN: usize = 16 * 1024;
+const CHUNK_SIZE_AVG: usize = 64 * 1024;
+const CHUNK_SIZE_MAX: usize = 256 * 1024;
+
+/// The largest raw block in a zstd frame
+const ZSTD_BLOCK_MAX: usize = 128 * 1024;
+
+pub struct CasyncArguments<'a> {
+    pub store_path: &'a str,
+    pub dest: &'a str,
+    pub key: &'a str,
+}
+
+/// Splits data into chunks at positions chosen by its contents
+struct Chunker {
+    gear: [u64; 256],
+    mask: u64,
+}
+
+impl Chunker {
+    fn new() -> Chunker {
+        // The table only has to be random looking and the same for every export
+        let mut gear = [0; 256];
+        for (byte, value) in gear.iter_mut().enumerate() {
+            let hash = Sha256::digest([byte as u8]);
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&hash[..8]);
+            *value = u64::from_le_bytes(bytes);
+        }
+
+        // The high bits of the hash depend on the most recent 64 bytes
+        let bits = (CHUNK_SIZE_AVG - CHUNK_SIZE_MIN).ilog2();
+        Chunker {
+            gear,
+            mask: !(u64::MAX >> bits),
+        }
+    }
+
+    /// The length of the first chunk of `data`
+    fn boundary(&self, data: &[u8]) -> usize {
+        let end = data.len().min(CHUNK_SIZE_MAX);
+        if end <= CHUNK_SIZE_MIN {
+            return end;
+        }
+
+        let mut hash = 0u64;
+        for (i, byte) in data[..end].iter().enumerate().skip(CHUNK_SIZE_MIN) {
+            hash = (hash << 1).wrapping_add(self.gear[*byte as usize]);
+            if hash & self.mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+
+    /// The chunks of `data`, in order
+    fn chunks<'a>(&self, mut data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(self.boundary(data));
+            chunks.push(chunk);
+            data = rest;
+        }
+        chunks
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Encode `data` as a zstd frame of raw blocks, with the content size
+fn zstd_raw(data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 16);
+    frame.extend_from_slice(&0xFD2FB528u32.to_le_bytes());
+    // Single segment, with an 8 byte content size and no checksum or dictionary
+    frame.push(0xE0);
+    frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+
+    let blocks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(ZSTD_BLOCK_MAX).collect()
+    };
+    for (i, block) in blocks.iter().enumerate() {
+        let last = (i + 1 == blocks.len()) as u32;
+        // Bit 0 marks the last block, bits 1-2 are the type, 0 for raw, and the rest the size
+        let header = last | (block.len() as u32) << 3;
+        frame.extend_from_slice(&header.to_le_bytes()[..3]);
+        frame.extend_from_slice(block);
+    }
+    frame
+}
+
+/// Write the chunks of `data` to the chunk store `castr`, returning the blob index
+fn write_blob(chunker: &Chunker, data: &[u8], castr: &Path) -> io::Result<Vec<u8>> {
+    let mut index = Vec::new();
+    for value in [
+        48,
+        CA_FORMAT_INDEX,
+        CA_FORMAT_SHA512_256,
+        CHUNK_SIZE_MIN as u64,
+        CHUNK_SIZE_AVG as u64,
+        CHUNK_SIZE_MAX as u64,
+    ] {
+        index.extend_from_slice(&value.to_le_bytes());
+    }
+    index.extend_from_slice(&u64::MAX.to_le_bytes());
+    index.extend_from_slice(&CA_FORMAT_TABLE.to_le_bytes());
+
+    let chunks = chunker.chunks(data);
+    let mut offset = 0u64;
+    for chunk in chunks.iter() {
+        let id = hex(&Sha512_256::digest(chunk));
+        let dir = castr.join(&id[..4]);
+        let path = dir.join(format!("{}.cacnk", id));
+        if !path.is_file() {
+            fs::create_dir_all(&dir)?;
+            let tmp = dir.join(format!(".{}.partial", id));
+            fs::write(&tmp, zstd_raw(chunk))?;
+            fs::rename(tmp, path)?;
+        }
+
+        // Each item is the end offset of the chunk and its ID
+        offset += chunk.len() as u64;
+        index.extend_from_slice(&offset.to_le_bytes());
+        index.extend_from_slice(&Sha512_256::digest(chunk));
+    }
+
+    let table_size = 16 + 40 * chunks.len() as u64 + 40;
+    for value in [0, 0, 48, table_size, CA_FORMAT_TABLE_TAIL_MARKER] {
+        index.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(index)
+}
+
+/// Export the verified artifacts of the tail of each branch in a store for casync
+///
+/// The indexes of a branch are replaced with those of its current tail, while chunks are kept,
+/// so clients can still extract earlier builds.
+pub fn casync_export(args: CasyncArguments) -> Result<(), String> {
+    let store = Store::new(args.store_path);
+    let dest = Path::new(args.dest);
+    let castr = dest.join("default.castr");
+    let chunker = Chunker::new();
+
+    for (project, branches) in store.tail_index().map_err(err_str)? {
+        for branch in branches {
+            let dl = Downloader::from_transport(
+                args.key,
+                &project,
+                &branch,
+                Box::new(LocalTransport::new(args.store_path)),
+            )
+            .map_err(err_str)?;
+            let block = dl.tail().map_err(err_str)?;
+            let manifest_json = dl.object(&block.digest).map_err(err_str)?;
+            let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
+
+            let index_dir = dest.join(&project).join(&branch);
+            let tmp_dir = dest.join(&project).join(format!(".{}.partial", branch));
+            if tmp_dir.exists() {
+                fs::remove_dir_all(&tmp_dir).map_err(err_str)?;
+            }
+            for (name, digest) in manifest.files.iter() {
+                if name.is_empty() || name.split('/').any(|part| part == ".." || part.is_empty()) {
+                    return Err(format!("invalid artifact name {}", name));
+                }
+                let data = dl.object(digest).map_err(err_str)?;
+                let index = write_blob(&chunker, &data, &castr).map_err(err_str)?;
+
+                let path = tmp_dir.join(format!("{}.caibx", name));
+                fs::create_dir_all(path.parent().unwrap()).map_err(err_str)?;
+                fs::write(path, index).map_err(err_str)?;
+            }
+            fs::create_dir_all(&tmp_dir).map_err(err_str)?;
+
+            if index_dir.exists() {
+                fs::remove_dir_all(&index_dir).map_err(err_str)?;
+            }
+            fs::rename(tmp_dir, &index_dir).map_err(err_str)?;
+            println!(
+                "Export {}/{} {} ({} artifacts)",
+                project,
+                branch,
+                block.counter,
+                manifest.files.len()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha512_256};
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{
+        casync_export, hex, zstd_raw, CasyncArguments, Chunker, CA_FORMAT_TABLE_TAIL_MARKER,
+        CHUNK_SIZE_MAX, CHUNK_SIZE_MIN,
+    };
+    use crate::block::tests::{random_data, signed_block};
+    use crate::id::b32enc;
+    use crate::{Manifest, Store};
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_chunker() {
+        let chunker = Chunker::new();
+        let original = random_data(1024 * 1024, 1);
+        let chunks = chunker.chunks(&original);
+        assert_eq!(chunks.concat(), original);
+        for chunk in chunks[..chunks.len() - 1].iter() {
+            assert!(chunk.len() >= CHUNK_SIZE_MIN && chunk.len() <= CHUNK_SIZE_MAX);
+        }
+
+        // Inserting data at the start only changes the first chunks
+        let mut changed = random_data(100, 2);
+        changed.extend_from_slice(&original);
+        let changed_chunks = chunker.chunks(&changed);
+        let shared = changed_chunks
+            .iter()
+            .filter(|chunk| chunks.contains(chunk))
+            .count();
+        assert!(shared + 2 >= chunks.len());
+
+        assert!(chunker.chunks(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_zstd_raw() {
+        let data = random_data(200 * 1024, 3);
+        let frame = zstd_raw(&data);
+        assert_eq!(frame[..4], [0x28, 0xB5, 0x2F, 0xFD]);
+        assert_eq!(u64_at(&frame, 5), data.len() as u64);
+
+        // Decode the raw blocks
+        let mut decoded = Vec::new();
+        let mut offset = 13;
+        loop {
+            let header =
+                u32::from_le_bytes([frame[offset], frame[offset + 1], frame[offset + 2], 0]);
+            assert_eq!((header >> 1) & 3, 0);
+            let size = (header >> 3) as usize;
+            decoded.extend_from_slice(&frame[offset + 3..offset + 3 + size]);
+            offset += 3 + size;
+            if header & 1 == 1 {
+                break;
+            }
+        }
+        assert_eq!(offset, frame.len());
+        assert_eq!(decoded, data);
+
+        assert_eq!(zstd_raw(&[]).len(), 16);
+    }
+
+    #[test]
+    fn test_casync_export() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path().join("store"));
+        fs::create_dir(store.path()).unwrap();
+
+        let artifact = random_data(300 * 1024, 4);
+        let file_key = store.write_object(&artifact).unwrap();
+        let manifest = Manifest {
+            time: 0,
+            files: [("dir/image.bin".to_string(), file_key)].into(),
+            ..Default::default()
+        };
+        let manifest_key = store
+            .write_object(&serde_json::to_vec(&manifest).unwrap())
+            .unwrap();
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &manifest_key);
+        store.write_tail("default", "master", &block).unwrap();
+
+        let dest = temp_dir.path().join("casync");
+        let args = CasyncArguments {
+            store_path: store.path().to_str().unwrap(),
+            dest: dest.to_str().unwrap(),
+            key: &b32enc(&public_key),
+        };
+        casync_export(args).unwrap();
+
+        let index = fs::read(dest.join("default/master/dir/image.bin.caibx")).unwrap();
+        assert_eq!(u64_at(&index, 0), 48);
+        assert_eq!(u64_at(&index, index.len() - 8), CA_FORMAT_TABLE_TAIL_MARKER);
+        assert_eq!(u64_at(&index, index.len() - 16) as usize, index.len() - 48);
+
+        // Every chunk is in the store, and they end at the length of the artifact
+        let items = (index.len() - 48 - 16 - 40) / 40;
+        let mut start = 0;
+        for item in 0..items {
+            let offset = 64 + item * 40;
+            let end = u64_at(&index, offset) as usize;
+            let id = hex(&index[offset + 8..offset + 40]);
+            assert_eq!(id, hex(&Sha512_256::digest(&artifact[start..end])));
+            let chunk = dest
+                .join("default.castr")
+                .join(&id[..4])
+                .join(format!("{}.cacnk", id));
+            assert!(chunk.is_file());
+            start = end;
+        }
+        assert_eq!(start, artifact.len());
+
+        temp_dir.close().unwrap();
+    }
+}
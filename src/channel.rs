@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha384};
+
+fn full_rollout() -> u8 {
+    100
+}
+
+fn is_full_rollout(rollout: &u8) -> bool {
+    *rollout >= 100
+}
+
+/// Staged rollout of a build, recorded in its manifest so that it is covered by the signature
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Channel {
+    /// The percentage of devices that should install the build
+    #[serde(default = "full_rollout", skip_serializing_if = "is_full_rollout")]
+    pub rollout: u8,
+    /// Device cohorts that should install the build, regardless of `rollout`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cohorts: Vec<String>,
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel {
+            rollout: full_rollout(),
+            cohorts: Vec::new(),
+        }
+    }
+}
+
+impl Channel {
+    /// True if the device with `seed`, in the cohort `cohort_opt`, should install the build
+    ///
+    /// The seed places each device in a fixed bucket from 0 to 99, so a device that is included
+    /// at one percentage stays included as the rollout grows.
+    pub fn includes(&self, seed: &[u8], cohort_opt: Option<&str>) -> bool {
+        if let Some(cohort) = cohort_opt {
+            if self.cohorts.iter().any(|c| c == cohort) {
+                return true;
+            }
+        }
+
+        let digest = Sha384::digest(seed);
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        u64::from_le_bytes(bytes) % 100 < u64::from(self.rollout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Channel;
+
+    #[test]
+    fn test_includes() {
+        let seeds: Vec<[u8; 4]> = (0..1000u32).map(|i| i.to_le_bytes()).collect();
+        let count = |channel: &Channel| {
+            seeds
+                .iter()
+                .filter(|seed| channel.includes(&seed[..], None))
+                .count()
+        };
+
+        let mut channel = Channel {
+            rollout: 0,
+            cohorts: vec!["beta".to_string()],
+        };
+        assert_eq!(count(&channel), 0);
+        assert!(channel.includes(b"device", Some("beta")));
+        assert!(!channel.includes(b"device", Some("stable")));
+
+        channel.rollout = 10;
+        let included: Vec<_> = seeds
+            .iter()
+            .filter(|seed| channel.includes(&seed[..], None))
+            .collect();
+        assert!((50..150).contains(&included.len()));
+
+        // Devices stay included as the rollout grows
+        channel.rollout = 50;
+        assert!(included
+            .iter()
+            .all(|seed| channel.includes(&seed[..], None)));
+
+        assert_eq!(count(&Channel::default()), seeds.len());
+        let json = serde_json::to_string(&Channel::default()).unwrap();
+        assert_eq!(json, "{}");
+        assert_eq!(
+            serde_json::from_str::<Channel>(&json).unwrap(),
+            Channel::default()
+        );
+    }
+}
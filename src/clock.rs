@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Sources of time and randomness, which tests and reproducibility tooling can pin
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+
+/// A source of the current time
+pub trait Clock: Debug + Send + Sync {
+    /// Seconds since the Unix epoch
+    fn now(&self) -> u64;
+}
+
+/// The system clock
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+}
+
+/// A clock that always returns the same time
+#[derive(Clone, Copy, Debug)]
+pub struct FixedClock(pub u64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A source of random bytes
+pub trait Rng: Debug + Send + Sync {
+    fn fill_bytes(&self, dest: &mut [u8]);
+}
+
+/// The operating system random number generator
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsRng;
+
+impl Rng for OsRng {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        rand::rngs::OsRng.fill_bytes(dest);
+    }
+}
+
+/// A deterministic random number generator
+#[derive(Debug)]
+pub struct SeededRng(Mutex<StdRng>);
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng(Mutex::new(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill_bytes(&self, dest: &mut [u8]) {
+        self.0.lock().unwrap().fill_bytes(dest);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, FixedClock, Rng, SeededRng, SystemClock};
+
+    #[test]
+    fn test_pinned() {
+        assert_eq!(FixedClock(1_500_000_000).now(), 1_500_000_000);
+        assert!(SystemClock.now() > 1_500_000_000);
+
+        let mut a = [0; 16];
+        let mut b = [0; 16];
+        SeededRng::new(1).fill_bytes(&mut a);
+        SeededRng::new(1).fill_bytes(&mut b);
+        assert_eq!(a, b);
+        SeededRng::new(2).fill_bytes(&mut b);
+        assert_ne!(a, b);
+    }
+}
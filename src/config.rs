@@ -1,16 +1,184 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::Device;
+
+/// A pinned environment for the build and publish commands
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Environment {
+    /// A nix flake output providing a development shell, such as `./source#default`
+    ///
+    /// Commands are run from `/root`, with the source checked out in `/root/source`. Nix must
+    /// be installed by the prepare commands.
+    pub nix: String,
+}
+
+/// The process settings of build and publish commands, which are the same on every host
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct Process {
+    /// The octal file mode creation mask
+    pub umask: String,
+    /// The locale, set as `LC_ALL`
+    pub locale: String,
+    /// The time zone, set as `TZ`
+    pub timezone: String,
+    /// The home directory, set as `HOME`
+    pub home: String,
+}
+
+impl Default for Process {
+    fn default() -> Process {
+        Process {
+            umask: "022".to_string(),
+            locale: "C.UTF-8".to_string(),
+            timezone: "UTC".to_string(),
+            home: "/root".to_string(),
+        }
+    }
+}
+
+/// The user that build and publish commands run as in the container
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct User {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// An artifact that the build must produce, checked before the manifest is signed
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RequiredArtifact {
+    /// The path relative to `/root/artifacts`, or a pattern as in [`Config::unsigned`] that
+    /// must match at least one artifact
+    pub name: String,
+    /// Whether the artifact must be executable, or must not be, if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub executable: Option<bool>,
+    /// The smallest size of the artifact, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<u64>,
+    /// The largest size of the artifact, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+}
+
+fn default_token_env() -> String {
+    "BUILDCHAIN_TOKEN".to_string()
+}
+
+/// A result of the build, written once the build directory is complete
+///
+/// Paths are [`crate::OutputTemplate`]s, such as `{name}-{branch}-{counter}.tar`.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum OutputTarget {
+    /// A tar archive of the build directory
+    Archive { path: String },
+    /// A copy of the build directory, which must not already exist
+    Directory { path: String },
+    /// An upload of the signed build to a server started with `buildchain serve`
+    Push {
+        url: String,
+        /// The environment variable holding the upload token, so that it is not in the
+        /// configuration
+        #[serde(default = "default_token_env")]
+        token_env: String,
+    },
+}
+
+/// Limits on the size of signed artifacts, checked before the manifest is signed
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct Budget {
+    /// The largest total size of the artifacts, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+    /// The largest size of each artifact matching a pattern, in bytes
+    ///
+    /// Patterns are matched as in [`Config::unsigned`].
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<String, u64>,
+    /// Warn about exceeded budgets instead of failing the build
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub warn: bool,
+}
+
+/// Describe the change of a size from `previous_opt`, or that there was no previous size
+fn size_change(size: u64, previous_opt: Option<u64>) -> String {
+    match previous_opt {
+        Some(previous) if size >= previous => format!("+{} bytes", size - previous),
+        Some(previous) => format!("-{} bytes", previous - size),
+        None => "new".to_string(),
+    }
+}
+
+impl Budget {
+    /// Describe each budget exceeded by the artifact `sizes`, with the change from the sizes
+    /// of the previous build if they are known
+    pub fn check(
+        &self,
+        sizes: &BTreeMap<String, u64>,
+        previous_opt: Option<&BTreeMap<String, u64>>,
+    ) -> Vec<String> {
+        let mut exceeded = Vec::new();
+        for (name, size) in sizes.iter() {
+            let limit_opt = self
+                .files
+                .iter()
+                .filter(|(pattern, _)| pattern_matches(pattern.as_bytes(), name.as_bytes()))
+                .min_by_key(|(_, limit)| **limit);
+            if let Some((pattern, limit)) = limit_opt {
+                if size > limit {
+                    let previous = previous_opt.map(|previous| previous.get(name).copied());
+                    exceeded.push(format!(
+                        "{} is {} bytes, over the budget of {} bytes for {} ({})",
+                        name,
+                        size,
+                        limit,
+                        pattern,
+                        previous.map_or("no previous build".to_string(), |previous| {
+                            size_change(*size, previous)
+                        })
+                    ));
+                }
+            }
+        }
+
+        if let Some(limit) = self.total {
+            let total: u64 = sizes.values().sum();
+            if total > limit {
+                exceeded.push(format!(
+                    "artifacts are {} bytes, over the total budget of {} bytes ({})",
+                    total,
+                    limit,
+                    previous_opt.map_or("no previous build".to_string(), |previous| {
+                        size_change(total, Some(previous.values().sum()))
+                    })
+                ));
+            }
+        }
+        exceeded
+    }
+}
 
 /// A build configuration
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Config {
     /// The name of this build project
     pub name: String,
     /// The LXC base to use
     pub base: String,
     /// True if the LXC container for builds should be privileged
-    #[serde(default = "Default::default")]
+    #[serde(default)]
     pub privileged: bool,
     /// The commands to run to generate a build environment
     pub prepare: Vec<Vec<String>>,
@@ -18,4 +186,396 @@ pub struct Config {
     pub build: Vec<Vec<String>>,
     /// The commands to run that publish the artifacts to /root/artifacts
     pub publish: Vec<Vec<String>>,
+    /// The environment the build and publish commands are run in, if pinned
+    #[serde(default)]
+    pub environment: Option<Environment>,
+    /// Digest algorithms to record for each artifact besides sha384, such as `sha256`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub digests: Vec<String>,
+    /// Artifacts that are archived in `unsigned/` instead of being signed, such as logs
+    ///
+    /// Patterns are matched against paths relative to `/root/artifacts`, where `*` matches
+    /// any characters and `?` matches one character, but neither matches `/`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsigned: Vec<String>,
+    /// The umask, locale, time zone, and home directory of the build and publish commands
+    #[serde(default)]
+    pub process: Process,
+    /// Run the build and publish commands as this user instead of root, with `setpriv`
+    ///
+    /// The source and artifacts directories are owned by the user, so artifacts are created
+    /// with the same owner on every host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    /// Map the source path, `/root/source`, to this path in debug info and `__FILE__`
+    ///
+    /// This sets `CFLAGS`, `CXXFLAGS`, and `RUSTFLAGS` for the build and publish commands.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix_map: Option<String>,
+    /// Run the build and publish commands under `faketime`, starting the clock at the source
+    /// time, for tools that record the current time despite `SOURCE_DATE_EPOCH`
+    ///
+    /// The `faketime` command must be installed by the prepare commands.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub faketime: bool,
+    /// Clear timestamps and owners in gzip, ar, and zip artifacts before they are signed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub normalize: bool,
+    /// Size limits on the artifacts, which fail the build or warn if they are exceeded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+    /// Artifacts that must be signed, so that a publish command that silently did nothing
+    /// fails the build instead of publishing an incomplete manifest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required: Vec<RequiredArtifact>,
+    /// The devices that artifacts apply to, by pattern as in [`Config::unsigned`], recorded in
+    /// [`crate::Manifest::devices`]
+    ///
+    /// Artifacts that match no pattern apply to every device.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub devices: BTreeMap<String, Vec<Device>>,
+    /// The path of the output archive, as a [`crate::OutputTemplate`] such as
+    /// `{name}-{branch}-{counter}.tar`, if it is not given to the build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// More results of the build, written along with [`Config::output`], so that one build
+    /// can be archived, exported, and pushed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<OutputTarget>,
+}
+
+/// Match `name` against the shell-style `pattern`
+fn pattern_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            pattern_matches(&pattern[1..], name)
+                || (name.first().is_some_and(|c| *c != b'/')
+                    && pattern_matches(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(c)) if *c != b'/' => pattern_matches(&pattern[1..], &name[1..]),
+        (Some(p), Some(c)) if p == c => pattern_matches(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+impl Config {
+    /// Parse a configuration from JSON, along with the keys that were ignored because no field
+    /// has their name
+    ///
+    /// Ignored keys are usually typos, such as `prepere`, that would leave steps out of the
+    /// build, so callers should warn about them or refuse the configuration. Keys in nested
+    /// objects are written as paths, such as `process.umsk`.
+    #[cfg(feature = "build")]
+    pub fn parse(json: &str) -> Result<(Config, Vec<String>), serde_json::Error> {
+        let mut ignored = Vec::new();
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        let config =
+            serde_ignored::deserialize(&mut deserializer, |path| ignored.push(path.to_string()))?;
+        deserializer.end()?;
+        Ok((config, ignored))
+    }
+
+    /// Describe each [`Config::required`] artifact that is missing from the signed artifacts
+    /// with `sizes`, or that does not match its constraints
+    ///
+    /// Executable artifacts are those with permission bits in `modes`, as in
+    /// [`crate::Manifest::modes`].
+    pub fn missing_artifacts(
+        &self,
+        sizes: &BTreeMap<String, u64>,
+        modes: &BTreeMap<String, u32>,
+    ) -> Vec<String> {
+        let mut missing = Vec::new();
+        for required in self.required.iter() {
+            let mut found = false;
+            for (name, size) in sizes.iter() {
+                if !pattern_matches(required.name.as_bytes(), name.as_bytes()) {
+                    continue;
+                }
+                found = true;
+
+                let executable = modes.contains_key(name);
+                match required.executable {
+                    Some(true) if !executable => {
+                        missing.push(format!("{} is not executable", name));
+                    }
+                    Some(false) if executable => {
+                        missing.push(format!("{} is executable", name));
+                    }
+                    _ => (),
+                }
+                if let Some(min_size) = required.min_size.filter(|min_size| size < min_size) {
+                    missing.push(format!(
+                        "{} is {} bytes, smaller than {} bytes",
+                        name, size, min_size
+                    ));
+                }
+                if let Some(max_size) = required.max_size.filter(|max_size| size > max_size) {
+                    missing.push(format!(
+                        "{} is {} bytes, larger than {} bytes",
+                        name, size, max_size
+                    ));
+                }
+            }
+            if !found {
+                missing.push(format!("{} is missing", required.name));
+            }
+        }
+        missing
+    }
+
+    /// The devices that each of the artifacts `names` applies to, from [`Config::devices`]
+    ///
+    /// An artifact matching several patterns applies to the devices of all of them.
+    pub fn device_targets<'a, I>(&self, names: I) -> BTreeMap<String, Vec<Device>>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let mut targets = BTreeMap::new();
+        for name in names {
+            let mut devices: Vec<Device> = self
+                .devices
+                .iter()
+                .filter(|(pattern, _)| pattern_matches(pattern.as_bytes(), name.as_bytes()))
+                .flat_map(|(_, devices)| devices.iter().cloned())
+                .collect();
+            if devices.is_empty() {
+                continue;
+            }
+            devices.sort();
+            devices.dedup();
+            targets.insert(name.clone(), devices);
+        }
+        targets
+    }
+
+    /// The results of the build, as set by [`Config::output`] and [`Config::outputs`], or an
+    /// archive at `default_output` if neither is set
+    pub fn output_targets(&self, default_output: &str) -> Vec<OutputTarget> {
+        let mut targets = Vec::new();
+        if let Some(path) = &self.output {
+            targets.push(OutputTarget::Archive { path: path.clone() });
+        }
+        targets.extend(self.outputs.iter().cloned());
+        if targets.is_empty() {
+            targets.push(OutputTarget::Archive {
+                path: default_output.to_string(),
+            });
+        }
+        targets
+    }
+
+    /// Whether the artifact `name` is kept out of the manifest by [`Config::unsigned`]
+    pub fn is_unsigned(&self, name: &str) -> bool {
+        self.unsigned
+            .iter()
+            .any(|pattern| pattern_matches(pattern.as_bytes(), name.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{Budget, Config, OutputTarget};
+
+    #[test]
+    fn test_missing_artifacts() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": [],
+                "required": [
+                    {"name": "bin/tool", "executable": true},
+                    {"name": "*.iso", "min_size": 100, "max_size": 1000},
+                    {"name": "README", "executable": false},
+                    {"name": "*.deb"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let sizes: BTreeMap<String, u64> = [
+            ("bin/tool".to_string(), 10),
+            ("small.iso".to_string(), 50),
+            ("ok.iso".to_string(), 500),
+            ("README".to_string(), 10),
+        ]
+        .into();
+        let mut modes: BTreeMap<String, u32> = [("README".to_string(), 0o755)].into();
+        assert_eq!(
+            config.missing_artifacts(&sizes, &modes),
+            [
+                "bin/tool is not executable",
+                "small.iso is 50 bytes, smaller than 100 bytes",
+                "README is executable",
+                "*.deb is missing",
+            ]
+        );
+
+        modes = [("bin/tool".to_string(), 0o755)].into();
+        let sizes: BTreeMap<String, u64> = sizes
+            .into_iter()
+            .filter(|(name, _)| name != "small.iso")
+            .chain([("tool.deb".to_string(), 1)])
+            .collect();
+        assert!(config.missing_artifacts(&sizes, &modes).is_empty());
+    }
+
+    #[test]
+    fn test_parse() {
+        let (config, ignored) = Config::parse(
+            r#"{"name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": []}"#,
+        )
+        .unwrap();
+        assert_eq!(config.name, "test");
+        assert!(ignored.is_empty());
+
+        let (config, ignored) = Config::parse(
+            r#"{
+                "name": "test", "base": "ubuntu:22.04", "prepere": [["apt", "update"]],
+                "prepare": [], "build": [], "publish": [], "process": {"umsk": "077"}
+            }"#,
+        )
+        .unwrap();
+        assert!(config.prepare.is_empty());
+        assert_eq!(config.process.umask, "022");
+        assert_eq!(ignored, ["prepere", "process.umsk"]);
+
+        assert!(Config::parse(r#"{"name": "test"}"#).is_err());
+        assert!(Config::parse(
+            r#"{"name": "test", "base": "b", "prepare": [], "build": [], "publish": []} {}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_is_unsigned() {
+        let mut config: Config = serde_json::from_str(
+            r#"{"name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": []}"#,
+        )
+        .unwrap();
+        assert!(!config.is_unsigned("build.log"));
+
+        config.unsigned = vec![
+            "*.log".to_string(),
+            "logs/*".to_string(),
+            "time?".to_string(),
+        ];
+        assert!(config.is_unsigned("build.log"));
+        assert!(config.is_unsigned("logs/test.txt"));
+        assert!(config.is_unsigned("time1"));
+        assert!(!config.is_unsigned("time"));
+        assert!(!config.is_unsigned("logs/sub/test.txt"));
+        assert!(!config.is_unsigned("sub/build.log"));
+        assert!(!config.is_unsigned("build.log.gz"));
+    }
+
+    #[test]
+    fn test_device_targets() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": [],
+                "devices": {
+                    "galp5*": [{"product": "galp5", "board_revision": "A1"}],
+                    "*.rom": [{"product": "galp5"}, {"product": "oryp6"}]
+                }
+            }"#,
+        )
+        .unwrap();
+        let names = [
+            "galp5.rom".to_string(),
+            "oryp6.rom".to_string(),
+            "README".to_string(),
+        ];
+        let targets = config.device_targets(names.iter());
+        let products = |name: &str| -> Vec<String> {
+            targets[name]
+                .iter()
+                .map(|device| device.to_string())
+                .collect()
+        };
+        assert_eq!(products("galp5.rom"), ["galp5", "galp5:A1", "oryp6"]);
+        assert_eq!(products("oryp6.rom"), ["galp5", "oryp6"]);
+        assert!(!targets.contains_key("README"));
+    }
+
+    #[test]
+    fn test_output_targets() {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "name": "test", "base": "ubuntu:22.04", "prepare": [], "build": [], "publish": [],
+                "outputs": [
+                    {"kind": "directory", "path": "out/{name}"},
+                    {"kind": "push", "url": "https://example.com/"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.output_targets("buildchain.tar"),
+            [
+                OutputTarget::Directory {
+                    path: "out/{name}".to_string()
+                },
+                OutputTarget::Push {
+                    url: "https://example.com/".to_string(),
+                    token_env: "BUILDCHAIN_TOKEN".to_string(),
+                },
+            ]
+        );
+
+        config.output = Some("{name}.tar".to_string());
+        assert_eq!(config.output_targets("buildchain.tar").len(), 3);
+        config.output = None;
+        config.outputs.clear();
+        assert_eq!(
+            config.output_targets("buildchain.tar"),
+            [OutputTarget::Archive {
+                path: "buildchain.tar".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget: Budget = serde_json::from_str(
+            r#"{"total": 1000, "files": {"*.so": 300, "libbig.so": 500, "*.iso": 100}}"#,
+        )
+        .unwrap();
+        let sizes: BTreeMap<String, u64> = [
+            ("libbig.so".to_string(), 400),
+            ("libsmall.so".to_string(), 200),
+            ("notes.txt".to_string(), 500),
+        ]
+        .into();
+        // The smallest budget of the matching patterns applies
+        assert_eq!(
+            budget.check(&sizes, None),
+            [
+                "libbig.so is 400 bytes, over the budget of 300 bytes for *.so (no previous build)",
+                "artifacts are 1100 bytes, over the total budget of 1000 bytes (no previous build)",
+            ]
+        );
+
+        let previous: BTreeMap<String, u64> = [
+            ("libbig.so".to_string(), 100),
+            ("notes.txt".to_string(), 600),
+        ]
+        .into();
+        assert_eq!(
+            budget.check(&sizes, Some(&previous)),
+            [
+                "libbig.so is 400 bytes, over the budget of 300 bytes for *.so (+300 bytes)",
+                "artifacts are 1100 bytes, over the total budget of 1000 bytes (+400 bytes)",
+            ]
+        );
+
+        let previous: BTreeMap<String, u64> = [("notes.txt".to_string(), 1200)].into();
+        assert_eq!(
+            budget.check(&sizes, Some(&previous))[0],
+            "libbig.so is 400 bytes, over the budget of 300 bytes for *.so (new)"
+        );
+        assert!(budget.check(&sizes, Some(&previous))[1].ends_with("(-100 bytes)"));
+        assert!(Budget::default().check(&sizes, None).is_empty());
+    }
 }
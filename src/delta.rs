@@ -0,0 +1,310 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Rolling checksum signatures of large objects, for zsync-style differential downloads
+//!
+//! The signature of an object is stored as `zsync/<digest>` in a store. It lists a weak rolling
+//! checksum and a strong hash of each block of the object, so that a client with a previous
+//! version can find the blocks it already has at any offset, and only request the rest with
+//! ranged requests. The signature is not trusted, the rebuilt object is verified by its digest.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::ops::Range;
+
+/// The default minimum size of objects to write signatures for, 16 MiB
+pub const DEFAULT_DELTA_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+const MAGIC: &[u8; 8] = b"BCDELTA1";
+const HEADER_SIZE: usize = 20;
+const ENTRY_SIZE: usize = 20;
+
+/// The smallest block size, blocks are doubled from here until there are at most
+/// [`TARGET_BLOCKS`]
+const MIN_BLOCK_SIZE: u32 = 4096;
+const TARGET_BLOCKS: u64 = 65536;
+
+/// The largest block size, so signatures are only written for objects of up to 64 TiB
+const MAX_BLOCK_SIZE: u32 = 1 << 30;
+
+/// The block size of the signature of an object of `length` bytes, or `None` if it is too large
+fn block_size_for(length: u64) -> Option<u32> {
+    let mut block_size = MIN_BLOCK_SIZE;
+    while length / block_size as u64 > TARGET_BLOCKS {
+        if block_size >= MAX_BLOCK_SIZE {
+            return None;
+        }
+        block_size *= 2;
+    }
+    Some(block_size)
+}
+
+/// The rsync rolling checksum of a window
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add(((window.len() - i) as u32).wrapping_mul(*byte as u32));
+        }
+        Rolling {
+            a,
+            b,
+            len: window.len() as u32,
+        }
+    }
+
+    /// Move the window one byte, removing `old` and adding `new`
+    fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(old as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong(block: &[u8]) -> [u8; 16] {
+    let mut hash = [0; 16];
+    hash.copy_from_slice(&Sha256::digest(block)[..16]);
+    hash
+}
+
+/// The block checksums of an object
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DeltaSignature {
+    pub length: u64,
+    pub block_size: u32,
+    /// The weak rolling checksum and truncated SHA-256 of each block
+    pub blocks: Vec<(u32, [u8; 16])>,
+}
+
+impl DeltaSignature {
+    /// Compute the signature of the `length` bytes of `reader`
+    pub fn new<R: Read>(length: u64, mut reader: R) -> io::Result<DeltaSignature> {
+        let block_size = block_size_for(length).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "object is too large for a delta signature",
+            )
+        })?;
+
+        let mut blocks = Vec::new();
+        let mut buffer = vec![0; block_size as usize];
+        let mut remaining = length;
+        while remaining > 0 {
+            let count = remaining.min(block_size as u64) as usize;
+            reader.read_exact(&mut buffer[..count])?;
+            blocks.push((
+                Rolling::new(&buffer[..count]).value(),
+                strong(&buffer[..count]),
+            ));
+            remaining -= count as u64;
+        }
+
+        Ok(DeltaSignature {
+            length,
+            block_size,
+            blocks,
+        })
+    }
+
+    /// Parse a signature written by [`DeltaSignature::to_bytes`]
+    ///
+    /// Signatures come from mirrors, so the block size must be the one
+    /// [`DeltaSignature::new`] uses for the length. The length itself must still be checked
+    /// against the object before [`DeltaSignature::rebuild`] allocates it.
+    pub fn from_bytes(data: &[u8]) -> Result<DeltaSignature, String> {
+        if data.len() < HEADER_SIZE || &data[..8] != MAGIC {
+            return Err("invalid delta signature".to_string());
+        }
+        let length = u64::from_le_bytes(data[8..16].try_into().unwrap());
+        let block_size = u32::from_le_bytes(data[16..20].try_into().unwrap());
+        if block_size_for(length) != Some(block_size) {
+            return Err("invalid delta signature block size".to_string());
+        }
+        let count = length.div_ceil(block_size as u64);
+        if (data.len() - HEADER_SIZE) as u64 != count.saturating_mul(ENTRY_SIZE as u64) {
+            return Err("delta signature does not match its length".to_string());
+        }
+
+        let blocks = data[HEADER_SIZE..]
+            .chunks(ENTRY_SIZE)
+            .map(|entry| {
+                let mut hash = [0; 16];
+                hash.copy_from_slice(&entry[4..]);
+                (u32::from_le_bytes(entry[..4].try_into().unwrap()), hash)
+            })
+            .collect();
+        Ok(DeltaSignature {
+            length,
+            block_size,
+            blocks,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + ENTRY_SIZE * self.blocks.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.length.to_le_bytes());
+        data.extend_from_slice(&self.block_size.to_le_bytes());
+        for (weak, hash) in self.blocks.iter() {
+            data.extend_from_slice(&weak.to_le_bytes());
+            data.extend_from_slice(hash);
+        }
+        data
+    }
+
+    /// The byte range of block `index`
+    fn range(&self, index: usize) -> Range<u64> {
+        let start = index as u64 * self.block_size as u64;
+        start..(start + self.block_size as u64).min(self.length)
+    }
+
+    /// Copy the blocks found anywhere in `basis` into a new object
+    ///
+    /// Returns the partly rebuilt object, and the byte ranges that are still missing, with
+    /// adjacent blocks merged.
+    pub fn rebuild(&self, basis: &[u8]) -> (Vec<u8>, Vec<Range<u64>>) {
+        let size = self.block_size as usize;
+        let mut data = vec![0; self.length as usize];
+        let mut found = vec![false; self.blocks.len()];
+
+        // Only full blocks are looked up, a short last block is always downloaded
+        let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, (weak, _hash)) in self.blocks.iter().enumerate() {
+            if self.range(index).end - self.range(index).start == size as u64 {
+                by_weak.entry(*weak).or_default().push(index);
+            }
+        }
+
+        let mut offset = 0;
+        let mut rolling_opt = None;
+        while offset + size <= basis.len() {
+            let window = &basis[offset..offset + size];
+            let rolling = *rolling_opt.get_or_insert_with(|| Rolling::new(window));
+
+            let mut matched = false;
+            if let Some(indexes) = by_weak.get(&rolling.value()) {
+                let hash = strong(window);
+                for index in indexes.iter() {
+                    if !found[*index] && self.blocks[*index].1 == hash {
+                        let range = self.range(*index);
+                        data[range.start as usize..range.end as usize].copy_from_slice(window);
+                        found[*index] = true;
+                        matched = true;
+                    }
+                }
+            }
+
+            if matched {
+                offset += size;
+                rolling_opt = None;
+            } else if offset + size < basis.len() {
+                rolling_opt
+                    .as_mut()
+                    .unwrap()
+                    .roll(basis[offset], basis[offset + size]);
+                offset += 1;
+            } else {
+                break;
+            }
+        }
+
+        let mut missing: Vec<Range<u64>> = Vec::new();
+        for (index, found) in found.iter().enumerate() {
+            if *found {
+                continue;
+            }
+            let range = self.range(index);
+            match missing.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => missing.push(range),
+            }
+        }
+        (data, missing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_size_for, DeltaSignature, Rolling, MAGIC, MIN_BLOCK_SIZE};
+    use crate::block::tests::random_data;
+
+    #[test]
+    fn test_rolling() {
+        let data = random_data(100, 1);
+        let mut rolling = Rolling::new(&data[..32]);
+        for offset in 1..=68 {
+            rolling.roll(data[offset - 1], data[offset + 31]);
+            assert_eq!(
+                rolling.value(),
+                Rolling::new(&data[offset..offset + 32]).value()
+            );
+        }
+    }
+
+    #[test]
+    fn test_delta_signature() {
+        let size = MIN_BLOCK_SIZE as usize;
+        let old = random_data(10 * size, 2);
+        // Insert data near the start, change a block, and append a short block
+        let mut new = old[..100].to_vec();
+        new.extend_from_slice(&random_data(50, 3));
+        new.extend_from_slice(&old[100..5 * size]);
+        new.extend_from_slice(&random_data(size, 4));
+        new.extend_from_slice(&old[6 * size..]);
+        new.extend_from_slice(&random_data(10, 5));
+
+        let signature = DeltaSignature::new(new.len() as u64, new.as_slice()).unwrap();
+        assert_eq!(signature.blocks.len(), 11);
+        let bytes = signature.to_bytes();
+        assert_eq!(DeltaSignature::from_bytes(&bytes).unwrap(), signature);
+        assert!(DeltaSignature::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let (mut rebuilt, missing) = signature.rebuild(&old);
+        let missing_size: u64 = missing.iter().map(|range| range.end - range.start).sum();
+        assert!(missing_size <= 4 * size as u64, "{:?}", missing);
+        for range in missing.iter() {
+            let range = range.start as usize..range.end as usize;
+            rebuilt[range.clone()].copy_from_slice(&new[range]);
+        }
+        assert_eq!(rebuilt, new);
+
+        // With no basis, everything is missing
+        let (_data, missing) = signature.rebuild(&[]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0], 0..new.len() as u64);
+    }
+
+    #[test]
+    fn test_hostile_signature() {
+        let header = |length: u64, block_size: u32, count: usize| {
+            let mut data = MAGIC.to_vec();
+            data.extend_from_slice(&length.to_le_bytes());
+            data.extend_from_slice(&block_size.to_le_bytes());
+            data.resize(data.len() + count * 20, 0);
+            data
+        };
+
+        // A huge length with a huge block size has few blocks, but would be allocated whole
+        assert_eq!(block_size_for(1 << 40), Some(1 << 24));
+        assert!(DeltaSignature::from_bytes(&header(1 << 40, 1 << 31, 512)).is_err());
+        assert!(DeltaSignature::from_bytes(&header(1 << 40, 1 << 24, 65536)).is_ok());
+        assert_eq!(block_size_for(u64::MAX), None);
+        assert!(DeltaSignature::from_bytes(&header(u64::MAX, 1 << 30, 0)).is_err());
+        assert!(DeltaSignature::from_bytes(&header(1, 0, 1)).is_err());
+    }
+}
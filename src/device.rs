@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The DMI directory of the running system
+const DMI_PATH: &str = "/sys/class/dmi/id";
+
+/// The hardware of a device, as identified by DMI, or the hardware an artifact is built for
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Device {
+    /// The DMI product name, such as `galp5`
+    pub product: String,
+    /// The DMI board version, if the artifact only applies to one revision of the product
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board_revision: Option<String>,
+}
+
+/// Read the DMI value `name` from `dir`, ignoring values that firmware leaves unset
+fn read_dmi(dir: &Path, name: &str) -> io::Result<Option<String>> {
+    let value = match fs::read_to_string(dir.join(name)) {
+        Ok(value) => value,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let value = value.trim();
+    match value {
+        "" | "Not Applicable" | "Default string" | "To Be Filled By O.E.M." => Ok(None),
+        _ => Ok(Some(value.to_string())),
+    }
+}
+
+impl Device {
+    /// Identify the running system from `/sys/class/dmi/id`
+    pub fn current() -> io::Result<Device> {
+        Device::from_dmi(DMI_PATH)
+    }
+
+    /// Identify a system from the DMI values in `dir`, laid out as in `/sys/class/dmi/id`
+    pub fn from_dmi<P: AsRef<Path>>(dir: P) -> io::Result<Device> {
+        let dir = dir.as_ref();
+        let product = read_dmi(dir, "product_name")?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no product name", dir.display()),
+            )
+        })?;
+        Ok(Device {
+            product,
+            board_revision: read_dmi(dir, "board_version")?,
+        })
+    }
+
+    /// True if an artifact built for `target` applies to this device
+    ///
+    /// A target without a board revision applies to every revision of its product.
+    pub fn matches(&self, target: &Device) -> bool {
+        self.product == target.product
+            && match &target.board_revision {
+                Some(revision) => self.board_revision.as_ref() == Some(revision),
+                None => true,
+            }
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.board_revision {
+            Some(revision) => write!(f, "{}:{}", self.product, revision),
+            None => write!(f, "{}", self.product),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = String;
+
+    /// Parse `PRODUCT` or `PRODUCT:REVISION`
+    fn from_str(s: &str) -> Result<Device, String> {
+        let (product, board_revision) = match s.split_once(':') {
+            Some((product, revision)) => (product, Some(revision.to_string())),
+            None => (s, None),
+        };
+        if product.is_empty() || board_revision.as_deref() == Some("") {
+            return Err(format!(
+                "invalid device {:?}, expected PRODUCT[:REVISION]",
+                s
+            ));
+        }
+        Ok(Device {
+            product: product.to_string(),
+            board_revision,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::Device;
+
+    #[test]
+    fn test_device() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        fs::write(temp_dir.path().join("product_name"), "galp5\n").unwrap();
+        fs::write(temp_dir.path().join("board_version"), "A1\n").unwrap();
+        let device = Device::from_dmi(temp_dir.path()).unwrap();
+        assert_eq!(device, "galp5:A1".parse().unwrap());
+        assert_eq!(device.to_string(), "galp5:A1");
+
+        assert!(device.matches(&"galp5".parse().unwrap()));
+        assert!(device.matches(&"galp5:A1".parse().unwrap()));
+        assert!(!device.matches(&"galp5:A2".parse().unwrap()));
+        assert!(!device.matches(&"oryp6".parse().unwrap()));
+
+        fs::write(temp_dir.path().join("board_version"), "Not Applicable\n").unwrap();
+        let device = Device::from_dmi(temp_dir.path()).unwrap();
+        assert_eq!(device.board_revision, None);
+        assert!(!device.matches(&"galp5:A1".parse().unwrap()));
+
+        assert!("".parse::<Device>().is_err());
+        assert!("galp5:".parse::<Device>().is_err());
+
+        fs::remove_file(temp_dir.path().join("product_name")).unwrap();
+        assert!(Device::from_dmi(temp_dir.path()).is_err());
+        temp_dir.close().unwrap();
+    }
+}
@@ -1,131 +1,1068 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::fs::File;
-use std::io::{stdout, Read, Write};
-
-use crate::block::PackedBlock;
-use crate::store::b32dec;
-use crate::{err_str, Block, Manifest, Sha384};
-
-pub struct DownloadArguments<'a> {
-    pub project: &'a str,
-    pub branch: &'a str,
-    pub cert_opt: Option<&'a str>,
-    pub cache_opt: Option<&'a str>,
-    pub key: &'a str,
-    pub url: &'a str,
-    pub file_opt: Option<&'a str>,
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, stdout, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::runtime::{self, Runtime};
+
+use crate::format::print_json;
+use crate::install::install_artifact;
+use crate::{
+    r#async, Annotation, Auth, Block, BlockIndex, BlockSig, Cache, CommandInstaller, Device,
+    DownloaderBuilder, Error, Format, Genesis, HistoryEntry, Identity, InstallArtifact, Keyring,
+    LocalTransport, Manifest, ManifestDiff, ObjectId, Policy, ProbeCheck, ProbeStatus, Store,
+    Transport,
+};
+
+/// A specific block in the chain of a project branch
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BlockPin {
+    /// The block with this counter
+    Counter(u64),
+    /// The block with this signature
+    Signature(BlockSig),
 }
 
-pub struct Downloader {
-    key: Vec<u8>,
-    url: reqwest::Url,
+impl fmt::Display for BlockPin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockPin::Counter(counter) => write!(f, "counter {}", counter),
+            BlockPin::Signature(signature) => write!(f, "block {}", signature),
+        }
+    }
+}
+
+/// Configures a call to [`download`]
+#[derive(Clone, Debug)]
+pub struct DownloadOptions {
+    key: String,
+    extra_keys: Vec<String>,
+    keyring_opt: Option<String>,
+    url: String,
     project: String,
     branch: String,
-    client: reqwest::blocking::Client,
+    cert_opt: Option<String>,
+    cache_opt: Option<String>,
+    file_opt: Option<String>,
+    output_opt: Option<String>,
+    force: bool,
+    install_opt: Option<String>,
+    dry_run: bool,
+    proxy_opt: Option<String>,
+    compression: bool,
+    gateway_opt: Option<String>,
+    torrent_command_opt: Option<Vec<String>>,
+    auth_opt: Option<Auth>,
+    identity_opt: Option<String>,
+    identity_key_opt: Option<String>,
+    identity_password_opt: Option<String>,
+    pin_opt: Option<BlockPin>,
+    device_seed_opt: Option<String>,
+    cohort_opt: Option<String>,
+    device_opt: Option<Device>,
+    history_opt: Option<usize>,
+    require_genesis: bool,
+    list: bool,
+    verify_server: bool,
+    update: bool,
+    sync_opt: Option<String>,
+    attestors: Vec<String>,
+    quorum_opt: Option<usize>,
+    annotation: bool,
+    wait_opt: Option<Duration>,
+    after_opt: Option<u64>,
+    format: Format,
+}
+
+impl DownloadOptions {
+    /// Download from the mirror at `url`, verified with the base32 public `key`
+    pub fn new(key: &str, url: &str) -> DownloadOptions {
+        DownloadOptions {
+            key: key.to_string(),
+            extra_keys: Vec::new(),
+            keyring_opt: None,
+            url: url.to_string(),
+            project: "default".to_string(),
+            branch: "master".to_string(),
+            cert_opt: None,
+            cache_opt: None,
+            file_opt: None,
+            output_opt: None,
+            force: false,
+            install_opt: None,
+            dry_run: false,
+            proxy_opt: None,
+            compression: true,
+            gateway_opt: None,
+            torrent_command_opt: None,
+            auth_opt: None,
+            identity_opt: None,
+            identity_key_opt: None,
+            identity_password_opt: None,
+            pin_opt: None,
+            device_seed_opt: None,
+            cohort_opt: None,
+            device_opt: None,
+            history_opt: None,
+            require_genesis: false,
+            list: false,
+            verify_server: false,
+            update: false,
+            sync_opt: None,
+            attestors: Vec::new(),
+            quorum_opt: None,
+            annotation: false,
+            wait_opt: None,
+            after_opt: None,
+            format: Format::Text,
+        }
+    }
+
+    /// Set the tail signature project name, `default` if not set
+    pub fn project(mut self, project: &str) -> DownloadOptions {
+        self.project = project.to_string();
+        self
+    }
+
+    /// Set the tail signature branch name, `master` if not set
+    pub fn branch(mut self, branch: &str) -> DownloadOptions {
+        self.branch = branch.to_string();
+        self
+    }
+
+    /// Also accept a tail signed by the base32 public `key`
+    pub fn key(mut self, key: &str) -> DownloadOptions {
+        self.extra_keys.push(key.to_string());
+        self
+    }
+
+    /// Also accept a tail signed by the signer keys of the keyring at `keyring_path`
+    ///
+    /// The keyring must be signed by `key`, which is a root key of the keyring.
+    pub fn keyring(mut self, keyring_path: &str) -> DownloadOptions {
+        self.keyring_opt = Some(keyring_path.to_string());
+        self
+    }
+
+    /// Trust the PEM encoded root certificate in the file `cert_path`
+    pub fn cert(mut self, cert_path: &str) -> DownloadOptions {
+        self.cert_opt = Some(cert_path.to_string());
+        self
+    }
+
+    /// Cache tails and objects under `cache_path`, see [`crate::Cache`]
+    pub fn cache(mut self, cache_path: &str) -> DownloadOptions {
+        self.cache_opt = Some(cache_path.to_string());
+        self
+    }
+
+    /// Fetch objects published to IPFS from the gateway at `url`, see [`crate::CasTransport`]
+    pub fn gateway(mut self, url: &str) -> DownloadOptions {
+        self.gateway_opt = Some(url.to_string());
+        self
+    }
+
+    /// Download large objects from peers with the BitTorrent client `command`, see
+    /// [`crate::TorrentTransport`]
+    pub fn torrent(mut self, command: Vec<String>) -> DownloadOptions {
+        self.torrent_command_opt = Some(command);
+        self
+    }
+
+    /// Write the artifact `file` to stdout, instead of listing the artifacts
+    pub fn file(mut self, file: &str) -> DownloadOptions {
+        self.file_opt = Some(file.to_string());
+        self
+    }
+
+    /// Write the artifact to `output_path` instead of stdout, with the permissions recorded in
+    /// the manifest
+    pub fn output(mut self, output_path: &str) -> DownloadOptions {
+        self.output_opt = Some(output_path.to_string());
+        self
+    }
+
+    /// Replace the output file if it already exists
+    pub fn force(mut self, force: bool) -> DownloadOptions {
+        self.force = force;
+        self
+    }
+
+    /// Install the verified artifact with the shell `command`, see [`CommandInstaller`]
+    ///
+    /// The artifact is written to the output path if one is set, and to a temporary directory
+    /// otherwise, instead of stdout.
+    pub fn install(mut self, command: &str) -> DownloadOptions {
+        self.install_opt = Some(command.to_string());
+        self
+    }
+
+    /// Only describe how the artifact would be installed
+    pub fn dry_run(mut self, dry_run: bool) -> DownloadOptions {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Send all HTTP(S) requests through the proxy at `proxy`
+    pub fn proxy(mut self, proxy: &str) -> DownloadOptions {
+        self.proxy_opt = Some(proxy.to_string());
+        self
+    }
+
+    /// Ask HTTP(S) mirrors to compress responses, enabled by default, see
+    /// [`DownloaderBuilder::compression`]
+    pub fn compression(mut self, compression: bool) -> DownloadOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// Authenticate to HTTP(S) mirrors with `auth`
+    pub fn auth(mut self, auth: Auth) -> DownloadOptions {
+        self.auth_opt = Some(auth);
+        self
+    }
+
+    /// Present the client certificate in the file `identity_path`
+    ///
+    /// Files ending in `.p12` or `.pfx` are read as PKCS#12 archives, and other files as PEM.
+    pub fn identity(mut self, identity_path: &str) -> DownloadOptions {
+        self.identity_opt = Some(identity_path.to_string());
+        self
+    }
+
+    /// Read the PEM private key of the client certificate from `key_path`, instead of the
+    /// certificate file
+    pub fn identity_key(mut self, key_path: &str) -> DownloadOptions {
+        self.identity_key_opt = Some(key_path.to_string());
+        self
+    }
+
+    /// Set the password of a PKCS#12 client certificate
+    pub fn identity_password(mut self, password: &str) -> DownloadOptions {
+        self.identity_password_opt = Some(password.to_string());
+        self
+    }
+
+    /// Download the build at `pin` instead of the tail
+    pub fn pin(mut self, pin: BlockPin) -> DownloadOptions {
+        self.pin_opt = Some(pin);
+        self
+    }
+
+    /// Download the newest build rolled out to the device with `seed`, instead of the tail
+    ///
+    /// See [`crate::Channel`] for how builds are rolled out.
+    pub fn device_seed(mut self, seed: &str) -> DownloadOptions {
+        self.device_seed_opt = Some(seed.to_string());
+        self
+    }
+
+    /// Set the cohort of the device, which may receive builds before other devices
+    pub fn cohort(mut self, cohort: &str) -> DownloadOptions {
+        self.cohort_opt = Some(cohort.to_string());
+        self
+    }
+
+    /// Only list and download the files of the build that apply to `device`
+    ///
+    /// See [`Manifest::devices`] for how files are matched to devices.
+    pub fn device(mut self, device: Device) -> DownloadOptions {
+        self.device_opt = Some(device);
+        self
+    }
+
+    /// Print up to `limit` blocks of the branch history instead of downloading, following
+    /// the branches it was cut from
+    pub fn history(mut self, limit: usize) -> DownloadOptions {
+        self.history_opt = Some(limit);
+        self
+    }
+
+    /// Require the downloaded block to be anchored on a [`crate::Genesis`] block that allows the
+    /// keys of every block back to it, see [`Downloader::genesis_from`]
+    pub fn require_genesis(mut self, require_genesis: bool) -> DownloadOptions {
+        self.require_genesis = require_genesis;
+        self
+    }
+
+    /// List the projects and branches of the mirror instead of downloading
+    pub fn list(mut self, list: bool) -> DownloadOptions {
+        self.list = list;
+        self
+    }
+
+    /// Check that the mirror serves the branch correctly instead of downloading, see
+    /// [`Downloader::probe`]
+    pub fn verify_server(mut self, verify_server: bool) -> DownloadOptions {
+        self.verify_server = verify_server;
+        self
+    }
+
+    /// Update the cache to the build, printing the changed artifacts
+    pub fn update(mut self, update: bool) -> DownloadOptions {
+        self.update = update;
+        self
+    }
+
+    /// Copy the verified chain and its builds into the store at `store_path`, to serve as a
+    /// mirror, instead of downloading
+    pub fn sync(mut self, store_path: &str) -> DownloadOptions {
+        self.sync_opt = Some(store_path.to_string());
+        self
+    }
+
+    /// Require the build to be reproduced by the rebuilder with the base32 public `key`
+    ///
+    /// See [`DownloadOptions::quorum`] to require only some of the rebuilders. Attestations
+    /// are only checked for the downloaded build, not with [`DownloadOptions::history`] or
+    /// [`DownloadOptions::sync`].
+    pub fn attestor(mut self, key: &str) -> DownloadOptions {
+        if !self.attestors.iter().any(|attestor| attestor == key) {
+            self.attestors.push(key.to_string());
+        }
+        self
+    }
+
+    /// Require attestations from `quorum` of the attestors, instead of all of them
+    pub fn quorum(mut self, quorum: usize) -> DownloadOptions {
+        self.quorum_opt = Some(quorum);
+        self
+    }
+
+    /// Print the [`Annotation`] of the block instead of downloading, see
+    /// [`Downloader::annotation`]
+    pub fn annotation(mut self, annotation: bool) -> DownloadOptions {
+        self.annotation = annotation;
+        self
+    }
+
+    /// Wait up to `timeout` for the tail to be updated before downloading it
+    ///
+    /// See [`Downloader::wait_for_update`]. The tail must be newer than the counter set with
+    /// [`DownloadOptions::after`], or the tail when the download starts if it is not set.
+    pub fn wait(mut self, timeout: Duration) -> DownloadOptions {
+        self.wait_opt = Some(timeout);
+        self
+    }
+
+    /// Wait for a tail with a counter greater than `counter`, see [`DownloadOptions::wait`]
+    pub fn after(mut self, counter: u64) -> DownloadOptions {
+        self.after_opt = Some(counter);
+        self
+    }
+
+    /// Set the output format, [`Format::Text`] if not set
+    pub fn format(mut self, format: Format) -> DownloadOptions {
+        self.format = format;
+        self
+    }
+}
+
+/// The verified block and manifest, as printed by [`download`] in JSON format
+#[derive(Serialize)]
+struct Listing<'a> {
+    block: &'a Block,
+    manifest: &'a Manifest,
+}
+
+/// Blocking wrapper around [`crate::r#async::Downloader`]
+pub struct Downloader {
+    inner: r#async::Downloader,
+    runtime: Runtime,
 }
 
 impl Downloader {
+    /// Create a new Downloader
+    ///
+    /// See [`crate::DownloaderBuilder`] for more options.
     pub fn new(
         key: &str,
         url: &str,
         project: &str,
         branch: &str,
         cert_opt: Option<&[u8]>,
-    ) -> Result<Downloader, String> {
-        let key = b32dec(key).ok_or_else(|| "key not in base32 format".to_string())?;
+    ) -> Result<Downloader, Error> {
+        let inner = r#async::Downloader::new(key, url, project, branch, cert_opt)?;
+        Downloader::from_async(inner)
+    }
 
-        let url = reqwest::Url::parse(url).map_err(err_str)?;
+    /// Create a Downloader that fetches files using `transport`
+    pub fn from_transport(
+        key: &str,
+        project: &str,
+        branch: &str,
+        transport: Box<dyn Transport>,
+    ) -> Result<Downloader, Error> {
+        let inner = r#async::Downloader::from_transport(key, project, branch, transport)?;
+        Downloader::from_async(inner)
+    }
 
-        let client = {
-            let mut builder = reqwest::blocking::Client::builder();
+    /// Create a Downloader that verifies the branch in the local `store`, using its
+    /// [`BlockIndex`] to find blocks
+    pub(crate) fn from_store(
+        key: &str,
+        project: &str,
+        branch: &str,
+        store: &Store,
+    ) -> Result<Downloader, Error> {
+        let mut inner = r#async::Downloader::from_transport(
+            key,
+            project,
+            branch,
+            Box::new(LocalTransport::new(store.path())),
+        )?;
+        inner.set_index(BlockIndex::read(store)?);
+        Downloader::from_async(inner)
+    }
 
-            if let Some(cert) = cert_opt {
-                builder = builder
-                    .add_root_certificate(reqwest::Certificate::from_pem(cert).map_err(err_str)?);
-            }
+    pub(crate) fn from_async(inner: r#async::Downloader) -> Result<Downloader, Error> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
 
-            builder.build().map_err(err_str)?
-        };
+        Ok(Downloader { inner, runtime })
+    }
 
-        Ok(Downloader {
-            key,
-            url,
-            project: project.to_string(),
-            branch: branch.to_string(),
-            client,
-        })
+    pub fn add_key(&mut self, key: &str) -> Result<(), Error> {
+        self.inner.add_key(key)
     }
 
-    fn download(&self, path: &str) -> Result<Vec<u8>, String> {
-        let url = self.url.join(path).map_err(err_str)?;
-        let mut response = self.client.get(url).send().map_err(err_str)?;
-        if !response.status().is_success() {
-            return Err(format!(
-                "failed to download {}: {:?}",
-                path,
-                response.status()
-            ));
-        }
+    pub fn add_keyring(&mut self, keyring: &Keyring) -> Result<(), Error> {
+        self.inner.add_keyring(keyring)
+    }
 
-        let mut data = Vec::new();
-        response.read_to_end(&mut data).map_err(err_str)?;
-        Ok(data)
+    pub fn object(&self, digest: &ObjectId) -> Result<Vec<u8>, Error> {
+        self.runtime.block_on(self.inner.object(digest))
     }
 
-    pub fn object(&self, digest: &str) -> Result<Vec<u8>, String> {
-        let path = format!("object/{}", digest);
-        let data = self.download(&path)?;
+    pub fn tail(&self) -> Result<Block, Error> {
+        self.runtime.block_on(self.inner.tail())
+    }
 
-        let sha = Sha384::new(data.as_slice()).map_err(err_str)?;
-        if sha.to_base32() != digest {
-            return Err("sha384 mismatch".to_string());
-        }
+    pub fn block(&self, signature: &BlockSig) -> Result<Block, Error> {
+        self.runtime.block_on(self.inner.block(signature))
+    }
+
+    pub fn find_block(&self, pin: &BlockPin) -> Result<Block, Error> {
+        self.runtime.block_on(self.inner.find_block(pin))
+    }
+
+    /// The cache of this Downloader, if it has one
+    pub fn cache(&self) -> Option<&Cache> {
+        self.inner.cache()
+    }
+
+    /// Check builds and their files with `policy` before downloading them, see
+    /// [`r#async::Downloader::set_policy`]
+    pub fn set_policy(&mut self, policy: Box<dyn Policy>) {
+        self.inner.set_policy(policy)
+    }
+
+    pub fn check_policy(
+        &self,
+        block: &Block,
+        manifest: &Manifest,
+        names: &[&str],
+    ) -> Result<(), Error> {
+        self.runtime
+            .block_on(self.inner.check_policy(block, manifest, names))
+    }
+
+    pub fn objects(&self, digests: &[ObjectId], dir: &Path) -> Result<Vec<PathBuf>, Error> {
+        self.runtime.block_on(self.inner.objects(digests, dir))
+    }
+
+    pub fn object_delta(&self, digest: &ObjectId, basis: &[u8]) -> Result<Vec<u8>, Error> {
+        self.runtime
+            .block_on(self.inner.object_delta(digest, basis))
+    }
+
+    pub fn object_cached(&self, digest: &ObjectId, cache: &Store) -> Result<Vec<u8>, Error> {
+        self.runtime
+            .block_on(self.inner.object_cached(digest, cache))
+    }
+
+    pub fn tail_for_device(&self, seed: &[u8], cohort_opt: Option<&str>) -> Result<Block, Error> {
+        self.runtime
+            .block_on(self.inner.tail_for_device(seed, cohort_opt))
+    }
+
+    pub fn select_for_device(
+        &self,
+        block: &Block,
+        device: &Device,
+    ) -> Result<BTreeMap<String, ObjectId>, Error> {
+        self.runtime
+            .block_on(self.inner.select_for_device(block, device))
+    }
+
+    pub fn wait_for_update(
+        &self,
+        current_counter: u64,
+        timeout: Duration,
+    ) -> Result<Option<Block>, Error> {
+        self.runtime
+            .block_on(self.inner.wait_for_update(current_counter, timeout))
+    }
+
+    pub fn genesis(&self) -> Result<(Block, Genesis), Error> {
+        self.runtime.block_on(self.inner.genesis())
+    }
+
+    pub fn genesis_from(&self, block: &Block) -> Result<(Block, Genesis), Error> {
+        self.runtime.block_on(self.inner.genesis_from(block))
+    }
+
+    pub fn history(&self, limit: usize) -> Result<Vec<HistoryEntry>, Error> {
+        self.runtime.block_on(self.inner.history(limit))
+    }
+
+    pub fn update(&self, block: &Block, cache: &Store) -> Result<ManifestDiff, Error> {
+        self.runtime.block_on(self.inner.update(block, cache))
+    }
+
+    pub fn sync_to_store(&self, store: &Store) -> Result<Block, Error> {
+        self.runtime.block_on(self.inner.sync_to_store(store))
+    }
+
+    pub fn annotation(&self, block: &Block) -> Result<Option<Annotation>, Error> {
+        self.runtime.block_on(self.inner.annotation(block))
+    }
 
-        Ok(data)
+    pub fn attestations(&self, block: &Block, attestors: &[String]) -> Result<Vec<Block>, Error> {
+        self.runtime
+            .block_on(self.inner.attestations(block, attestors))
     }
 
-    pub fn tail(&self) -> Result<Block, String> {
-        let path = format!("tail/{}/{}", self.project, self.branch);
-        let data = self.download(&path)?;
+    pub fn index(&self) -> Result<BTreeMap<String, Vec<String>>, Error> {
+        self.runtime.block_on(self.inner.index())
+    }
+
+    pub fn probe(&self) -> Vec<ProbeCheck> {
+        self.runtime.block_on(self.inner.probe())
+    }
+
+    pub fn projects(&self) -> Result<Vec<String>, Error> {
+        self.runtime.block_on(self.inner.projects())
+    }
+
+    pub fn branches(&self) -> Result<Vec<String>, Error> {
+        self.runtime.block_on(self.inner.branches())
+    }
+}
+
+/// Write a downloaded artifact to `path`, replacing it only if `force` is set
+///
+/// The data is written to a temporary file next to `path` and renamed into place, so that
+/// `path` never holds a partial artifact.
+fn write_output(
+    path: &Path,
+    data: &[u8],
+    mode_opt: Option<&u32>,
+    force: bool,
+) -> Result<(), Error> {
+    if !force && fs::symlink_metadata(path).is_ok() {
+        return Err(Error::Config(format!(
+            "{} already exists, use --force to replace it",
+            path.display()
+        )));
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".partial");
+    let tmp = Path::new(&tmp);
+    {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(tmp)?;
+        file.write_all(data)?;
+        if let Some(mode) = mode_opt {
+            file.set_permissions(fs::Permissions::from_mode(*mode))?;
+        }
+        file.sync_all()?;
+    }
+
+    Ok(fs::rename(tmp, path)?)
+}
+
+/// Hand the verified `data` of `file` to the installer `command`
+fn install_file(
+    args: &DownloadOptions,
+    command: &str,
+    block: &Block,
+    manifest: &Manifest,
+    file: &str,
+    data: &[u8],
+) -> Result<(), Error> {
+    // The staged copy is removed after it is installed
+    let mut staged_opt = None;
+    let path = match &args.output_opt {
+        Some(output) => PathBuf::from(output),
+        None => {
+            let dir = tempfile::TempDir::with_prefix("buildchain-install.")?;
+            let name = Path::new(file).file_name().unwrap_or(file.as_ref());
+            let path = dir.path().join(name);
+            write_output(&path, data, manifest.modes.get(file), false)?;
+            staged_opt = Some(dir);
+            path
+        }
+    };
 
-        let b: &PackedBlock =
-            plain::from_bytes(&data).map_err(|_| "response too small".to_string())?;
-        b.verify(&self.key)
+    let artifact = InstallArtifact {
+        name: file,
+        path: &path,
+        digest: &manifest.files[file],
+        block,
+    };
+    let outcome = install_artifact(&CommandInstaller::new(command), &artifact, args.dry_run)
+        .map_err(|reason| Error::Exec(io::Error::other(reason)))?;
+    drop(staged_opt);
+    match args.format {
+        Format::Text => {
+            if !outcome.message.is_empty() {
+                println!("{}", outcome.message);
+            }
+            if !outcome.dry_run {
+                eprintln!(
+                    "buildchain: installed {} from block {}",
+                    file, block.signature
+                );
+            }
+        }
+        Format::Json => print_json(&outcome)?,
     }
+    Ok(())
 }
 
-pub fn download(args: DownloadArguments) -> Result<(), String> {
+pub fn download(args: &DownloadOptions) -> Result<(), Error> {
+    if !args.attestors.is_empty() && (args.history_opt.is_some() || args.sync_opt.is_some()) {
+        return Err(Error::Config(
+            "attestations are only checked for a downloaded build".to_string(),
+        ));
+    }
+
     let mut cert = Vec::new();
-    let cert_opt = if let Some(cert_path) = args.cert_opt {
+    let cert_opt = if let Some(cert_path) = &args.cert_opt {
         {
-            let mut file = File::open(cert_path).map_err(err_str)?;
-            file.read_to_end(&mut cert).map_err(err_str)?;
+            let mut file = File::open(cert_path)?;
+            file.read_to_end(&mut cert)?;
         }
         Some(cert.as_slice())
     } else {
         None
     };
 
-    let dl = Downloader::new(args.key, args.url, args.project, args.branch, cert_opt)?;
+    let mut builder = DownloaderBuilder::new(&args.key, &args.url)
+        .project(&args.project)
+        .branch(&args.branch)
+        .compression(args.compression);
+    for key in args.extra_keys.iter() {
+        builder = builder.key(key);
+    }
+    if let Some(keyring_path) = &args.keyring_opt {
+        builder = builder.keyring(Keyring::open(keyring_path, &args.key)?);
+    }
+    if let Some(cert) = cert_opt {
+        builder = builder.cert(cert);
+    }
+    if let Some(proxy) = &args.proxy_opt {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(cache_path) = &args.cache_opt {
+        builder = builder.cache(cache_path);
+    }
+    if let Some(gateway) = &args.gateway_opt {
+        builder = builder.gateway(gateway);
+    }
+    if let Some(command) = &args.torrent_command_opt {
+        builder = builder.torrent(command.clone());
+    }
+    if let Some(auth) = &args.auth_opt {
+        builder = builder.auth(auth.clone());
+    }
+    if let Some(identity_path) = &args.identity_opt {
+        let identity = fs::read(identity_path)?;
+        let pkcs12 = identity_path.ends_with(".p12") || identity_path.ends_with(".pfx");
+        builder = builder.identity(if pkcs12 {
+            Identity::Pkcs12 {
+                der: identity,
+                password: args.identity_password_opt.clone().unwrap_or_default(),
+            }
+        } else {
+            let key = match &args.identity_key_opt {
+                Some(key_path) => fs::read(key_path)?,
+                None => identity.clone(),
+            };
+            Identity::Pem {
+                cert: identity,
+                key,
+            }
+        });
+    }
+    let dl = builder.build_blocking()?;
 
-    let tail = dl.tail()?;
+    if args.list {
+        let index = dl.index()?;
+        match args.format {
+            Format::Text => {
+                for (project, branches) in index {
+                    for branch in branches {
+                        println!("{}/{}", project, branch);
+                    }
+                }
+            }
+            Format::Json => print_json(&index)?,
+        }
+        return Ok(());
+    }
 
-    let manifest_json = dl.object(&tail.digest)?;
-    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
+    if args.verify_server {
+        let checks = dl.probe();
+        match args.format {
+            Format::Text => {
+                for check in checks.iter() {
+                    println!("{} {}: {}", check.status, check.path, check.message);
+                }
+            }
+            Format::Json => print_json(&checks)?,
+        }
+        let failed = checks
+            .iter()
+            .filter(|check| check.status == ProbeStatus::Failed)
+            .count();
+        if failed > 0 {
+            return Err(Error::Http(format!(
+                "{} failed {} of {} checks",
+                args.url,
+                failed,
+                checks.len()
+            )));
+        }
+        return Ok(());
+    }
+
+    // The chain that is walked, synced, or downloaded is the one anchored on its genesis
+    let anchor = |block: &Block| -> Result<(), Error> {
+        if args.require_genesis {
+            let (genesis, _) = dl.genesis_from(block)?;
+            eprintln!(
+                "buildchain: block {} is anchored on genesis block {}",
+                block.signature, genesis.signature
+            );
+        }
+        Ok(())
+    };
 
-    if let Some(file) = args.file_opt {
+    if let Some(limit) = args.history_opt {
+        let history = dl.history(limit)?;
+        if let Some(entry) = history.first() {
+            anchor(&entry.block)?;
+        }
+        match args.format {
+            Format::Text => {
+                for entry in history.iter() {
+                    println!(
+                        "{} {} {} {}",
+                        entry.block.counter,
+                        entry.branch,
+                        entry.block.signature,
+                        entry.block.digest
+                    );
+                }
+            }
+            Format::Json => print_json(&history)?,
+        }
+        return Ok(());
+    }
+
+    if let Some(store_path) = &args.sync_opt {
+        fs::create_dir_all(store_path)?;
+        let block = dl.sync_to_store(&Store::open(store_path)?)?;
+        anchor(&block)?;
+        match args.format {
+            Format::Text => println!(
+                "buildchain: synced tail/{}/{} to {} with counter {}",
+                args.project, args.branch, store_path, block.counter
+            ),
+            Format::Json => print_json(&block)?,
+        }
+        return Ok(());
+    }
+
+    let block = match (&args.pin_opt, &args.device_seed_opt, args.wait_opt) {
+        (Some(_), _, Some(_)) | (_, Some(_), Some(_)) => {
+            return Err(Error::Config(
+                "waiting for an update only applies to the tail".to_string(),
+            ))
+        }
+        (Some(pin), _, None) => dl.find_block(pin)?,
+        (None, Some(seed), None) => {
+            dl.tail_for_device(seed.as_bytes(), args.cohort_opt.as_deref())?
+        }
+        (None, None, Some(timeout)) => {
+            let current = match args.after_opt {
+                Some(counter) => counter,
+                None => dl.tail()?.counter,
+            };
+            dl.wait_for_update(current, timeout)?.ok_or_else(|| {
+                Error::NotFound(format!(
+                    "tail/{}/{} was not updated past counter {} within {} seconds",
+                    args.project,
+                    args.branch,
+                    current,
+                    timeout.as_secs()
+                ))
+            })?
+        }
+        (None, None, None) => dl.tail()?,
+    };
+    anchor(&block)?;
+    if !args.extra_keys.is_empty() || args.keyring_opt.is_some() {
+        eprintln!(
+            "buildchain: block {} is signed by {}",
+            block.signature, block.public_key
+        );
+    }
+
+    if !args.attestors.is_empty() {
+        let quorum = args.quorum_opt.unwrap_or(args.attestors.len());
+        let attestations = dl.attestations(&block, &args.attestors)?;
+        if attestations.len() < quorum {
+            return Err(Error::Verify(format!(
+                "block {} is attested by {} rebuilders, {} required",
+                block.signature,
+                attestations.len(),
+                quorum
+            )));
+        }
+        eprintln!(
+            "buildchain: block {} is attested by {} rebuilders",
+            block.signature,
+            attestations.len()
+        );
+    }
+
+    if args.annotation {
+        let annotation = dl.annotation(&block)?.ok_or_else(|| {
+            Error::NotFound(format!("block {} has no annotation", block.signature))
+        })?;
+        match args.format {
+            Format::Text => {
+                if let Some(urgency) = annotation.urgency {
+                    println!("urgency: {}", urgency);
+                }
+                if !annotation.hardware.is_empty() {
+                    println!("hardware: {}", annotation.hardware.join(", "));
+                }
+                if let Some(changelog) = &annotation.changelog {
+                    println!("{}", changelog);
+                }
+            }
+            Format::Json => print_json(&annotation)?,
+        }
+        return Ok(());
+    }
+
+    if args.update {
+        let cache = dl
+            .cache()
+            .ok_or_else(|| Error::Config("updating requires a cache".to_string()))?;
+        let diff = dl.update(&block, cache.store())?;
+        match args.format {
+            Format::Text => {
+                for file in diff.added.keys() {
+                    println!("+ {}", file);
+                }
+                for file in diff.changed.keys() {
+                    println!("~ {}", file);
+                }
+                for file in diff.removed.iter() {
+                    println!("- {}", file);
+                }
+            }
+            Format::Json => print_json(&diff)?,
+        }
+        return Ok(());
+    }
+
+    let manifest_json = dl.object(&block.digest)?;
+    let mut manifest = Manifest::from_signed(&manifest_json, &block).map_err(Error::Verify)?;
+    if let Some(device) = &args.device_opt {
+        if let Some(file) = &args.file_opt {
+            if manifest.files.contains_key(file) && !manifest.applies_to(file, device) {
+                return Err(Error::NotFound(format!(
+                    "{} does not apply to device {}",
+                    file, device
+                )));
+            }
+        }
+        // The listing only shows what this device would install
+        manifest.files = manifest.files_for_device(device);
+    }
+    if args.format == Format::Text {
+        eprintln!(
+            "buildchain: verified block {} with counter {}, signed at {}",
+            block.signature, block.counter, block.timestamp
+        );
+    }
+
+    if let Some(file) = &args.file_opt {
         if let Some(digest) = manifest.files.get(file) {
+            dl.check_policy(&block, &manifest, &[file])?;
             let data = dl.object(digest)?;
-            stdout().write(&data).map_err(err_str)?;
+            manifest.verify_file(file, &data).map_err(Error::Verify)?;
+            match (&args.output_opt, &args.install_opt) {
+                (Some(output), _) => write_output(
+                    Path::new(output),
+                    &data,
+                    manifest.modes.get(file),
+                    args.force,
+                )?,
+                (None, None) => stdout().write_all(&data)?,
+                (None, Some(_)) => (),
+            }
+            if let Some(command) = &args.install_opt {
+                install_file(args, command, &block, &manifest, file, &data)?;
+            }
         } else {
-            return Err(format!("{} not found", file));
+            return Err(Error::NotFound(format!("{} not found", file)));
         }
     } else {
-        for (file, _digest) in manifest.files.iter() {
-            println!("{}", file);
+        match args.format {
+            Format::Text => {
+                for (file, _digest) in manifest.files.iter() {
+                    println!("{}", file);
+                }
+            }
+            Format::Json => print_json(&Listing {
+                block: &block,
+                manifest: &manifest,
+            })?,
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, remove_file, File};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::{download, write_output, DownloadOptions, Downloader};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{BlockPin, Error, Store};
+
+    const KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+
+    #[test]
+    fn test_write_output() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let path = temp_dir.path().join("tool");
+
+        write_output(&path, b"first", Some(&0o755), false).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        assert!(matches!(
+            write_output(&path, b"second", None, false),
+            Err(Error::Config(_))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), b"first");
+
+        write_output(&path, b"second", None, true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o111, 0);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_local_object() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+
+        let content = b"local object";
+        let digest = store.write_object(content).unwrap();
+
+        let url = format!("file://{}/", temp_dir.path().display());
+        for url in [url.as_str(), temp_dir.path().to_str().unwrap()] {
+            let dl = Downloader::new(KEY, url, "default", "master", None).unwrap();
+            assert_eq!(dl.object(&digest).unwrap(), content);
+        }
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_local_object_mismatch() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+
+        let key = store.write_object(b"original").unwrap();
+        let path = store.object_path(&key);
+        remove_file(&path).unwrap();
+        File::create(&path).unwrap().write_all(b"tampered").unwrap();
+
+        let url = temp_dir.path().to_str().unwrap();
+        let dl = Downloader::new(KEY, url, "default", "master", None).unwrap();
+        assert!(matches!(
+            dl.object(&key),
+            Err(Error::Verify(message)) if message == "sha384 mismatch"
+        ));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_attestors() {
+        let options = DownloadOptions::new(KEY, "/nonexistent")
+            .attestor(KEY)
+            .attestor(KEY);
+        assert_eq!(options.attestors, [KEY]);
+        assert!(matches!(
+            download(&options.clone().sync("/nonexistent")),
+            Err(Error::Config(_))
+        ));
+        assert!(matches!(
+            download(&options.history(1)),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_store_index() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let (public_key, first) = signed_block(1, &[0; 64], 0, &[1; 48]);
+        let (_, second) = signed_block(1, first[..64].try_into().unwrap(), 1, &[2; 48]);
+        let (_, third) = signed_block(1, second[..64].try_into().unwrap(), 2, &[3; 48]);
+        for block in [&first, &second, &third] {
+            store.write_tail("default", "master", block).unwrap();
+        }
+
+        // Indexed blocks are found without walking back through the missing block
+        let second_sig = second[..64].try_into().unwrap();
+        remove_file(store.block_path(&second_sig)).unwrap();
+        let dl = Downloader::from_store(&b32enc(&public_key), "default", "master", &store).unwrap();
+        assert_eq!(dl.find_block(&BlockPin::Counter(0)).unwrap().counter, 0);
+        assert!(dl.find_block(&BlockPin::Counter(1)).is_err());
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::io;
+
+/// The errors returned by buildchain
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The build configuration or arguments are invalid
+    #[error("{0}")]
+    Config(String),
+    /// The source could not be downloaded
+    #[error("failed to download source: {0}")]
+    Source(#[source] io::Error),
+    /// A command failed while preparing, building, or archiving
+    #[error("failed to run: {0}")]
+    Exec(#[source] io::Error),
+    /// A store or other local file could not be read or written
+    #[error("{0}")]
+    Store(#[from] io::Error),
+    /// The manifest could not be signed
+    #[error("failed to sign: {0}")]
+    Sign(#[source] io::Error),
+    /// A mirror could not be reached, or responded with an error
+    #[error("{0}")]
+    Http(String),
+    /// A block, object, or manifest did not verify
+    #[error("{0}")]
+    Verify(String),
+    /// A file, block, or project does not exist
+    #[error("{0}")]
+    NotFound(String),
+    /// A build or file was refused by a [`crate::Policy`]
+    #[error("{0}")]
+    Denied(String),
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Source(err) | Error::Exec(err) | Error::Store(err) | Error::Sign(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
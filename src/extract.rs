@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::TempDir;
+
+use crate::verify::verify_object;
+use crate::{Downloader, Error, LocalTransport, Manifest, ObjectId, Provenance, Sha384, Store};
+
+pub struct ExtractArguments<'a> {
+    pub archive: &'a str,
+    pub dest: &'a str,
+    pub key_opt: Option<&'a str>,
+    /// Without a key, check a provenance against the key recorded in the archive, and
+    /// extract the artifacts unverified
+    pub trust_embedded_key: bool,
+    pub project: &'a str,
+    pub branch: &'a str,
+}
+
+/// Extract a build archive into a temporary directory
+pub(crate) fn extract_archive(archive: &Path) -> Result<TempDir, Error> {
+    let temp_dir = TempDir::with_prefix("buildchain.")?;
+
+    let status = Command::new("tar")
+        .arg("--extract")
+        .arg("--file")
+        .arg(archive)
+        .arg("--directory")
+        .arg(temp_dir.path())
+        .status()
+        .map_err(Error::Exec)?;
+
+    if !status.success() {
+        return Err(Error::Exec(io::Error::other(format!(
+            "tar failed with status: {}",
+            status
+        ))));
+    }
+
+    Ok(temp_dir)
+}
+
+/// Read and verify `manifest.json`, returning the manifest and its digest
+fn verified_manifest(store: &Store) -> Result<(Manifest, ObjectId), Error> {
+    let link = store.path().join("manifest.json");
+    let target = fs::read_link(&link)
+        .map_err(|err| Error::NotFound(format!("failed to read manifest.json: {}", err)))?;
+    let digest: ObjectId = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse().ok())
+        .ok_or_else(|| Error::Verify(format!("invalid manifest link {}", target.display())))?;
+
+    let data = fs::read(&link)?;
+    if Sha384::new(data.as_slice())?.to_id() != digest {
+        return Err(Error::Verify("manifest sha384 mismatch".to_string()));
+    }
+
+    let manifest = serde_json::from_slice(&data).map_err(io::Error::from)?;
+    Ok((manifest, digest))
+}
+
+/// Write the verified artifacts of `manifest` into `dest`
+fn write_artifacts(store: &Store, manifest: &Manifest, dest: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dest)?;
+
+    for (name, digest) in manifest.files.iter() {
+        let path = dest.join(name);
+        if name.is_empty() || name.split('/').any(|part| part == ".." || part.is_empty()) {
+            return Err(Error::Verify(format!("invalid artifact name {}", name)));
+        }
+
+        let data = fs::read(store.object_path(digest))
+            .map_err(|err| Error::NotFound(format!("failed to read {}: {}", name, err)))?;
+        verify_object(&data, digest).map_err(|err| Error::Verify(format!("{}: {}", name, err)))?;
+        manifest.verify_file(name, &data).map_err(Error::Verify)?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, data)?;
+        println!("Extract {}", name);
+    }
+
+    Ok(())
+}
+
+/// Verify a build archive and extract its artifacts into a directory
+///
+/// The manifest and every artifact are checked against their digests. If the archive has a
+/// tail for the project and branch, it must be signed by `key` and refer to the manifest. If
+/// the archive has a [`Provenance`], its block must be signed by `key` and refer to the
+/// manifest. The key recorded in the provenance is only used with `trust_embedded_key`, and
+/// the archive is then reported as unverified, since anyone can sign an archive with their
+/// own key.
+pub fn extract(args: ExtractArguments) -> Result<(), Error> {
+    let temp_dir = extract_archive(Path::new(args.archive))?;
+    let store = Store::new(&temp_dir);
+
+    let (manifest, digest) = verified_manifest(&store)?;
+
+    let provenance_opt = Provenance::read(temp_dir.path())?;
+    match (&provenance_opt, args.key_opt) {
+        (Some(provenance), Some(key)) => {
+            let block = provenance.verify(key, &digest)?;
+            println!(
+                "buildchain: verified provenance of tail/{}/{} counter {}",
+                provenance.project, provenance.branch, block.counter
+            );
+        }
+        (Some(provenance), None) if args.trust_embedded_key => {
+            let block = provenance.verify_recorded(&digest)?;
+            eprintln!(
+                "buildchain: warning: {} is signed by the key it records, {}, which is not \
+                 verified",
+                args.archive, block.public_key
+            );
+            println!(
+                "buildchain: unverified provenance of tail/{}/{} counter {}",
+                provenance.project, provenance.branch, block.counter
+            );
+        }
+        (Some(_), None) => {
+            return Err(Error::Config(format!(
+                "{} has a provenance, a key is required to verify it",
+                args.archive
+            )));
+        }
+        (None, _) => (),
+    }
+
+    // With `trust_embedded_key`, the tail is checked against the key of the provenance, but is
+    // no more trusted than it
+    let (key_opt, checked) = match (&provenance_opt, args.key_opt) {
+        (Some(provenance), None) => (Some(provenance.public_key.as_str()), "unverified"),
+        (_, key_opt) => (key_opt, "verified"),
+    };
+    let tail_opt = store.read_tail(args.project, args.branch)?;
+    match (tail_opt, key_opt) {
+        (Some(_), Some(key)) => {
+            let dl = Downloader::from_transport(
+                key,
+                args.project,
+                args.branch,
+                Box::new(LocalTransport::new(temp_dir.path())),
+            )?;
+            let block = dl.tail()?;
+            if block.digest != digest {
+                return Err(Error::Verify(format!(
+                    "tail/{}/{} does not refer to manifest.json",
+                    args.project, args.branch
+                )));
+            }
+            println!(
+                "buildchain: {} tail/{}/{} counter {}",
+                checked, args.project, args.branch, block.counter
+            );
+        }
+        (Some(_), None) => {
+            return Err(Error::Config(format!(
+                "{} has a tail for {}/{}, a key is required to verify it",
+                args.archive, args.project, args.branch
+            )));
+        }
+        (None, Some(_)) => {
+            return Err(Error::NotFound(format!(
+                "{} has no tail for {}/{}",
+                args.archive, args.project, args.branch
+            )));
+        }
+        (None, None) => (),
+    }
+
+    write_artifacts(&store, &manifest, Path::new(args.dest))?;
+    temp_dir.close()?;
+
+    println!("buildchain: extracted {} to {}", args.archive, args.dest);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+
+    use tempfile::TempDir;
+
+    use super::{extract, verified_manifest, write_artifacts, ExtractArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{Error, Manifest, Provenance, Store};
+
+    #[test]
+    fn test_write_artifacts() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path().join("store"));
+        fs::create_dir(store.path()).unwrap();
+
+        let key = store.write_object(b"artifact").unwrap();
+        let manifest = Manifest {
+            time: 0,
+            files: [("dir/a.bin".to_string(), key)].into(),
+            ..Default::default()
+        };
+        let manifest_json = serde_json::to_vec(&manifest).unwrap();
+        store.write_manifest(&manifest_json).unwrap();
+
+        let (read, _digest) = verified_manifest(&store).unwrap();
+        assert_eq!(read, manifest);
+
+        let dest = temp_dir.path().join("dest");
+        write_artifacts(&store, &manifest, &dest).unwrap();
+        assert_eq!(fs::read(dest.join("dir/a.bin")).unwrap(), b"artifact");
+
+        let escape = Manifest {
+            time: 0,
+            files: [("../a.bin".to_string(), key)].into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            write_artifacts(&store, &escape, &dest),
+            Err(Error::Verify(_))
+        ));
+
+        // A manifest link that does not match its contents is rejected
+        let fake = temp_dir.path().join(key.to_string());
+        fs::write(&fake, &manifest_json).unwrap();
+        fs::remove_file(store.path().join("manifest.json")).unwrap();
+        symlink(&fake, store.path().join("manifest.json")).unwrap();
+        assert!(matches!(verified_manifest(&store), Err(Error::Verify(_))));
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_extract_provenance() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path().join("store"));
+        fs::create_dir(store.path()).unwrap();
+        let manifest_json = serde_json::to_vec(&Manifest::default()).unwrap();
+        let digest = store.write_manifest(&manifest_json).unwrap();
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &digest);
+        store.write_tail("default", "master", &block).unwrap();
+        Provenance::new("default", "master", &block)
+            .unwrap()
+            .write(store.path())
+            .unwrap();
+
+        let archive = temp_dir.path().join("buildchain.tar");
+        let status = Command::new("tar")
+            .arg("--create")
+            .arg("--file")
+            .arg(&archive)
+            .arg("--directory")
+            .arg(store.path())
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let key = b32enc(&public_key);
+        let (other, _) = signed_block(2, &[0; 64], 0, &digest);
+        let other = b32enc(&other);
+        let dest = temp_dir.path().join("dest");
+        let args = |key_opt, trust_embedded_key| ExtractArguments {
+            archive: archive.to_str().unwrap(),
+            dest: dest.to_str().unwrap(),
+            key_opt,
+            trust_embedded_key,
+            project: "default",
+            branch: "master",
+        };
+
+        // The key recorded in the archive is only used when asked for
+        assert!(matches!(extract(args(None, false)), Err(Error::Config(_))));
+        assert!(matches!(
+            extract(args(Some(&other), false)),
+            Err(Error::Verify(_))
+        ));
+        extract(args(None, true)).unwrap();
+        extract(args(Some(&key), false)).unwrap();
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! C interface for verifying blocks, objects, and manifests
+//!
+//! The matching header is `include/buildchain.h`, generated with `cbindgen`.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::{ptr, slice};
+
+use crate::verify::{verify_block, verify_object, VerifyError, BLOCK_SIZE};
+use crate::Manifest;
+
+pub const BUILDCHAIN_OK: c_int = 0;
+pub const BUILDCHAIN_ERROR_NULL: c_int = -1;
+pub const BUILDCHAIN_ERROR_LENGTH: c_int = -2;
+pub const BUILDCHAIN_ERROR_PUBLIC_KEY_MISMATCH: c_int = -3;
+pub const BUILDCHAIN_ERROR_SIGNATURE_INVALID: c_int = -4;
+pub const BUILDCHAIN_ERROR_MESSAGE_INVALID: c_int = -5;
+pub const BUILDCHAIN_ERROR_DIGEST_MISMATCH: c_int = -6;
+pub const BUILDCHAIN_ERROR_NOT_FOUND: c_int = -7;
+
+fn error_code(err: VerifyError) -> c_int {
+    match err {
+        VerifyError::PublicKeyMismatch => BUILDCHAIN_ERROR_PUBLIC_KEY_MISMATCH,
+        VerifyError::SignatureInvalid => BUILDCHAIN_ERROR_SIGNATURE_INVALID,
+        VerifyError::MessageInvalid => BUILDCHAIN_ERROR_MESSAGE_INVALID,
+        VerifyError::DigestMismatch => BUILDCHAIN_ERROR_DIGEST_MISMATCH,
+    }
+}
+
+/// The fields of a verified block
+#[repr(C)]
+pub struct BuildchainBlock {
+    pub signature: [u8; 64],
+    pub public_key: [u8; 32],
+    pub previous_signature: [u8; 64],
+    pub counter: u64,
+    pub timestamp: u64,
+    /// The sha384 of the manifest
+    pub digest: [u8; 48],
+}
+
+/// A parsed manifest, created by `buildchain_manifest_parse`
+pub struct BuildchainManifest {
+    time: u64,
+    files: Vec<(CString, [u8; 48])>,
+}
+
+/// Verify that `data` is a block signed by `key`, filling `out` if it is not null
+///
+/// Returns `BUILDCHAIN_OK` or a negative error code
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, `key` to 32 readable bytes, and `out` must be
+/// null or point to a writable `BuildchainBlock`
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_verify_block(
+    data: *const u8,
+    len: usize,
+    key: *const u8,
+    out: *mut BuildchainBlock,
+) -> c_int {
+    if data.is_null() || key.is_null() {
+        return BUILDCHAIN_ERROR_NULL;
+    }
+    let data: &[u8; BLOCK_SIZE] = match slice::from_raw_parts(data, len).try_into() {
+        Ok(data) => data,
+        Err(_) => return BUILDCHAIN_ERROR_LENGTH,
+    };
+    let key = &*(key as *const [u8; 32]);
+
+    let block = match verify_block(data, key) {
+        Ok(block) => block,
+        Err(err) => return error_code(err),
+    };
+
+    if let Some(out) = out.as_mut() {
+        out.signature.copy_from_slice(block.signature());
+        out.public_key.copy_from_slice(block.public_key());
+        out.previous_signature
+            .copy_from_slice(block.previous_signature());
+        out.counter = block.counter();
+        out.timestamp = block.timestamp();
+        out.digest = *block.digest();
+    }
+
+    BUILDCHAIN_OK
+}
+
+/// Verify that `data` has the sha384 `digest`
+///
+/// Returns `BUILDCHAIN_OK` or a negative error code
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes and `digest` to 48 readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_verify_object(
+    data: *const u8,
+    len: usize,
+    digest: *const u8,
+) -> c_int {
+    if (data.is_null() && len > 0) || digest.is_null() {
+        return BUILDCHAIN_ERROR_NULL;
+    }
+    let data = if len > 0 {
+        slice::from_raw_parts(data, len)
+    } else {
+        &[]
+    };
+    let digest = &*(digest as *const [u8; 48]);
+
+    match verify_object(data, digest) {
+        Ok(()) => BUILDCHAIN_OK,
+        Err(err) => error_code(err),
+    }
+}
+
+/// Parse the JSON manifest in `data`, returning null if it is invalid
+///
+/// The result must be freed with `buildchain_manifest_free`
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_manifest_parse(
+    data: *const u8,
+    len: usize,
+) -> *mut BuildchainManifest {
+    if data.is_null() {
+        return ptr::null_mut();
+    }
+    let manifest: Manifest = match serde_json::from_slice(slice::from_raw_parts(data, len)) {
+        Ok(manifest) => manifest,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let mut files = Vec::with_capacity(manifest.files.len());
+    for (name, digest) in manifest.files {
+        let Ok(name) = CString::new(name) else {
+            return ptr::null_mut();
+        };
+        files.push((name, digest.0));
+    }
+
+    Box::into_raw(Box::new(BuildchainManifest {
+        time: manifest.time,
+        files,
+    }))
+}
+
+/// Free a manifest returned by `buildchain_manifest_parse`
+///
+/// # Safety
+///
+/// `manifest` must be null or a pointer returned by `buildchain_manifest_parse` that has not
+/// already been freed
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_manifest_free(manifest: *mut BuildchainManifest) {
+    if !manifest.is_null() {
+        drop(Box::from_raw(manifest));
+    }
+}
+
+/// The timestamp of the source control revision that was built
+///
+/// # Safety
+///
+/// `manifest` must be a valid pointer returned by `buildchain_manifest_parse`
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_manifest_time(manifest: *const BuildchainManifest) -> u64 {
+    manifest.as_ref().map_or(0, |manifest| manifest.time)
+}
+
+/// The number of files in the manifest
+///
+/// # Safety
+///
+/// `manifest` must be a valid pointer returned by `buildchain_manifest_parse`
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_manifest_len(manifest: *const BuildchainManifest) -> usize {
+    manifest.as_ref().map_or(0, |manifest| manifest.files.len())
+}
+
+/// The name of the file at `index`, or null if `index` is out of range
+///
+/// The name is owned by the manifest and is valid until it is freed
+///
+/// # Safety
+///
+/// `manifest` must be a valid pointer returned by `buildchain_manifest_parse`
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_manifest_name(
+    manifest: *const BuildchainManifest,
+    index: usize,
+) -> *const c_char {
+    manifest
+        .as_ref()
+        .and_then(|manifest| manifest.files.get(index))
+        .map_or(ptr::null(), |(name, _digest)| name.as_ptr())
+}
+
+/// Copy the sha384 of the file `name` into `digest`
+///
+/// Returns `BUILDCHAIN_OK` or a negative error code
+///
+/// # Safety
+///
+/// `manifest` must be a valid pointer returned by `buildchain_manifest_parse`, `name` a
+/// NUL-terminated string, and `digest` must point to 48 writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn buildchain_manifest_digest(
+    manifest: *const BuildchainManifest,
+    name: *const c_char,
+    digest: *mut u8,
+) -> c_int {
+    let Some(manifest) = manifest.as_ref() else {
+        return BUILDCHAIN_ERROR_NULL;
+    };
+    if name.is_null() || digest.is_null() {
+        return BUILDCHAIN_ERROR_NULL;
+    }
+    let name = CStr::from_ptr(name);
+
+    match manifest
+        .files
+        .iter()
+        .find(|(file_name, _digest)| file_name.as_c_str() == name)
+    {
+        Some((_name, key)) => {
+            ptr::copy_nonoverlapping(key.as_ptr(), digest, key.len());
+            BUILDCHAIN_OK
+        }
+        None => BUILDCHAIN_ERROR_NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{CStr, CString};
+    use std::mem::MaybeUninit;
+    use std::ptr;
+
+    use sha2::{Digest, Sha384};
+
+    use super::*;
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+
+    #[test]
+    fn test_verify_block() {
+        let (key, block) = signed_block(1, &[2; 64], 3, &[4; 48]);
+
+        let mut out = MaybeUninit::<BuildchainBlock>::uninit();
+        let res = unsafe {
+            buildchain_verify_block(block.as_ptr(), block.len(), key.as_ptr(), out.as_mut_ptr())
+        };
+        assert_eq!(res, BUILDCHAIN_OK);
+        let out = unsafe { out.assume_init() };
+        assert_eq!(out.counter, 3);
+        assert_eq!(out.digest, [4; 48]);
+
+        let res =
+            unsafe { buildchain_verify_block(block.as_ptr(), 399, key.as_ptr(), ptr::null_mut()) };
+        assert_eq!(res, BUILDCHAIN_ERROR_LENGTH);
+
+        let other_key = [0; 32];
+        let res = unsafe {
+            buildchain_verify_block(
+                block.as_ptr(),
+                block.len(),
+                other_key.as_ptr(),
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(res, BUILDCHAIN_ERROR_PUBLIC_KEY_MISMATCH);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let digest: [u8; 48] = Sha384::digest(b"data").into();
+        let json = format!(r#"{{"time":1,"files":{{"a.bin":"{}"}}}}"#, b32enc(&digest));
+
+        unsafe {
+            let manifest = buildchain_manifest_parse(json.as_ptr(), json.len());
+            assert!(!manifest.is_null());
+            assert_eq!(buildchain_manifest_time(manifest), 1);
+            assert_eq!(buildchain_manifest_len(manifest), 1);
+            let name = buildchain_manifest_name(manifest, 0);
+            assert_eq!(CStr::from_ptr(name).to_str(), Ok("a.bin"));
+            assert!(buildchain_manifest_name(manifest, 1).is_null());
+
+            let mut out = [0; 48];
+            assert_eq!(
+                buildchain_manifest_digest(manifest, name, out.as_mut_ptr()),
+                BUILDCHAIN_OK
+            );
+            assert_eq!(
+                buildchain_verify_object(b"data".as_ptr(), 4, out.as_ptr()),
+                BUILDCHAIN_OK
+            );
+            assert_eq!(
+                buildchain_verify_object(b"atad".as_ptr(), 4, out.as_ptr()),
+                BUILDCHAIN_ERROR_DIGEST_MISMATCH
+            );
+
+            let missing = CString::new("b.bin").unwrap();
+            assert_eq!(
+                buildchain_manifest_digest(manifest, missing.as_ptr(), out.as_mut_ptr()),
+                BUILDCHAIN_ERROR_NOT_FOUND
+            );
+            buildchain_manifest_free(manifest);
+
+            assert!(buildchain_manifest_parse(b"{".as_ptr(), 1).is_null());
+        }
+    }
+}
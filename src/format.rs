@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::str::FromStr;
+
+/// How command results are printed
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    /// Human readable text
+    #[default]
+    Text,
+    /// One JSON document, for scripts and daemons
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+/// Print `value` as pretty JSON on stdout
+#[cfg(feature = "download")]
+pub(crate) fn print_json<T: serde::Serialize>(value: &T) -> Result<(), crate::Error> {
+    let json = serde_json::to_string_pretty(value).map_err(std::io::Error::from)?;
+    println!("{}", json);
+    Ok(())
+}
@@ -0,0 +1,478 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Checks and repairs of stores left inconsistent by interrupted runs
+//!
+//! A build or upload that is killed part way may leave files in `tmp/`, which makes
+//! [`Store::remove_tmp_dir`] fail, tails that no longer point at a block, and artifact links
+//! that no longer point at an object. Blocks and objects are never modified, so the links and
+//! the [`BlockIndex`] can be rebuilt from them.
+
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, read_dir, remove_dir_all, remove_file, rename, File};
+use std::io::{self, Read};
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::store::{object_relpath, tail_to_block};
+use crate::verify::{BLOCK_SIZE, PREVIOUS_SIGNATURE};
+use crate::{BlockIndex, BlockSig, Error, Format, Store};
+
+/// The default age after which files in `tmp/` are considered left over by a crashed run
+pub const DEFAULT_STALE_TMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A problem found in a store by [`fsck_store`]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct FsckIssue {
+    /// The path of the problem, relative to the store
+    pub path: String,
+    pub problem: String,
+    /// Whether the problem was repaired
+    pub repaired: bool,
+}
+
+pub struct FsckArguments<'a> {
+    pub store: &'a Store,
+    /// Repair the problems that can be repaired, instead of only reporting them
+    pub repair: bool,
+    /// Files in `tmp/` older than this are removed when repairing
+    pub tmp_age: Duration,
+    pub format: Format,
+}
+
+/// Replace `link` with a symlink to `target`, through a temporary path in the store
+fn relink(store: &Store, link: &Path, target: &Path) -> io::Result<()> {
+    let tmp = store.temp_path();
+    fs::create_dir_all(tmp.parent().unwrap())?;
+    symlink(target, &tmp)?;
+    rename(tmp, link)
+}
+
+/// Remove entries of `tmp/` older than `tmp_age`
+fn check_tmp(
+    store: &Store,
+    repair: bool,
+    tmp_age: Duration,
+    issues: &mut Vec<FsckIssue>,
+) -> Result<(), Error> {
+    let entries = match read_dir(store.path().join("tmp")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let now = SystemTime::now();
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.path().symlink_metadata()?;
+        let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+        if age < tmp_age {
+            continue;
+        }
+
+        if repair {
+            if metadata.is_dir() {
+                remove_dir_all(entry.path())?;
+            } else {
+                remove_file(entry.path())?;
+            }
+        }
+        issues.push(FsckIssue {
+            path: format!("tmp/{}", entry.file_name().to_string_lossy()),
+            problem: format!("stale temporary file, {} seconds old", age.as_secs()),
+            repaired: repair,
+        });
+    }
+    Ok(())
+}
+
+/// Read the previous signature of every block in `block/`
+fn read_blocks(
+    store: &Store,
+    issues: &mut Vec<FsckIssue>,
+) -> Result<BTreeMap<BlockSig, BlockSig>, Error> {
+    let mut blocks = BTreeMap::new();
+    let entries = match read_dir(store.path().join("block")) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(blocks),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Ok(sig) = name.parse::<BlockSig>() else {
+            continue;
+        };
+
+        let mut block = [0u8; BLOCK_SIZE];
+        if File::open(entry.path())?.read_exact(&mut block).is_err() {
+            issues.push(FsckIssue {
+                path: format!("block/{}", name),
+                problem: format!("block is shorter than {} bytes", BLOCK_SIZE),
+                repaired: false,
+            });
+            continue;
+        }
+        let previous = BlockSig::try_from(&block[PREVIOUS_SIGNATURE]).unwrap();
+        blocks.insert(sig, previous);
+    }
+    Ok(blocks)
+}
+
+/// The block a tail link points at, if it can be read
+fn tail_target(store: &Store, project: &str, branch: &str) -> Option<BlockSig> {
+    let link = store.path().join("tail").join(project).join(branch);
+    let target = fs::read_link(link).ok()?;
+    target.file_name()?.to_str()?.parse().ok()
+}
+
+/// Rebuild tails listed in `tail/index.json` that are missing or point at a missing block
+///
+/// A tail is rebuilt only if exactly one tail is broken, and exactly one block is the head of
+/// a chain that no other tail leads to, since blocks do not record their project and branch.
+fn check_tails(store: &Store, repair: bool, issues: &mut Vec<FsckIssue>) -> Result<(), Error> {
+    let mut tails: BTreeSet<(String, String)> = BTreeSet::new();
+    match fs::read(store.path().join("tail").join("index.json")) {
+        Ok(data) => {
+            let index: BTreeMap<String, Vec<String>> =
+                serde_json::from_slice(&data).map_err(io::Error::from)?;
+            for (project, branches) in index {
+                for branch in branches {
+                    tails.insert((project.clone(), branch));
+                }
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+    for (project, branches) in store.tail_index()? {
+        for branch in branches {
+            tails.insert((project.clone(), branch));
+        }
+    }
+    if tails.is_empty() {
+        return Ok(());
+    }
+
+    let blocks = read_blocks(store, issues)?;
+
+    // Blocks reachable from an intact tail belong to its branch
+    let mut claimed = BTreeSet::new();
+    let mut broken = Vec::new();
+    for (project, branch) in tails.iter() {
+        match tail_target(store, project, branch) {
+            Some(mut sig) if blocks.contains_key(&sig) => {
+                while let Some(previous) = blocks.get(&sig) {
+                    if !claimed.insert(sig) {
+                        break;
+                    }
+                    sig = *previous;
+                }
+            }
+            _ => broken.push((project, branch)),
+        }
+    }
+
+    let referenced: BTreeSet<&BlockSig> = blocks.values().collect();
+    let heads: Vec<&BlockSig> = blocks
+        .keys()
+        .filter(|sig| !referenced.contains(sig) && !claimed.contains(*sig))
+        .collect();
+
+    let repairable = broken.len() == 1 && heads.len() == 1;
+    for (project, branch) in broken.iter() {
+        let path = format!("tail/{}/{}", project, branch);
+        if repairable {
+            let sig = heads[0];
+            if repair {
+                let dir = store.path().join("tail").join(project);
+                fs::create_dir_all(&dir)?;
+                relink(store, &dir.join(branch), &tail_to_block(sig))?;
+            }
+            issues.push(FsckIssue {
+                path,
+                problem: format!("missing tail, the head of its chain is block {}", sig),
+                repaired: repair,
+            });
+        } else {
+            issues.push(FsckIssue {
+                path,
+                problem: format!(
+                    "missing tail, {} blocks could be its head and {} tails are missing",
+                    heads.len(),
+                    broken.len()
+                ),
+                repaired: false,
+            });
+        }
+    }
+
+    // Only rewrite the index once no tail listed in it is missing, so that none are forgotten
+    if repair && repairable {
+        store.write_tail_index()?;
+    }
+    Ok(())
+}
+
+/// Rebuild the block index if the newest block it lists for a branch is not its tail
+fn check_index(store: &Store, repair: bool, issues: &mut Vec<FsckIssue>) -> Result<(), Error> {
+    let index = BlockIndex::read(store)?;
+    let mut stale = Vec::new();
+    for (project, branches) in store.tail_index()? {
+        for branch in branches {
+            let Some(tail) = tail_target(store, &project, &branch) else {
+                continue;
+            };
+            if index.latest(&project, &branch).map(|block| block.signature) != Some(tail) {
+                stale.push(format!("{}/{}", project, branch));
+            }
+        }
+    }
+    if stale.is_empty() {
+        return Ok(());
+    }
+
+    if repair {
+        BlockIndex::rebuild(store)?;
+    }
+    issues.push(FsckIssue {
+        path: "index/blocks.jsonl".to_string(),
+        problem: format!("block index is stale for {}", stale.join(", ")),
+        repaired: repair,
+    });
+    Ok(())
+}
+
+/// Relink `artifacts/` to the objects listed in the manifest of the store
+fn check_artifacts(store: &Store, repair: bool, issues: &mut Vec<FsckIssue>) -> Result<(), Error> {
+    let link = store.path().join("manifest.json");
+    if link.symlink_metadata().is_ok() && !link.exists() {
+        issues.push(FsckIssue {
+            path: "manifest.json".to_string(),
+            problem: "points at a missing object".to_string(),
+            repaired: false,
+        });
+        return Ok(());
+    }
+
+    let artifacts = store.path().join("artifacts");
+    let manifest = match store.read_manifest()? {
+        Some(manifest) if artifacts.is_dir() => manifest,
+        _ => return Ok(()),
+    };
+
+    for (name, key) in manifest.files.iter() {
+        let path = artifacts.join(name);
+        let target = Path::new("..").join(object_relpath(key));
+        match fs::read_link(&path) {
+            Ok(link_target) if link_target == target && path.exists() => continue,
+            // Artifacts imported with a hard link or reflink are regular files
+            Err(_) if path.is_file() => continue,
+            _ => (),
+        }
+
+        let path_name = format!("artifacts/{}", name);
+        if !store.contains(key) {
+            issues.push(FsckIssue {
+                path: path_name,
+                problem: format!("object {} is missing", key),
+                repaired: false,
+            });
+            continue;
+        }
+
+        if repair {
+            relink(store, &path, &target)?;
+        }
+        issues.push(FsckIssue {
+            path: path_name,
+            problem: format!("not linked to object {}", key),
+            repaired: repair,
+        });
+    }
+    Ok(())
+}
+
+/// Check `store` for stale temporary files, missing tails, a stale block index, and broken
+/// artifact links, repairing them if `repair` is set
+///
+/// Files in `tmp/` are only stale once they are older than `tmp_age`, so that a build or
+/// upload in progress is not disturbed.
+pub fn fsck_store(store: &Store, repair: bool, tmp_age: Duration) -> Result<Vec<FsckIssue>, Error> {
+    if repair {
+        store.check_writable()?;
+    }
+    let mut issues = Vec::new();
+    check_tmp(store, repair, tmp_age, &mut issues)?;
+    check_tails(store, repair, &mut issues)?;
+    check_index(store, repair, &mut issues)?;
+    check_artifacts(store, repair, &mut issues)?;
+    Ok(issues)
+}
+
+/// Check a store, and print the problems found
+///
+/// Fails with [`Error::Verify`] if any problem was not repaired.
+pub fn fsck(args: FsckArguments) -> Result<(), Error> {
+    let issues = fsck_store(args.store, args.repair, args.tmp_age)?;
+
+    match args.format {
+        Format::Text => {
+            for issue in issues.iter() {
+                if issue.repaired {
+                    println!("{}: {} (repaired)", issue.path, issue.problem);
+                } else {
+                    println!("{}: {}", issue.path, issue.problem);
+                }
+            }
+        }
+        Format::Json => {
+            let json = serde_json::to_string_pretty(&issues).map_err(io::Error::from)?;
+            println!("{}", json);
+        }
+    }
+
+    let remaining = issues.iter().filter(|issue| !issue.repaired).count();
+    if remaining > 0 {
+        return Err(Error::Verify(if args.repair {
+            format!("{} problems could not be repaired", remaining)
+        } else {
+            format!(
+                "{} problems found, run with --repair to fix them",
+                remaining
+            )
+        }));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
+
+    use rand::{rngs::OsRng, RngCore};
+    use tempfile::TempDir;
+
+    use super::{fsck_store, DEFAULT_STALE_TMP_AGE};
+    use crate::Store;
+
+    fn block(previous: &[u8]) -> [u8; 400] {
+        let mut block = [0u8; 400];
+        OsRng.fill_bytes(&mut block);
+        block[96..160].copy_from_slice(previous);
+        block
+    }
+
+    #[test]
+    fn test_fsck_tmp() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        fs::create_dir_all(temp_dir.path().join("tmp")).unwrap();
+        fs::write(store.temp_path(), b"partial").unwrap();
+
+        // Recent files may belong to a run in progress
+        assert_eq!(fsck_store(&store, true, DEFAULT_STALE_TMP_AGE).unwrap(), []);
+        assert!(store.remove_tmp_dir().is_err());
+
+        let issues = fsck_store(&store, false, Duration::ZERO).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert!(!issues[0].repaired);
+        assert!(issues[0].path.starts_with("tmp/"));
+
+        let issues = fsck_store(&store, true, Duration::ZERO).unwrap();
+        assert!(issues[0].repaired);
+        store.remove_tmp_dir().unwrap();
+    }
+
+    #[test]
+    fn test_fsck_tails() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+
+        let first = block(&[0; 64]);
+        store.write_tail("default", "master", &first).unwrap();
+        let second = block(&first[..64]);
+        store.write_tail("default", "master", &second).unwrap();
+        let other = block(&[0; 64]);
+        store.write_tail("default", "other", &other).unwrap();
+        assert_eq!(fsck_store(&store, false, Duration::ZERO).unwrap(), []);
+
+        // An interrupted run removed the tail, but it is still in the index
+        let tail = temp_dir.path().join("tail/default/master");
+        fs::remove_file(&tail).unwrap();
+        let issues = fsck_store(&store, false, Duration::ZERO).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "tail/default/master");
+        assert!(!tail.exists());
+
+        fsck_store(&store, true, Duration::ZERO).unwrap();
+        assert_eq!(store.read_tail("default", "master").unwrap(), Some(second));
+        assert_eq!(fsck_store(&store, false, Duration::ZERO).unwrap(), []);
+
+        // With two tails missing, the heads cannot be told apart
+        fs::remove_file(&tail).unwrap();
+        fs::remove_file(temp_dir.path().join("tail/default/other")).unwrap();
+        let issues = fsck_store(&store, true, Duration::ZERO).unwrap();
+        assert_eq!(issues.len(), 2);
+        assert!(issues.iter().all(|issue| !issue.repaired));
+        let index = fs::read_to_string(temp_dir.path().join("tail/index.json")).unwrap();
+        assert!(index.contains("master") && index.contains("other"));
+    }
+
+    #[test]
+    fn test_fsck_index() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let first = block(&[0; 64]);
+        store.write_tail("default", "master", &first).unwrap();
+        store
+            .write_tail("default", "master", &block(&first[..64]))
+            .unwrap();
+
+        let index = temp_dir.path().join("index/blocks.jsonl");
+        fs::remove_file(&index).unwrap();
+        let issues = fsck_store(&store, false, Duration::ZERO).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].path, "index/blocks.jsonl");
+
+        fsck_store(&store, true, Duration::ZERO).unwrap();
+        assert!(index.is_file());
+        assert_eq!(fsck_store(&store, false, Duration::ZERO).unwrap(), []);
+    }
+
+    #[test]
+    fn test_fsck_artifacts() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let artifacts = temp_dir.path().join("artifacts");
+        fs::create_dir(&artifacts).unwrap();
+        fs::write(artifacts.join("a.txt"), b"a").unwrap();
+        fs::write(artifacts.join("b.txt"), b"b").unwrap();
+
+        let manifest = store.import_artifacts(0).unwrap();
+        store
+            .write_manifest(&serde_json::to_vec(&manifest).unwrap())
+            .unwrap();
+        assert_eq!(fsck_store(&store, false, Duration::ZERO).unwrap(), []);
+
+        fs::remove_file(artifacts.join("a.txt")).unwrap();
+        fs::remove_file(artifacts.join("b.txt")).unwrap();
+        symlink("../object/missing", artifacts.join("b.txt")).unwrap();
+        let issues = fsck_store(&store, true, Duration::ZERO).unwrap();
+        assert_eq!(issues.len(), 2);
+        assert!(issues.iter().all(|issue| issue.repaired));
+        assert_eq!(fs::read(artifacts.join("a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(artifacts.join("b.txt")).unwrap(), b"b");
+
+        // An artifact whose object is gone cannot be relinked
+        let key = &manifest.files["a.txt"];
+        fs::remove_file(store.object_path(key)).unwrap();
+        let issues = fsck_store(&store, true, Duration::ZERO).unwrap();
+        assert_eq!(issues.len(), 1);
+        assert!(!issues[0].repaired);
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use crate::{err_str, xml_escape, ObjectId, Sha384, Store};
+
+pub struct FwupdArguments<'a> {
+    pub store_path: &'a str,
+    pub file: &'a str,
+    pub output: &'a str,
+    pub id: &'a str,
+    pub name: &'a str,
+    pub summary: &'a str,
+    pub version: &'a str,
+    pub guids: Vec<&'a str>,
+    pub vendor_opt: Option<&'a str>,
+    pub description_opt: Option<&'a str>,
+}
+
+/// The contents of a firmware `metainfo.xml`, as read by fwupd and LVFS
+struct Metainfo<'a> {
+    id: &'a str,
+    name: &'a str,
+    summary: &'a str,
+    version: &'a str,
+    guids: &'a [&'a str],
+    vendor_opt: Option<&'a str>,
+    description_opt: Option<&'a str>,
+    timestamp: u64,
+    filename: &'a str,
+    sha256: String,
+    /// Buildchain digests of the manifest and firmware, so the cab can be traced to a build
+    manifest_digest: String,
+    file_digest: &'a ObjectId,
+}
+
+impl<'a> Metainfo<'a> {
+    fn to_xml(&self) -> String {
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<component type=\"firmware\">\n");
+        xml.push_str(&format!("  <id>{}</id>\n", xml_escape(self.id)));
+        xml.push_str(&format!("  <name>{}</name>\n", xml_escape(self.name)));
+        xml.push_str(&format!(
+            "  <summary>{}</summary>\n",
+            xml_escape(self.summary)
+        ));
+        if let Some(vendor) = self.vendor_opt {
+            xml.push_str(&format!(
+                "  <developer_name>{}</developer_name>\n",
+                xml_escape(vendor)
+            ));
+        }
+        xml.push_str("  <provides>\n");
+        for guid in self.guids.iter() {
+            xml.push_str(&format!(
+                "    <firmware type=\"flashed\">{}</firmware>\n",
+                xml_escape(guid)
+            ));
+        }
+        xml.push_str("  </provides>\n");
+        xml.push_str("  <metadata_license>CC0-1.0</metadata_license>\n");
+        xml.push_str("  <project_license>proprietary</project_license>\n");
+        xml.push_str("  <releases>\n");
+        xml.push_str(&format!(
+            "    <release version=\"{}\" timestamp=\"{}\">\n",
+            xml_escape(self.version),
+            self.timestamp
+        ));
+        xml.push_str(&format!(
+            "      <checksum filename=\"{}\" target=\"content\" type=\"sha256\">{}</checksum>\n",
+            xml_escape(self.filename),
+            self.sha256
+        ));
+        if let Some(description) = self.description_opt {
+            xml.push_str(&format!(
+                "      <description>\n        <p>{}</p>\n      </description>\n",
+                xml_escape(description)
+            ));
+        }
+        xml.push_str("    </release>\n");
+        xml.push_str("  </releases>\n");
+        xml.push_str("  <custom>\n");
+        xml.push_str(&format!(
+            "    <value key=\"buildchain::manifest\">{}</value>\n",
+            self.manifest_digest
+        ));
+        xml.push_str(&format!(
+            "    <value key=\"buildchain::sha384\">{}</value>\n",
+            self.file_digest
+        ));
+        xml.push_str("  </custom>\n");
+        xml.push_str("</component>\n");
+        xml
+    }
+}
+
+/// Package a firmware artifact of a build as a cabinet archive accepted by LVFS and fwupd
+///
+/// The `metainfo.xml` records the digest of the build manifest, and the release timestamp
+/// is the time of the build, so the cab contents are reproducible.
+pub fn fwupd(args: FwupdArguments) -> Result<(), String> {
+    let store = Store::new(args.store_path);
+    let manifest = store
+        .read_manifest()
+        .map_err(err_str)?
+        .ok_or_else(|| format!("{} has no manifest.json", args.store_path))?;
+    let manifest_json = fs::read(store.path().join("manifest.json")).map_err(err_str)?;
+    let manifest_digest = Sha384::new(manifest_json.as_slice())
+        .map_err(err_str)?
+        .to_base32();
+
+    let file_digest = manifest
+        .files
+        .get(args.file)
+        .ok_or_else(|| format!("{} not found", args.file))?;
+    let data = fs::read(store.object_path(file_digest)).map_err(err_str)?;
+
+    let filename = Path::new(args.file)
+        .file_name()
+        .and_then(|filename| filename.to_str())
+        .ok_or_else(|| format!("invalid artifact name {}", args.file))?;
+
+    if args.guids.is_empty() {
+        return Err("at least one GUID is required".to_string());
+    }
+
+    let metainfo = Metainfo {
+        id: args.id,
+        name: args.name,
+        summary: args.summary,
+        version: args.version,
+        guids: &args.guids,
+        vendor_opt: args.vendor_opt,
+        description_opt: args.description_opt,
+        timestamp: manifest.time,
+        filename,
+        sha256: format!("{:x}", Sha256::digest(&data)),
+        manifest_digest,
+        file_digest,
+    };
+
+    let temp_dir = TempDir::with_prefix("buildchain.").map_err(err_str)?;
+    fs::write(temp_dir.path().join(filename), &data).map_err(err_str)?;
+    fs::write(
+        temp_dir.path().join("firmware.metainfo.xml"),
+        metainfo.to_xml(),
+    )
+    .map_err(err_str)?;
+
+    // gcab runs in the temporary directory, so the output path must be absolute
+    let output = env::current_dir().map_err(err_str)?.join(args.output);
+
+    let status = Command::new("gcab")
+        .arg("--create")
+        .arg("--nopath")
+        .arg(&output)
+        .arg(filename)
+        .arg("firmware.metainfo.xml")
+        .current_dir(temp_dir.path())
+        .status()
+        .map_err(err_str)?;
+
+    if !status.success() {
+        return Err(format!("gcab failed with status: {}", status));
+    }
+
+    temp_dir.close().map_err(err_str)?;
+
+    println!("buildchain: packaged {} into {}", args.file, args.output);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metainfo;
+    use crate::{xml_escape, ObjectId};
+
+    #[test]
+    fn test_metainfo() {
+        assert_eq!(
+            xml_escape("a <b> & \"c\""),
+            "a &lt;b&gt; &amp; &quot;c&quot;"
+        );
+
+        let metainfo = Metainfo {
+            id: "com.system76.galp5.firmware",
+            name: "galp5",
+            summary: "Firmware for the galp5",
+            version: "2023-09-01",
+            guids: &["12345678-1234-1234-1234-123456789abc"],
+            vendor_opt: None,
+            description_opt: Some("Fixes & improvements"),
+            timestamp: 1_500_000_000,
+            filename: "firmware.rom",
+            sha256: "abc".to_string(),
+            manifest_digest: "MANIFEST".to_string(),
+            file_digest: &ObjectId([1; 48]),
+        };
+
+        let xml = metainfo.to_xml();
+        assert!(xml.contains("<id>com.system76.galp5.firmware</id>\n"));
+        assert!(xml.contains(
+            "<firmware type=\"flashed\">12345678-1234-1234-1234-123456789abc</firmware>\n"
+        ));
+        assert!(xml.contains("<release version=\"2023-09-01\" timestamp=\"1500000000\">\n"));
+        assert!(xml.contains(
+            "<checksum filename=\"firmware.rom\" target=\"content\" type=\"sha256\">abc</checksum>\n"
+        ));
+        assert!(xml.contains("<p>Fixes &amp; improvements</p>"));
+        assert!(xml.contains("<value key=\"buildchain::manifest\">MANIFEST</value>\n"));
+        assert!(!xml.contains("developer_name"));
+    }
+}
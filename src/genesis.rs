@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::io;
+
+use crate::id::{b32dec, b32enc};
+use crate::verify::verify_block;
+use crate::{sign_manifest, Error, Genesis, Manifest, Store};
+
+pub struct GenesisArguments<'a> {
+    pub store_path: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// The base32 public keys allowed to sign the branch, the genesis block must be signed by
+    /// one of them
+    pub keys: &'a [String],
+    pub time: u64,
+}
+
+/// Sign the genesis manifest of `args.branch`, using `sign` to produce the block
+pub(crate) fn genesis_store<F>(store: &Store, args: &GenesisArguments, sign: F) -> Result<(), Error>
+where
+    F: FnOnce(&[u8]) -> io::Result<[u8; 400]>,
+{
+    if args.keys.is_empty() {
+        return Err(Error::Config("at least one key is required".to_string()));
+    }
+    let mut keys = Vec::new();
+    for key in args.keys.iter() {
+        let key: [u8; 32] = b32dec(key)
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| Error::Config(format!("{} is not a base32 public key", key)))?;
+        keys.push(key);
+    }
+
+    if store.read_tail(args.project, args.branch)?.is_some() {
+        return Err(Error::Config(format!(
+            "tail/{}/{} already exists",
+            args.project, args.branch
+        )));
+    }
+
+    let manifest = Manifest {
+        time: args.time,
+        genesis: Some(Genesis {
+            project: args.project.to_string(),
+            branch: args.branch.to_string(),
+            hash: "sha384".to_string(),
+            keys: args.keys.to_vec(),
+        }),
+        ..Default::default()
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+    let digest = store.write_object(&manifest_json)?;
+
+    let response = sign(&manifest_json).map_err(Error::Sign)?;
+    let verified = keys
+        .iter()
+        .find_map(|key| verify_block(&response, key).ok())
+        .ok_or_else(|| {
+            Error::Verify("genesis block is not signed by any of the keys".to_string())
+        })?;
+    if *verified.digest() != *digest {
+        return Err(Error::Verify(
+            "genesis block does not refer to the manifest".to_string(),
+        ));
+    }
+    if verified.counter() != 0 || verified.previous_signature().iter().any(|byte| *byte != 0) {
+        return Err(Error::Verify(
+            "genesis block is not the first block of the branch".to_string(),
+        ));
+    }
+
+    store.write_tail(args.project, args.branch, &response)?;
+    println!(
+        "buildchain: created genesis block {} for {}/{}",
+        b32enc(verified.signature()),
+        args.project,
+        args.branch
+    );
+    Ok(())
+}
+
+/// Start a branch with a genesis block signed by PiHSM, recording the keys allowed to sign it
+pub fn genesis(args: GenesisArguments) -> Result<(), Error> {
+    let store = Store::open(args.store_path)?;
+    genesis_store(&store, &args, sign_manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{genesis_store, GenesisArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::verify::verify_block;
+    use crate::{Error, Manifest, Sha384, Store};
+
+    #[test]
+    fn test_genesis() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let (public_key, _block) = signed_block(1, &[0; 64], 0, &[0; 48]);
+        let keys = vec![b32enc(&[0; 32]), b32enc(&public_key)];
+        let args = GenesisArguments {
+            store_path: "",
+            project: "default",
+            branch: "master",
+            keys: &keys,
+            time: 0,
+        };
+
+        let sign = |data: &[u8]| {
+            let manifest: Manifest = serde_json::from_slice(data).unwrap();
+            assert_eq!(manifest.genesis.unwrap().keys, keys);
+            let digest = Sha384::new(data)?.to_id();
+            Ok(signed_block(1, &[0; 64], 0, &digest).1)
+        };
+        genesis_store(&store, &args, sign).unwrap();
+
+        let tail = store.read_tail("default", "master").unwrap().unwrap();
+        assert_eq!(verify_block(&tail, &public_key).unwrap().counter(), 0);
+
+        // A branch only has one genesis
+        assert!(matches!(
+            genesis_store(&store, &args, |_: &[u8]| unreachable!()),
+            Err(Error::Config(_))
+        ));
+
+        // The genesis block must be signed by one of the keys
+        let args = GenesisArguments {
+            branch: "other",
+            ..args
+        };
+        let sign = |_: &[u8]| Ok(signed_block(2, &[0; 64], 0, &[0; 48]).1);
+        assert!(matches!(
+            genesis_store(&store, &args, sign),
+            Err(Error::Verify(_))
+        ));
+
+        // The genesis block must be the first block of the branch
+        let sign = |data: &[u8]| {
+            let digest = Sha384::new(data)?.to_id();
+            Ok(signed_block(1, &[0; 64], 1, &digest).1)
+        };
+        assert!(matches!(
+            genesis_store(&store, &args, sign),
+            Err(Error::Verify(_))
+        ));
+        assert!(store.read_tail("default", "other").unwrap().is_none());
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Typed identifiers of objects and blocks
+//!
+//! Objects are named by their sha384 digest and blocks by their signature, both written in
+//! base32 on mirrors and in manifests. [`ObjectId`] and [`BlockSig`] hold the raw bytes, so
+//! that digests and signatures cannot be mixed up with each other or with other byte arrays,
+//! and print the base32 form.
+//!
+//! Both also parse lowercase or uppercase hex, as printed by `sha384sum` and most registries.
+//! The lengths differ, 96 hex digits against 77 base32 characters for a digest, so the two
+//! forms cannot be confused.
+
+use base32::{self, Alphabet};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+const B32_ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+pub fn b32enc(bin: &[u8]) -> String {
+    base32::encode(B32_ALPHABET, bin)
+}
+
+pub fn b32dec(txt: &str) -> Option<Vec<u8>> {
+    base32::decode(B32_ALPHABET, txt)
+}
+
+/// How digests and signatures are written for people and other tools
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DigestEncoding {
+    /// RFC4648 base32 without padding, as used in stores and manifests
+    #[default]
+    Base32,
+    /// Lowercase hex, as used by `sha384sum`
+    Hex,
+}
+
+impl FromStr for DigestEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DigestEncoding, String> {
+        match s {
+            "base32" => Ok(DigestEncoding::Base32),
+            "hex" => Ok(DigestEncoding::Hex),
+            _ => Err(format!("unknown digest encoding: {}", s)),
+        }
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    let chunks = text.as_bytes().chunks_exact(2);
+    // from_str_radix also takes a leading sign
+    if !chunks.remainder().is_empty() || !text.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    chunks
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+macro_rules! id_type {
+    ($(#[$attr:meta])* $name:ident, $size:expr, $what:expr) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+        pub struct $name(pub [u8; $size]);
+
+        impl $name {
+            /// The length in bytes
+            pub const SIZE: usize = $size;
+
+            pub fn as_bytes(&self) -> &[u8; $size] {
+                &self.0
+            }
+
+            /// The lowercase hex form
+            pub fn to_hex(&self) -> String {
+                hex_encode(&self.0)
+            }
+
+            /// The form selected by `encoding`
+            pub fn encode(&self, encoding: DigestEncoding) -> String {
+                match encoding {
+                    DigestEncoding::Base32 => self.to_string(),
+                    DigestEncoding::Hex => self.to_hex(),
+                }
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> $name {
+                $name([0; $size])
+            }
+        }
+
+        impl From<[u8; $size]> for $name {
+            fn from(bytes: [u8; $size]) -> $name {
+                $name(bytes)
+            }
+        }
+
+        impl From<$name> for [u8; $size] {
+            fn from(id: $name) -> [u8; $size] {
+                id.0
+            }
+        }
+
+        impl TryFrom<&[u8]> for $name {
+            type Error = String;
+
+            fn try_from(bytes: &[u8]) -> Result<$name, String> {
+                bytes.try_into().map($name).map_err(|_| {
+                    format!(
+                        "{} is {} bytes, expected {} bytes",
+                        $what,
+                        bytes.len(),
+                        $size
+                    )
+                })
+            }
+        }
+
+        impl Deref for $name {
+            type Target = [u8; $size];
+
+            fn deref(&self) -> &[u8; $size] {
+                &self.0
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            /// Parse the base32 or hex form
+            fn from_str(text: &str) -> Result<$name, String> {
+                if text.len() == $size * 2 {
+                    if let Some(bytes) = hex_decode(text) {
+                        return $name::try_from(bytes.as_slice());
+                    }
+                }
+                let bytes = b32dec(text)
+                    .ok_or_else(|| format!("{} {} is not base32 or hex", $what, text))?;
+                $name::try_from(bytes.as_slice())
+            }
+        }
+
+        impl fmt::Display for $name {
+            /// Write the base32 form
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", b32enc(&self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_str(&self.to_string())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<$name, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(de::Error::custom)
+            }
+        }
+
+        #[cfg(feature = "schema")]
+        impl schemars::JsonSchema for $name {
+            fn schema_name() -> String {
+                stringify!($name).to_string()
+            }
+
+            /// A string in the base32 form, or the hex form that is also accepted
+            fn json_schema(_gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+                use schemars::schema::{InstanceType, Metadata, SchemaObject, StringValidation};
+
+                SchemaObject {
+                    instance_type: Some(InstanceType::String.into()),
+                    metadata: Some(Box::new(Metadata {
+                        description: Some(format!("The base32 or hex {}", $what)),
+                        ..Default::default()
+                    })),
+                    string: Some(Box::new(StringValidation {
+                        pattern: Some(format!(
+                            "^([A-Z2-7]{{{}}}|[0-9A-Fa-f]{{{}}})$",
+                            ($size * 8 + 4) / 5,
+                            $size * 2
+                        )),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }
+                .into()
+            }
+        }
+    };
+}
+
+id_type!(
+    /// The sha384 digest that names an object
+    ObjectId,
+    48,
+    "object digest"
+);
+
+id_type!(
+    /// The signature that names a block
+    BlockSig,
+    64,
+    "block signature"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockSig, DigestEncoding, ObjectId};
+    use crate::Sha384;
+
+    #[test]
+    fn test_object_id() {
+        let digest = Sha384::new(&b"object"[..]).unwrap().to_base32();
+        let id: ObjectId = digest.parse().unwrap();
+        assert_eq!(id.to_string(), digest);
+        assert_eq!(
+            serde_json::to_string(&id).unwrap(),
+            format!("\"{}\"", digest)
+        );
+        assert_eq!(
+            serde_json::from_str::<ObjectId>(&format!("\"{}\"", digest)).unwrap(),
+            id
+        );
+
+        // A signature is not a digest
+        let signature = BlockSig([1; 64]).to_string();
+        assert_eq!(
+            signature.parse::<ObjectId>(),
+            Err("object digest is 64 bytes, expected 48 bytes".to_string())
+        );
+        assert_eq!(signature.parse::<BlockSig>(), Ok(BlockSig([1; 64])));
+        assert!("not base32!".parse::<ObjectId>().is_err());
+        assert!(serde_json::from_str::<BlockSig>("\"AAAA\"").is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        // The digest of "abc" from FIPS 180-2
+        let hex = "cb00753f45a35e8bb5a03d699ac65007272c32ab0eded1631a8b605a43ff5bed\
+                   8086072ba1e7cc2358baeca134c825a7";
+        let id = Sha384::new(&b"abc"[..]).unwrap().to_id();
+        assert_eq!(id.to_hex(), hex);
+        assert_eq!(id.encode(DigestEncoding::Hex), hex);
+        assert_eq!(id.encode(DigestEncoding::Base32), id.to_string());
+        assert_eq!(hex.parse::<ObjectId>(), Ok(id));
+        assert_eq!(hex.to_uppercase().parse::<ObjectId>(), Ok(id));
+        assert_eq!(
+            serde_json::from_str::<ObjectId>(&format!("\"{}\"", hex)).unwrap(),
+            id
+        );
+
+        let signature = BlockSig([0xab; 64]);
+        assert_eq!(signature.to_hex().parse::<BlockSig>(), Ok(signature));
+        // A hex digest is not a hex signature
+        assert!(hex.parse::<BlockSig>().is_err());
+        assert!(hex[1..].parse::<ObjectId>().is_err());
+    }
+}
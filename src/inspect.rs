@@ -0,0 +1,259 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::extract::extract_archive;
+use crate::format::print_json;
+use crate::{Block, DigestEncoding, Error, Format, ObjectId, Provenance, Store};
+
+pub struct InspectArguments<'a> {
+    pub path: &'a str,
+    pub format: Format,
+    /// Encoding of digests in text output, JSON output always uses base32
+    pub digest_encoding: DigestEncoding,
+}
+
+/// The tail of a project and branch, as found in the store
+#[derive(Debug, Serialize)]
+pub struct InspectTail {
+    pub project: String,
+    pub branch: String,
+    /// The tail block, decoded without verifying its signature
+    pub block: Block,
+}
+
+/// The manifest of a store, and the digest of its contents
+#[derive(Debug, Serialize)]
+pub struct InspectManifest {
+    pub digest: ObjectId,
+    pub time: u64,
+    pub files: BTreeMap<String, ObjectId>,
+}
+
+/// The provenance of a build archive, and whether it verifies
+#[derive(Debug, Serialize)]
+pub struct InspectProvenance {
+    pub project: String,
+    pub branch: String,
+    pub public_key: String,
+    /// Why the provenance did not verify with its recorded key, if it did not
+    ///
+    /// The recorded key is not trusted, so this only shows whether the provenance is intact.
+    pub error: Option<String>,
+}
+
+/// A description of a build archive or store, as printed by [`inspect`]
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    /// The project name from `buildinfo.json`, if there is one
+    pub name: Option<String>,
+    pub manifest: Option<InspectManifest>,
+    pub tails: Vec<InspectTail>,
+    /// The provenance from `provenance.json`, if there is one
+    pub provenance: Option<InspectProvenance>,
+}
+
+#[derive(serde::Deserialize)]
+struct BuildName {
+    name: String,
+}
+
+/// Describe the contents of `store`
+///
+/// Only the provenance is checked, against the key it records and the manifest digest, which
+/// does not show who signed it.
+pub fn inspect_store(store: &Store) -> Result<Inspection, Error> {
+    let name = match fs::read(store.path().join("buildinfo.json")) {
+        Ok(data) => {
+            let build: BuildName = serde_json::from_slice(&data).map_err(io::Error::from)?;
+            Some(build.name)
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err.into()),
+    };
+
+    let manifest = match store.read_manifest()? {
+        Some(manifest) => {
+            let target = fs::read_link(store.path().join("manifest.json"))?;
+            let digest = target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse().ok())
+                .unwrap_or_default();
+            Some(InspectManifest {
+                digest,
+                time: manifest.time,
+                files: manifest.files,
+            })
+        }
+        None => None,
+    };
+
+    let mut tails = Vec::new();
+    for (project, branches) in store.tail_index()? {
+        for branch in branches {
+            if let Some(data) = store.read_tail(&project, &branch)? {
+                tails.push(InspectTail {
+                    block: Block::from_unverified(&data),
+                    project: project.clone(),
+                    branch,
+                });
+            }
+        }
+    }
+
+    let provenance = Provenance::read(store.path())?.map(|provenance| {
+        let digest = manifest
+            .as_ref()
+            .map(|manifest| manifest.digest)
+            .unwrap_or_default();
+        InspectProvenance {
+            error: provenance
+                .verify_recorded(&digest)
+                .err()
+                .map(|err| err.to_string()),
+            project: provenance.project,
+            branch: provenance.branch,
+            public_key: provenance.public_key,
+        }
+    });
+
+    Ok(Inspection {
+        name,
+        manifest,
+        tails,
+        provenance,
+    })
+}
+
+/// Print the project name, tails, provenance, and manifest of a build archive or store
+/// directory
+///
+/// Only the provenance is checked, against the key it records, use `extract` or `download`
+/// with a trusted key to check artifacts and signatures.
+pub fn inspect(args: InspectArguments) -> Result<(), Error> {
+    let path = Path::new(args.path);
+    let inspection = if path.is_file() {
+        let temp_dir = extract_archive(path)?;
+        let inspection = inspect_store(&Store::new(&temp_dir))?;
+        temp_dir.close()?;
+        inspection
+    } else {
+        inspect_store(&Store::open_read_only(path)?)?
+    };
+
+    let encoding = args.digest_encoding;
+    match args.format {
+        Format::Text => {
+            if let Some(name) = &inspection.name {
+                println!("name: {}", name);
+            }
+            for tail in inspection.tails.iter() {
+                println!(
+                    "tail: {}/{} counter {} timestamp {} digest {}",
+                    tail.project,
+                    tail.branch,
+                    tail.block.counter,
+                    tail.block.timestamp,
+                    tail.block.digest.encode(encoding)
+                );
+            }
+            if let Some(provenance) = &inspection.provenance {
+                println!(
+                    "provenance: {}/{} key {} {}",
+                    provenance.project,
+                    provenance.branch,
+                    provenance.public_key,
+                    provenance
+                        .error
+                        .as_deref()
+                        .unwrap_or("intact, key not verified")
+                );
+            }
+            if let Some(manifest) = &inspection.manifest {
+                println!(
+                    "manifest: {} time {}",
+                    manifest.digest.encode(encoding),
+                    manifest.time
+                );
+                for (file, digest) in manifest.files.iter() {
+                    println!("{} {}", digest.encode(encoding), file);
+                }
+            }
+        }
+        Format::Json => print_json(&inspection)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::inspect_store;
+    use crate::block::tests::signed_block;
+    use crate::{Manifest, Provenance, Store};
+
+    #[test]
+    fn test_inspect_store() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let inspection = inspect_store(&store).unwrap();
+        assert!(inspection.name.is_none());
+        assert!(inspection.manifest.is_none());
+        assert!(inspection.tails.is_empty());
+        assert!(inspection.provenance.is_none());
+
+        let key = store.write_object(b"artifact").unwrap();
+        let manifest = Manifest {
+            time: 42,
+            files: [("a.bin".to_string(), key)].into(),
+            ..Default::default()
+        };
+        let digest = store
+            .write_manifest(&serde_json::to_vec(&manifest).unwrap())
+            .unwrap();
+        let (_public_key, block) = signed_block(1, &[0; 64], 3, &digest);
+        store.write_tail("default", "master", &block).unwrap();
+        fs::write(
+            temp_dir.path().join("buildinfo.json"),
+            br#"{"name":"test"}"#,
+        )
+        .unwrap();
+
+        let inspection = inspect_store(&store).unwrap();
+        assert_eq!(inspection.name.as_deref(), Some("test"));
+        let read = inspection.manifest.unwrap();
+        assert_eq!(read.digest, digest);
+        assert_eq!(read.time, 42);
+        assert_eq!(read.files, manifest.files);
+        assert_eq!(inspection.tails.len(), 1);
+        assert_eq!(inspection.tails[0].project, "default");
+        assert_eq!(inspection.tails[0].block.counter, 3);
+        assert_eq!(inspection.tails[0].block.digest, digest);
+
+        Provenance::new("default", "master", &block)
+            .unwrap()
+            .write(temp_dir.path())
+            .unwrap();
+        let provenance = inspect_store(&store).unwrap().provenance.unwrap();
+        assert_eq!(provenance.error, None);
+        let (_public_key, other) = signed_block(1, &[0; 64], 3, &[0; 48]);
+        Provenance::new("default", "master", &other)
+            .unwrap()
+            .write(temp_dir.path())
+            .unwrap();
+        let provenance = inspect_store(&store).unwrap().provenance.unwrap();
+        assert!(provenance.error.is_some());
+
+        temp_dir.close().unwrap();
+    }
+}
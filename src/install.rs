@@ -0,0 +1,182 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Installation of verified artifacts, the apply phase of an update
+//!
+//! Downloading and verifying a build never changes the system. An [`Installer`] passed to
+//! [`crate::Updater::apply`], or given to `buildchain download --install`, is handed each
+//! artifact only after it is verified, for example to run `fwupdmgr install` or to write an
+//! image to a partition. With a dry run, installers describe what they would do instead.
+
+use serde::Serialize;
+use std::fmt::Debug;
+use std::path::Path;
+use std::process::Command;
+
+use crate::{Block, ObjectId};
+
+/// A verified artifact to install
+#[derive(Clone, Copy, Debug)]
+pub struct InstallArtifact<'a> {
+    /// The name of the artifact in the manifest
+    pub name: &'a str,
+    /// The path of the verified artifact
+    pub path: &'a Path,
+    /// The sha384 digest the artifact was verified against
+    pub digest: &'a ObjectId,
+    /// The block of the build the artifact is from
+    pub block: &'a Block,
+}
+
+/// Installs verified artifacts
+pub trait Installer: Debug + Send + Sync {
+    /// The name of the installer in install outcomes
+    fn name(&self) -> String;
+
+    /// Describe what installing `artifact` would do, for a dry run
+    fn describe(&self, artifact: &InstallArtifact) -> String;
+
+    /// Install `artifact`
+    ///
+    /// Returns a summary of the installation, or the reason it failed as an error.
+    fn install(&self, artifact: &InstallArtifact) -> Result<String, String>;
+}
+
+/// An installer that runs a shell command, with the artifact path as `$1` and its name as `$2`
+///
+/// The counter and signature of the block, and the digest of the artifact, are set as
+/// `BUILDCHAIN_COUNTER`, `BUILDCHAIN_BLOCK`, and `BUILDCHAIN_DIGEST`. The installation
+/// succeeds if the command exits successfully. Its output is the summary or reason.
+#[derive(Clone, Debug)]
+pub struct CommandInstaller {
+    command: String,
+}
+
+impl CommandInstaller {
+    pub fn new(command: &str) -> CommandInstaller {
+        CommandInstaller {
+            command: command.to_string(),
+        }
+    }
+}
+
+impl Installer for CommandInstaller {
+    fn name(&self) -> String {
+        self.command.clone()
+    }
+
+    fn describe(&self, artifact: &InstallArtifact) -> String {
+        format!(
+            "would run {:?} with {}",
+            self.command,
+            artifact.path.display()
+        )
+    }
+
+    fn install(&self, artifact: &InstallArtifact) -> Result<String, String> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .arg("sh")
+            .arg(artifact.path)
+            .arg(artifact.name)
+            .env("BUILDCHAIN_COUNTER", artifact.block.counter.to_string())
+            .env("BUILDCHAIN_BLOCK", artifact.block.signature.to_string())
+            .env("BUILDCHAIN_DIGEST", artifact.digest.to_string())
+            .output()
+            .map_err(|err| format!("failed to run: {}", err))?;
+
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        let text = text.trim().to_string();
+        if output.status.success() {
+            Ok(text)
+        } else if text.is_empty() {
+            Err(format!("exited with {}", output.status))
+        } else {
+            Err(text)
+        }
+    }
+}
+
+/// The outcome of installing one artifact with an [`Installer`]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct InstallOutcome {
+    pub name: String,
+    pub installer: String,
+    /// Whether the artifact was only described, not installed
+    pub dry_run: bool,
+    /// The summary of the installation, or what it would do
+    pub message: String,
+}
+
+/// Hand `artifact` to `installer`, or describe it with `dry_run`
+pub(crate) fn install_artifact(
+    installer: &dyn Installer,
+    artifact: &InstallArtifact,
+    dry_run: bool,
+) -> Result<InstallOutcome, String> {
+    let message = if dry_run {
+        installer.describe(artifact)
+    } else {
+        installer.install(artifact).map_err(|reason| {
+            format!(
+                "{} failed to install {}: {}",
+                installer.name(),
+                artifact.name,
+                reason
+            )
+        })?
+    };
+    Ok(InstallOutcome {
+        name: artifact.name.to_string(),
+        installer: installer.name(),
+        dry_run,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{install_artifact, CommandInstaller, InstallArtifact, Installer};
+    use crate::block::tests::signed_block;
+    use crate::{Block, ObjectId};
+
+    #[test]
+    fn test_command_installer() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let path = temp_dir.path().join("image.bin");
+        fs::write(&path, "image").unwrap();
+        let digest = ObjectId([1; 48]);
+        let block = Block::from_unverified(&signed_block(1, &[0; 64], 7, &digest.0).1);
+        let artifact = InstallArtifact {
+            name: "image.bin",
+            path: &path,
+            digest: &digest,
+            block: &block,
+        };
+
+        let installer = CommandInstaller::new("cp \"$1\" \"$1.$BUILDCHAIN_COUNTER\" && echo $2");
+        let outcome = install_artifact(&installer, &artifact, true).unwrap();
+        assert!(outcome.dry_run);
+        assert!(outcome.message.starts_with("would run"));
+        assert!(!temp_dir.path().join("image.bin.7").exists());
+
+        let outcome = install_artifact(&installer, &artifact, false).unwrap();
+        assert_eq!(outcome.message, "image.bin");
+        assert_eq!(
+            fs::read(temp_dir.path().join("image.bin.7")).unwrap(),
+            b"image"
+        );
+
+        assert_eq!(
+            CommandInstaller::new("exit 3").install(&artifact),
+            Err("exited with exit status: 3".to_string())
+        );
+        assert!(install_artifact(&CommandInstaller::new("false"), &artifact, false).is_err());
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::id::b32dec;
+use crate::verify::{verify_block, verify_object, BLOCK_SIZE};
+use crate::Error;
+
+/// What a key in a [`Keyring`] may sign
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Role {
+    /// Keyrings
+    Root,
+    /// Tails of builds
+    Signer,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Root => write!(f, "root"),
+            Role::Signer => write!(f, "signer"),
+        }
+    }
+}
+
+/// A public key in a [`Keyring`]
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct KeyringEntry {
+    /// The base32 public key
+    pub key: String,
+    pub role: Role,
+    /// The first block timestamp the key is valid for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<u64>,
+    /// The last block timestamp the key is valid for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<u64>,
+}
+
+impl KeyringEntry {
+    /// True if the key is valid for a block with `timestamp`
+    pub fn valid_at(&self, timestamp: u64) -> bool {
+        in_window(timestamp, self.not_before, self.not_after)
+    }
+}
+
+/// True if `timestamp` is within the optional bounds `not_before` and `not_after`
+pub(crate) fn in_window(timestamp: u64, not_before: Option<u64>, not_after: Option<u64>) -> bool {
+    !matches!(not_before, Some(time) if timestamp < time)
+        && !matches!(not_after, Some(time) if timestamp > time)
+}
+
+/// A set of public keys with roles and validity windows
+///
+/// A keyring is stored as JSON, with its signature in a block next to it named with a `.sig`
+/// extension. The block is signed by a root key of the keyring and refers to the sha384 of the
+/// JSON, so a client that pins one root key can follow rotations of signer keys.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Keyring {
+    pub keys: Vec<KeyringEntry>,
+}
+
+impl Keyring {
+    /// The path of the signature of the keyring at `path`
+    pub fn signature_path(path: &Path) -> PathBuf {
+        let mut sig_path = path.as_os_str().to_owned();
+        sig_path.push(".sig");
+        PathBuf::from(sig_path)
+    }
+
+    /// Read the keyring at `path` without verifying it, for editing
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Keyring, Error> {
+        let data = fs::read(path)?;
+        serde_json::from_slice(&data).map_err(|err| Error::Config(format!("keyring: {}", err)))
+    }
+
+    /// Write the keyring to `path`, removing its signature, which no longer applies
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::from)?;
+        fs::write(path, json)?;
+        match fs::remove_file(Keyring::signature_path(path)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the keyring at `path` and verify that it is signed by the base32 `root` key
+    pub fn open<P: AsRef<Path>>(path: P, root: &str) -> Result<Keyring, Error> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let signature = fs::read(Keyring::signature_path(path))
+            .map_err(|err| Error::NotFound(format!("failed to read keyring signature: {}", err)))?;
+        let block: &[u8; BLOCK_SIZE] = signature
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::Verify("keyring signature is not a block".to_string()))?;
+
+        let root_key: [u8; 32] = b32dec(root)
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| Error::Config("key not in base32 format".to_string()))?;
+        let verified = verify_block(block, &root_key)
+            .map_err(|err| Error::Verify(format!("keyring signature: {}", err)))?;
+        verify_object(&data, verified.digest())
+            .map_err(|err| Error::Verify(format!("keyring: {}", err)))?;
+
+        let keyring: Keyring = serde_json::from_slice(&data)
+            .map_err(|err| Error::Verify(format!("keyring: {}", err)))?;
+        let root_valid = keyring.keys.iter().any(|entry| {
+            entry.role == Role::Root && entry.key == root && entry.valid_at(verified.timestamp())
+        });
+        if !root_valid {
+            return Err(Error::Verify(format!(
+                "keyring is signed by {}, which is not a valid root key",
+                root
+            )));
+        }
+
+        Ok(keyring)
+    }
+
+    /// Add a key, replacing any entry with the same key
+    pub fn add(&mut self, entry: KeyringEntry) {
+        self.remove(&entry.key);
+        self.keys.push(entry);
+    }
+
+    /// Remove the entry with `key`, returning true if there was one
+    pub fn remove(&mut self, key: &str) -> bool {
+        let len = self.keys.len();
+        self.keys.retain(|entry| entry.key != key);
+        self.keys.len() != len
+    }
+
+    /// The entries that may sign tails
+    pub fn signers(&self) -> impl Iterator<Item = &KeyringEntry> {
+        self.keys.iter().filter(|entry| entry.role == Role::Signer)
+    }
+}
+
+/// Sign the keyring at `path` with PiHSM, writing the block to its `.sig` file
+#[cfg(feature = "sign")]
+pub fn sign_keyring<P: AsRef<Path>>(path: P) -> Result<(), Error> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+    Keyring::read(path)?;
+    let block = crate::sign_manifest(&data).map_err(Error::Sign)?;
+    fs::write(Keyring::signature_path(path), block)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{Keyring, KeyringEntry, Role};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{Error, Sha384};
+
+    #[test]
+    fn test_keyring() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let path = temp_dir.path().join("keyring.json");
+
+        let root = b32enc(&signed_block(1, &[0; 64], 0, &[0; 48]).0);
+        let signer = b32enc(&signed_block(2, &[0; 64], 0, &[0; 48]).0);
+        let mut keyring = Keyring::default();
+        keyring.add(KeyringEntry {
+            key: root.clone(),
+            role: Role::Root,
+            not_before: None,
+            not_after: None,
+        });
+        keyring.add(KeyringEntry {
+            key: signer.clone(),
+            role: Role::Signer,
+            not_before: Some(10),
+            not_after: None,
+        });
+        keyring.write(&path).unwrap();
+        assert_eq!(Keyring::read(&path).unwrap(), keyring);
+        assert_eq!(keyring.signers().count(), 1);
+        assert!(!keyring.signers().next().unwrap().valid_at(9));
+
+        // Unsigned keyrings are not trusted
+        assert!(matches!(
+            Keyring::open(&path, &root),
+            Err(Error::NotFound(_))
+        ));
+
+        let sign = |seed| {
+            let data = fs::read(&path).unwrap();
+            let digest = Sha384::new(data.as_slice()).unwrap().to_id();
+            let block = signed_block(seed, &[0; 64], 0, &digest).1;
+            fs::write(Keyring::signature_path(&path), block).unwrap();
+        };
+        sign(1);
+        assert_eq!(Keyring::open(&path, &root).unwrap(), keyring);
+
+        // Signer keys may not sign keyrings
+        sign(2);
+        assert!(matches!(
+            Keyring::open(&path, &signer),
+            Err(Error::Verify(_))
+        ));
+
+        // Editing removes the signature
+        assert!(keyring.remove(&signer));
+        keyring.write(&path).unwrap();
+        assert!(!Keyring::signature_path(&path).exists());
+
+        temp_dir.close().unwrap();
+    }
+}
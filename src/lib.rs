@@ -1,32 +1,255 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 //! Buildchain creates and manages a distributed and reproducible chain of builds
+//!
+//! The default `cli` feature enables everything used by the `buildchain` command. Clients
+//! that only need part of the crate can disable default features and enable `build`,
+//! `download`, `sign`, or `serve`. With no features, the [`verify`] module can still check
+//! blocks and objects, and the `ffi` feature exports it to C. The `testing` feature provides
+//! fixtures for end-to-end tests of clients in [`testing`].
 
 #![allow(clippy::uninlined_format_args)]
 
+#[cfg(feature = "build")]
 pub use lxd::Location;
 
+#[cfg(all(feature = "download", feature = "sign"))]
+pub use crate::annotate::{annotate, AnnotateArguments};
+pub use crate::annotation::{Annotation, Urgency};
+#[cfg(feature = "build")]
+pub use crate::apt::{apt_repo, AptArguments};
+#[cfg(feature = "sign")]
+pub use crate::attest::{attest, AttestArguments};
 pub use crate::block::Block;
-pub use crate::build::{build, BuildArguments};
-pub use crate::config::Config;
-pub use crate::download::{download, DownloadArguments, Downloader};
-pub use crate::manifest::Manifest;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+pub use crate::block_index::{BlockIndex, IndexedBlock};
+#[cfg(feature = "build")]
+pub use crate::build::{build, BuildOptions};
+#[cfg(feature = "build")]
+pub use crate::buildinfo::{BuildInfo, EnvironmentInfo};
+#[cfg(feature = "download")]
+pub use crate::bundle::{
+    bundle, verify_bundle, Bundle, BundleArguments, BundleVerification, VerifyBundleArguments,
+};
+#[cfg(feature = "download")]
+pub use crate::cache::{Cache, CacheState};
+#[cfg(feature = "download")]
+pub use crate::casync::{casync_export, CasyncArguments};
+pub use crate::channel::Channel;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+pub use crate::clock::{Clock, FixedClock, OsRng, Rng, SeededRng, SystemClock};
+pub use crate::config::{
+    Budget, Config, Environment, OutputTarget, Process, RequiredArtifact, User,
+};
+#[cfg(feature = "download")]
+pub use crate::delta::{DeltaSignature, DEFAULT_DELTA_MIN_SIZE};
+pub use crate::device::Device;
+#[cfg(feature = "download")]
+pub use crate::download::{download, BlockPin, DownloadOptions, Downloader};
+pub use crate::error::Error;
+#[cfg(feature = "download")]
+pub use crate::extract::{extract, ExtractArguments};
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+pub use crate::format::Format;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+pub use crate::fsck::{fsck, fsck_store, FsckArguments, FsckIssue, DEFAULT_STALE_TMP_AGE};
+#[cfg(feature = "build")]
+pub use crate::fwupd::{fwupd, FwupdArguments};
+#[cfg(feature = "sign")]
+pub use crate::genesis::{genesis, GenesisArguments};
+pub use crate::id::{BlockSig, DigestEncoding, ObjectId};
+#[cfg(feature = "download")]
+pub use crate::inspect::{
+    inspect, inspect_store, InspectArguments, InspectManifest, InspectProvenance, InspectTail,
+    Inspection,
+};
+#[cfg(feature = "download")]
+pub use crate::install::{CommandInstaller, InstallArtifact, InstallOutcome, Installer};
+#[cfg(feature = "sign")]
+pub use crate::keyring::sign_keyring;
+pub use crate::keyring::{Keyring, KeyringEntry, Role};
+#[cfg(feature = "build")]
+pub use crate::log::{Event, Log};
+pub use crate::manifest::{Fork, Genesis, Manifest, ManifestDiff, Rollback, SourceState};
+#[cfg(feature = "download")]
+pub use crate::monitor::{check_mirror, monitor, MirrorHealth, MonitorArguments};
+#[cfg(feature = "serve")]
+pub use crate::oci::OciPublisher;
+#[cfg(all(feature = "build", feature = "download"))]
+pub use crate::ostree::{ostree_export, OstreeArguments};
+#[cfg(feature = "build")]
+pub use crate::output_template::{OutputTemplate, OutputVars};
+#[cfg(feature = "sign")]
 pub use crate::pihsm::sign_manifest;
+#[cfg(feature = "download")]
+pub use crate::policy::{Policy, PolicyFile};
+#[cfg(all(feature = "download", feature = "sign"))]
+pub use crate::promote::{promote, PromoteArguments};
+#[cfg(any(feature = "build", feature = "download"))]
+pub use crate::provenance::Provenance;
+#[cfg(feature = "serve")]
+pub use crate::publish::{publish, PublishArguments, Publisher};
+#[cfg(feature = "download")]
+pub use crate::r#async::{
+    Auth, DownloaderBuilder, HistoryEntry, Identity, ProbeCheck, ProbeStatus,
+};
+#[cfg(any(feature = "build", feature = "download"))]
+pub use crate::record::{BuildRecord, CommandRecord, HostInfo};
+#[cfg(feature = "build")]
+pub use crate::report::{BuildReport, Stage, StageStatus};
+#[cfg(feature = "build")]
+pub use crate::reproduce::Reproduction;
+#[cfg(all(feature = "build", feature = "download"))]
+pub use crate::reproduce::{env_capture, EnvCaptureArguments};
+#[cfg(all(feature = "download", feature = "sign"))]
+pub use crate::rollback::{rollback, RollbackArguments};
+#[cfg(feature = "build")]
+pub use crate::scan::{CommandScanner, ScanVerdict, Scanner};
+#[cfg(feature = "schema")]
+pub use crate::schema::{json_schema, SchemaKind};
+#[cfg(feature = "serve")]
+pub use crate::serve::{serve, ServeArguments, Server, DEFAULT_MAX_UPLOAD_SIZE};
 pub use crate::sha384::Sha384;
+#[cfg(feature = "build")]
 pub use crate::source::Source;
-pub use crate::store::Store;
+#[cfg(feature = "download")]
+pub use crate::stats::{build_stats, stats, BuildStats, StatsArguments};
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+pub use crate::store::{ImportMode, Store, StorePermissions};
+#[cfg(feature = "download")]
+pub use crate::torrent::{Torrent, DEFAULT_TORRENT_MIN_SIZE};
+#[cfg(feature = "download")]
+pub use crate::transport::{
+    CasTransport, Fetched, FileInfo, HttpTransport, LocalTransport, MemoryTransport,
+    TorrentTransport, Transport, TransportFuture, Validators, CAS_INDEX_PATH,
+    DEFAULT_TORRENT_COMMAND, TORRENT_INDEX_PATH,
+};
+#[cfg(feature = "download")]
+pub use crate::updater::{Update, UpdatePlan, Updater};
+#[cfg(feature = "serve")]
+pub use crate::webhook::{TailEvent, Webhook};
 
+#[cfg(all(feature = "download", feature = "sign"))]
+mod annotate;
+mod annotation;
+#[cfg(feature = "build")]
+mod apt;
+#[cfg(feature = "build")]
+mod archive;
+#[cfg(feature = "download")]
+pub mod r#async;
+#[cfg(feature = "sign")]
+mod attest;
 mod block;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+mod block_index;
+#[cfg(feature = "build")]
 mod build;
+#[cfg(feature = "build")]
+mod buildinfo;
+#[cfg(feature = "download")]
+mod bundle;
+#[cfg(feature = "download")]
+mod cache;
+#[cfg(feature = "download")]
+mod casync;
+mod channel;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+mod clock;
 mod config;
+#[cfg(feature = "download")]
+mod delta;
+mod device;
+#[cfg(feature = "download")]
 mod download;
+mod error;
+#[cfg(feature = "download")]
+mod extract;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+mod format;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
+mod fsck;
+#[cfg(feature = "build")]
+mod fwupd;
+#[cfg(feature = "sign")]
+mod genesis;
+mod id;
+#[cfg(feature = "download")]
+mod inspect;
+#[cfg(feature = "download")]
+mod install;
+mod keyring;
+#[cfg(feature = "build")]
+mod log;
 mod manifest;
+#[cfg(feature = "serve")]
+mod metrics;
+#[cfg(feature = "download")]
+mod monitor;
+#[cfg(feature = "build")]
+mod normalize;
+#[cfg(feature = "serve")]
+mod oci;
+#[cfg(all(feature = "build", feature = "download"))]
+mod ostree;
+#[cfg(feature = "build")]
+mod output_template;
+#[cfg(feature = "sign")]
 mod pihsm;
+#[cfg(feature = "download")]
+mod policy;
+#[cfg(all(feature = "download", feature = "sign"))]
+mod promote;
+#[cfg(any(feature = "build", feature = "download"))]
+mod provenance;
+#[cfg(feature = "serve")]
+mod publish;
+#[cfg(any(feature = "build", feature = "download"))]
+mod record;
+#[cfg(feature = "build")]
+mod report;
+#[cfg(feature = "build")]
+mod reproduce;
+#[cfg(all(feature = "download", feature = "sign"))]
+mod rollback;
+#[cfg(feature = "build")]
+mod scan;
+#[cfg(feature = "schema")]
+mod schema;
+#[cfg(feature = "serve")]
+mod serve;
 mod sha384;
+#[cfg(feature = "build")]
 mod source;
+#[cfg(feature = "download")]
+mod stats;
+#[cfg(any(feature = "build", feature = "download", feature = "sign"))]
 mod store;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "download")]
+mod torrent;
+#[cfg(feature = "download")]
+mod transport;
+#[cfg(feature = "download")]
+mod updater;
+pub mod verify;
+#[cfg(feature = "serve")]
+mod webhook;
 
 // Helper function for errors
+#[allow(dead_code)]
 pub(crate) fn err_str<E: ::std::error::Error>(err: E) -> String {
     format!("{}: {:?}", err, err)
 }
+
+// Helper function for XML text and attributes
+#[cfg(feature = "build")]
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
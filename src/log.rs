@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::Serialize;
+
+use crate::{Format, ObjectId, Stage};
+
+/// An event emitted while building, as one line of JSON with `--log-format json`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event<'a> {
+    /// A progress message
+    Message { message: &'a str },
+    /// A command about to be run in a container
+    Command { args: &'a [&'a str] },
+    /// A finished build stage
+    Stage(&'a Stage),
+    /// An artifact moved into the store, and how many of the artifacts have been
+    Import {
+        name: &'a str,
+        index: usize,
+        total: usize,
+    },
+    /// An artifact and its digest
+    Artifact { name: &'a str, digest: &'a ObjectId },
+    /// The digest of the manifest
+    Manifest { digest: &'a ObjectId },
+    /// The error that stopped the build
+    Error { message: &'a str },
+}
+
+/// Prints build progress as text or JSON events
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Log {
+    format: Format,
+}
+
+impl Log {
+    pub fn new(format: Format) -> Log {
+        Log { format }
+    }
+
+    /// Print `event`, as JSON or in the human readable format
+    pub fn event(&self, event: &Event) {
+        match self.format {
+            Format::Json => match serde_json::to_string(event) {
+                Ok(json) => println!("{}", json),
+                Err(err) => eprintln!("buildchain: failed to serialize event: {}", err),
+            },
+            Format::Text => match event {
+                Event::Message { message } => println!("{}", message),
+                Event::Command { args } => println!("Command {:?}", args),
+                Event::Stage(stage) => match &stage.error {
+                    Some(error) => println!("Stage {} failed: {}", stage.name, error),
+                    None => println!("Stage {} passed in {:.3}s", stage.name, stage.duration),
+                },
+                Event::Import { name, index, total } => {
+                    println!("Import {}/{} {}", index, total, name)
+                }
+                Event::Artifact { name, digest } => println!("Artifact {} {}", name, digest),
+                Event::Manifest { digest } => println!("Manifest {}", digest),
+                // Errors are printed to stderr by the caller
+                Event::Error { .. } => (),
+            },
+        }
+    }
+
+    pub fn message(&self, message: &str) {
+        self.event(&Event::Message { message });
+    }
+
+    pub fn command(&self, args: &[&str]) {
+        self.event(&Event::Command { args });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+    use crate::{ObjectId, Stage, StageStatus};
+
+    #[test]
+    fn test_event_json() {
+        let json = |event: &Event| serde_json::to_string(event).unwrap();
+
+        assert_eq!(
+            json(&Event::Command {
+                args: &["make", "all"]
+            }),
+            r#"{"event":"command","args":["make","all"]}"#
+        );
+        assert_eq!(
+            json(&Event::Artifact {
+                name: "a.bin",
+                digest: &ObjectId([0; 48])
+            }),
+            format!(
+                r#"{{"event":"artifact","name":"a.bin","digest":"{}"}}"#,
+                ObjectId([0; 48])
+            )
+        );
+        assert_eq!(
+            json(&Event::Import {
+                name: "a.bin",
+                index: 1,
+                total: 2
+            }),
+            r#"{"event":"import","name":"a.bin","index":1,"total":2}"#
+        );
+        assert_eq!(
+            json(&Event::Stage(&Stage {
+                name: "build".to_string(),
+                status: StageStatus::Failed,
+                duration: 1.5,
+                error: Some("failed to run".to_string()),
+            })),
+            r#"{"event":"stage","name":"build","status":"failed","duration":1.5,"error":"failed to run"}"#
+        );
+    }
+}
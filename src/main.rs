@@ -2,152 +2,1745 @@
 
 #![allow(clippy::uninlined_format_args)]
 
-use buildchain::{build, download, BuildArguments, DownloadArguments};
-use clap::{App, Arg};
-use std::process;
-
-fn buildchain() -> Result<(), String> {
-    let matches = App::new("buildchain")
-        .version(env!("CARGO_PKG_VERSION"))
-        .subcommand(
-            App::new("build")
-                .about("Build a buildchain project")
-                .arg(
-                    Arg::new("use_pihsm")
-                        .short('p')
-                        .long("pihsm")
-                        .help("Sign manifest with PiHSM"),
-                )
-                .arg(
-                    Arg::new("config")
-                        .short('c')
-                        .long("config")
-                        .takes_value(true)
-                        .help("Configuration file"),
-                )
-                .arg(
-                    Arg::new("output")
-                        .short('o')
-                        .long("output")
-                        .takes_value(true)
-                        .help("Output directory"),
-                )
-                .arg(
-                    Arg::new("project")
-                        .long("project")
-                        .takes_value(true)
-                        .help("Tail signature project name"),
-                )
-                .arg(
-                    Arg::new("branch")
-                        .long("branch")
-                        .takes_value(true)
-                        .help("Tail signature branch name"),
-                )
-                .arg(
-                    Arg::new("remote")
-                        .short('r')
-                        .long("remote")
-                        .takes_value(true)
-                        .help("Remote LXC server"),
-                )
-                .arg(
-                    Arg::new("source_url")
-                        .takes_value(true)
-                        .help("Source URL"),
-                )
-                .arg(
-                    Arg::new("source_kind")
-                        .takes_value(true)
-                        .help("Source Kind (dir, git)"),
-                )
-                .arg(
-                    Arg::new("exclude_source")
-                        .long("exclude-source")
-                        .help("Exclude the source checkout from the archive"),
-                ),
-        )
-        .subcommand(
-            App::new("download")
-                .about("Download from a buildchain project")
-                .arg(
-                    Arg::new("project")
-                        .long("project")
-                        .takes_value(true)
-                        .help("Tail signature project name"),
-                )
-                .arg(
-                    Arg::new("branch")
-                        .long("branch")
-                        .takes_value(true)
-                        .help("Tail signature branch name"),
-                )
-                .arg(
-                    Arg::new("cert")
-                        .long("cert")
-                        .takes_value(true)
-                        .help("Remote URL certificate"),
-                )
-                .arg(
-                    Arg::new("cache")
-                        .long("cache")
-                        .takes_value(true)
-                        .help("Local cache"),
-                )
-                .arg(
-                    Arg::new("key")
-                        .takes_value(true)
-                        .required(true)
-                        .help("Remote public key"),
-                )
-                .arg(
-                    Arg::new("url")
-                        .takes_value(true)
-                        .required(true)
-                        .help("Remote URL"),
-                )
-                .arg(
-                    Arg::new("file")
-                        .takes_value(true)
-                        .help("Requested file"),
-                ),
-        )
-        .get_matches();
-
-    if let Some(matches) = matches.subcommand_matches("build") {
-        build(BuildArguments {
-            config_path: matches.value_of("config").unwrap_or("buildchain.json"),
-            output_path: matches.value_of("output").unwrap_or("buildchain.tar"),
-            project_name: matches.value_of("project").unwrap_or("default"),
-            branch_name: matches.value_of("branch").unwrap_or("master"),
-            remote_opt: matches.value_of("remote"),
-            source_url: matches.value_of("source_url").unwrap_or("."),
-            source_kind: matches.value_of("source_kind").unwrap_or("dir"),
-            use_pihsm: matches.is_present("use_pihsm"),
-            exclude_source: matches.is_present("exclude_source"),
+use buildchain::{
+    annotate, apt_repo, attest, build, bundle, casync_export, download, env_capture, extract, fsck,
+    fwupd, genesis, inspect, json_schema, monitor, ostree_export, promote, publish, rollback,
+    serve, sign_keyring, stats, verify_bundle, AnnotateArguments, AptArguments, AttestArguments,
+    Auth, BlockIndex, BlockPin, BlockSig, BuildOptions, BundleArguments, CasyncArguments, Channel,
+    Clock, CommandScanner, Device, DigestEncoding, DownloadOptions, EnvCaptureArguments, Error,
+    ExtractArguments, Format, FsckArguments, FwupdArguments, GenesisArguments, InspectArguments,
+    Keyring, KeyringEntry, MonitorArguments, OstreeArguments, PromoteArguments, PublishArguments,
+    Role, RollbackArguments, SchemaKind, ServeArguments, StatsArguments, Store, StorePermissions,
+    SystemClock, Urgency, VerifyBundleArguments, DEFAULT_DELTA_MIN_SIZE, DEFAULT_STALE_TMP_AGE,
+    DEFAULT_TORRENT_COMMAND, DEFAULT_TORRENT_MIN_SIZE,
+};
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use clap_mangen::Man;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, io, process};
+
+#[derive(Parser)]
+#[command(
+    name = "buildchain",
+    version,
+    about,
+    after_long_help = "Exit codes: 1 for other failures, 2 for invalid arguments or configuration, \
+        3 for build failures, 4 for verification failures, 5 for network failures, 6 if something \
+        was not found, and 7 if a policy refused the build"
+)]
+struct Cli {
+    /// Output format
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    format: Format,
+
+    /// Progress format, json prints one event per line
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    log_format: Format,
+
+    #[command(flatten)]
+    store: StoreArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The store of commands that operate on an existing store
+#[derive(Args)]
+struct StoreArgs {
+    /// Store directory, for commands that operate on an existing store
+    #[arg(long, global = true, env = "BUILDCHAIN_STORE", default_value = ".")]
+    store: String,
+
+    /// Permissions of the files written to the store, group to share it with a group
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        env = "BUILDCHAIN_STORE_PERMISSIONS",
+        default_value = "owner"
+    )]
+    store_permissions: StorePermissions,
+
+    /// Use this namespace of the store, which has its own objects, blocks, and tails
+    #[arg(long, global = true, env = "BUILDCHAIN_NAMESPACE")]
+    namespace: Option<String>,
+}
+
+impl StoreArgs {
+    /// Open the store, which must already exist
+    fn open(&self) -> Result<Store, Failure> {
+        let store = Store::open(&self.store)
+            .map(|store| store.with_permissions(self.store_permissions))
+            .map_err(failure("failed to open store"))?;
+        self.select_namespace(store)
+    }
+
+    /// Open the store for commands that only read it
+    fn open_read_only(&self) -> Result<Store, Failure> {
+        let store = Store::open_read_only(&self.store).map_err(failure("failed to open store"))?;
+        self.select_namespace(store)
+    }
+
+    fn select_namespace(&self, store: Store) -> Result<Store, Failure> {
+        match &self.namespace {
+            Some(name) => store
+                .namespace(name)
+                .map_err(failure("failed to open namespace")),
+            None => Ok(store),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    Build(Build),
+    Download(Box<Download>),
+    Serve(Serve),
+    ExportMirror(ExportMirror),
+    CasIndex(CasIndex),
+    Torrent(Torrent),
+    DeltaSignatures(DeltaSignatures),
+    Fsck(Fsck),
+    Namespaces(Namespaces),
+    BlockIndex(BlockIndexCommand),
+    Genesis(Genesis),
+    Attest(Attest),
+    Annotate(Annotate),
+    Extract(Extract),
+    Inspect(Inspect),
+    EnvCapture(EnvCapture),
+    Monitor(Monitor),
+    Stats(Stats),
+    Bundle(Bundle),
+    VerifyBundle(VerifyBundle),
+    #[command(subcommand)]
+    Key(Key),
+    Promote(Promote),
+    Rollback(Rollback),
+    Publish(Publish),
+    AptRepo(AptRepo),
+    Fwupd(Fwupd),
+    OstreeExport(OstreeExport),
+    CasyncExport(CasyncExport),
+    Schema(Schema),
+    Completions(Completions),
+    /// Print the manual page
+    Man,
+}
+
+/// Build a buildchain project
+#[derive(Args)]
+struct Build {
+    /// Sign manifest with PiHSM
+    #[arg(short = 'p', long = "pihsm")]
+    use_pihsm: bool,
+
+    /// Configuration file
+    #[arg(short, long, default_value = "buildchain.json")]
+    config: String,
+
+    /// Output archive, as a template such as '{name}-{branch}-{counter}.tar', instead of the
+    /// outputs of the configuration, or buildchain.tar if it has none
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Remote LXC server
+    #[arg(short, long)]
+    remote: Option<String>,
+
+    /// Source URL
+    #[arg(default_value = ".")]
+    source_url: String,
+
+    /// Source Kind (dir, git)
+    #[arg(default_value = "dir", value_parser = ["dir", "git"])]
+    source_kind: String,
+
+    /// Exclude the source checkout from the archive
+    #[arg(long)]
+    exclude_source: bool,
+
+    /// Write a JUnit XML report, or JSON if the path ends with .json
+    #[arg(long)]
+    report: Option<String>,
+
+    /// Store a record of the stages, commands, and host in the chain, which makes the manifest
+    /// differ between rebuilds
+    #[arg(long)]
+    store_report: bool,
+
+    /// Create the temporary build directory in this directory
+    #[arg(long)]
+    tmpdir: Option<String>,
+
+    /// Free space required for the temporary build directory, in MiB
+    #[arg(long, default_value_t = 1024)]
+    min_free_space: u64,
+
+    /// Fail if the configuration has unknown keys, instead of warning about them
+    #[arg(long)]
+    strict: bool,
+
+    /// Store directory of the previous build, to compare sizes with if a budget is exceeded,
+    /// and the source with for --skip-unchanged
+    #[arg(long)]
+    previous: Option<String>,
+
+    /// Succeed without building if the source and configuration are the same as those of the
+    /// tail of the branch in the --previous store
+    #[arg(long, requires = "previous")]
+    skip_unchanged: bool,
+
+    /// Shell command that scans the artifact directory, given as $1, before signing, and
+    /// vetoes the build if it fails
+    #[arg(long)]
+    scan_command: Vec<String>,
+}
+
+impl Build {
+    fn run(self, log_format: Format) -> Result<(), Failure> {
+        let mut options = BuildOptions::new(&self.config)
+            .project(&self.project)
+            .branch(&self.branch)
+            .source(&self.source_url, &self.source_kind)
+            .pihsm(self.use_pihsm)
+            .exclude_source(self.exclude_source)
+            .store_report(self.store_report)
+            .min_free_space(self.min_free_space * 1024 * 1024)
+            .strict(self.strict)
+            .skip_unchanged(self.skip_unchanged)
+            .log_format(log_format);
+        if let Some(remote) = &self.remote {
+            options = options.remote(remote);
+        }
+        if let Some(report) = &self.report {
+            options = options.report(report);
+        }
+        if let Some(output) = &self.output {
+            options = options.output(output);
+        }
+        if let Some(tmpdir) = &self.tmpdir {
+            options = options.tmpdir(tmpdir);
+        }
+        if let Some(previous) = &self.previous {
+            options = options.previous(previous);
+        }
+        for command in self.scan_command.iter() {
+            options = options.scanner(Arc::new(CommandScanner::new(command)));
+        }
+
+        build(&options).map_err(failure("failed to build"))
+    }
+}
+
+/// Parse basic authentication, as user[:password]
+fn parse_basic(basic: &str) -> Result<Auth, String> {
+    Ok(match basic.split_once(':') {
+        Some((username, password)) => Auth::Basic {
+            username: username.to_string(),
+            password: Some(password.to_string()),
+        },
+        None => Auth::Basic {
+            username: basic.to_string(),
+            password: None,
+        },
+    })
+}
+
+/// Download from a buildchain project
+#[derive(Args)]
+struct Download {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Remote URL certificate
+    #[arg(long)]
+    cert: Option<String>,
+
+    /// Proxy URL, overriding https_proxy
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Do not ask the remote to compress responses, for artifacts that are already compressed
+    #[arg(long)]
+    no_compression: bool,
+
+    /// Bearer token for the remote URL
+    #[arg(long, conflicts_with = "auth_basic")]
+    auth_token: Option<String>,
+
+    /// Basic authentication for the remote URL, as user[:password]
+    #[arg(long, value_parser = parse_basic)]
+    auth_basic: Option<Auth>,
+
+    /// Client certificate, as PEM or PKCS#12 (.p12, .pfx)
+    #[arg(long)]
+    identity: Option<String>,
+
+    /// Client PKCS#8 PEM key, if not in the client certificate file
+    #[arg(long, requires = "identity")]
+    identity_key: Option<String>,
+
+    /// Client PKCS#12 password
+    #[arg(long, requires = "identity")]
+    identity_password: Option<String>,
+
+    /// Cache directory for tails and objects, such as ~/.cache/buildchain, with a directory
+    /// for each mirror
+    #[arg(long)]
+    cache: Option<String>,
+
+    /// IPFS gateway to fetch objects from when the mirror publishes them there, such as
+    /// https://ipfs.io/
+    #[arg(long)]
+    gateway: Option<String>,
+
+    /// Download large objects from peers when the mirror publishes torrents, using aria2c
+    #[arg(long)]
+    p2p: bool,
+
+    /// BitTorrent client command for --p2p, with {dir} and {torrent} replaced by the download
+    /// directory and the torrent file
+    #[arg(long, requires = "p2p")]
+    p2p_command: Option<String>,
+
+    /// Update the local cache, downloading only changed files
+    #[arg(long, requires = "cache", conflicts_with_all = ["file", "list"])]
+    update: bool,
+
+    /// Download the build with this block counter
+    #[arg(long, conflicts_with = "block")]
+    counter: Option<u64>,
+
+    /// Download the build with this block signature
+    #[arg(long)]
+    block: Option<BlockSig>,
+
+    /// Download the newest build rolled out to the device with this seed
+    #[arg(long, conflicts_with_all = ["counter", "block"])]
+    device_seed: Option<String>,
+
+    /// Cohort of the device, for builds rolled out to cohorts
+    #[arg(long, requires = "device_seed")]
+    cohort: Option<String>,
+
+    /// Only list and download files for this device, as PRODUCT[:REVISION], or `this` to read
+    /// it from DMI
+    #[arg(long, conflicts_with_all = ["list", "update", "history", "sync"])]
+    device: Option<String>,
+
+    /// List the projects and branches on the remote
+    #[arg(long, conflicts_with_all = ["file", "counter", "block"])]
+    list: bool,
+
+    /// Check that the remote serves the branch correctly, with HEAD and range requests,
+    /// instead of downloading
+    #[arg(long, conflicts_with_all = [
+        "file", "counter", "block", "device_seed", "list", "update", "history", "sync", "wait",
+    ])]
+    verify_server: bool,
+
+    /// Require the downloaded block to be anchored on a genesis block that allows the keys of
+    /// every block back to it
+    #[arg(long, conflicts_with = "list")]
+    require_genesis: bool,
+
+    /// Print this many blocks of the branch history, following the branches it was cut from
+    #[arg(long, conflicts_with_all = ["file", "counter", "block", "list", "update"])]
+    history: Option<usize>,
+
+    /// Copy the verified chain and its builds into this store, to serve as a mirror
+    #[arg(long, conflicts_with_all = [
+        "file", "counter", "block", "device_seed", "list", "update", "history",
+    ])]
+    sync: Option<String>,
+
+    /// Public key of a rebuilder that must attest the build, may be repeated
+    #[arg(long, conflicts_with_all = ["history", "sync"])]
+    attestor: Vec<String>,
+
+    /// Number of attestors that must attest the build, all of them if not set
+    #[arg(long, requires = "attestor")]
+    quorum: Option<usize>,
+
+    /// Print the release notes, urgency, and supported hardware attached to the build, instead
+    /// of downloading
+    #[arg(long, conflicts_with_all = ["file", "list", "update", "history", "sync"])]
+    annotation: bool,
+
+    /// Wait up to this many seconds for the tail to be updated, then download it
+    #[arg(long, conflicts_with_all = [
+        "counter", "block", "device_seed", "list", "history", "sync",
+    ])]
+    wait: Option<u64>,
+
+    /// Wait for a tail with a counter greater than this, instead of the current tail
+    #[arg(long, requires = "wait")]
+    after: Option<u64>,
+
+    /// Additional public key that may sign the tail, may be repeated
+    #[arg(long = "key")]
+    extra_key: Vec<String>,
+
+    /// Keyring signed by the remote public key, whose signer keys may sign the tail
+    #[arg(long)]
+    keyring: Option<String>,
+
+    /// Remote public key
+    key: String,
+
+    /// Remote URL
+    url: String,
+
+    /// Write the requested file here instead of stdout, restoring its permissions
+    #[arg(short, long, requires = "file")]
+    output: Option<String>,
+
+    /// Replace the output file if it already exists
+    #[arg(long, requires = "output")]
+    force: bool,
+
+    /// Install the verified file with this shell command, with its path as $1 and its name as
+    /// $2, such as 'fwupdmgr install "$1"'
+    #[arg(long, requires = "file")]
+    install: Option<String>,
+
+    /// Print how the file would be installed, without running the install command
+    #[arg(long, requires = "install")]
+    dry_run: bool,
+
+    /// Requested file
+    file: Option<String>,
+}
+
+impl Download {
+    fn run(self, format: Format) -> Result<(), Failure> {
+        let pin_opt = match (self.counter, self.block) {
+            (Some(counter), _) => Some(BlockPin::Counter(counter)),
+            (None, Some(signature)) => Some(BlockPin::Signature(signature)),
+            (None, None) => None,
+        };
+        let auth_opt = self.auth_token.map(Auth::Bearer).or(self.auth_basic);
+
+        let mut options = DownloadOptions::new(&self.key, &self.url)
+            .project(&self.project)
+            .branch(&self.branch)
+            .list(self.list)
+            .verify_server(self.verify_server)
+            .compression(!self.no_compression)
+            .update(self.update)
+            .force(self.force)
+            .dry_run(self.dry_run)
+            .require_genesis(self.require_genesis)
+            .annotation(self.annotation)
+            .format(format);
+        for key in self.extra_key.iter() {
+            options = options.key(key);
+        }
+        for attestor in self.attestor.iter() {
+            options = options.attestor(attestor);
+        }
+        if let Some(quorum) = self.quorum {
+            options = options.quorum(quorum);
+        }
+        if let Some(wait) = self.wait {
+            options = options.wait(Duration::from_secs(wait));
+        }
+        if let Some(after) = self.after {
+            options = options.after(after);
+        }
+        if let Some(keyring) = &self.keyring {
+            options = options.keyring(keyring);
+        }
+        if let Some(cert) = &self.cert {
+            options = options.cert(cert);
+        }
+        if let Some(cache) = &self.cache {
+            options = options.cache(cache);
+        }
+        if let Some(gateway) = &self.gateway {
+            options = options.gateway(gateway);
+        }
+        if self.p2p {
+            let command = match &self.p2p_command {
+                Some(command) => command.split_whitespace().map(str::to_string).collect(),
+                None => DEFAULT_TORRENT_COMMAND
+                    .iter()
+                    .map(|arg| arg.to_string())
+                    .collect(),
+            };
+            options = options.torrent(command);
+        }
+        if let Some(file) = &self.file {
+            options = options.file(file);
+        }
+        if let Some(output) = &self.output {
+            options = options.output(output);
+        }
+        if let Some(install) = &self.install {
+            options = options.install(install);
+        }
+        if let Some(history) = self.history {
+            options = options.history(history);
+        }
+        if let Some(sync) = &self.sync {
+            options = options.sync(sync);
+        }
+        if let Some(device_seed) = &self.device_seed {
+            options = options.device_seed(device_seed);
+        }
+        if let Some(cohort) = &self.cohort {
+            options = options.cohort(cohort);
+        }
+        if let Some(device) = &self.device {
+            let device = if device == "this" {
+                Device::current()
+                    .map_err(Error::from)
+                    .map_err(failure("failed to identify this device"))?
+            } else {
+                device
+                    .parse()
+                    .map_err(Error::Config)
+                    .map_err(failure("invalid device"))?
+            };
+            options = options.device(device);
+        }
+        if let Some(proxy) = &self.proxy {
+            options = options.proxy(proxy);
+        }
+        if let Some(auth) = auth_opt {
+            options = options.auth(auth);
+        }
+        if let Some(identity) = &self.identity {
+            options = options.identity(identity);
+        }
+        if let Some(identity_key) = &self.identity_key {
+            options = options.identity_key(identity_key);
+        }
+        if let Some(identity_password) = &self.identity_password {
+            options = options.identity_password(identity_password);
+        }
+        if let Some(pin) = pin_opt {
+            options = options.pin(pin);
+        }
+
+        download(&options).map_err(failure("failed to download"))
+    }
+}
+
+/// Serve a buildchain store over HTTP
+#[derive(Args)]
+struct Serve {
+    /// Listen address
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    address: String,
+
+    /// Accept uploads signed by this public key
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Upload access token
+    #[arg(long, env = "BUILDCHAIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Largest object to accept, in bytes
+    #[arg(long)]
+    max_upload_size: Option<u64>,
+
+    /// URL to notify of new tails, may be repeated
+    #[arg(long)]
+    webhook: Vec<String>,
+}
+
+impl Serve {
+    fn run(self, store: &Store, permissions: StorePermissions) -> Result<(), Failure> {
+        Ok(serve(ServeArguments {
+            store_path: store_path(store)?,
+            permissions,
+            address: &self.address,
+            key_opt: self.key.as_deref(),
+            token_opt: self.token.as_deref(),
+            max_upload_size_opt: self.max_upload_size,
+            webhooks: self
+                .webhook
+                .iter()
+                .map(|webhook| webhook.as_str())
+                .collect(),
+        })?)
+    }
+}
+
+/// Export a buildchain store as a static mirror
+#[derive(Args)]
+struct ExportMirror {
+    /// Mirror directory
+    dest: String,
+}
+
+impl ExportMirror {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        store
+            .export_mirror(&self.dest)
+            .map_err(failure("failed to export mirror"))?;
+        println!(
+            "buildchain: exported {} to {}",
+            store.path().display(),
+            self.dest
+        );
+        Ok(())
+    }
+}
+
+/// Record objects published to IPFS, so clients can fetch them from a gateway
+///
+/// Reads the output of `ipfs add -r object/`, run in the store directory, and adds it to
+/// `cas/index.json`, which is exported and published with the store.
+#[derive(Args)]
+struct CasIndex {
+    /// Output of `ipfs add`, read from stdin if not provided
+    listing: Option<String>,
+}
+
+impl CasIndex {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        let listing = match &self.listing {
+            Some(path) => fs::read_to_string(path),
+            None => io::read_to_string(io::stdin()),
+        }
+        .map_err(Error::from)
+        .map_err(failure("failed to read listing"))?;
+        let added = store
+            .add_cas_index(&listing)
+            .map_err(failure("failed to update CAS index"))?;
+        println!("buildchain: added {} objects to the CAS index", added);
+        Ok(())
+    }
+}
+
+/// Write torrents for large objects, so clients can download them from peers
+///
+/// Torrents are written to `torrent/` in the store, which is exported and published with it.
+#[derive(Args)]
+struct Torrent {
+    /// Minimum size in MiB of objects to write torrents for
+    #[arg(long, default_value_t = DEFAULT_TORRENT_MIN_SIZE / 1024 / 1024)]
+    min_size: u64,
+
+    /// Mirror URL to add as a web seed, may be repeated
+    #[arg(long)]
+    webseed: Vec<String>,
+}
+
+impl Torrent {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        let written = store
+            .write_torrents(self.min_size * 1024 * 1024, &self.webseed)
+            .map_err(failure("failed to write torrents"))?;
+        println!("buildchain: wrote {} torrents", written);
+        Ok(())
+    }
+}
+
+/// Check a store for stale temporary files, missing tails, and broken artifact links
+///
+/// Exits with code 4 if any problem is left unrepaired.
+#[derive(Args)]
+struct Fsck {
+    /// Remove stale temporary files, and rebuild tails and artifact links from blocks and objects
+    #[arg(long)]
+    repair: bool,
+
+    /// Age in seconds after which temporary files are stale
+    #[arg(long, default_value_t = DEFAULT_STALE_TMP_AGE.as_secs())]
+    tmp_age: u64,
+}
+
+impl Fsck {
+    fn run(self, store: &Store, format: Format) -> Result<(), Failure> {
+        fsck(FsckArguments {
+            store,
+            repair: self.repair,
+            tmp_age: Duration::from_secs(self.tmp_age),
+            format,
+        })
+        .map_err(failure("failed to check store"))
+    }
+}
+
+/// List the namespaces of a store, which each have their own objects, blocks, and tails
+///
+/// Other commands operate on a namespace with `--namespace`.
+#[derive(Args)]
+struct Namespaces {}
+
+impl Namespaces {
+    fn run(self, store: &Store, format: Format) -> Result<(), Failure> {
+        let namespaces = store
+            .namespaces()
+            .map_err(failure("failed to list namespaces"))?;
+        match format {
+            Format::Text => {
+                for namespace in namespaces.iter() {
+                    println!("{}", namespace);
+                }
+            }
+            Format::Json => {
+                let json = serde_json::to_string_pretty(&namespaces)
+                    .map_err(|err| format!("failed to serialize namespaces: {}", err))?;
+                println!("{}", json);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print the blocks of a branch from the block index of a store, oldest first
+///
+/// The index is updated when tails are written, and can be rebuilt from the tails and blocks
+/// with `--rebuild`.
+#[derive(Args)]
+struct BlockIndexCommand {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Rebuild the index from the tails and blocks of the store before printing it
+    #[arg(long)]
+    rebuild: bool,
+}
+
+impl BlockIndexCommand {
+    fn run(self, store: &Store, format: Format) -> Result<(), Failure> {
+        let index = if self.rebuild {
+            BlockIndex::rebuild(store).map_err(failure("failed to rebuild block index"))?
+        } else {
+            BlockIndex::read(store).map_err(failure("failed to read block index"))?
+        };
+        let blocks = index.blocks(&self.project, &self.branch);
+        match format {
+            Format::Text => {
+                for block in blocks.iter() {
+                    println!("{} {}", block.counter, block.signature);
+                }
+            }
+            Format::Json => {
+                let json = serde_json::to_string_pretty(blocks)
+                    .map_err(|err| format!("failed to serialize block index: {}", err))?;
+                println!("{}", json);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write delta signatures for large objects, so clients can download only the changed parts
+///
+/// Signatures are written to `zsync/` in the store, which is exported and published with it.
+#[derive(Args)]
+struct DeltaSignatures {
+    /// Minimum size in MiB of objects to write signatures for
+    #[arg(long, default_value_t = DEFAULT_DELTA_MIN_SIZE / 1024 / 1024)]
+    min_size: u64,
+}
+
+impl DeltaSignatures {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        let written = store
+            .write_signatures(self.min_size * 1024 * 1024)
+            .map_err(failure("failed to write delta signatures"))?;
+        println!("buildchain: wrote {} delta signatures", written);
+        Ok(())
+    }
+}
+
+/// Verify a build archive and extract its artifacts
+#[derive(Args)]
+struct Extract {
+    /// Public key used to verify the tail, required if the archive is signed
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Without --key, accept the key recorded in a signed archive, extracting it unverified
+    #[arg(long, conflicts_with = "key")]
+    trust_embedded_key: bool,
+
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Build archive
+    archive: String,
+
+    /// Artifact directory
+    dest: String,
+}
+
+impl Extract {
+    fn run(self) -> Result<(), Failure> {
+        extract(ExtractArguments {
+            archive: &self.archive,
+            dest: &self.dest,
+            key_opt: self.key.as_deref(),
+            trust_embedded_key: self.trust_embedded_key,
+            project: &self.project,
+            branch: &self.branch,
+        })
+        .map_err(failure("failed to extract"))
+    }
+}
+
+/// Print the project, tails, and artifacts of a build archive or store
+#[derive(Args)]
+struct Inspect {
+    /// Build archive or store directory
+    #[arg(default_value = ".")]
+    path: String,
+
+    /// Encoding of digests in text output, hex matches sha384sum
+    #[arg(long, value_enum, default_value = "base32")]
+    digest_encoding: DigestEncoding,
+}
+
+impl Inspect {
+    fn run(self, format: Format) -> Result<(), Failure> {
+        inspect(InspectArguments {
+            path: &self.path,
+            format,
+            digest_encoding: self.digest_encoding,
+        })
+        .map_err(failure("failed to inspect"))
+    }
+}
+
+/// Write the environment a build ran in as a standalone reproduce.sh and reproduce.json
+#[derive(Args)]
+struct EnvCapture {
+    /// Build archive or store directory
+    #[arg(default_value = ".")]
+    path: String,
+
+    /// Directory to write the script and captured environment to
+    #[arg(short, long, default_value = "reproduce")]
+    output: String,
+}
+
+impl EnvCapture {
+    fn run(self) -> Result<(), Failure> {
+        env_capture(EnvCaptureArguments {
+            path: &self.path,
+            dest: &self.output,
         })
-        .map_err(|err| format!("failed to build: {}", err))
-    } else if let Some(matches) = matches.subcommand_matches("download") {
-        download(DownloadArguments {
-            project: matches.value_of("project").unwrap_or("default"),
-            branch: matches.value_of("branch").unwrap_or("master"),
-            cert_opt: matches.value_of("cert"),
-            cache_opt: matches.value_of("cache"),
-            key: matches.value_of("key").unwrap(),
-            url: matches.value_of("url").unwrap(),
-            file_opt: matches.value_of("file"),
+        .map_err(failure("failed to capture environment"))
+    }
+}
+
+/// Check that a mirror serves a fresh, continuous, and intact chain
+#[derive(Args)]
+struct Monitor {
+    /// Remote URL or local directory of the mirror
+    #[arg(long)]
+    url: String,
+
+    /// Public key used to verify the tail
+    #[arg(long)]
+    key: String,
+
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Number of blocks to verify back from the tail
+    #[arg(long, default_value = "16")]
+    depth: usize,
+
+    /// Number of files of the tail build to download and verify
+    #[arg(long, default_value = "4")]
+    samples: usize,
+
+    /// Report the mirror as stale if the tail is older than this many seconds
+    #[arg(long)]
+    max_age: Option<u64>,
+
+    /// Check again after this many seconds, until interrupted
+    #[arg(long)]
+    interval: Option<u64>,
+
+    /// Write Prometheus metrics to this file, for the node exporter textfile collector
+    #[arg(long)]
+    textfile: Option<String>,
+}
+
+impl Monitor {
+    fn run(self, format: Format) -> Result<(), Failure> {
+        monitor(MonitorArguments {
+            key: &self.key,
+            url: &self.url,
+            project: &self.project,
+            branch: &self.branch,
+            depth: self.depth,
+            samples: self.samples,
+            max_age_opt: self.max_age,
+            interval_opt: self.interval.map(Duration::from_secs),
+            textfile_opt: self.textfile.as_deref(),
+            format,
+        })
+        .map_err(failure("failed to monitor mirror"))
+    }
+}
+
+/// Print build duration, artifact size, and file churn across the history of a branch
+#[derive(Args)]
+struct Stats {
+    /// Remote URL or local directory of the mirror
+    #[arg(long)]
+    url: String,
+
+    /// Public key used to verify the tail
+    #[arg(long)]
+    key: String,
+
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Number of builds to analyze back from the tail
+    #[arg(long, default_value = "20")]
+    limit: usize,
+}
+
+impl Stats {
+    fn run(self, format: Format) -> Result<(), Failure> {
+        stats(StatsArguments {
+            key: &self.key,
+            url: &self.url,
+            project: &self.project,
+            branch: &self.branch,
+            limit: self.limit,
+            format,
+        })
+        .map_err(failure("failed to collect stats"))
+    }
+}
+
+/// Package a verified block and its manifest for offline verification
+#[derive(Args)]
+struct Bundle {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Bundle the build with this block counter instead of the tail
+    #[arg(long, conflicts_with = "block")]
+    counter: Option<u64>,
+
+    /// Bundle the build with this block signature instead of the tail
+    #[arg(long)]
+    block: Option<BlockSig>,
+
+    /// Bundle file
+    #[arg(short, long, default_value = "buildchain-bundle.json")]
+    output: String,
+
+    /// Public key used to verify the block
+    key: String,
+}
+
+impl Bundle {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        let pin_opt = match (self.counter, self.block) {
+            (Some(counter), _) => Some(BlockPin::Counter(counter)),
+            (None, Some(signature)) => Some(BlockPin::Signature(signature)),
+            (None, None) => None,
+        };
+        bundle(BundleArguments {
+            store_path: store_path(store)?,
+            key: &self.key,
+            project: &self.project,
+            branch: &self.branch,
+            pin_opt,
+            output: &self.output,
+        })
+        .map_err(failure("failed to bundle"))
+    }
+}
+
+/// Verify a bundle offline, and optionally the artifacts it describes
+#[derive(Args)]
+struct VerifyBundle {
+    /// Public key the bundle must be signed by, obtained from the publisher
+    #[arg(long)]
+    key: Option<String>,
+
+    /// Directory with the artifacts of the build to check against the manifest
+    #[arg(long)]
+    artifacts: Option<String>,
+
+    /// Bundle file
+    path: String,
+}
+
+impl VerifyBundle {
+    fn run(self, format: Format) -> Result<(), Failure> {
+        verify_bundle(VerifyBundleArguments {
+            path: &self.path,
+            key_opt: self.key.as_deref(),
+            artifacts_opt: self.artifacts.as_deref(),
+            format,
+        })
+        .map_err(failure("failed to verify bundle"))
+    }
+}
+
+/// Upload a build archive or store to a buildchain server
+#[derive(Args)]
+struct Publish {
+    /// Upload access token
+    #[arg(long, env = "BUILDCHAIN_TOKEN", hide_env_values = true)]
+    token: String,
+
+    /// Remote URL certificate
+    #[arg(long)]
+    cert: Option<String>,
+
+    /// Tail signature project name, for OCI registries
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name, for OCI registries
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Write torrents for large objects before uploading, with the remote as a web seed
+    #[arg(long)]
+    torrents: bool,
+
+    /// Minimum size in MiB of objects to write torrents for
+    #[arg(long, default_value_t = DEFAULT_TORRENT_MIN_SIZE / 1024 / 1024, requires = "torrents")]
+    torrent_min_size: u64,
+
+    /// Write delta signatures for large objects before uploading, so clients can download
+    /// only the changed parts
+    #[arg(long)]
+    delta_signatures: bool,
+
+    /// Minimum size in MiB of objects to write delta signatures for
+    #[arg(long, default_value_t = DEFAULT_DELTA_MIN_SIZE / 1024 / 1024, requires = "delta_signatures")]
+    delta_min_size: u64,
+
+    /// Build archive or store directory
+    source: String,
+
+    /// Remote URL, or oci://registry/repository:tag
+    url: String,
+}
+
+impl Publish {
+    fn run(self) -> Result<(), Failure> {
+        Ok(publish(PublishArguments {
+            source: &self.source,
+            url: &self.url,
+            token: &self.token,
+            cert_opt: self.cert.as_deref(),
+            project: &self.project,
+            branch: &self.branch,
+            torrent_min_size_opt: self.torrents.then_some(self.torrent_min_size * 1024 * 1024),
+            delta_min_size_opt: self
+                .delta_signatures
+                .then_some(self.delta_min_size * 1024 * 1024),
+        })?)
+    }
+}
+
+/// Start a branch with a genesis block signed with PiHSM
+#[derive(Args)]
+struct Genesis {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Public key allowed to sign the branch, may be repeated
+    #[arg(long, required = true)]
+    key: Vec<String>,
+}
+
+impl Genesis {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        genesis(GenesisArguments {
+            store_path: store_path(store)?,
+            project: &self.project,
+            branch: &self.branch,
+            keys: &self.key,
+            time: SystemClock.now(),
+        })
+        .map_err(failure("failed to create genesis"))
+    }
+}
+
+/// Attest that a rebuild reproduced a build, signing its manifest with PiHSM
+#[derive(Args)]
+struct Attest {
+    /// Public key of the rebuilder, which must sign the attestation
+    key: String,
+}
+
+impl Attest {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        attest(AttestArguments {
+            store_path: store_path(store)?,
+            key: &self.key,
+        })
+        .map_err(failure("failed to attest"))
+    }
+}
+
+/// Attach release notes, urgency, and supported hardware to a published build, signed with
+/// PiHSM
+///
+/// The annotation is written to `annotation/` in the store, which is exported with it, and
+/// can be replaced without publishing a new build.
+#[derive(Args)]
+struct Annotate {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Annotate the build with this block counter instead of the tail
+    #[arg(long, conflicts_with = "block")]
+    counter: Option<u64>,
+
+    /// Annotate the build with this block signature instead of the tail
+    #[arg(long)]
+    block: Option<BlockSig>,
+
+    /// File with the release notes of the build
+    #[arg(long)]
+    changelog: Option<String>,
+
+    /// How urgently devices should install the build
+    #[arg(long, value_enum)]
+    urgency: Option<Urgency>,
+
+    /// Hardware identifier of a supported device, may be repeated, all devices if not set
+    #[arg(long)]
+    hardware: Vec<String>,
+
+    /// Public key used to verify the build, which must also sign the annotation
+    key: String,
+}
+
+impl Annotate {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        let pin_opt = match (self.counter, self.block) {
+            (Some(counter), _) => Some(BlockPin::Counter(counter)),
+            (None, Some(signature)) => Some(BlockPin::Signature(signature)),
+            (None, None) => None,
+        };
+        let changelog_opt = match &self.changelog {
+            Some(path) => Some(
+                fs::read_to_string(path)
+                    .map_err(Error::from)
+                    .map_err(failure("failed to read changelog"))?,
+            ),
+            None => None,
+        };
+        annotate(AnnotateArguments {
+            store_path: store_path(store)?,
+            key: &self.key,
+            project: &self.project,
+            branch: &self.branch,
+            pin_opt,
+            changelog_opt,
+            urgency_opt: self.urgency,
+            hardware: self.hardware,
         })
-    } else {
-        Err("no subcommand provided".to_string())
+        .map_err(failure("failed to annotate"))
+    }
+}
+
+/// Manage a keyring of public keys
+#[derive(Subcommand)]
+enum Key {
+    /// Add a key to the keyring, replacing any entry for the same key
+    Add {
+        /// Keyring file
+        #[arg(long, default_value = "keyring.json")]
+        keyring: String,
+
+        /// What the key may sign
+        #[arg(long, value_enum, default_value = "signer")]
+        role: Role,
+
+        /// First block timestamp the key is valid for
+        #[arg(long)]
+        not_before: Option<u64>,
+
+        /// Last block timestamp the key is valid for
+        #[arg(long)]
+        not_after: Option<u64>,
+
+        /// Public key
+        key: String,
+    },
+    /// Remove a key from the keyring
+    Remove {
+        /// Keyring file
+        #[arg(long, default_value = "keyring.json")]
+        keyring: String,
+
+        /// Public key
+        key: String,
+    },
+    /// List the keys of the keyring
+    List {
+        /// Keyring file
+        #[arg(long, default_value = "keyring.json")]
+        keyring: String,
+    },
+    /// Sign the keyring with PiHSM
+    Sign {
+        /// Keyring file
+        #[arg(long, default_value = "keyring.json")]
+        keyring: String,
+    },
+}
+
+impl Key {
+    fn run(self, format: Format) -> Result<(), Failure> {
+        self.manage(format)
+            .map_err(failure("failed to manage keyring"))
+    }
+
+    fn manage(self, format: Format) -> Result<(), Error> {
+        match self {
+            Key::Add {
+                keyring: path,
+                role,
+                not_before,
+                not_after,
+                key,
+            } => {
+                let mut keyring = if Path::new(&path).exists() {
+                    Keyring::read(&path)?
+                } else {
+                    Keyring::default()
+                };
+                keyring.add(KeyringEntry {
+                    key,
+                    role,
+                    not_before,
+                    not_after,
+                });
+                keyring.write(&path)
+            }
+            Key::Remove { keyring: path, key } => {
+                let mut keyring = Keyring::read(&path)?;
+                if !keyring.remove(&key) {
+                    return Err(Error::NotFound(format!("{} is not in {}", key, path)));
+                }
+                keyring.write(&path)
+            }
+            Key::List { keyring: path } => {
+                let keyring = Keyring::read(&path)?;
+                match format {
+                    Format::Text => {
+                        let time = |time_opt: Option<u64>| {
+                            time_opt.map_or("-".to_string(), |time| time.to_string())
+                        };
+                        for entry in keyring.keys.iter() {
+                            println!(
+                                "{} {} {} {}",
+                                entry.key,
+                                entry.role,
+                                time(entry.not_before),
+                                time(entry.not_after)
+                            );
+                        }
+                    }
+                    Format::Json => {
+                        let json =
+                            serde_json::to_string_pretty(&keyring).map_err(io::Error::from)?;
+                        println!("{}", json);
+                    }
+                }
+                Ok(())
+            }
+            Key::Sign { keyring: path } => sign_keyring(&path),
+        }
+    }
+}
+
+/// Sign the build of one branch for another branch with PiHSM, without rebuilding it
+#[derive(Args)]
+struct Promote {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Roll the build out to this percentage of devices, signing a new manifest
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    rollout: Option<u8>,
+
+    /// Roll the build out to devices in this cohort, signing a new manifest
+    #[arg(long)]
+    cohort: Vec<String>,
+
+    /// Record the tail of the source branch as the point the target branch was cut from
+    #[arg(long)]
+    fork: bool,
+
+    /// Public key used to verify both tails
+    key: String,
+
+    /// Branch with the verified build, such as staging
+    from: String,
+
+    /// Branch to publish the build on, such as stable
+    to: String,
+}
+
+impl Promote {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        let channel_opt = if self.rollout.is_some() || !self.cohort.is_empty() {
+            Some(Channel {
+                rollout: self.rollout.unwrap_or(0),
+                cohorts: self.cohort,
+            })
+        } else {
+            None
+        };
+        promote(PromoteArguments {
+            store_path: store_path(store)?,
+            key: &self.key,
+            project: &self.project,
+            from: &self.from,
+            to: &self.to,
+            channel_opt,
+            fork: self.fork,
+        })
+        .map_err(failure("failed to promote"))
+    }
+}
+
+/// Pull the tail of a branch by republishing an earlier build of it, signed with PiHSM
+///
+/// The new block restores the files of the earlier build and records the rollback in its
+/// manifest, so the counter of the branch keeps increasing and the rollback can be audited.
+#[derive(Args)]
+struct Rollback {
+    /// Tail signature project name
+    #[arg(long, default_value = "default")]
+    project: String,
+
+    /// Tail signature branch name
+    #[arg(long, default_value = "master")]
+    branch: String,
+
+    /// Counter of the earlier block to restore
+    #[arg(long)]
+    to_counter: u64,
+
+    /// Public key used to verify the tail and the earlier block
+    key: String,
+}
+
+impl Rollback {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        rollback(RollbackArguments {
+            store_path: store_path(store)?,
+            key: &self.key,
+            project: &self.project,
+            branch: &self.branch,
+            to_counter: self.to_counter,
+        })
+        .map_err(failure("failed to roll back"))
+    }
+}
+
+/// Generate a Debian repository from the .deb artifacts of a build
+#[derive(Args)]
+struct AptRepo {
+    /// Suite name
+    #[arg(long, default_value = "stable")]
+    suite: String,
+
+    /// Component name
+    #[arg(long, default_value = "main")]
+    component: String,
+
+    /// Origin and Label of the Release file
+    #[arg(long)]
+    origin: Option<String>,
+
+    /// GPG key used to sign InRelease and Release.gpg
+    #[arg(long)]
+    gpg_key: Option<String>,
+
+    /// Repository directory
+    dest: String,
+}
+
+impl AptRepo {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        Ok(apt_repo(AptArguments {
+            store_path: store_path(store)?,
+            dest: &self.dest,
+            suite: &self.suite,
+            component: &self.component,
+            origin_opt: self.origin.as_deref(),
+            gpg_key_opt: self.gpg_key.as_deref(),
+        })?)
+    }
+}
+
+/// Package a firmware artifact of a build as a cab for LVFS and fwupd
+#[derive(Args)]
+struct Fwupd {
+    /// Component ID, such as com.example.device.firmware
+    #[arg(long)]
+    id: String,
+
+    /// Component name
+    #[arg(long)]
+    name: String,
+
+    /// Component summary
+    #[arg(long)]
+    summary: String,
+
+    /// Release version
+    #[arg(long)]
+    version: String,
+
+    /// Device GUID the firmware is flashed to, may be repeated
+    #[arg(long, required = true)]
+    guid: Vec<String>,
+
+    /// Developer name
+    #[arg(long)]
+    vendor: Option<String>,
+
+    /// Release description
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Firmware artifact name
+    file: String,
+
+    /// Cabinet archive output path
+    output: String,
+}
+
+impl Fwupd {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        Ok(fwupd(FwupdArguments {
+            store_path: store_path(store)?,
+            file: &self.file,
+            output: &self.output,
+            id: &self.id,
+            name: &self.name,
+            summary: &self.summary,
+            version: &self.version,
+            guids: self.guid.iter().map(|guid| guid.as_str()).collect(),
+            vendor_opt: self.vendor.as_deref(),
+            description_opt: self.description.as_deref(),
+        })?)
+    }
+}
+
+/// Commit the artifacts of each branch in a store to an OSTree repository
+#[derive(Args)]
+struct OstreeExport {
+    /// OSTree ref prefix
+    #[arg(long, default_value = "buildchain")]
+    prefix: String,
+
+    /// Public key used to verify tails
+    #[arg(long)]
+    key: String,
+
+    /// OSTree repository
+    repo: String,
+}
+
+impl OstreeExport {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        Ok(ostree_export(OstreeArguments {
+            store_path: store_path(store)?,
+            repo: &self.repo,
+            key: &self.key,
+            prefix: &self.prefix,
+        })?)
+    }
+}
+
+/// Export the artifacts of each tail as a casync chunk store and blob indexes
+#[derive(Args)]
+struct CasyncExport {
+    /// Public key used to verify tails
+    #[arg(long)]
+    key: String,
+
+    /// Destination directory
+    dest: String,
+}
+
+impl CasyncExport {
+    fn run(self, store: &Store) -> Result<(), Failure> {
+        Ok(casync_export(CasyncArguments {
+            store_path: store_path(store)?,
+            dest: &self.dest,
+            key: &self.key,
+        })?)
+    }
+}
+
+/// Print the JSON Schema of a configuration, manifest, or block
+#[derive(Args)]
+struct Schema {
+    /// Document to print the schema of
+    #[arg(value_enum)]
+    kind: SchemaKind,
+}
+
+impl Schema {
+    fn run(self) -> Result<(), Failure> {
+        println!("{}", json_schema(self.kind));
+        Ok(())
+    }
+}
+
+/// Print a shell completion script
+#[derive(Args)]
+struct Completions {
+    /// Shell to generate completions for
+    shell: Shell,
+}
+
+impl Completions {
+    fn run(self) -> Result<(), Failure> {
+        clap_complete::generate(
+            self.shell,
+            &mut Cli::command(),
+            "buildchain",
+            &mut io::stdout(),
+        );
+        Ok(())
+    }
+}
+
+fn man() -> Result<(), Failure> {
+    Man::new(Cli::command())
+        .render(&mut io::stdout())
+        .map_err(|err| format!("failed to write manual page: {}", err).into())
+}
+
+fn store_path(store: &Store) -> Result<&str, String> {
+    store
+        .path()
+        .to_str()
+        .ok_or_else(|| format!("store {} is not UTF-8", store.path().display()))
+}
+
+fn buildchain() -> Result<(), Failure> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Build(command) => command.run(cli.log_format),
+        Command::Download(command) => command.run(cli.format),
+        Command::Serve(command) => {
+            command.run(&cli.store.open_read_only()?, cli.store.store_permissions)
+        }
+        Command::ExportMirror(command) => command.run(&cli.store.open_read_only()?),
+        Command::CasIndex(command) => command.run(&cli.store.open()?),
+        Command::Torrent(command) => command.run(&cli.store.open()?),
+        Command::DeltaSignatures(command) => command.run(&cli.store.open()?),
+        Command::Fsck(command) => {
+            let store = if command.repair {
+                cli.store.open()?
+            } else {
+                cli.store.open_read_only()?
+            };
+            command.run(&store, cli.format)
+        }
+        Command::Namespaces(command) => command.run(&cli.store.open_read_only()?, cli.format),
+        Command::BlockIndex(command) => {
+            let store = if command.rebuild {
+                cli.store.open()?
+            } else {
+                cli.store.open_read_only()?
+            };
+            command.run(&store, cli.format)
+        }
+        Command::Genesis(command) => command.run(&cli.store.open()?),
+        Command::Attest(command) => command.run(&cli.store.open()?),
+        Command::Annotate(command) => command.run(&cli.store.open()?),
+        Command::Extract(command) => command.run(),
+        Command::Inspect(command) => command.run(cli.format),
+        Command::EnvCapture(command) => command.run(),
+        Command::Monitor(command) => command.run(cli.format),
+        Command::Stats(command) => command.run(cli.format),
+        Command::Bundle(command) => command.run(&cli.store.open()?),
+        Command::VerifyBundle(command) => command.run(cli.format),
+        Command::Key(command) => command.run(cli.format),
+        Command::Promote(command) => command.run(&cli.store.open()?),
+        Command::Rollback(command) => command.run(&cli.store.open()?),
+        Command::Publish(command) => command.run(),
+        Command::AptRepo(command) => command.run(&cli.store.open()?),
+        Command::Fwupd(command) => command.run(&cli.store.open()?),
+        Command::OstreeExport(command) => command.run(&cli.store.open()?),
+        Command::CasyncExport(command) => command.run(&cli.store.open()?),
+        Command::Schema(command) => command.run(),
+        Command::Completions(command) => command.run(),
+        Command::Man => man(),
+    }
+}
+
+/// A failed command, and the exit code for its kind of failure
+///
+/// | Code | Failure                                               |
+/// |------|-------------------------------------------------------|
+/// | 1    | Other failures                                        |
+/// | 2    | Invalid arguments or configuration                    |
+/// | 3    | A build, source download, or signing command          |
+/// | 4    | Signature or digest verification                      |
+/// | 5    | Network or mirror errors                              |
+/// | 6    | A file, block, project, or store is not found         |
+/// | 7    | Refused by a download policy, scanner, or size budget |
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure { code: 1, message }
+    }
+}
+
+/// Convert an [`Error`] into a [`Failure`], prefixing its message with `context`
+fn failure(context: &'static str) -> impl Fn(Error) -> Failure {
+    move |err| {
+        let code = match &err {
+            Error::Config(_) => 2,
+            Error::Source(_) | Error::Exec(_) | Error::Sign(_) => 3,
+            Error::Verify(_) => 4,
+            Error::Http(_) => 5,
+            Error::NotFound(_) => 6,
+            Error::Store(err) if err.kind() == io::ErrorKind::NotFound => 6,
+            Error::Store(_) => 1,
+            Error::Denied(_) => 7,
+        };
+        Failure {
+            code,
+            message: format!("{}: {}", context, err),
+        }
     }
 }
 
 fn main() {
     match buildchain() {
         Ok(()) => (),
-        Err(err) => {
-            eprintln!("buildchain: {}", err);
-            process::exit(1);
+        Err(failure) => {
+            eprintln!("buildchain: {}", failure.message);
+            process::exit(failure.code);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use super::{failure, Cli};
+    use buildchain::Error;
+    use std::io;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+
+        let parse = |args: &[&str]| Cli::try_parse_from(args).is_ok();
+        assert!(parse(&["buildchain", "--log-format", "json", "build"]));
+        assert!(!parse(&["buildchain", "build", ".", "svn"]));
+        assert!(!parse(&[
+            "buildchain",
+            "download",
+            "--counter",
+            "x",
+            "K",
+            "U"
+        ]));
+        assert!(!parse(&[
+            "buildchain",
+            "download",
+            "--list",
+            "--counter",
+            "1",
+            "K",
+            "U"
+        ]));
+        assert!(!parse(&["buildchain", "download", "--update", "K", "U"]));
+    }
+
+    #[test]
+    fn test_failure() {
+        let code = |err| failure("failed")(err).code;
+        assert_eq!(code(Error::Config("bad".to_string())), 2);
+        assert_eq!(code(Error::Exec(io::Error::from(io::ErrorKind::Other))), 3);
+        assert_eq!(code(Error::Verify("bad".to_string())), 4);
+        assert_eq!(code(Error::Http("bad".to_string())), 5);
+        assert_eq!(code(Error::NotFound("bad".to_string())), 6);
+        assert_eq!(code(Error::Denied("bad".to_string())), 7);
+        assert_eq!(
+            code(Error::Store(io::Error::from(io::ErrorKind::NotFound))),
+            6
+        );
+
+        let failure = failure("failed to download")(Error::Verify("sha384 mismatch".to_string()));
+        assert_eq!(failure.message, "failed to download: sha384 mismatch");
+    }
+}
@@ -2,24 +2,231 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::fs::{read_dir, File};
-use std::io::{Error, ErrorKind, Result};
+#[cfg(feature = "build")]
+use std::ffi::OsString;
+#[cfg(feature = "build")]
+use std::fs::{metadata, read_dir, Metadata};
+use std::io::{Error, ErrorKind, Read, Result};
+#[cfg(feature = "build")]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(feature = "build")]
 use std::path::Path;
 
-use crate::Sha384;
+#[cfg(feature = "build")]
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+#[cfg(feature = "build")]
+use unicode_normalization::UnicodeNormalization;
+
+use crate::id::b32enc;
+use crate::sha384::BUFFER_SIZE;
+use crate::{Block, BlockSig, Channel, Device, ObjectId, Sha384};
 
 /// A manifest of build artifacts
-#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Manifest {
     /// The timestamp of the source control revision
     pub time: u64,
     /// A dictionary of filenames and their hashes
-    pub files: BTreeMap<String, String>,
+    ///
+    /// Names are UTF-8 in Unicode normalization form C, without control characters.
+    pub files: BTreeMap<String, ObjectId>,
+    /// The permission bits of executable files, omitted if there are none so that manifests
+    /// without executables are unchanged
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub modes: BTreeMap<String, u32>,
+    /// The staged rollout of this build, if it is not available to every device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+    /// The block this branch was cut from, if this build starts a branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fork: Option<Fork>,
+    /// The policy of the branch, if this build is its genesis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genesis: Option<Genesis>,
+    /// Digests of each file with other algorithms, by algorithm name, such as `sha256`
+    ///
+    /// Clients check every digest they find, so algorithms can be changed without breaking
+    /// clients that only check `files`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub digests: BTreeMap<String, BTreeMap<String, String>>,
+    /// The digest of the record of how this build ran, if one was stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<ObjectId>,
+    /// The pulled build and the earlier build it was rolled back to, if this block restores an
+    /// earlier build of the branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollback: Option<Rollback>,
+    /// The devices that each file applies to, by file name
+    ///
+    /// Files that are not listed apply to every device, so one branch can carry the builds of
+    /// several products while each device only fetches its own.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub devices: BTreeMap<String, Vec<Device>>,
+    /// The source and configuration the build was made from, if they were recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceState>,
+}
+
+/// The policy of a branch, recorded in the manifest of its first block
+///
+/// History is not followed past a genesis block, so verification of a branch is anchored on it.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Genesis {
+    /// The project of the branch
+    pub project: String,
+    /// The name of the branch
+    pub branch: String,
+    /// The hash algorithm of objects, only `sha384` is supported
+    pub hash: String,
+    /// The base32 public keys allowed to sign the branch
+    pub keys: Vec<String>,
+}
+
+/// The block of another branch that a branch was cut from, linking their histories
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Fork {
+    /// The branch that was cut from
+    pub branch: String,
+    /// The signature of the block the branch was cut from
+    pub signature: BlockSig,
+}
+
+/// A rollback of a branch to an earlier build, recorded in the manifest of the block that
+/// restores it
+///
+/// The manifest has the same files as the earlier build, and is signed as a new block, so the
+/// counter of the branch keeps increasing and the rollback stays in its history.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Rollback {
+    /// The signature of the tail that was pulled
+    pub from: BlockSig,
+    /// The signature of the earlier block whose build is restored
+    pub to: BlockSig,
+    /// The counter of the earlier block
+    pub counter: u64,
+}
+
+/// The source tree and configuration of a build, recorded so that a build of the same source
+/// can be skipped
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct SourceState {
+    /// The source control revision, if the source is a git repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// The base32 sha384 of the names, contents, and executable bits of the source files,
+    /// without version control data
+    pub tree: String,
+    /// The base32 sha384 of the build configuration file
+    pub config: String,
+}
+
+impl SourceState {
+    /// True if a build of `other` would repeat this build
+    ///
+    /// The revision is not compared, so a new commit that does not change the tree, such as a
+    /// merge of an identical branch, is not built again.
+    pub fn is_unchanged(&self, other: &SourceState) -> bool {
+        self.tree == other.tree && self.config == other.config
+    }
+}
+
+/// How far the source time of a manifest may be ahead of the timestamp of its block, in seconds
+const MAX_CLOCK_SKEW: u64 = 24 * 60 * 60;
+
+/// The base32 digest of `input` with `algorithm`, or `None` if the algorithm is not supported
+pub(crate) fn digest<R: Read>(algorithm: &str, mut input: R) -> Result<Option<String>> {
+    fn hash<D: Digest, R: Read>(mut input: R) -> Result<String> {
+        let mut hasher = D::new();
+        let mut data = vec![0; BUFFER_SIZE];
+        loop {
+            let count = input.read(&mut data)?;
+            if count == 0 {
+                break;
+            }
+            hasher.update(&data[..count]);
+        }
+        Ok(b32enc(&hasher.finalize()))
+    }
+
+    match algorithm {
+        "sha256" => hash::<Sha256, _>(&mut input).map(Some),
+        "sha384" => hash::<sha2::Sha384, _>(&mut input).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// The base32 digest of `input` with `algorithm`, failing if the algorithm is not supported
+pub(crate) fn file_digest<R: Read>(algorithm: &str, input: R) -> Result<String> {
+    digest(algorithm, input)?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported digest algorithm {}", algorithm),
+        )
+    })
+}
+
+/// The name to record in a manifest for the artifact with `file_name`
+///
+/// Names must be UTF-8 without control characters, and are normalized to NFC, so that a name
+/// is recorded the same way whatever encoding the filesystem of the build host uses.
+#[cfg(feature = "build")]
+pub(crate) fn artifact_name(file_name: OsString) -> Result<String> {
+    let name = file_name.into_string().map_err(|file_name| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("artifact name {:?} is not UTF-8", file_name),
+        )
+    })?;
+    if name.chars().any(char::is_control) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("artifact name {:?} contains a control character", name),
+        ));
+    }
+    Ok(name.nfc().collect())
+}
+
+/// Check that no two of the artifact `names`, in name order, were normalized to the same name
+#[cfg(feature = "build")]
+pub(crate) fn check_unique<'a, I: Iterator<Item = &'a str>>(names: I) -> Result<()> {
+    let mut previous_opt: Option<&str> = None;
+    for name in names {
+        if previous_opt == Some(name) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "more than one artifact is named {:?} after normalization",
+                    name
+                ),
+            ));
+        }
+        previous_opt = Some(name);
+    }
+    Ok(())
+}
+
+/// The permission bits to record in a manifest for a file, if it is executable
+#[cfg(feature = "build")]
+pub(crate) fn executable_mode(metadata: &Metadata) -> Option<u32> {
+    let mode = metadata.permissions().mode() & 0o777;
+    if mode & 0o111 != 0 {
+        Some(mode)
+    } else {
+        None
+    }
 }
 
 impl Manifest {
     /// Create a new Manifest by reading the provided build directory
     ///
+    /// File names are normalized as described in [`Manifest::files`].
+    ///
     /// # Arguments
     ///
     /// * `time` - the timestamp of the source control revision that was built
@@ -32,23 +239,401 @@ impl Manifest {
     /// # Errors
     ///
     /// Errors that are encountered while reading will be returned
+    #[cfg(feature = "build")]
     pub fn new<P: AsRef<Path>>(time: u64, path: P) -> Result<Manifest> {
-        let mut files = BTreeMap::new();
-
+        let mut entries = Vec::new();
         for entry_res in read_dir(path.as_ref())? {
             let entry = entry_res?;
 
-            let name = entry
-                .file_name()
-                .into_string()
-                .map_err(|_| Error::new(ErrorKind::InvalidData, "Filename is not UTF-8"))?;
+            let name = artifact_name(entry.file_name())?;
+            entries.push((name, entry.path()));
+        }
+        // Names are ordered by bytes, which does not depend on the locale
+        entries.sort();
+        check_unique(entries.iter().map(|(name, _)| name.as_str()))?;
+
+        // Files are hashed concurrently, as large artifacts are otherwise hashed one at a time
+        let hashed = entries
+            .into_par_iter()
+            .map(|(name, path)| {
+                let mode_opt = executable_mode(&metadata(&path)?);
+                let sha = Sha384::from_path(&path)?;
+                Ok((name, sha.to_id(), mode_opt))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut files = BTreeMap::new();
+        let mut modes = BTreeMap::new();
+        for (name, sha, mode_opt) in hashed {
+            if let Some(mode) = mode_opt {
+                modes.insert(name.clone(), mode);
+            }
+            files.insert(name, sha);
+        }
+
+        Ok(Manifest {
+            time,
+            files,
+            modes,
+            channel: None,
+            fork: None,
+            genesis: None,
+            digests: BTreeMap::new(),
+            report: None,
+            rollback: None,
+            devices: BTreeMap::new(),
+            source: None,
+        })
+    }
+
+    /// Record the `algorithm` digest of every file, reading each file with `open`
+    ///
+    /// # Errors
+    ///
+    /// Unsupported algorithms, and errors that are encountered while reading, will be returned
+    pub fn add_digests<R, F>(&mut self, algorithm: &str, mut open: F) -> Result<()>
+    where
+        R: Read,
+        F: FnMut(&str, &ObjectId) -> Result<R>,
+    {
+        let mut digests = BTreeMap::new();
+        for (name, sha384) in self.files.iter() {
+            digests.insert(name.clone(), file_digest(algorithm, open(name, sha384)?)?);
+        }
+        self.digests.insert(algorithm.to_string(), digests);
+        Ok(())
+    }
+
+    /// Parse the manifest `data` that `block` refers to, checking that it is bound to the block
+    ///
+    /// The data must match the digest of the block, which binds the signed bytes themselves, so
+    /// manifests with fields this version does not know are still accepted. The source time may
+    /// not be after the block was signed, allowing for some clock skew.
+    ///
+    /// # Errors
+    ///
+    /// A message describing the first check that failed
+    pub fn from_signed(data: &[u8], block: &Block) -> std::result::Result<Manifest, String> {
+        let sha384 = |data: &[u8]| Sha384::new(data).map(|sha| sha.to_id());
+        if sha384(data).map_err(|err| err.to_string())? != block.digest {
+            return Err("manifest does not match the digest of the block".to_string());
+        }
+
+        let manifest: Manifest =
+            serde_json::from_slice(data).map_err(|err| format!("invalid manifest: {}", err))?;
+
+        if manifest.time > block.timestamp.saturating_add(MAX_CLOCK_SKEW) {
+            return Err(format!(
+                "manifest time {} is after block timestamp {}",
+                manifest.time, block.timestamp
+            ));
+        }
+
+        Ok(manifest)
+    }
 
-            let file = File::open(entry.path())?;
-            let sha = Sha384::new(file)?;
+    /// True if the file `name` applies to `device`, as listed in [`Manifest::devices`]
+    pub fn applies_to(&self, name: &str, device: &Device) -> bool {
+        match self.devices.get(name) {
+            Some(targets) => targets.iter().any(|target| device.matches(target)),
+            None => true,
+        }
+    }
 
-            files.insert(name, sha.to_base32());
+    /// The files that apply to `device`, and their hashes
+    pub fn files_for_device(&self, device: &Device) -> BTreeMap<String, ObjectId> {
+        self.files
+            .iter()
+            .filter(|(name, _)| self.applies_to(name, device))
+            .map(|(name, digest)| (name.clone(), *digest))
+            .collect()
+    }
+
+    /// Check `data` against every digest of the file `name`
+    ///
+    /// # Errors
+    ///
+    /// A message describing the first digest that is missing or does not match
+    pub fn verify_file(&self, name: &str, data: &[u8]) -> std::result::Result<(), String> {
+        let sha384 = self
+            .files
+            .get(name)
+            .ok_or_else(|| format!("{} is not in the manifest", name))?;
+        let mut expected = Vec::new();
+        for (algorithm, digests) in self.digests.iter() {
+            let digest = digests
+                .get(name)
+                .ok_or_else(|| format!("{} has no {} digest", name, algorithm))?;
+            expected.push((algorithm, digest));
         }
 
-        Ok(Manifest { time, files })
+        if Sha384::new(data).map(|sha| sha.to_id()).ok().as_ref() != Some(sha384) {
+            return Err(format!("{} sha384 mismatch", name));
+        }
+        for (algorithm, digest) in expected {
+            match self::digest(algorithm, data) {
+                Ok(Some(actual)) if &actual == digest => (),
+                Ok(Some(_)) => return Err(format!("{} {} mismatch", name, algorithm)),
+                _ => return Err(format!("unsupported digest algorithm {}", algorithm)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare this Manifest to an older one
+    ///
+    /// # Arguments
+    ///
+    /// * `old` - the Manifest of a previous build
+    ///
+    /// # Return
+    ///
+    /// The files that were added, changed, or removed since `old`
+    pub fn diff(&self, old: &Manifest) -> ManifestDiff {
+        let mut diff = ManifestDiff::default();
+
+        for (name, digest) in self.files.iter() {
+            match old.files.get(name) {
+                Some(old_digest) if old_digest == digest => (),
+                Some(_) => {
+                    diff.changed.insert(name.clone(), *digest);
+                }
+                None => {
+                    diff.added.insert(name.clone(), *digest);
+                }
+            }
+        }
+
+        for name in old.files.keys() {
+            if !self.files.contains_key(name) {
+                diff.removed.push(name.clone());
+            }
+        }
+
+        diff
+    }
+}
+
+/// The differences between two manifests
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ManifestDiff {
+    /// Files only in the new manifest, and their hashes
+    pub added: BTreeMap<String, ObjectId>,
+    /// Files with a different hash in the new manifest, and their new hashes
+    pub changed: BTreeMap<String, ObjectId>,
+    /// Files only in the old manifest
+    pub removed: Vec<String>,
+}
+
+impl ManifestDiff {
+    /// True if the manifests have the same files
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::{artifact_name, Manifest};
+    use crate::{Block, BlockSig, Device, ObjectId, Sha384, Store};
+
+    fn manifest(files: &[(&str, ObjectId)]) -> Manifest {
+        Manifest {
+            time: 0,
+            files: files
+                .iter()
+                .map(|(name, digest)| (name.to_string(), *digest))
+                .collect(),
+            modes: BTreeMap::new(),
+            channel: None,
+            fork: None,
+            genesis: None,
+            digests: BTreeMap::new(),
+            report: None,
+            rollback: None,
+            devices: BTreeMap::new(),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let [a, b, c, d, e] = [1, 2, 3, 4, 5].map(|fill| ObjectId([fill; 48]));
+        let old = manifest(&[("same", a), ("changed", b), ("removed", c)]);
+        let new = manifest(&[("same", a), ("changed", d), ("added", e)]);
+
+        let diff = new.diff(&old);
+        assert_eq!(diff.added, BTreeMap::from([("added".to_string(), e)]));
+        assert_eq!(diff.changed, BTreeMap::from([("changed".to_string(), d)]));
+        assert_eq!(diff.removed, vec!["removed".to_string()]);
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn test_artifact_name() {
+        assert_eq!(artifact_name("plain.iso".into()).unwrap(), "plain.iso");
+        // A decomposed accent, as written by some filesystems, is composed
+        assert_eq!(
+            artifact_name("cafe\u{301}.txt".into()).unwrap(),
+            "caf\u{e9}.txt"
+        );
+        assert!(artifact_name("bad\nname".into())
+            .unwrap_err()
+            .to_string()
+            .contains("control character"));
+        assert!(artifact_name(OsString::from_vec(vec![b'a', 0xff]))
+            .unwrap_err()
+            .to_string()
+            .contains("is not UTF-8"));
+
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        fs::write(temp_dir.path().join("cafe\u{301}.txt"), b"data").unwrap();
+        let built = Manifest::new(0, temp_dir.path()).unwrap();
+        assert!(built.files.contains_key("caf\u{e9}.txt"));
+
+        // Both forms of a name in one build cannot be told apart once normalized
+        fs::write(temp_dir.path().join("caf\u{e9}.txt"), b"other").unwrap();
+        assert!(Manifest::new(0, temp_dir.path()).is_err());
+
+        let store_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let artifacts = store_dir.path().join("artifacts");
+        fs::create_dir(&artifacts).unwrap();
+        fs::write(artifacts.join("cafe\u{301}.txt"), b"data").unwrap();
+        let imported = Store::new(store_dir.path()).import_artifacts(0).unwrap();
+        assert_eq!(imported.files, built.files);
+        assert!(artifacts.join("caf\u{e9}.txt").exists());
+        assert!(!artifacts.join("cafe\u{301}.txt").exists());
+    }
+
+    #[test]
+    fn test_modes() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        fs::write(temp_dir.path().join("data.bin"), b"data").unwrap();
+        fs::write(temp_dir.path().join("tool"), b"#!/bin/sh").unwrap();
+        fs::set_permissions(
+            temp_dir.path().join("tool"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        let built = Manifest::new(0, temp_dir.path()).unwrap();
+        assert_eq!(built.modes, BTreeMap::from([("tool".to_string(), 0o755)]));
+
+        // Manifests without executables serialize as they did before modes were recorded
+        let digest = ObjectId([0; 48]);
+        let json = serde_json::to_string(&manifest(&[("a", digest)])).unwrap();
+        assert_eq!(
+            json,
+            format!(r#"{{"time":0,"files":{{"a":"{}"}}}}"#, digest)
+        );
+        let read: Manifest = serde_json::from_str(&json).unwrap();
+        assert!(read.modes.is_empty());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_digests() {
+        let data = b"data";
+        let sha384 = Sha384::new(&data[..]).unwrap().to_id();
+        let mut manifest = manifest(&[("a", sha384)]);
+        assert_eq!(manifest.verify_file("a", data), Ok(()));
+
+        manifest
+            .add_digests("sha256", |_name, _sha384| Ok(&data[..]))
+            .unwrap();
+        assert_eq!(manifest.verify_file("a", data), Ok(()));
+        assert!(manifest.verify_file("a", b"other").is_err());
+        assert!(manifest.verify_file("b", data).is_err());
+
+        manifest.digests.get_mut("sha256").unwrap().insert(
+            "a".to_string(),
+            manifest.files["a"].to_string()[..52].to_string(),
+        );
+        assert_eq!(
+            manifest.verify_file("a", data),
+            Err("a sha256 mismatch".to_string())
+        );
+
+        assert!(manifest
+            .add_digests("md5", |_name, _sha384| Ok(&data[..]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_diff_empty() {
+        let old = manifest(&[("same", ObjectId([1; 48]))]);
+        assert!(old.diff(&old.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_files_for_device() {
+        let mut manifest = manifest(&[
+            ("common.txt", ObjectId([1; 48])),
+            ("galp5.rom", ObjectId([2; 48])),
+            ("oryp6.rom", ObjectId([3; 48])),
+        ]);
+        manifest.devices = BTreeMap::from([
+            ("galp5.rom".to_string(), vec!["galp5:A1".parse().unwrap()]),
+            ("oryp6.rom".to_string(), vec!["oryp6".parse().unwrap()]),
+        ]);
+
+        let names = |device: &str| -> Vec<String> {
+            let device: Device = device.parse().unwrap();
+            manifest.files_for_device(&device).into_keys().collect()
+        };
+        assert_eq!(names("galp5:A1"), ["common.txt", "galp5.rom"]);
+        assert_eq!(names("galp5:A2"), ["common.txt"]);
+        assert_eq!(names("oryp6:B3"), ["common.txt", "oryp6.rom"]);
+
+        // Manifests without devices are unchanged
+        let json = serde_json::to_string(&Manifest::default()).unwrap();
+        assert!(!json.contains("devices"));
+    }
+
+    #[test]
+    fn test_from_signed() {
+        let mut built = manifest(&[("file", ObjectId([1; 48]))]);
+        built.time = 1_500_000_000;
+        let block = |data: &[u8]| Block {
+            signature: BlockSig::default(),
+            public_key: String::new(),
+            previous_signature: BlockSig::default(),
+            counter: 0,
+            timestamp: 1_500_000_000,
+            digest: Sha384::new(data).unwrap().to_id(),
+        };
+
+        let pretty = serde_json::to_vec_pretty(&built).unwrap();
+        assert_eq!(
+            Manifest::from_signed(&pretty, &block(&pretty)),
+            Ok(built.clone())
+        );
+        assert!(Manifest::from_signed(&pretty, &block(b"other")).is_err());
+
+        // Manifests written by other versions, with fields this one does not know, are read
+        let mut value = serde_json::to_value(&built).unwrap();
+        value["future"] = serde_json::json!({ "field": 1 });
+        let newer = serde_json::to_vec(&value).unwrap();
+        assert_eq!(
+            Manifest::from_signed(&newer, &block(&newer)),
+            Ok(built.clone())
+        );
+
+        // The source cannot be from after the block was signed
+        built.time += 2 * 24 * 60 * 60;
+        let future = serde_json::to_vec_pretty(&built).unwrap();
+        assert!(Manifest::from_signed(&future, &block(&future))
+            .unwrap_err()
+            .starts_with("manifest time"));
     }
 }
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::verify::{u64_le, COUNTER, TIMESTAMP};
+use crate::Store;
+
+/// Counters kept by a [`crate::Server`], rendered in the Prometheus text format
+#[derive(Default)]
+pub(crate) struct Metrics {
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    bytes_served: AtomicU64,
+    upload_rejections: Mutex<BTreeMap<u16, u64>>,
+}
+
+/// Escape a Prometheus label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Count a response with `status` to a request with `method`, of `bytes` length
+    pub(crate) fn request(&self, method: &str, status: u16, bytes: u64) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), status))
+            .or_default() += 1;
+        self.bytes_served.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count an upload refused with `status`
+    pub(crate) fn upload_rejection(&self, status: u16) {
+        *self
+            .upload_rejections
+            .lock()
+            .unwrap()
+            .entry(status)
+            .or_default() += 1;
+    }
+
+    /// Render the metrics, including the counter and timestamp of each tail in `store`
+    pub(crate) fn render(&self, store: &Store) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP buildchain_requests_total HTTP requests handled\n");
+        out.push_str("# TYPE buildchain_requests_total counter\n");
+        for ((method, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "buildchain_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                label(method),
+                status,
+                count
+            );
+        }
+
+        out.push_str("# HELP buildchain_served_bytes_total Bytes of response bodies sent\n");
+        out.push_str("# TYPE buildchain_served_bytes_total counter\n");
+        let _ = writeln!(
+            out,
+            "buildchain_served_bytes_total {}",
+            self.bytes_served.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP buildchain_upload_rejections_total Uploads refused\n");
+        out.push_str("# TYPE buildchain_upload_rejections_total counter\n");
+        for (status, count) in self.upload_rejections.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "buildchain_upload_rejections_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        let mut tails = Vec::new();
+        for (project, branches) in store.tail_index().unwrap_or_default() {
+            for branch in branches {
+                if let Ok(Some(block)) = store.read_tail(&project, &branch) {
+                    tails.push((project.clone(), branch, block));
+                }
+            }
+        }
+
+        out.push_str("# HELP buildchain_tail_counter Counter of the tail block\n");
+        out.push_str("# TYPE buildchain_tail_counter gauge\n");
+        for (project, branch, block) in tails.iter() {
+            let _ = writeln!(
+                out,
+                "buildchain_tail_counter{{project=\"{}\",branch=\"{}\"}} {}",
+                label(project),
+                label(branch),
+                u64_le(block, COUNTER)
+            );
+        }
+
+        out.push_str("# HELP buildchain_tail_timestamp_seconds Timestamp of the tail block\n");
+        out.push_str("# TYPE buildchain_tail_timestamp_seconds gauge\n");
+        for (project, branch, block) in tails.iter() {
+            let _ = writeln!(
+                out,
+                "buildchain_tail_timestamp_seconds{{project=\"{}\",branch=\"{}\"}} {}",
+                label(project),
+                label(branch),
+                u64_le(block, TIMESTAMP)
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::Metrics;
+    use crate::block::tests::signed_block;
+    use crate::Store;
+
+    #[test]
+    fn test_render() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let (_key, block) = signed_block(1, &[0; 64], 4, &[0; 48]);
+        store.write_tail("stuff", "junk", &block).unwrap();
+
+        let metrics = Metrics::default();
+        metrics.request("GET", 200, 400);
+        metrics.request("GET", 200, 100);
+        metrics.request("GET", 404, 0);
+        metrics.upload_rejection(403);
+
+        let text = metrics.render(&store);
+        let lines: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            [
+                "buildchain_requests_total{method=\"GET\",status=\"200\"} 2",
+                "buildchain_requests_total{method=\"GET\",status=\"404\"} 1",
+                "buildchain_served_bytes_total 500",
+                "buildchain_upload_rejections_total{status=\"403\"} 1",
+                "buildchain_tail_counter{project=\"stuff\",branch=\"junk\"} 4",
+                "buildchain_tail_timestamp_seconds{project=\"stuff\",branch=\"junk\"} 1500000004",
+            ]
+        );
+
+        temp_dir.close().unwrap();
+    }
+}
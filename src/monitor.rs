@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+use crate::format::print_json;
+use crate::{
+    Block, Clock, Downloader, DownloaderBuilder, Error, Format, Manifest, OsRng, Rng, SystemClock,
+};
+
+pub struct MonitorArguments<'a> {
+    pub key: &'a str,
+    pub url: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// The number of blocks to verify back from the tail
+    pub depth: usize,
+    /// The number of files of the tail build to download and verify
+    pub samples: usize,
+    /// Report the mirror as stale if the tail was signed longer ago than this, in seconds
+    pub max_age_opt: Option<u64>,
+    /// Check the mirror repeatedly, waiting this long between checks, instead of once
+    pub interval_opt: Option<Duration>,
+    /// Write Prometheus metrics to this file, for the node exporter textfile collector
+    pub textfile_opt: Option<&'a str>,
+    pub format: Format,
+}
+
+/// The result of checking a mirror with [`check_mirror`]
+#[derive(Debug, Serialize)]
+pub struct MirrorHealth {
+    pub project: String,
+    pub branch: String,
+    pub healthy: bool,
+    /// When the check was made
+    pub time: u64,
+    /// The verified tail, if it could be downloaded
+    pub tail: Option<Block>,
+    /// Blocks verified back from the tail, including the tail
+    pub blocks_verified: usize,
+    pub objects_verified: usize,
+    pub objects_failed: usize,
+    /// The problems found, empty if the mirror is healthy
+    pub errors: Vec<String>,
+}
+
+/// Escape a Prometheus label value
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MirrorHealth {
+    /// Render the result in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let labels = format!(
+            "project=\"{}\",branch=\"{}\"",
+            label(&self.project),
+            label(&self.branch)
+        );
+        let mut out = String::new();
+        let mut metric = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP buildchain_mirror_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE buildchain_mirror_{} gauge", name);
+            let _ = writeln!(out, "buildchain_mirror_{}{{{}}} {}", name, labels, value);
+        };
+
+        metric(
+            "healthy",
+            "Whether the last check found no problems",
+            u64::from(self.healthy),
+        );
+        metric(
+            "check_timestamp_seconds",
+            "When the mirror was last checked",
+            self.time,
+        );
+        if let Some(tail) = &self.tail {
+            metric("tail_counter", "Counter of the tail block", tail.counter);
+            metric(
+                "tail_timestamp_seconds",
+                "When the tail block was signed",
+                tail.timestamp,
+            );
+        }
+        metric(
+            "blocks_verified",
+            "Blocks verified back from the tail",
+            self.blocks_verified as u64,
+        );
+        metric(
+            "objects_verified",
+            "Sampled objects that verified",
+            self.objects_verified as u64,
+        );
+        metric(
+            "objects_failed",
+            "Sampled objects that could not be downloaded or verified",
+            self.objects_failed as u64,
+        );
+        out
+    }
+}
+
+/// Verify the tail of `dl`, `depth` blocks of its chain, and `samples` files of its build
+///
+/// Files are sampled from a random position in the manifest, so that repeated checks cover
+/// different files. Problems are recorded in the result rather than returned as errors.
+pub fn check_mirror(
+    dl: &Downloader,
+    args: &MonitorArguments,
+    clock: &dyn Clock,
+    rng: &dyn Rng,
+) -> MirrorHealth {
+    let mut health = MirrorHealth {
+        project: args.project.to_string(),
+        branch: args.branch.to_string(),
+        healthy: false,
+        time: clock.now(),
+        tail: None,
+        blocks_verified: 0,
+        objects_verified: 0,
+        objects_failed: 0,
+        errors: Vec::new(),
+    };
+
+    let tail = match dl.tail() {
+        Ok(tail) => tail,
+        Err(err) => {
+            health.errors.push(format!("tail: {}", err));
+            return health;
+        }
+    };
+
+    if let Some(max_age) = args.max_age_opt {
+        let age = health.time.saturating_sub(tail.timestamp);
+        if age > max_age {
+            health
+                .errors
+                .push(format!("tail is stale, it was signed {} seconds ago", age));
+        }
+    }
+
+    match dl.history(args.depth.max(1)) {
+        Ok(history) => health.blocks_verified = history.len(),
+        Err(err) => health.errors.push(format!("chain: {}", err)),
+    }
+
+    let manifest_res = dl
+        .object(&tail.digest)
+        .and_then(|data| Manifest::from_signed(&data, &tail).map_err(Error::Verify));
+    match manifest_res {
+        Ok(manifest) => {
+            let files: Vec<_> = manifest.files.iter().collect();
+            if !files.is_empty() {
+                let mut bytes = [0u8; 8];
+                rng.fill_bytes(&mut bytes);
+                let start = (u64::from_le_bytes(bytes) % files.len() as u64) as usize;
+                for i in 0..args.samples.min(files.len()) {
+                    let (name, digest) = files[(start + i) % files.len()];
+                    let verified = dl
+                        .object(digest)
+                        .and_then(|data| manifest.verify_file(name, &data).map_err(Error::Verify));
+                    match verified {
+                        Ok(()) => health.objects_verified += 1,
+                        Err(err) => {
+                            health.objects_failed += 1;
+                            health.errors.push(format!("{}: {}", name, err));
+                        }
+                    }
+                }
+            }
+        }
+        Err(err) => health.errors.push(format!("manifest: {}", err)),
+    }
+
+    health.tail = Some(tail);
+    health.healthy = health.errors.is_empty();
+    health
+}
+
+/// Write `data` to `path` atomically, so that collectors never read a partial file
+fn write_textfile(path: &str, data: &str) -> Result<(), Error> {
+    let tmp = format!("{}.partial", path);
+    fs::write(&tmp, data)?;
+    Ok(fs::rename(tmp, path)?)
+}
+
+/// Check a mirror once, or repeatedly if `args.interval_opt` is set
+///
+/// A single check fails with [`Error::Verify`] if the mirror is not healthy. Repeated checks
+/// only report problems, so they run until interrupted.
+pub fn monitor(args: MonitorArguments) -> Result<(), Error> {
+    let dl = DownloaderBuilder::new(args.key, args.url)
+        .project(args.project)
+        .branch(args.branch)
+        .build_blocking()?;
+
+    loop {
+        let health = check_mirror(&dl, &args, &SystemClock, &OsRng);
+
+        if let Some(textfile) = args.textfile_opt {
+            write_textfile(textfile, &health.to_prometheus())?;
+        }
+        match args.format {
+            Format::Text => {
+                if health.healthy {
+                    println!(
+                        "buildchain: tail/{}/{} is healthy, verified {} blocks and {} objects",
+                        args.project, args.branch, health.blocks_verified, health.objects_verified
+                    );
+                }
+                for err in health.errors.iter() {
+                    eprintln!("buildchain: tail/{}/{}: {}", args.project, args.branch, err);
+                }
+            }
+            Format::Json => print_json(&health)?,
+        }
+
+        match args.interval_opt {
+            Some(interval) => thread::sleep(interval),
+            None if health.healthy => return Ok(()),
+            None => {
+                return Err(Error::Verify(format!(
+                    "tail/{}/{} is not healthy",
+                    args.project, args.branch
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_mirror, MonitorArguments};
+    use crate::block::tests::publish_chain;
+    use crate::{Downloader, FixedClock, Format, Manifest, MemoryTransport, SeededRng, Sha384};
+
+    /// Publish two builds of two files, corrupting one file of the tail if `corrupt` is set
+    fn mirror(corrupt: bool) -> (String, MemoryTransport) {
+        let transport = MemoryTransport::new();
+        let manifests: Vec<Manifest> = (0..2)
+            .map(|counter| {
+                let mut manifest = Manifest::default();
+                for name in ["a", "b"] {
+                    let data = format!("{} {}", name, counter);
+                    let digest = Sha384::new(data.as_bytes()).unwrap().to_id();
+                    let served = if corrupt && name == "b" {
+                        "corrupt".to_string()
+                    } else {
+                        data
+                    };
+                    transport.insert(&format!("object/{}", digest), served.as_bytes());
+                    manifest.files.insert(name.to_string(), digest);
+                }
+                manifest
+            })
+            .collect();
+        let key = publish_chain(&transport, &manifests);
+        (key, transport)
+    }
+
+    #[test]
+    fn test_check_mirror() {
+        let args = |max_age_opt| MonitorArguments {
+            key: "",
+            url: "",
+            project: "default",
+            branch: "master",
+            depth: 5,
+            samples: 2,
+            max_age_opt,
+            interval_opt: None,
+            textfile_opt: None,
+            format: Format::Text,
+        };
+        let clock = FixedClock(1_500_000_101);
+        let rng = SeededRng::new(0);
+
+        let (key, transport) = mirror(false);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        let health = check_mirror(&dl, &args(None), &clock, &rng);
+        assert!(health.healthy, "{:?}", health.errors);
+        assert_eq!(health.blocks_verified, 2);
+        assert_eq!(health.objects_verified, 2);
+        let metrics = health.to_prometheus();
+        assert!(
+            metrics.contains("buildchain_mirror_healthy{project=\"default\",branch=\"master\"} 1")
+        );
+        assert!(metrics
+            .contains("buildchain_mirror_tail_counter{project=\"default\",branch=\"master\"} 1"));
+
+        // The tail was signed 100 seconds before the check
+        let health = check_mirror(&dl, &args(Some(60)), &clock, &rng);
+        assert!(!health.healthy);
+        assert!(health.errors[0].starts_with("tail is stale"));
+
+        let (key, transport) = mirror(true);
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        let health = check_mirror(&dl, &args(None), &clock, &rng);
+        assert!(!health.healthy);
+        assert_eq!(health.objects_failed, 1);
+        assert!(health
+            .to_prometheus()
+            .contains("objects_failed{project=\"default\",branch=\"master\"} 1"));
+
+        // A missing tail is reported rather than returned
+        let (key, transport) = mirror(false);
+        transport.remove("tail/default/master");
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        let health = check_mirror(&dl, &args(None), &clock, &rng);
+        assert!(health.tail.is_none());
+        assert!(health.errors[0].starts_with("tail:"));
+    }
+}
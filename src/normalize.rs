@@ -0,0 +1,257 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Normalization of timestamps and owners in archive formats, as with `strip-nondeterminism`
+//!
+//! Each pass rewrites fields in place, so the size of an artifact never changes. Artifacts that
+//! cannot be parsed completely are left as they are.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The DOS date of 1980-01-01, the earliest date a zip entry can have
+const ZIP_DATE: u16 = (1 << 5) | 1;
+
+fn u16_le(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn u32_le(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn set_u16_le(data: &mut [u8], offset: usize, value: u16) -> Option<()> {
+    data.get_mut(offset..offset + 2)?
+        .copy_from_slice(&value.to_le_bytes());
+    Some(())
+}
+
+/// Clear the modification time of a gzip member
+fn gzip(data: &mut [u8]) -> Option<()> {
+    if data.get(..3)? != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    data.get_mut(4..8)?.fill(0);
+    Some(())
+}
+
+/// Clear the modification times and owners of the members of an ar archive, such as a .deb
+fn ar(data: &mut [u8]) -> Option<()> {
+    if data.get(..8)? != b"!<arch>\n" {
+        return None;
+    }
+
+    let mut offset = 8;
+    while offset < data.len() {
+        let header = data.get_mut(offset..offset + 60)?;
+        if &header[58..60] != b"`\n" {
+            return None;
+        }
+        let size: usize = std::str::from_utf8(&header[48..58])
+            .ok()?
+            .trim_end()
+            .parse()
+            .ok()?;
+        for field in [16..28, 28..34, 34..40] {
+            header[field.clone()].fill(b' ');
+            header[field.start] = b'0';
+        }
+        offset += 60 + size + size % 2;
+    }
+    Some(())
+}
+
+/// Clear the timestamps of an extended timestamp (UT) extra field
+fn zip_extra(extra: &mut [u8]) -> Option<()> {
+    let mut offset = 0;
+    while offset + 4 <= extra.len() {
+        let id = u16_le(extra, offset)?;
+        let size = usize::from(u16_le(extra, offset + 2)?);
+        let field = extra.get_mut(offset + 4..offset + 4 + size)?;
+        if id == 0x5455 && !field.is_empty() {
+            field[1..].fill(0);
+        }
+        offset += 4 + size;
+    }
+    Some(())
+}
+
+/// Set the modification times of the entries of a zip archive, such as a .jar, to 1980-01-01
+fn zip(data: &mut [u8]) -> Option<()> {
+    // The end of central directory record is followed by a comment of up to 64 KiB
+    let search = data.len().checked_sub(22)?;
+    let end = (search.saturating_sub(65535)..=search)
+        .rev()
+        .find(|&offset| u32_le(data, offset) == Some(0x06054b50))?;
+    let entries = u16_le(data, end + 10)?;
+    let mut offset = usize::try_from(u32_le(data, end + 16)?).ok()?;
+
+    for _ in 0..entries {
+        if u32_le(data, offset)? != 0x02014b50 {
+            return None;
+        }
+        set_u16_le(data, offset + 12, 0)?;
+        set_u16_le(data, offset + 14, ZIP_DATE)?;
+        let name_len = usize::from(u16_le(data, offset + 28)?);
+        let extra_len = usize::from(u16_le(data, offset + 30)?);
+        let comment_len = usize::from(u16_le(data, offset + 32)?);
+        let extra = offset + 46 + name_len;
+        zip_extra(data.get_mut(extra..extra + extra_len)?)?;
+
+        let local = usize::try_from(u32_le(data, offset + 42)?).ok()?;
+        if u32_le(data, local)? != 0x04034b50 {
+            return None;
+        }
+        set_u16_le(data, local + 10, 0)?;
+        set_u16_le(data, local + 12, ZIP_DATE)?;
+        let local_extra = local + 30 + usize::from(u16_le(data, local + 26)?);
+        let local_extra_len = usize::from(u16_le(data, local + 28)?);
+        zip_extra(data.get_mut(local_extra..local_extra + local_extra_len)?)?;
+
+        offset = extra + extra_len + comment_len;
+    }
+    Some(())
+}
+
+/// A normalization pass, which returns `None` if the data is not in its format
+type Pass = fn(&mut [u8]) -> Option<()>;
+
+/// Normalize `data` in place, returning the format if it was changed
+pub(crate) fn normalize(data: &mut Vec<u8>) -> Option<&'static str> {
+    let passes: [(&'static str, Pass); 3] = [("gzip", gzip), ("ar", ar), ("zip", zip)];
+    for (format, pass) in passes {
+        let mut normalized = data.clone();
+        if pass(&mut normalized).is_some() {
+            if normalized == *data {
+                return None;
+            }
+            *data = normalized;
+            return Some(format);
+        }
+    }
+    None
+}
+
+/// Normalize the artifacts in `dir`, returning the artifacts that changed and their formats
+pub(crate) fn normalize_dir<P: AsRef<Path>>(dir: P) -> io::Result<BTreeMap<String, String>> {
+    fn walk(dir: &Path, prefix: &str, normalized: &mut BTreeMap<String, String>) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                walk(&entry.path(), &format!("{}/", name), normalized)?;
+            } else if file_type.is_file() {
+                let mut data = fs::read(entry.path())?;
+                if let Some(format) = normalize(&mut data) {
+                    fs::write(entry.path(), data)?;
+                    normalized.insert(name, format.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let mut normalized = BTreeMap::new();
+    walk(dir.as_ref(), "", &mut normalized)?;
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{normalize, normalize_dir, ZIP_DATE};
+
+    /// A zip archive with one stored entry named `a`, modified at `time` and `date`
+    fn zip(time: u16, date: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        // Local file header
+        data.extend(0x04034b50u32.to_le_bytes());
+        data.extend([10, 0, 0, 0, 0, 0]);
+        data.extend(time.to_le_bytes());
+        data.extend(date.to_le_bytes());
+        data.extend([0; 12]);
+        data.extend(1u16.to_le_bytes());
+        data.extend(0u16.to_le_bytes());
+        data.push(b'a');
+        // Central directory
+        let central = data.len() as u32;
+        data.extend(0x02014b50u32.to_le_bytes());
+        data.extend([20, 0, 10, 0, 0, 0, 0, 0]);
+        data.extend(time.to_le_bytes());
+        data.extend(date.to_le_bytes());
+        data.extend([0; 12]);
+        data.extend(1u16.to_le_bytes());
+        data.extend([0; 12]);
+        data.extend(0u32.to_le_bytes());
+        data.push(b'a');
+        let size = data.len() as u32 - central;
+        // End of central directory
+        data.extend(0x06054b50u32.to_le_bytes());
+        data.extend([0; 4]);
+        data.extend(1u16.to_le_bytes());
+        data.extend(1u16.to_le_bytes());
+        data.extend(size.to_le_bytes());
+        data.extend(central.to_le_bytes());
+        data.extend(0u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_normalize() {
+        let mut gzip = vec![0x1f, 0x8b, 8, 0, 1, 2, 3, 4, 0, 3, 0];
+        assert_eq!(normalize(&mut gzip), Some("gzip"));
+        assert_eq!(gzip, [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3, 0]);
+        assert_eq!(normalize(&mut gzip), None);
+
+        let mut ar = b"!<arch>\n".to_vec();
+        ar.extend(b"data.tar.gz/    1700000000  1000  1000  100644  3         `\nabc\n");
+        assert_eq!(normalize(&mut ar), Some("ar"));
+        assert_eq!(
+            &ar[8..],
+            b"data.tar.gz/    0           0     0     100644  3         `\nabc\n"
+        );
+
+        let mut data = zip(0x6000, 0x5721);
+        assert_eq!(normalize(&mut data), Some("zip"));
+        assert_eq!(data, zip(0, ZIP_DATE));
+
+        // Truncated archives are not changed
+        let mut truncated = zip(0x6000, 0x5721);
+        truncated.truncate(40);
+        assert_eq!(normalize(&mut truncated), None);
+        assert_eq!(truncated, &zip(0x6000, 0x5721)[..40]);
+    }
+
+    #[test]
+    fn test_normalize_dir() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        fs::create_dir(temp_dir.path().join("sub")).unwrap();
+        fs::write(
+            temp_dir.path().join("sub/a.gz"),
+            [0x1f, 0x8b, 8, 0, 1, 0, 0, 0],
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("b.txt"), "text").unwrap();
+
+        let normalized = normalize_dir(temp_dir.path()).unwrap();
+        assert_eq!(
+            normalized,
+            [("sub/a.gz".to_string(), "gzip".to_string())].into()
+        );
+        assert_eq!(
+            fs::read(temp_dir.path().join("sub/a.gz")).unwrap(),
+            [0x1f, 0x8b, 8, 0, 0, 0, 0, 0]
+        );
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,396 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::id::b32enc;
+use crate::{err_str, Auth, Manifest, ObjectId, Store};
+
+const EMPTY_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+const IMAGE_MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// The artifact type of the pushed build, with the manifest and artifacts as layers
+pub const MANIFEST_ARTIFACT_TYPE: &str = "application/vnd.buildchain.manifest.v1+json";
+
+/// The artifact type of the referrer holding the signed block for a build
+pub const BLOCK_ARTIFACT_TYPE: &str = "application/vnd.buildchain.block.v1";
+
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// A content descriptor, as defined by the OCI image specification
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+impl Descriptor {
+    fn new(media_type: &str, data: &[u8]) -> Descriptor {
+        Descriptor {
+            media_type: media_type.to_string(),
+            digest: sha256(data),
+            size: data.len() as u64,
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    fn title(mut self, title: &str) -> Descriptor {
+        self.annotations
+            .insert(TITLE_ANNOTATION.to_string(), title.to_string());
+        self
+    }
+}
+
+/// An OCI image manifest, used to describe an artifact
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImageManifest {
+    schema_version: u32,
+    media_type: String,
+    artifact_type: String,
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<Descriptor>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+impl ImageManifest {
+    fn new(artifact_type: &str, layers: Vec<Descriptor>) -> ImageManifest {
+        ImageManifest {
+            schema_version: 2,
+            media_type: IMAGE_MANIFEST_MEDIA_TYPE.to_string(),
+            artifact_type: artifact_type.to_string(),
+            config: Descriptor::new(EMPTY_MEDIA_TYPE, b"{}"),
+            layers,
+            subject: None,
+            annotations: BTreeMap::new(),
+        }
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// A parsed `oci://registry/repository:tag` reference
+#[derive(Debug, Eq, PartialEq)]
+struct Reference {
+    base: String,
+    repository: String,
+    tag: String,
+}
+
+impl Reference {
+    /// Parse `oci://` references, or `oci+http://` for registries without TLS
+    fn parse(url: &str) -> Result<Reference, String> {
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("oci://") {
+            ("https", rest)
+        } else if let Some(rest) = url.strip_prefix("oci+http://") {
+            ("http", rest)
+        } else {
+            return Err(format!("{} is not an oci:// reference", url));
+        };
+
+        let (registry, path) = rest
+            .split_once('/')
+            .ok_or_else(|| format!("{} has no repository", url))?;
+
+        // A colon after the last slash separates the tag, colons before it are ports
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => (repository, tag),
+            _ => (path, "latest"),
+        };
+
+        if registry.is_empty() || repository.is_empty() || tag.is_empty() {
+            return Err(format!("{} is not a valid reference", url));
+        }
+
+        Ok(Reference {
+            base: format!("{}://{}/v2/{}/", scheme, registry, repository),
+            repository: repository.to_string(),
+            tag: tag.to_string(),
+        })
+    }
+}
+
+/// Pushes builds to an OCI registry as artifacts, using the ORAS conventions
+///
+/// The manifest and each artifact are pushed as layers of an artifact tagged with the
+/// reference's tag. The signed block is pushed as a referrer of that artifact, so that it
+/// can be found with the registry's referrers API.
+pub struct OciPublisher {
+    reference: Reference,
+    auth_opt: Option<Auth>,
+    client: reqwest::Client,
+}
+
+impl OciPublisher {
+    pub fn new(
+        reference: &str,
+        auth_opt: Option<Auth>,
+        cert_opt: Option<&[u8]>,
+    ) -> Result<OciPublisher, String> {
+        let reference = Reference::parse(reference)?;
+
+        let client = {
+            let mut builder = reqwest::Client::builder();
+
+            if let Some(cert) = cert_opt {
+                builder = builder
+                    .add_root_certificate(reqwest::Certificate::from_pem(cert).map_err(err_str)?);
+            }
+
+            builder.build().map_err(err_str)?
+        };
+
+        Ok(OciPublisher {
+            reference,
+            auth_opt,
+            client,
+        })
+    }
+
+    fn url(&self, path: &str) -> Result<reqwest::Url, String> {
+        reqwest::Url::parse(&self.reference.base)
+            .and_then(|base| base.join(path))
+            .map_err(err_str)
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth_opt {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic { username, password }) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            None => request,
+        }
+    }
+
+    async fn check(response: reqwest::Response, what: &str) -> Result<reqwest::Response, String> {
+        let status = response.status();
+        if status.is_success() {
+            Ok(response)
+        } else {
+            let message = response.text().await.unwrap_or_default();
+            Err(format!(
+                "failed to push {}: {:?}: {}",
+                what, status, message
+            ))
+        }
+    }
+
+    /// Push a blob, unless the registry already has it
+    async fn push_blob(&self, descriptor: &Descriptor, data: Vec<u8>) -> Result<(), String> {
+        let exists = self
+            .authorize(
+                self.client
+                    .head(self.url(&format!("blobs/{}", descriptor.digest))?),
+            )
+            .send()
+            .await
+            .map_err(err_str)?;
+        if exists.status() == StatusCode::OK {
+            return Ok(());
+        }
+
+        let response = self
+            .authorize(self.client.post(self.url("blobs/uploads/")?))
+            .send()
+            .await
+            .map_err(err_str)?;
+        let response = OciPublisher::check(response, &descriptor.digest).await?;
+
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| format!("no upload location for {}", descriptor.digest))?;
+        let mut url = self.url(location)?;
+        url.query_pairs_mut()
+            .append_pair("digest", &descriptor.digest);
+
+        let response = self
+            .authorize(self.client.put(url))
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .body(data)
+            .send()
+            .await
+            .map_err(err_str)?;
+        OciPublisher::check(response, &descriptor.digest).await?;
+
+        Ok(())
+    }
+
+    /// Push an image manifest, tagged with `reference` which may also be its digest
+    async fn push_manifest(
+        &self,
+        manifest: &ImageManifest,
+        reference_opt: Option<&str>,
+    ) -> Result<Descriptor, String> {
+        let data = serde_json::to_vec(manifest).map_err(err_str)?;
+        let descriptor = Descriptor::new(IMAGE_MANIFEST_MEDIA_TYPE, &data);
+        let reference = reference_opt.unwrap_or(&descriptor.digest);
+
+        let response = self
+            .authorize(
+                self.client
+                    .put(self.url(&format!("manifests/{}", reference))?),
+            )
+            .header(reqwest::header::CONTENT_TYPE, IMAGE_MANIFEST_MEDIA_TYPE)
+            .body(data)
+            .send()
+            .await
+            .map_err(err_str)?;
+        OciPublisher::check(response, &format!("manifest {}", reference)).await?;
+
+        Ok(descriptor)
+    }
+
+    /// Push the build at the tail of `project` and `branch` in `store`
+    ///
+    /// # Return
+    ///
+    /// The digest of the pushed artifact manifest
+    pub async fn publish(
+        &self,
+        store: &Store,
+        project: &str,
+        branch: &str,
+    ) -> Result<String, String> {
+        let block = store
+            .read_tail(project, branch)
+            .map_err(err_str)?
+            .ok_or_else(|| format!("tail/{}/{} not found", project, branch))?;
+        let digest = ObjectId::try_from(&block[352..]).unwrap();
+
+        let read_object = |digest: &ObjectId| fs::read(store.object_path(digest)).map_err(err_str);
+
+        let manifest_json = read_object(&digest)?;
+        let manifest: Manifest = serde_json::from_slice(&manifest_json).map_err(err_str)?;
+
+        let config = Descriptor::new(EMPTY_MEDIA_TYPE, b"{}");
+        self.push_blob(&config, b"{}".to_vec()).await?;
+
+        let mut layers = Vec::new();
+
+        let descriptor =
+            Descriptor::new(MANIFEST_ARTIFACT_TYPE, &manifest_json).title("manifest.json");
+        self.push_blob(&descriptor, manifest_json).await?;
+        layers.push(descriptor);
+
+        for (name, file_digest) in manifest.files.iter() {
+            let data = read_object(file_digest)?;
+            let descriptor = Descriptor::new("application/octet-stream", &data).title(name);
+            println!("Push {}", name);
+            self.push_blob(&descriptor, data).await?;
+            layers.push(descriptor);
+        }
+
+        let mut artifact = ImageManifest::new(MANIFEST_ARTIFACT_TYPE, layers);
+        artifact.annotations = annotations(project, branch, &block);
+        let subject = self
+            .push_manifest(&artifact, Some(&self.reference.tag))
+            .await?;
+
+        let descriptor = Descriptor::new(BLOCK_ARTIFACT_TYPE, &block).title("block");
+        self.push_blob(&descriptor, block.to_vec()).await?;
+
+        let mut referrer = ImageManifest::new(BLOCK_ARTIFACT_TYPE, vec![descriptor]);
+        referrer.subject = Some(subject.clone());
+        referrer.annotations = annotations(project, branch, &block);
+        self.push_manifest(&referrer, None).await?;
+
+        println!(
+            "buildchain: pushed {}:{}@{}",
+            self.reference.repository, self.reference.tag, subject.digest
+        );
+
+        Ok(subject.digest)
+    }
+}
+
+/// Annotations identifying the block a pushed build belongs to
+fn annotations(project: &str, branch: &str, block: &[u8; 400]) -> BTreeMap<String, String> {
+    let mut counter = [0u8; 8];
+    counter.copy_from_slice(&block[160..168]);
+
+    [
+        ("org.buildchain.project", project.to_string()),
+        ("org.buildchain.branch", branch.to_string()),
+        (
+            "org.buildchain.counter",
+            u64::from_le_bytes(counter).to_string(),
+        ),
+        ("org.buildchain.signature", b32enc(&block[..64])),
+        ("org.buildchain.digest", b32enc(&block[352..])),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Descriptor, ImageManifest, Reference, BLOCK_ARTIFACT_TYPE};
+
+    #[test]
+    fn test_reference() {
+        assert_eq!(
+            Reference::parse("oci://registry.example.com/firmware/galp5:1.2").unwrap(),
+            Reference {
+                base: "https://registry.example.com/v2/firmware/galp5/".to_string(),
+                repository: "firmware/galp5".to_string(),
+                tag: "1.2".to_string(),
+            }
+        );
+        assert_eq!(
+            Reference::parse("oci+http://localhost:5000/firmware").unwrap(),
+            Reference {
+                base: "http://localhost:5000/v2/firmware/".to_string(),
+                repository: "firmware".to_string(),
+                tag: "latest".to_string(),
+            }
+        );
+        assert!(Reference::parse("https://registry.example.com/firmware").is_err());
+        assert!(Reference::parse("oci://registry.example.com").is_err());
+    }
+
+    #[test]
+    fn test_referrer_manifest() {
+        let subject = Descriptor::new("application/vnd.oci.image.manifest.v1+json", b"subject");
+        let mut referrer = ImageManifest::new(
+            BLOCK_ARTIFACT_TYPE,
+            vec![Descriptor::new(BLOCK_ARTIFACT_TYPE, &[0; 400]).title("block")],
+        );
+        referrer.subject = Some(subject.clone());
+
+        let json = serde_json::to_value(&referrer).unwrap();
+        assert_eq!(json["schemaVersion"], 2);
+        assert_eq!(json["artifactType"], BLOCK_ARTIFACT_TYPE);
+        assert_eq!(
+            json["config"]["mediaType"],
+            "application/vnd.oci.empty.v1+json"
+        );
+        assert_eq!(
+            json["config"]["digest"],
+            "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        );
+        assert_eq!(json["layers"][0]["size"], 400);
+        assert_eq!(
+            json["layers"][0]["annotations"]["org.opencontainers.image.title"],
+            "block"
+        );
+        assert_eq!(json["subject"]["digest"], subject.digest);
+        assert!(json.get("annotations").is_none());
+    }
+}
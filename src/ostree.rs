@@ -0,0 +1,172 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::TempDir;
+
+use crate::{err_str, Block, Downloader, LocalTransport, Manifest, ObjectId, Store};
+
+pub struct OstreeArguments<'a> {
+    pub store_path: &'a str,
+    pub repo: &'a str,
+    pub key: &'a str,
+    pub prefix: &'a str,
+}
+
+/// Write the verified artifacts of the build referenced by `block` into `dir`
+fn write_tree(dl: &Downloader, block: &Block, dir: &Path) -> Result<(), String> {
+    let manifest_json = dl.object(&block.digest).map_err(err_str)?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
+
+    for (name, digest) in manifest.files.iter() {
+        let path = dir.join(name);
+        if !path.starts_with(dir) || name.split('/').any(|part| part == "..") {
+            return Err(format!("invalid artifact name {}", name));
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(err_str)?;
+        }
+        fs::write(&path, dl.object(digest).map_err(err_str)?).map_err(err_str)?;
+    }
+
+    Ok(())
+}
+
+/// Read the buildchain digest recorded in the commit at `ostree_ref`, if it exists
+fn committed_digest(repo: &str, ostree_ref: &str) -> Option<ObjectId> {
+    let output = Command::new("ostree")
+        .arg("show")
+        .arg(format!("--repo={}", repo))
+        .arg("--print-metadata-key=buildchain.digest")
+        .arg(ostree_ref)
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    // The value is printed as a quoted GVariant string
+    let value = String::from_utf8(output.stdout).ok()?;
+    value.trim().trim_matches('\'').parse().ok()
+}
+
+fn commit(repo: &str, ostree_ref: &str, block: &Block, tree: &Path) -> Result<(), String> {
+    let status = Command::new("ostree")
+        .arg("commit")
+        .arg(format!("--repo={}", repo))
+        .arg(format!("--branch={}", ostree_ref))
+        .arg(format!("--tree=dir={}", tree.display()))
+        .arg(format!("--subject=buildchain {}", block.counter))
+        .arg(format!("--timestamp=@{}", block.timestamp))
+        .arg("--owner-uid=0")
+        .arg("--owner-gid=0")
+        .arg(format!(
+            "--add-metadata-string=buildchain.digest={}",
+            block.digest
+        ))
+        .arg(format!(
+            "--add-metadata-string=buildchain.signature={}",
+            block.signature
+        ))
+        .arg(format!(
+            "--add-metadata-string=buildchain.counter={}",
+            block.counter
+        ))
+        .status()
+        .map_err(err_str)?;
+
+    if !status.success() {
+        return Err(format!("ostree failed with status: {}", status));
+    }
+
+    Ok(())
+}
+
+/// Commit the artifacts of each tail in a store to an OSTree repository
+///
+/// Each chain branch is committed to `<prefix>/<project>/<branch>`, after verifying the
+/// tail and every artifact with `key`. Branches whose build is already committed are skipped.
+pub fn ostree_export(args: OstreeArguments) -> Result<(), String> {
+    let store = Store::new(args.store_path);
+
+    for (project, branches) in store.tail_index().map_err(err_str)? {
+        for branch in branches {
+            let dl = Downloader::from_transport(
+                args.key,
+                &project,
+                &branch,
+                Box::new(LocalTransport::new(args.store_path)),
+            )
+            .map_err(err_str)?;
+            let block = dl.tail().map_err(err_str)?;
+
+            let ostree_ref = format!("{}/{}/{}", args.prefix, project, branch);
+            if committed_digest(args.repo, &ostree_ref) == Some(block.digest) {
+                println!("Skip {}", ostree_ref);
+                continue;
+            }
+
+            let temp_dir = TempDir::with_prefix("buildchain.").map_err(err_str)?;
+            write_tree(&dl, &block, temp_dir.path())?;
+
+            println!("Commit {}", ostree_ref);
+            commit(args.repo, &ostree_ref, &block, temp_dir.path())?;
+
+            temp_dir.close().map_err(err_str)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::write_tree;
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{Downloader, LocalTransport, Manifest, Store};
+
+    #[test]
+    fn test_write_tree() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+
+        let file_key = store.write_object(b"artifact").unwrap();
+        let manifest = Manifest {
+            time: 0,
+            files: [("dir/artifact".to_string(), file_key)].into(),
+            ..Default::default()
+        };
+        let manifest_key = store
+            .write_object(&serde_json::to_vec(&manifest).unwrap())
+            .unwrap();
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &manifest_key);
+        store.write_tail("default", "master", &block).unwrap();
+
+        let dl = Downloader::from_transport(
+            &b32enc(&public_key),
+            "default",
+            "master",
+            Box::new(LocalTransport::new(temp_dir.path())),
+        )
+        .unwrap();
+        let block = dl.tail().unwrap();
+
+        let tree_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        write_tree(&dl, &block, tree_dir.path()).unwrap();
+        assert_eq!(
+            fs::read(tree_dir.path().join("dir/artifact")).unwrap(),
+            b"artifact"
+        );
+
+        tree_dir.close().unwrap();
+        temp_dir.close().unwrap();
+    }
+}
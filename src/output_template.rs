@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ObjectId;
+
+/// A variable of an [`OutputTemplate`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Variable {
+    Name,
+    Project,
+    Branch,
+    Counter,
+    Time,
+    Date,
+    Manifest,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Option<Variable> {
+        match name {
+            "name" => Some(Variable::Name),
+            "project" => Some(Variable::Project),
+            "branch" => Some(Variable::Branch),
+            "counter" => Some(Variable::Counter),
+            "time" => Some(Variable::Time),
+            "date" => Some(Variable::Date),
+            "manifest" => Some(Variable::Manifest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Text(String),
+    Variable(Variable),
+}
+
+/// The values of the variables of an [`OutputTemplate`] for one build
+#[derive(Clone, Copy, Debug)]
+pub struct OutputVars<'a> {
+    /// The name of the build project in its configuration
+    pub name: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// The counter of the signed block, if the manifest was signed
+    pub counter_opt: Option<u64>,
+    /// The source time, as recorded in the manifest
+    pub time: u64,
+    pub manifest: &'a ObjectId,
+}
+
+/// A template for the name of a build output, such as `{name}-{branch}-{counter}.tar`
+///
+/// The variables are `{name}`, `{project}`, `{branch}`, `{counter}`, `{time}` as seconds since
+/// the epoch, `{date}` as `YYYYMMDD` in UTC, and `{manifest}`, the first 16 characters of the
+/// manifest digest. Both time variables use the source time, so rebuilds are named the same.
+/// `/` in values is replaced by `-`, so a branch such as `release/22.04` cannot change the
+/// directory of the output. Braces are written as `{{` and `}}`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputTemplate {
+    segments: Vec<Segment>,
+}
+
+/// The civil date in UTC of `time`, as `YYYYMMDD`
+fn utc_date(time: u64) -> String {
+    // Days since 0000-03-01, with years starting in March so leap days end each year
+    let days = time / 86_400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+impl OutputTemplate {
+    /// True if the template uses `{counter}`, which is only known once the manifest is signed
+    pub fn uses_counter(&self) -> bool {
+        self.segments
+            .contains(&Segment::Variable(Variable::Counter))
+    }
+
+    /// The output name of the build with `vars`
+    ///
+    /// # Errors
+    ///
+    /// The template uses `{counter}`, and the manifest was not signed
+    pub fn render(&self, vars: &OutputVars) -> Result<String, String> {
+        let mut name = String::new();
+        for segment in self.segments.iter() {
+            let value = match segment {
+                Segment::Text(text) => {
+                    name.push_str(text);
+                    continue;
+                }
+                Segment::Variable(Variable::Name) => vars.name.to_string(),
+                Segment::Variable(Variable::Project) => vars.project.to_string(),
+                Segment::Variable(Variable::Branch) => vars.branch.to_string(),
+                Segment::Variable(Variable::Counter) => vars
+                    .counter_opt
+                    .ok_or("{counter} is only known when the manifest is signed")?
+                    .to_string(),
+                Segment::Variable(Variable::Time) => vars.time.to_string(),
+                Segment::Variable(Variable::Date) => utc_date(vars.time),
+                Segment::Variable(Variable::Manifest) => {
+                    vars.manifest.to_string()[..16].to_string()
+                }
+            };
+            name.push_str(&value.replace('/', "-"));
+        }
+        Ok(name)
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputTemplate, String> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed {{ in {:?}", s)),
+                        }
+                    }
+                    let variable = Variable::parse(&name)
+                        .ok_or_else(|| format!("unknown variable {{{}}} in {:?}", name, s))?;
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(Segment::Variable(variable));
+                }
+                '}' => return Err(format!("unmatched }} in {:?}, use }}}}", s)),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        if segments.is_empty() {
+            return Err("output name is empty".to_string());
+        }
+        Ok(OutputTemplate { segments })
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Text(text) => write!(f, "{}", text.replace('{', "{{").replace('}', "}}"))?,
+                Segment::Variable(variable) => {
+                    let name = match variable {
+                        Variable::Name => "name",
+                        Variable::Project => "project",
+                        Variable::Branch => "branch",
+                        Variable::Counter => "counter",
+                        Variable::Time => "time",
+                        Variable::Date => "date",
+                        Variable::Manifest => "manifest",
+                    };
+                    write!(f, "{{{}}}", name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{utc_date, OutputTemplate, OutputVars};
+    use crate::ObjectId;
+
+    #[test]
+    fn test_output_template() {
+        let manifest = ObjectId([0; 48]);
+        let mut vars = OutputVars {
+            name: "firmware",
+            project: "default",
+            branch: "release/22.04",
+            counter_opt: Some(12),
+            time: 1_700_000_000,
+            manifest: &manifest,
+        };
+
+        let template: OutputTemplate = "{name}-{branch}-{counter}.tar.zst".parse().unwrap();
+        assert!(template.uses_counter());
+        assert_eq!(
+            template.render(&vars).unwrap(),
+            "firmware-release-22.04-12.tar.zst"
+        );
+        assert_eq!(template.to_string(), "{name}-{branch}-{counter}.tar.zst");
+
+        let template: OutputTemplate = "out/{project}_{date}_{time}_{{x}}.tar".parse().unwrap();
+        assert_eq!(
+            template.render(&vars).unwrap(),
+            "out/default_20231114_1700000000_{x}.tar"
+        );
+        assert_eq!(
+            template.to_string(),
+            "out/{project}_{date}_{time}_{{x}}.tar"
+        );
+        let template: OutputTemplate = "{manifest}".parse().unwrap();
+        assert_eq!(template.render(&vars).unwrap().len(), 16);
+
+        let template: OutputTemplate = "buildchain.tar".parse().unwrap();
+        assert!(!template.uses_counter());
+
+        vars.counter_opt = None;
+        let template: OutputTemplate = "{counter}.tar".parse().unwrap();
+        assert!(template.render(&vars).is_err());
+
+        assert!("{nme}.tar".parse::<OutputTemplate>().is_err());
+        assert!("{name.tar".parse::<OutputTemplate>().is_err());
+        assert!("name}.tar".parse::<OutputTemplate>().is_err());
+        assert!("".parse::<OutputTemplate>().is_err());
+    }
+
+    #[test]
+    fn test_utc_date() {
+        assert_eq!(utc_date(0), "19700101");
+        assert_eq!(utc_date(951_782_400), "20000229");
+        assert_eq!(utc_date(1_709_251_199), "20240229");
+        assert_eq!(utc_date(1_709_251_200), "20240301");
+    }
+}
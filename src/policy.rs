@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Rules that embedding applications enforce on builds before their files are downloaded
+//!
+//! A [`Policy`] set with [`crate::r#async::Downloader::set_policy`] sees the verified block and
+//! manifest of a build, and each file that is about to be downloaded, and may refuse them with
+//! [`crate::Error::Denied`]. Policies only add restrictions, verification is unchanged.
+
+use crate::{Block, Manifest, ObjectId};
+
+/// A file of a build that is about to be downloaded
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PolicyFile<'a> {
+    /// The name of the file in the manifest
+    pub name: &'a str,
+    /// The digest of the file
+    pub digest: &'a ObjectId,
+    /// The length of the file, if the mirror reports it
+    pub length: Option<u64>,
+    /// The permission bits of the file, if it is executable
+    pub mode: Option<u32>,
+}
+
+/// Decides whether builds and their files may be downloaded
+///
+/// Both methods allow everything by default, and return the reason as an error to refuse.
+pub trait Policy: Send + Sync {
+    /// Check a verified build, before any of its files are downloaded
+    ///
+    /// The `block` timestamp is when the build was signed, and the manifest `time` is the
+    /// time of its source.
+    fn check_build(&self, _block: &Block, _manifest: &Manifest) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Check a file of the build, before it is downloaded
+    fn check_file(
+        &self,
+        _block: &Block,
+        _manifest: &Manifest,
+        _file: &PolicyFile,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
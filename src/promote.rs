@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::io;
+
+use crate::id::b32dec;
+use crate::verify::verify_block;
+use crate::{err_str, sign_manifest, Channel, Downloader, Error, Fork, Manifest, Store};
+
+pub struct PromoteArguments<'a> {
+    pub store_path: &'a str,
+    pub key: &'a str,
+    pub project: &'a str,
+    pub from: &'a str,
+    pub to: &'a str,
+    /// Replace the rollout of the build on `to`, signing a new manifest with the same files
+    pub channel_opt: Option<Channel>,
+    /// Record the tail of `from` as the fork point of `to`, signing a new manifest
+    pub fork: bool,
+}
+
+/// Sign the manifest of the tail of `args.from` for `args.to`, using `sign` to produce the block
+///
+/// The tail of `args.from` must be signed by `args.key`, and so must the new block.
+pub(crate) fn promote_store<F>(store: &Store, args: &PromoteArguments, sign: F) -> Result<(), Error>
+where
+    F: FnOnce(&[u8]) -> io::Result<[u8; 400]>,
+{
+    let key: [u8; 32] = b32dec(args.key)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::Config("key is not a base32 public key".to_string()))?;
+
+    let downloader = |branch: &str| Downloader::from_store(args.key, args.project, branch, store);
+
+    let block = downloader(args.from)?.tail()?;
+
+    // The manifest is verified against its digest before it is signed again
+    let mut manifest_json = downloader(args.from)?.object(&block.digest)?;
+    let mut digest = block.digest;
+    if args.channel_opt.is_some() || args.fork {
+        let mut manifest = serde_json::from_slice::<Manifest>(&manifest_json)
+            .map_err(|err| Error::Verify(err_str(err)))?;
+        if let Some(channel) = &args.channel_opt {
+            manifest.channel = Some(channel.clone());
+        }
+        if args.fork {
+            manifest.fork = Some(Fork {
+                branch: args.from.to_string(),
+                signature: block.signature,
+            });
+        }
+        manifest_json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+        digest = store.write_object(&manifest_json)?;
+    }
+
+    if store.read_tail(args.project, args.to)?.is_some() {
+        let current = downloader(args.to)?.tail()?;
+        if current.digest == digest {
+            println!(
+                "buildchain: tail/{}/{} already refers to manifest {}",
+                args.project, args.to, digest
+            );
+            return Ok(());
+        }
+    }
+
+    let response = sign(&manifest_json).map_err(Error::Sign)?;
+    let verified = verify_block(&response, &key)
+        .map_err(|err| Error::Verify(format!("promoted block: {}", err)))?;
+    if *verified.digest() != *digest {
+        return Err(Error::Verify(
+            "promoted block does not refer to the manifest".to_string(),
+        ));
+    }
+
+    store.write_tail(args.project, args.to, &response)?;
+    println!(
+        "buildchain: promoted manifest {} from {} to {} with counter {}",
+        digest,
+        args.from,
+        args.to,
+        verified.counter()
+    );
+    Ok(())
+}
+
+/// Publish the verified build of one branch on another branch, without rebuilding it
+///
+/// The manifest of the tail of `args.from` is signed with PiHSM, and the new block becomes the
+/// tail of `args.to`.
+pub fn promote(args: PromoteArguments) -> Result<(), Error> {
+    let store = Store::open(args.store_path)?;
+    promote_store(&store, &args, sign_manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tempfile::TempDir;
+
+    use super::{promote_store, PromoteArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::verify::verify_block;
+    use crate::{Channel, Error, Manifest, Store};
+
+    #[test]
+    fn test_promote() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let manifest = serde_json::to_vec(&Manifest::default()).unwrap();
+        let digest = store.write_manifest(&manifest).unwrap();
+        let (public_key, staging) = signed_block(1, &[0; 64], 0, &digest);
+        store.write_tail("default", "staging", &staging).unwrap();
+
+        let key = b32enc(&public_key);
+        let args = PromoteArguments {
+            store_path: "",
+            key: &key,
+            project: "default",
+            from: "staging",
+            to: "stable",
+            channel_opt: None,
+            fork: false,
+        };
+
+        let sign = |data: &[u8]| -> io::Result<[u8; 400]> {
+            assert_eq!(data, manifest);
+            Ok(signed_block(1, &staging[..64].try_into().unwrap(), 1, &digest).1)
+        };
+        promote_store(&store, &args, sign).unwrap();
+
+        let stable = store.read_tail("default", "stable").unwrap().unwrap();
+        let verified = verify_block(&stable, &public_key).unwrap();
+        assert_eq!(verified.counter(), 1);
+        assert_eq!(*verified.digest(), *digest);
+
+        // Promoting again does not sign a new block
+        promote_store(&store, &args, |_: &[u8]| unreachable!()).unwrap();
+
+        // A block signed by another key is rejected
+        let args = PromoteArguments {
+            to: "other",
+            ..args
+        };
+        let sign = |_: &[u8]| Ok(signed_block(2, &[0; 64], 1, &digest).1);
+        assert!(matches!(
+            promote_store(&store, &args, sign),
+            Err(Error::Verify(_))
+        ));
+        assert!(store.read_tail("default", "other").unwrap().is_none());
+
+        // A rollout is signed as a new manifest with the same files
+        let channel = Channel {
+            rollout: 10,
+            cohorts: Vec::new(),
+        };
+        let args = PromoteArguments {
+            channel_opt: Some(channel.clone()),
+            ..args
+        };
+        let sign = |data: &[u8]| -> io::Result<[u8; 400]> {
+            let manifest: Manifest = serde_json::from_slice(data).unwrap();
+            assert_eq!(manifest.channel, Some(channel.clone()));
+            let key = store.write_object(data).unwrap();
+            Ok(signed_block(1, &[0; 64], 2, &key).1)
+        };
+        promote_store(&store, &args, sign).unwrap();
+        let other = store.read_tail("default", "other").unwrap().unwrap();
+        assert_ne!(
+            *verify_block(&other, &public_key).unwrap().digest(),
+            *digest
+        );
+
+        temp_dir.close().unwrap();
+    }
+}
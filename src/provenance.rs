@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::block::PackedBlock;
+use crate::id::{b32dec, b32enc};
+use crate::verify::PublicKey;
+use crate::{Block, Error, ObjectId};
+
+/// The name of the provenance file in a build archive
+pub const PROVENANCE_NAME: &str = "provenance.json";
+
+/// The signed block of a build and the key that signed it, stored in its archive
+///
+/// A build archive with provenance can be verified without the mirror it was published to,
+/// since the block binds the manifest of the archive to the key. The recorded key only says
+/// who claims to have signed it, so it must be checked against a trusted key.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Provenance {
+    pub project: String,
+    pub branch: String,
+    /// The base32 public key that signed the block
+    pub public_key: String,
+    /// The base32 signed block
+    pub block: String,
+}
+
+impl Provenance {
+    /// Record the signed block `data` of `project` and `branch`
+    pub fn new(project: &str, branch: &str, data: &[u8]) -> Result<Provenance, Error> {
+        let packed = PackedBlock::from_bytes(data).map_err(Error::Verify)?;
+        Ok(Provenance {
+            project: project.to_string(),
+            branch: branch.to_string(),
+            public_key: b32enc(packed.public_key()),
+            block: b32enc(data),
+        })
+    }
+
+    /// Read the provenance of the build directory `dir`, if it has one
+    pub fn read<P: AsRef<Path>>(dir: P) -> Result<Option<Provenance>, Error> {
+        match fs::read(dir.as_ref().join(PROVENANCE_NAME)) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|err| Error::Verify(format!("invalid {}: {}", PROVENANCE_NAME, err))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the provenance into the build directory `dir`
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), Error> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::from)?;
+        Ok(fs::write(dir.as_ref().join(PROVENANCE_NAME), json)?)
+    }
+
+    /// Verify that the block is signed by the trusted `key` and refers to the manifest with
+    /// `digest`
+    pub fn verify(&self, key: &str, digest: &ObjectId) -> Result<Block, Error> {
+        let decode = |key: &str| {
+            b32dec(key)
+                .and_then(|key| PublicKey::try_from(key.as_slice()).ok())
+                .ok_or_else(|| Error::Config(format!("{} is not a base32 public key", key)))
+        };
+        let recorded = decode(&self.public_key)?;
+        let key = decode(key)?;
+        if recorded != key {
+            return Err(Error::Verify(format!(
+                "provenance is signed by {}, not {}",
+                self.public_key,
+                b32enc(key.as_bytes())
+            )));
+        }
+
+        let data = b32dec(&self.block)
+            .ok_or_else(|| Error::Verify("provenance block not in base32 format".to_string()))?;
+        let block = PackedBlock::from_bytes(&data)
+            .map_err(Error::Verify)?
+            .verify(&key)
+            .map_err(|err| Error::Verify(format!("provenance block: {}", err)))?;
+        if block.digest != *digest {
+            return Err(Error::Verify(
+                "provenance block does not refer to manifest.json".to_string(),
+            ));
+        }
+        Ok(block)
+    }
+
+    /// Check the block against the key recorded with it and the manifest with `digest`
+    ///
+    /// This only shows the provenance is intact, anyone can sign an archive with their own
+    /// key, so the signer is not verified.
+    pub fn verify_recorded(&self, digest: &ObjectId) -> Result<Block, Error> {
+        self.verify(&self.public_key, digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::Provenance;
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::{Error, ObjectId};
+
+    #[test]
+    fn test_provenance() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        assert!(Provenance::read(temp_dir.path()).unwrap().is_none());
+
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &[1; 48]);
+        let provenance = Provenance::new("default", "master", &block).unwrap();
+        provenance.write(temp_dir.path()).unwrap();
+        let read = Provenance::read(temp_dir.path()).unwrap().unwrap();
+        assert_eq!(read, provenance);
+
+        let key = b32enc(&public_key);
+        let digest = ObjectId([1; 48]);
+        assert_eq!(read.verify_recorded(&digest).unwrap().public_key, key);
+        read.verify(&key, &digest).unwrap();
+
+        let (other, _) = signed_block(2, &[0; 64], 0, &[1; 48]);
+        assert!(matches!(
+            read.verify(&b32enc(&other), &digest),
+            Err(Error::Verify(_))
+        ));
+        assert!(matches!(
+            read.verify(&key, &ObjectId([2; 48])),
+            Err(Error::Verify(_))
+        ));
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::fs::{self, read_dir};
+use std::path::Path;
+
+use reqwest::StatusCode;
+use tokio::runtime;
+
+use crate::extract::extract_archive;
+use crate::{err_str, Auth, OciPublisher, Store, CAS_INDEX_PATH};
+
+pub struct PublishArguments<'a> {
+    pub source: &'a str,
+    pub url: &'a str,
+    pub token: &'a str,
+    pub cert_opt: Option<&'a str>,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// Write torrents for objects of at least this many bytes before uploading, with the
+    /// remote as a web seed, see [`crate::Torrent`]
+    pub torrent_min_size_opt: Option<u64>,
+    /// Write delta signatures for objects of at least this many bytes before uploading, see
+    /// [`crate::DeltaSignature`]
+    pub delta_min_size_opt: Option<u64>,
+}
+
+/// Uploads the contents of a store to a server started with `buildchain serve`
+pub struct Publisher {
+    url: reqwest::Url,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl Publisher {
+    pub fn new(url: &str, token: &str, cert_opt: Option<&[u8]>) -> Result<Publisher, String> {
+        let url = reqwest::Url::parse(url).map_err(err_str)?;
+
+        let client = {
+            let mut builder = reqwest::Client::builder();
+
+            if let Some(cert) = cert_opt {
+                builder = builder
+                    .add_root_certificate(reqwest::Certificate::from_pem(cert).map_err(err_str)?);
+            }
+
+            builder.build().map_err(err_str)?
+        };
+
+        Ok(Publisher {
+            url,
+            token: token.to_string(),
+            client,
+        })
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool, String> {
+        let url = self.url.join(path).map_err(err_str)?;
+        let response = self.client.head(url).send().await.map_err(err_str)?;
+        Ok(response.status().is_success())
+    }
+
+    async fn put(&self, path: &str, data: Vec<u8>) -> Result<(), String> {
+        let url = self.url.join(path).map_err(err_str)?;
+        let response = self
+            .client
+            .put(url)
+            .bearer_auth(&self.token)
+            .body(data)
+            .send()
+            .await
+            .map_err(err_str)?;
+
+        let status = response.status();
+        if status == StatusCode::CREATED || status.is_success() {
+            Ok(())
+        } else {
+            let message = response.text().await.unwrap_or_default();
+            Err(format!(
+                "failed to upload {}: {:?}: {}",
+                path, status, message
+            ))
+        }
+    }
+
+    /// Upload all objects, blocks, delta signatures, torrents, the CAS index, and tails in
+    /// `store`
+    ///
+    /// Objects are uploaded first and tails last, so that the server never has a tail
+    /// referencing a build that is not completely uploaded.
+    pub async fn publish(&self, store: &Store) -> Result<(), String> {
+        for kind in ["object", "block", "zsync"] {
+            let dir = store.path().join(kind);
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for entry in read_dir(dir).map_err(err_str)? {
+                let entry = entry.map_err(err_str)?;
+                let name = entry
+                    .file_name()
+                    .into_string()
+                    .map_err(|name| format!("{:?} is not UTF-8", name))?;
+
+                let path = format!("{}/{}", kind, name);
+                if self.exists(&path).await? {
+                    continue;
+                }
+
+                println!("Upload {}", path);
+                let data = fs::read(entry.path()).map_err(err_str)?;
+                self.put(&path, data).await?;
+            }
+        }
+
+        let torrent_dir = store.path().join("torrent");
+        if torrent_dir.is_dir() {
+            for entry in read_dir(torrent_dir).map_err(err_str)? {
+                let entry = entry.map_err(err_str)?;
+                let name = entry
+                    .file_name()
+                    .into_string()
+                    .map_err(|name| format!("{:?} is not UTF-8", name))?;
+
+                // Torrents never change, but the index does
+                let path = format!("torrent/{}", name);
+                if name != "index.json" && self.exists(&path).await? {
+                    continue;
+                }
+
+                println!("Upload {}", path);
+                let data = fs::read(entry.path()).map_err(err_str)?;
+                self.put(&path, data).await?;
+            }
+        }
+
+        let cas_index = store.path().join(CAS_INDEX_PATH);
+        if cas_index.is_file() {
+            println!("Upload {}", CAS_INDEX_PATH);
+            let data = fs::read(cas_index).map_err(err_str)?;
+            self.put(CAS_INDEX_PATH, data).await?;
+        }
+
+        for (project, branches) in store.tail_index().map_err(err_str)? {
+            for branch in branches {
+                let block = store
+                    .read_tail(&project, &branch)
+                    .map_err(err_str)?
+                    .ok_or_else(|| format!("tail/{}/{} not found", project, branch))?;
+
+                let path = format!("tail/{}/{}", project, branch);
+                println!("Upload {}", path);
+                self.put(&path, block.to_vec()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn publish(args: PublishArguments) -> Result<(), String> {
+    let cert_opt = match args.cert_opt {
+        Some(cert_path) => Some(fs::read(cert_path).map_err(err_str)?),
+        None => None,
+    };
+
+    let source = Path::new(args.source);
+    let temp_dir_opt = if source.is_file() {
+        Some(extract_archive(source).map_err(err_str)?)
+    } else {
+        None
+    };
+
+    let store = match &temp_dir_opt {
+        Some(temp_dir) => Store::new(temp_dir),
+        None => Store::new(source),
+    };
+
+    if let Some(min_size) = args.torrent_min_size_opt {
+        let written = store
+            .write_torrents(min_size, &[args.url.to_string()])
+            .map_err(err_str)?;
+        println!("buildchain: wrote {} torrents", written);
+    }
+    if let Some(min_size) = args.delta_min_size_opt {
+        let written = store.write_signatures(min_size).map_err(err_str)?;
+        println!("buildchain: wrote {} delta signatures", written);
+    }
+
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(err_str)?;
+
+    if args.url.starts_with("oci://") || args.url.starts_with("oci+http://") {
+        let auth = Auth::Bearer(args.token.to_string());
+        let publisher = OciPublisher::new(args.url, Some(auth), cert_opt.as_deref())?;
+        runtime.block_on(publisher.publish(&store, args.project, args.branch))?;
+    } else {
+        let publisher = Publisher::new(args.url, args.token, cert_opt.as_deref())?;
+        runtime.block_on(publisher.publish(&store))?;
+    }
+
+    println!("buildchain: published {} to {}", args.source, args.url);
+
+    Ok(())
+}
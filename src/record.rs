@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// A configured build or publish command, as recorded in a [`BuildRecord`]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CommandRecord {
+    pub command: Vec<String>,
+    /// The exit code of the command, which is always 0 in a stored record, since a failed
+    /// command fails the build
+    pub exit_code: i32,
+    /// The duration of the command in seconds
+    pub duration: f64,
+}
+
+/// The host that ran buildchain
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HostInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// The `PRETTY_NAME` from `/etc/os-release`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<String>,
+    pub arch: String,
+    pub cpus: usize,
+}
+
+#[cfg(feature = "build")]
+impl HostInfo {
+    /// Describe the current host, leaving out anything that cannot be read
+    pub fn current() -> HostInfo {
+        let read = |path: &str| {
+            std::fs::read_to_string(path)
+                .ok()
+                .map(|text| text.trim().to_string())
+                .filter(|text| !text.is_empty())
+        };
+        let os = read("/etc/os-release").and_then(|text| {
+            text.lines()
+                .find_map(|line| line.strip_prefix("PRETTY_NAME="))
+                .map(|name| name.trim_matches('"').to_string())
+        });
+        HostInfo {
+            hostname: read("/proc/sys/kernel/hostname"),
+            os,
+            kernel: read("/proc/sys/kernel/osrelease"),
+            arch: std::env::consts::ARCH.to_string(),
+            cpus: std::thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+        }
+    }
+}
+
+/// How a build ran, stored as an object referenced by [`crate::Manifest::report`]
+///
+/// Records are kept in the chain so that the history of a branch can be analyzed from the
+/// store alone. Since durations and hosts change between builds, a manifest that refers to a
+/// record is not reproducible, so records are only stored when requested, see
+/// [`crate::BuildOptions::store_report`].
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BuildRecord {
+    pub name: String,
+    /// The durations of the stages before the manifest was written, in seconds
+    pub stages: BTreeMap<String, f64>,
+    pub commands: Vec<CommandRecord>,
+    /// The total size of the artifacts in the manifest, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifacts_size: Option<u64>,
+    pub host: HostInfo,
+    /// The executor of the commands, only `lxd` is supported
+    pub executor: String,
+    /// The LXD remote the commands ran on, if not on the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildRecord, CommandRecord, HostInfo};
+
+    #[cfg(feature = "build")]
+    #[test]
+    fn test_host_info() {
+        let host = HostInfo::current();
+        assert_eq!(host.arch, std::env::consts::ARCH);
+        assert!(host.cpus >= 1);
+    }
+
+    #[test]
+    fn test_record() {
+        let record = BuildRecord {
+            name: "test".to_string(),
+            stages: [("build".to_string(), 1.5)].into(),
+            commands: vec![CommandRecord {
+                command: vec!["make".to_string()],
+                exit_code: 0,
+                duration: 1.25,
+            }],
+            artifacts_size: Some(4096),
+            host: HostInfo {
+                arch: "x86_64".to_string(),
+                cpus: 4,
+                ..Default::default()
+            },
+            executor: "lxd".to_string(),
+            remote: None,
+        };
+        let json = serde_json::to_value(&record).unwrap();
+        assert!(json.get("remote").is_none());
+        assert_eq!(json["commands"][0]["command"][0], "make");
+        let read: BuildRecord = serde_json::from_value(json).unwrap();
+        assert_eq!(read, record);
+    }
+}
@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::{xml_escape, Error, ObjectId, ScanVerdict};
+
+/// The result of a build stage
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StageStatus {
+    Passed,
+    Failed,
+}
+
+/// A build stage, as recorded in a [`BuildReport`]
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stage {
+    pub name: String,
+    pub status: StageStatus,
+    /// The duration of the stage in seconds
+    pub duration: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A summary of a build, for CI systems to display without parsing the build log
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct BuildReport {
+    pub name: String,
+    pub project: String,
+    pub branch: String,
+    pub stages: Vec<Stage>,
+    /// The artifacts and their digests
+    pub artifacts: BTreeMap<String, ObjectId>,
+    /// The digest of the manifest
+    pub manifest: Option<ObjectId>,
+    /// The verdicts of the scanners that checked the artifacts before signing
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub scans: Vec<ScanVerdict>,
+}
+
+impl BuildReport {
+    pub fn new(project: &str, branch: &str) -> BuildReport {
+        BuildReport {
+            project: project.to_string(),
+            branch: branch.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Run the stage `name`, recording its status and duration
+    pub fn stage<T, F: FnOnce() -> Result<T, Error>>(
+        &mut self,
+        name: &str,
+        f: F,
+    ) -> Result<T, Error> {
+        let start = Instant::now();
+        let result = f();
+        self.stages.push(Stage {
+            name: name.to_string(),
+            status: if result.is_ok() {
+                StageStatus::Passed
+            } else {
+                StageStatus::Failed
+            },
+            duration: start.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+        });
+        result
+    }
+
+    /// Format the report as JUnit XML, with a test case for each stage
+    pub fn to_junit(&self) -> String {
+        let failures = self
+            .stages
+            .iter()
+            .filter(|stage| stage.status == StageStatus::Failed)
+            .count();
+        let time: f64 = self.stages.iter().map(|stage| stage.duration).sum();
+
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!(
+            "<testsuites name=\"buildchain\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            self.stages.len(),
+            failures,
+            time
+        ));
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}/{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+            xml_escape(&self.project),
+            xml_escape(&self.branch),
+            self.stages.len(),
+            failures,
+            time
+        ));
+        for stage in self.stages.iter() {
+            xml.push_str(&format!(
+                "    <testcase classname=\"buildchain.{}\" name=\"{}\" time=\"{:.3}\"",
+                xml_escape(&self.name),
+                xml_escape(&stage.name),
+                stage.duration
+            ));
+            match &stage.error {
+                Some(error) => xml.push_str(&format!(
+                    ">\n      <failure message=\"{}\"/>\n    </testcase>\n",
+                    xml_escape(error)
+                )),
+                None => xml.push_str("/>\n"),
+            }
+        }
+
+        let mut out = String::new();
+        if let Some(manifest) = &self.manifest {
+            out.push_str(&format!("manifest {}\n", manifest));
+        }
+        for (name, digest) in self.artifacts.iter() {
+            out.push_str(&format!("{} {}\n", digest, name));
+        }
+        for scan in self.scans.iter() {
+            out.push_str(&format!(
+                "scan {} {}: {}\n",
+                scan.scanner,
+                if scan.passed { "passed" } else { "failed" },
+                scan.message
+            ));
+        }
+        xml.push_str(&format!(
+            "    <system-out>{}</system-out>\n",
+            xml_escape(&out)
+        ));
+
+        xml.push_str("  </testsuite>\n");
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+
+    /// Write the report to `path`, as JSON if it ends with `.json` and as JUnit XML otherwise
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let data = if path.extension() == Some(OsStr::new("json")) {
+            serde_json::to_vec_pretty(self)?
+        } else {
+            self.to_junit().into_bytes()
+        };
+        fs::write(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildReport, StageStatus};
+    use crate::{Error, ScanVerdict};
+
+    #[test]
+    fn test_report() {
+        let mut report = BuildReport::new("default", "master");
+        report.name = "example".to_string();
+        report.stage("source", || Ok(())).unwrap();
+        report
+            .stage::<(), _>("build", || Err(Error::Config("exit <1>".to_string())))
+            .unwrap_err();
+
+        assert_eq!(report.stages[0].status, StageStatus::Passed);
+        assert_eq!(report.stages[1].status, StageStatus::Failed);
+        assert_eq!(report.stages[1].error.as_deref(), Some("exit <1>"));
+
+        let junit = report.to_junit();
+        assert!(junit.contains("tests=\"2\" failures=\"1\""));
+        assert!(junit.contains("<testcase classname=\"buildchain.example\" name=\"source\""));
+        assert!(junit.contains("<failure message=\"exit &lt;1&gt;\"/>"));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["stages"][1]["status"], "failed");
+        assert!(json["stages"][0].get("error").is_none());
+        assert!(json.get("scans").is_none());
+
+        report.scans.push(ScanVerdict {
+            scanner: "secrets".to_string(),
+            passed: false,
+            message: "found <key>".to_string(),
+        });
+        assert!(report
+            .to_junit()
+            .contains("scan secrets failed: found &lt;key&gt;"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["scans"][0]["passed"], false);
+    }
+}
@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use lxd::{Image, Location};
+
+use crate::build::{command_env, command_prefix, in_environment, SOURCE_PATH};
+use crate::{Config, Error};
+
+/// The name of the captured environment in a build archive
+pub const REPRODUCTION_NAME: &str = "reproduce.json";
+
+/// The name of the script generated from the captured environment
+pub const REPRODUCE_SCRIPT_NAME: &str = "reproduce.sh";
+
+/// Everything needed to run the commands of a build again, captured while it ran
+///
+/// It is stored as `reproduce.json` in the build archive, next to `buildinfo.json`, along with
+/// a standalone `reproduce.sh` generated from it. Neither is in the manifest, since the image
+/// fingerprint changes as the base image is updated.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Reproduction {
+    pub name: String,
+    /// The executor of the commands, only `lxd` is supported
+    pub executor: String,
+    /// The LXC base from the build configuration
+    pub base: String,
+    /// The fingerprint of the base image when the build ran, as `remote:fingerprint`, if it
+    /// could be found
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    pub privileged: bool,
+    /// The timestamp of the source control revision
+    pub source_time: u64,
+    /// The environment variables of the build and publish commands, as `NAME=VALUE`
+    pub env: Vec<String>,
+    /// The owner of the source and artifacts, as `uid:gid`, if the commands did not run as root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub prepare: Vec<Vec<String>>,
+    /// The build commands, as they were run in the container
+    pub build: Vec<Vec<String>>,
+    /// The publish commands, as they were run in the container
+    pub publish: Vec<Vec<String>>,
+}
+
+/// Quote `arg` for a POSIX shell
+fn quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"%+,-./:=@_".contains(&c))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Look up the fingerprint of the base image, which may be on a remote such as `ubuntu:`
+fn base_fingerprint(base: &str) -> Option<String> {
+    match base.split_once(':') {
+        Some((remote, alias)) => Image::new(Location::Remote(remote.to_string()), alias)
+            .ok()
+            .map(|image| format!("{}:{}", remote, image.fingerprint)),
+        None => Image::new(Location::Local, base)
+            .ok()
+            .map(|image| image.fingerprint),
+    }
+}
+
+impl Reproduction {
+    /// Capture the commands of `config` as they run at `source_time`, without the image
+    pub(crate) fn new(config: &Config, source_time: u64) -> Reproduction {
+        let prefix = command_prefix(config, source_time);
+        let commands = |commands: &[Vec<String>]| {
+            commands
+                .iter()
+                .map(|command| {
+                    in_environment(config, &prefix, command)
+                        .into_iter()
+                        .map(String::from)
+                        .collect()
+                })
+                .collect()
+        };
+        Reproduction {
+            name: config.name.clone(),
+            executor: "lxd".to_string(),
+            base: config.base.clone(),
+            image: None,
+            privileged: config.privileged,
+            source_time,
+            env: command_env(config, source_time),
+            owner: config
+                .user
+                .as_ref()
+                .map(|user| format!("{}:{}", user.uid, user.gid)),
+            prepare: config.prepare.clone(),
+            build: commands(&config.build),
+            publish: commands(&config.publish),
+        }
+    }
+
+    /// Capture the commands of `config`, and the fingerprint of its base image
+    pub(crate) fn capture(config: &Config, source_time: u64) -> Reproduction {
+        let mut reproduction = Reproduction::new(config, source_time);
+        reproduction.image = base_fingerprint(&config.base);
+        reproduction
+    }
+
+    /// Read `reproduce.json` from the build directory `dir`, if there is one
+    pub fn read<P: AsRef<Path>>(dir: P) -> Result<Option<Reproduction>, Error> {
+        match fs::read(dir.as_ref().join(REPRODUCTION_NAME)) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|err| Error::Config(format!("invalid {}: {}", REPRODUCTION_NAME, err))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write `reproduce.json` and `reproduce.sh` into the directory `dir`
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::from)?;
+        fs::write(dir.join(REPRODUCTION_NAME), json)?;
+
+        let script_path = dir.join(REPRODUCE_SCRIPT_NAME);
+        fs::write(&script_path, self.script())?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    /// A shell script that runs the commands in a new LXD container, given the source
+    /// directory, and pulls the artifacts into the current directory
+    pub fn script(&self) -> String {
+        let exec = |args: &[String]| {
+            let args: Vec<String> = args.iter().map(|arg| quote(arg)).collect();
+            format!("lxc exec \"$CONTAINER\" -- {}\n", args.join(" "))
+        };
+
+        let mut script = String::new();
+        script.push_str("#!/bin/sh\n");
+        script.push_str(&format!(
+            "# Reproduce the build of {} at source time {}\n",
+            self.name, self.source_time
+        ));
+        script.push_str(&format!(
+            "# Usage: {} SOURCE_DIR, the artifacts are pulled into ./artifacts\n",
+            REPRODUCE_SCRIPT_NAME
+        ));
+        script.push_str("set -ex\n\n");
+        script.push_str(&format!(
+            "SOURCE=\"${{1:?usage: {} SOURCE_DIR}}\"\n",
+            REPRODUCE_SCRIPT_NAME
+        ));
+        script.push_str(&format!(
+            "CONTAINER={}\n",
+            quote(&format!("buildchain-{}-reproduce", self.name))
+        ));
+        match &self.image {
+            Some(image) => {
+                script.push_str(&format!("# The base was {}\n", self.base));
+                script.push_str(&format!("IMAGE={}\n\n", quote(image)));
+            }
+            None => script.push_str(&format!("IMAGE={}\n\n", quote(&self.base))),
+        }
+
+        if self.privileged {
+            script.push_str(
+                "lxc launch --config security.privileged=true \"$IMAGE\" \"$CONTAINER\"\n",
+            );
+        } else {
+            script.push_str("lxc launch \"$IMAGE\" \"$CONTAINER\"\n");
+        }
+        for command in self.prepare.iter() {
+            script.push_str(&exec(command));
+        }
+
+        script.push('\n');
+        script.push_str(&exec(&["mkdir".to_string(), SOURCE_PATH.to_string()]));
+        script.push_str(&format!(
+            "tar --create --directory \"$SOURCE\" . | lxc exec \"$CONTAINER\" -- tar --extract --directory {}\n",
+            SOURCE_PATH
+        ));
+        if let Some(owner) = &self.owner {
+            script.push_str(&exec(&["chmod", "0755", "/root"].map(String::from)));
+            script.push_str(&exec(&[
+                "chown".to_string(),
+                "--recursive".to_string(),
+                owner.clone(),
+                SOURCE_PATH.to_string(),
+            ]));
+        }
+
+        script.push('\n');
+        for command in self.build.iter() {
+            script.push_str(&exec(command));
+        }
+        script.push_str(&exec(&["mkdir", "/root/artifacts"].map(String::from)));
+        if let Some(owner) = &self.owner {
+            script.push_str(&exec(&[
+                "chown".to_string(),
+                owner.clone(),
+                "/root/artifacts".to_string(),
+            ]));
+        }
+        for command in self.publish.iter() {
+            script.push_str(&exec(command));
+        }
+
+        script.push('\n');
+        script.push_str("lxc file pull --recursive \"$CONTAINER/root/artifacts\" .\n");
+        script
+    }
+}
+
+/// Arguments of [`env_capture`]
+#[cfg(feature = "download")]
+pub struct EnvCaptureArguments<'a> {
+    /// Build archive or store directory
+    pub path: &'a str,
+    /// Directory to write `reproduce.json` and `reproduce.sh` to
+    pub dest: &'a str,
+}
+
+/// Write the environment captured by a build, from its archive or store directory, as a
+/// standalone `reproduce.json` and `reproduce.sh` in `dest`
+#[cfg(feature = "download")]
+pub fn env_capture(args: EnvCaptureArguments) -> Result<(), Error> {
+    let path = Path::new(args.path);
+    let reproduction = if path.is_file() {
+        let temp_dir = crate::extract::extract_archive(path)?;
+        let reproduction = Reproduction::read(&temp_dir)?;
+        temp_dir.close()?;
+        reproduction
+    } else {
+        Reproduction::read(path)?
+    }
+    .ok_or_else(|| {
+        Error::NotFound(format!(
+            "{} has no captured environment, {}",
+            args.path, REPRODUCTION_NAME
+        ))
+    })?;
+
+    fs::create_dir_all(args.dest)?;
+    reproduction.write(args.dest)?;
+    println!(
+        "buildchain: wrote {} and {} to {}",
+        REPRODUCTION_NAME, REPRODUCE_SCRIPT_NAME, args.dest
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::{quote, Reproduction, REPRODUCE_SCRIPT_NAME};
+    use crate::{Config, User};
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("make"), "make");
+        assert_eq!(quote("HOME=/root"), "HOME=/root");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_reproduction() {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "name": "test", "base": "ubuntu:22.04", "prepare": [["apt", "update"]],
+                "build": [["make", "all"]], "publish": [["cp", "out", "/root/artifacts"]]
+            }"#,
+        )
+        .unwrap();
+        config.user = Some(User {
+            uid: 1000,
+            gid: 100,
+        });
+
+        let mut reproduction = Reproduction::new(&config, 42);
+        assert_eq!(reproduction.env[3], "SOURCE_DATE_EPOCH=42");
+        assert_eq!(reproduction.owner.as_deref(), Some("1000:100"));
+        assert_eq!(reproduction.build[0][0], "setpriv");
+        assert_eq!(
+            reproduction.build[0][reproduction.build[0].len() - 2..],
+            ["make", "all"]
+        );
+
+        let script = reproduction.script();
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("IMAGE=ubuntu:22.04\n"));
+        assert!(script.contains("lxc exec \"$CONTAINER\" -- apt update\n"));
+        assert!(script.contains("chown --recursive 1000:100 /root/source\n"));
+        assert!(script.contains(
+            "SOURCE_DATE_EPOCH=42 sh -c 'umask \"$1\" && shift && exec \"$@\"' sh 022 make all\n"
+        ));
+
+        reproduction.image = Some("ubuntu:abc123".to_string());
+        assert!(reproduction.script().contains("IMAGE=ubuntu:abc123\n"));
+
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        assert_eq!(Reproduction::read(temp_dir.path()).unwrap(), None);
+        reproduction.write(temp_dir.path()).unwrap();
+        assert_eq!(
+            Reproduction::read(temp_dir.path()).unwrap(),
+            Some(reproduction.clone())
+        );
+        assert_eq!(
+            std::fs::read_to_string(temp_dir.path().join(REPRODUCE_SCRIPT_NAME)).unwrap(),
+            reproduction.script()
+        );
+    }
+}
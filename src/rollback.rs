@@ -0,0 +1,183 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::io;
+
+use crate::id::b32dec;
+use crate::verify::verify_block;
+use crate::{err_str, sign_manifest, BlockPin, Downloader, Error, Manifest, Rollback, Store};
+
+pub struct RollbackArguments<'a> {
+    pub store_path: &'a str,
+    pub key: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// The counter of the earlier block to restore
+    pub to_counter: u64,
+}
+
+/// Restore the build of the block of `args.branch` with `args.to_counter`, using `sign` to
+/// produce the new tail
+///
+/// The earlier block is looked up in the [`crate::BlockIndex`], or found by walking back from
+/// the tail if it is not indexed, and must be signed by `args.key`, as must the new block.
+pub(crate) fn rollback_store<F>(
+    store: &Store,
+    args: &RollbackArguments,
+    sign: F,
+) -> Result<(), Error>
+where
+    F: FnOnce(&[u8]) -> io::Result<[u8; 400]>,
+{
+    let key: [u8; 32] = b32dec(args.key)
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::Config("key is not a base32 public key".to_string()))?;
+
+    let dl = Downloader::from_store(args.key, args.project, args.branch, store)?;
+    let tail = dl.tail()?;
+    if args.to_counter >= tail.counter {
+        return Err(Error::Config(format!(
+            "tail/{}/{} has counter {}, which is not after counter {}",
+            args.project, args.branch, tail.counter, args.to_counter
+        )));
+    }
+
+    let target = dl.find_block(&BlockPin::Counter(args.to_counter))?;
+
+    // The manifest is verified against its digest before it is signed again
+    let mut manifest = serde_json::from_slice::<Manifest>(&dl.object(&target.digest)?)
+        .map_err(|err| Error::Verify(err_str(err)))?;
+    manifest.rollback = Some(Rollback {
+        from: tail.signature,
+        to: target.signature,
+        counter: target.counter,
+    });
+    let manifest_json = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+    let digest = store.write_object(&manifest_json)?;
+
+    let response = sign(&manifest_json).map_err(Error::Sign)?;
+    let verified = verify_block(&response, &key)
+        .map_err(|err| Error::Verify(format!("rollback block: {}", err)))?;
+    if *verified.digest() != *digest {
+        return Err(Error::Verify(
+            "rollback block does not refer to the manifest".to_string(),
+        ));
+    }
+    if verified.counter() <= tail.counter {
+        return Err(Error::Verify(format!(
+            "rollback block has counter {}, which is not after the tail counter {}",
+            verified.counter(),
+            tail.counter
+        )));
+    }
+
+    store.write_tail(args.project, args.branch, &response)?;
+    println!(
+        "buildchain: rolled tail/{}/{} back to the build of counter {}, with counter {}",
+        args.project,
+        args.branch,
+        target.counter,
+        verified.counter()
+    );
+    Ok(())
+}
+
+/// Pull the tail of a branch by republishing an earlier build of it
+///
+/// The manifest of the earlier block is signed with PiHSM, recording the rollback, and the new
+/// block becomes the tail, so clients that follow the counter install the earlier build.
+pub fn rollback(args: RollbackArguments) -> Result<(), Error> {
+    let store = Store::open(args.store_path)?;
+    rollback_store(&store, &args, sign_manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use tempfile::TempDir;
+
+    use super::{rollback_store, RollbackArguments};
+    use crate::block::tests::signed_block;
+    use crate::id::b32enc;
+    use crate::verify::verify_block;
+    use crate::{BlockIndex, Error, Manifest, Store};
+
+    #[test]
+    fn test_rollback() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+
+        let good = Manifest {
+            time: 1,
+            ..Default::default()
+        };
+        let good_digest = store
+            .write_object(&serde_json::to_vec(&good).unwrap())
+            .unwrap();
+        let (public_key, first) = signed_block(1, &[0; 64], 0, &good_digest);
+        store.write_tail("default", "stable", &first).unwrap();
+        let bad_digest = store
+            .write_object(&serde_json::to_vec(&Manifest::default()).unwrap())
+            .unwrap();
+        let (_, second) = signed_block(1, first[..64].try_into().unwrap(), 1, &bad_digest);
+        store.write_tail("default", "stable", &second).unwrap();
+
+        let key = b32enc(&public_key);
+        let args = RollbackArguments {
+            store_path: "",
+            key: &key,
+            project: "default",
+            branch: "stable",
+            to_counter: 0,
+        };
+
+        let sign = |data: &[u8]| -> io::Result<[u8; 400]> {
+            let manifest: Manifest = serde_json::from_slice(data).unwrap();
+            let rollback = manifest.rollback.clone().unwrap();
+            assert_eq!(rollback.from.0[..], second[..64]);
+            assert_eq!(rollback.to.0[..], first[..64]);
+            assert_eq!(
+                Manifest {
+                    rollback: None,
+                    ..manifest
+                },
+                good
+            );
+            let digest = store.write_object(data).unwrap();
+            Ok(signed_block(1, second[..64].try_into().unwrap(), 2, &digest).1)
+        };
+        rollback_store(&store, &args, sign).unwrap();
+
+        let tail = store.read_tail("default", "stable").unwrap().unwrap();
+        assert_eq!(verify_block(&tail, &public_key).unwrap().counter(), 2);
+        let index = BlockIndex::read(&store).unwrap();
+        assert_eq!(index.latest("default", "stable").unwrap().counter, 2);
+
+        // Rolling forward is refused before signing
+        let args = RollbackArguments {
+            to_counter: 2,
+            ..args
+        };
+        assert!(matches!(
+            rollback_store(&store, &args, |_: &[u8]| unreachable!()),
+            Err(Error::Config(_))
+        ));
+
+        // A block that does not advance the counter is rejected
+        let args = RollbackArguments {
+            to_counter: 1,
+            ..args
+        };
+        let sign = |data: &[u8]| {
+            let digest = store.write_object(data).unwrap();
+            Ok(signed_block(1, &[0; 64], 2, &digest).1)
+        };
+        assert!(matches!(
+            rollback_store(&store, &args, sign),
+            Err(Error::Verify(_))
+        ));
+        assert_eq!(store.read_tail("default", "stable").unwrap(), Some(tail));
+
+        temp_dir.close().unwrap();
+    }
+}
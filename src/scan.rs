@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Checks of the artifacts of a build before they are signed
+//!
+//! A [`Scanner`] added with [`crate::BuildOptions::scanner`] sees the artifact directory after
+//! the build and publish commands, and may veto signing, for example to look for secrets,
+//! malware, or licenses. Each verdict is recorded in the [`crate::BuildReport`].
+
+use serde::Serialize;
+use std::fmt::Debug;
+use std::path::Path;
+use std::process::Command;
+
+/// Decides whether the artifacts of a build may be signed
+pub trait Scanner: Debug + Send + Sync {
+    /// The name of the scanner in the build report
+    fn name(&self) -> String;
+
+    /// Scan the artifact directory `artifacts`
+    ///
+    /// Returns a summary of the scan if the artifacts may be signed, and the reason as an error
+    /// to veto signing.
+    fn scan(&self, artifacts: &Path) -> Result<String, String>;
+}
+
+/// A scanner that runs a shell command, with the artifact directory as `$1`
+///
+/// The command allows signing if it exits successfully. Its output is the summary or reason.
+#[derive(Clone, Debug)]
+pub struct CommandScanner {
+    command: String,
+}
+
+impl CommandScanner {
+    pub fn new(command: &str) -> CommandScanner {
+        CommandScanner {
+            command: command.to_string(),
+        }
+    }
+}
+
+impl Scanner for CommandScanner {
+    fn name(&self) -> String {
+        self.command.clone()
+    }
+
+    fn scan(&self, artifacts: &Path) -> Result<String, String> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .arg("sh")
+            .arg(artifacts)
+            .output()
+            .map_err(|err| format!("failed to run: {}", err))?;
+
+        let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+        text.push_str(&String::from_utf8_lossy(&output.stderr));
+        let text = text.trim().to_string();
+        if output.status.success() {
+            Ok(text)
+        } else if text.is_empty() {
+            Err(format!("exited with {}", output.status))
+        } else {
+            Err(text)
+        }
+    }
+}
+
+/// The verdict of a [`Scanner`], as recorded in a [`crate::BuildReport`]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ScanVerdict {
+    pub scanner: String,
+    /// Whether the scanner allowed signing
+    pub passed: bool,
+    /// The summary of the scan, or the reason signing was vetoed
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{CommandScanner, Scanner};
+
+    #[test]
+    fn test_command_scanner() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        fs::write(temp_dir.path().join("clean.txt"), "clean").unwrap();
+
+        let scanner = CommandScanner::new("! grep -rl SECRET \"$1\" && echo clean");
+        assert_eq!(scanner.name(), "! grep -rl SECRET \"$1\" && echo clean");
+        assert_eq!(scanner.scan(temp_dir.path()), Ok("clean".to_string()));
+
+        fs::write(temp_dir.path().join("leak.txt"), "SECRET").unwrap();
+        let reason = scanner.scan(temp_dir.path()).unwrap_err();
+        assert!(reason.ends_with("leak.txt"), "{}", reason);
+
+        assert_eq!(
+            CommandScanner::new("exit 3").scan(temp_dir.path()),
+            Err("exited with exit status: 3".to_string())
+        );
+    }
+}
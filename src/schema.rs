@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! JSON Schemas of the documents buildchain reads and writes, generated from the Rust types so
+//! that validators and code generators in other languages stay in sync with them
+
+use schemars::schema_for;
+use std::str::FromStr;
+
+use crate::{Block, Config, Manifest};
+
+/// A JSON document with a schema
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SchemaKind {
+    /// The build configuration, `buildchain.json`
+    Config,
+    /// The manifest of build artifacts
+    Manifest,
+    /// The JSON form of a block, as printed by `inspect`
+    Block,
+}
+
+impl FromStr for SchemaKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SchemaKind, String> {
+        match s {
+            "config" => Ok(SchemaKind::Config),
+            "manifest" => Ok(SchemaKind::Manifest),
+            "block" => Ok(SchemaKind::Block),
+            _ => Err(format!("unknown schema: {}", s)),
+        }
+    }
+}
+
+/// The JSON Schema of `kind`, as pretty JSON
+pub fn json_schema(kind: SchemaKind) -> String {
+    let schema = match kind {
+        SchemaKind::Config => schema_for!(Config),
+        SchemaKind::Manifest => schema_for!(Manifest),
+        SchemaKind::Block => schema_for!(Block),
+    };
+    serde_json::to_string_pretty(&schema).expect("failed to serialize schema")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{json_schema, SchemaKind};
+    use crate::Sha384;
+
+    fn schema(kind: SchemaKind) -> Value {
+        serde_json::from_str(&json_schema(kind)).unwrap()
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let config = schema(SchemaKind::Config);
+        assert_eq!(config["title"], "Config");
+        assert_eq!(
+            config["required"],
+            serde_json::json!(["base", "build", "name", "prepare", "publish"])
+        );
+        assert!(config["properties"]["process"].is_object());
+
+        let manifest = schema(SchemaKind::Manifest);
+        assert_eq!(manifest["required"], serde_json::json!(["files", "time"]));
+        assert_eq!(
+            manifest["properties"]["files"]["additionalProperties"]["$ref"],
+            "#/definitions/ObjectId"
+        );
+
+        // The digest pattern matches both forms of a real digest
+        let block = schema(SchemaKind::Block);
+        let pattern = block["definitions"]["ObjectId"]["pattern"]
+            .as_str()
+            .unwrap();
+        let id = Sha384::new(&b"schema"[..]).unwrap().to_id();
+        assert_eq!(
+            pattern,
+            format!(
+                "^([A-Z2-7]{{{}}}|[0-9A-Fa-f]{{{}}})$",
+                id.to_string().len(),
+                id.to_hex().len()
+            )
+        );
+        assert!(block["definitions"]["BlockSig"]["pattern"]
+            .as_str()
+            .unwrap()
+            .contains("{103}"));
+    }
+}
@@ -0,0 +1,899 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+use crate::block::PackedBlock;
+use crate::id::b32dec;
+use crate::metrics::Metrics;
+use crate::store::object_key;
+use crate::verify::{constant_time_eq, PublicKey};
+use crate::{
+    err_str, Block, BlockSig, DeltaSignature, Error, Manifest, ObjectId, Sha384, Store,
+    StorePermissions, TailEvent, Webhook,
+};
+
+/// Objects and blocks are named by their contents, so they can be cached forever
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Tails and the index change with each build, so caches must revalidate them
+const MUTABLE: &str = "no-cache";
+
+/// The largest index or torrent that can be uploaded, such as `cas/index.json`
+const MAX_INDEX_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The number of threads handling requests
+const WORKERS: usize = 8;
+
+/// How long a connection can stall reading a request or writing a response
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The largest object that can be uploaded, unless changed with [`Server::limit_uploads`]
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+pub struct ServeArguments<'a> {
+    pub store_path: &'a str,
+    /// Permissions of the files uploaded to the store
+    pub permissions: StorePermissions,
+    pub address: &'a str,
+    pub key_opt: Option<&'a str>,
+    pub token_opt: Option<&'a str>,
+    /// The largest object that can be uploaded, [`DEFAULT_MAX_UPLOAD_SIZE`] by default
+    pub max_upload_size_opt: Option<u64>,
+    pub webhooks: Vec<&'a str>,
+}
+
+/// The credentials required to upload to a server
+struct Upload {
+    key: PublicKey,
+    token: String,
+}
+
+/// Serves a [`Store`] over HTTP, in the layout expected by [`crate::Downloader`]
+///
+/// Prometheus metrics are served at `/metrics`. The namespaces of the store are served under
+/// `/namespace/<name>/`, but uploads are only accepted to the store itself. Requests are
+/// handled by a fixed pool of threads, and connections that stall are closed, so a slow client
+/// cannot hold up others.
+pub struct Server {
+    server: tiny_http::Server,
+    store: Store,
+    upload_opt: Option<Upload>,
+    max_upload_size: u64,
+    webhooks: Vec<Webhook>,
+    metrics: Metrics,
+    stopped: AtomicBool,
+}
+
+/// An error response to a request
+struct Rejection(u16, String);
+
+impl Rejection {
+    fn new<S: Into<String>>(status: u16, message: S) -> Rejection {
+        Rejection(status, message.into())
+    }
+}
+
+impl From<io::Error> for Rejection {
+    fn from(err: io::Error) -> Rejection {
+        Rejection(500, err_str(err))
+    }
+}
+
+impl From<Error> for Rejection {
+    fn from(err: Error) -> Rejection {
+        Rejection(500, err.to_string())
+    }
+}
+
+/// Check that `url` is a file a mirror serves, returning its path relative to the store
+///
+/// Files of a namespace are served under `/namespace/<name>/`, see [`Store::namespace`].
+fn resolve(url: &str) -> Option<(PathBuf, bool)> {
+    let path = url.split('?').next()?.trim_start_matches('/');
+
+    let parts: Vec<&str> = path.split('/').collect();
+    if parts
+        .iter()
+        .any(|part| part.is_empty() || *part == "." || *part == ".." || part.contains('\\'))
+    {
+        return None;
+    }
+
+    match parts.as_slice() {
+        ["namespace", name, rest @ ..] if !rest.is_empty() && !name.starts_with('.') => {
+            let (relpath, immutable) = resolve_file(&rest.join("/"), rest)?;
+            Some((
+                PathBuf::from("namespace").join(name).join(relpath),
+                immutable,
+            ))
+        }
+        _ => resolve_file(path, &parts),
+    }
+}
+
+/// Resolve the file at `path`, split into `parts`, within one namespace
+fn resolve_file(path: &str, parts: &[&str]) -> Option<(PathBuf, bool)> {
+    match parts {
+        ["torrent", "index.json"] => Some((PathBuf::from(path), false)),
+        // Hex names are accepted for tools that do not speak base32, and map to the same files
+        ["object", digest] => match digest.parse::<ObjectId>() {
+            Ok(key) => Some((PathBuf::from(format!("object/{}", key)), true)),
+            Err(_) => Some((PathBuf::from(path), true)),
+        },
+        ["block", signature] => match signature.parse::<BlockSig>() {
+            Ok(sig) => Some((PathBuf::from(format!("block/{}", sig)), true)),
+            Err(_) => Some((PathBuf::from(path), true)),
+        },
+        ["torrent", _] | ["zsync", _] => Some((PathBuf::from(path), true)),
+        ["tail", "index.json"]
+        | ["tail", _, _]
+        | ["attestation", _, _]
+        | ["annotation", _]
+        | ["cas", "index.json"] => Some((PathBuf::from(path), false)),
+        _ => None,
+    }
+}
+
+/// Set the read and write timeouts of `listener`, which the connections it accepts inherit
+fn set_timeouts(listener: &TcpListener, timeout: Duration) -> io::Result<()> {
+    let timeval = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    for option in [libc::SO_RCVTIMEO, libc::SO_SNDTIMEO] {
+        let result = unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                &timeval as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("invalid header")
+}
+
+impl Server {
+    /// Create a server for `store`, listening on `address`
+    pub fn new<A: AsRef<str>>(store: Store, address: A) -> Result<Server, String> {
+        let listener = TcpListener::bind(address.as_ref())
+            .map_err(|err| format!("failed to listen on {}: {}", address.as_ref(), err))?;
+        set_timeouts(&listener, TIMEOUT).map_err(err_str)?;
+        let server = tiny_http::Server::from_listener(listener, None)
+            .map_err(|err| format!("failed to listen on {}: {}", address.as_ref(), err))?;
+        Ok(Server {
+            server,
+            store,
+            upload_opt: None,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            webhooks: Vec::new(),
+            metrics: Metrics::default(),
+            stopped: AtomicBool::new(false),
+        })
+    }
+
+    /// Accept uploads with `Authorization: Bearer <token>`
+    ///
+    /// Blocks and tails are only accepted if they are signed by the base32 public `key`, and
+    /// objects only if their contents match their digest.
+    pub fn allow_upload(&mut self, key: &str, token: &str) -> Result<(), String> {
+        let key = b32dec(key).ok_or_else(|| "key not in base32 format".to_string())?;
+        let key = PublicKey::try_from(key.as_slice())
+            .map_err(|_| format!("key is {} bytes, expected 32 bytes", key.len()))?;
+        self.upload_opt = Some(Upload {
+            key,
+            token: token.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Refuse objects larger than `max_size` bytes
+    pub fn limit_uploads(&mut self, max_size: u64) {
+        self.max_upload_size = max_size;
+    }
+
+    /// Notify `webhook` of each tail accepted by [`Server::allow_upload`]
+    pub fn add_webhook(&mut self, webhook: Webhook) {
+        self.webhooks.push(webhook);
+    }
+
+    /// The address the server is listening on
+    pub fn address(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Handle requests until the listener fails or [`Server::stop`] is called
+    pub fn run(&self) -> Result<(), String> {
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..WORKERS).map(|_| scope.spawn(|| self.work())).collect();
+            workers
+                .into_iter()
+                .try_for_each(|worker| worker.join().expect("server worker panicked"))
+        })
+    }
+
+    /// Handle requests on one thread of [`Server::run`]
+    fn work(&self) -> Result<(), String> {
+        loop {
+            let request = match self.server.recv() {
+                Ok(request) => request,
+                Err(_) if self.stopped.load(Ordering::SeqCst) => return Ok(()),
+                Err(err) => {
+                    self.stop();
+                    return Err(err_str(err));
+                }
+            };
+            if let Err(err) = self.handle(request) {
+                eprintln!("buildchain: serve: {}", err);
+            }
+        }
+    }
+
+    /// Make [`Server::run`] return once the current requests are handled
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        for _ in 0..WORKERS {
+            self.server.unblock();
+        }
+    }
+
+    fn handle(&self, mut request: Request) -> io::Result<()> {
+        if *request.method() == Method::Put {
+            return match self.upload(&mut request) {
+                Ok(()) => self.respond(request, Response::empty(201)),
+                Err(Rejection(status, message)) => {
+                    self.metrics.upload_rejection(status);
+                    self.respond(
+                        request,
+                        Response::from_string(message).with_status_code(status),
+                    )
+                }
+            };
+        }
+
+        if *request.method() != Method::Get && *request.method() != Method::Head {
+            return self.respond(
+                request,
+                Response::empty(405).with_header(header("Allow", "GET, HEAD, PUT")),
+            );
+        }
+
+        if request.url().split('?').next() == Some("/metrics") {
+            let response = Response::from_string(self.metrics.render(&self.store))
+                .with_header(header("Content-Type", "text/plain; version=0.0.4"));
+            return self.respond(request, response);
+        }
+
+        let (relpath, immutable) = match resolve(request.url()) {
+            Some(some) => some,
+            None => return self.respond(request, Response::empty(404)),
+        };
+
+        let path = self.store.path().join(relpath);
+        let file = match File::open(&path) {
+            Ok(file) if file.metadata()?.is_file() => file,
+            _ => return self.respond(request, Response::empty(404)),
+        };
+
+        // The ETag is the name of the object or block, following tail symlinks, indexes
+        // have none
+        let etag_opt = if path.file_name() == Some(OsStr::new("index.json")) {
+            None
+        } else {
+            fs::canonicalize(&path)?
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| format!("\"{}\"", name))
+        };
+
+        let cache_control = if immutable { IMMUTABLE } else { MUTABLE };
+
+        if let Some(etag) = &etag_opt {
+            let not_modified = request
+                .headers()
+                .iter()
+                .filter(|h| h.field.equiv("If-None-Match"))
+                .any(|h| h.value.as_str().split(',').any(|v| v.trim() == etag));
+            if not_modified {
+                return self.respond(
+                    request,
+                    Response::empty(StatusCode(304))
+                        .with_header(header("ETag", etag))
+                        .with_header(header("Cache-Control", cache_control)),
+                );
+            }
+        }
+
+        let mut response = Response::from_file(file)
+            .with_header(header("Content-Type", "application/octet-stream"))
+            .with_header(header("Cache-Control", cache_control));
+        if let Some(etag) = &etag_opt {
+            response.add_header(header("ETag", etag));
+        }
+        self.respond(request, response)
+    }
+
+    /// Send `response`, counting it in the metrics
+    fn respond<R: Read>(&self, request: Request, response: Response<R>) -> io::Result<()> {
+        let bytes = if *request.method() == Method::Get {
+            response.data_length().unwrap_or(0) as u64
+        } else {
+            0
+        };
+        self.metrics
+            .request(request.method().as_str(), response.status_code().0, bytes);
+        request.respond(response)
+    }
+
+    /// Read a signed block from the request body, verifying it against the upload key
+    fn read_block(request: &mut Request, key: &PublicKey) -> Result<([u8; 400], Block), Rejection> {
+        let mut data = Vec::new();
+        request.as_reader().take(401).read_to_end(&mut data)?;
+        if data.len() != 400 {
+            return Err(Rejection::new(400, "block must be 400 bytes"));
+        }
+
+        let mut block = [0u8; 400];
+        block.copy_from_slice(&data);
+
+        let b = PackedBlock::from_bytes(&block).map_err(|err| Rejection::new(400, err))?;
+        let verified = b
+            .verify(key)
+            .map_err(|err| Rejection::new(403, err.to_string()))?;
+
+        Ok((block, verified))
+    }
+
+    /// Read an index or torrent from the request body, limited to [`MAX_INDEX_SIZE`]
+    fn read_index(request: &mut Request) -> Result<Vec<u8>, Rejection> {
+        let mut data = Vec::new();
+        request
+            .as_reader()
+            .take(MAX_INDEX_SIZE + 1)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 > MAX_INDEX_SIZE {
+            return Err(Rejection::new(413, "index is too large"));
+        }
+        Ok(data)
+    }
+
+    /// Receive an object into `tmp`, and import it if its contents match `key`
+    fn receive_object(
+        &self,
+        request: &mut Request,
+        tmp: &Path,
+        key: &ObjectId,
+    ) -> Result<(), Rejection> {
+        let size = io::copy(
+            &mut request.as_reader().take(self.max_upload_size + 1),
+            &mut File::create(tmp)?,
+        )?;
+        if size > self.max_upload_size {
+            return Err(Rejection::new(413, "object is too large"));
+        }
+
+        let sha = Sha384::new(File::open(tmp)?)?;
+        if sha.to_id() != *key {
+            return Err(Rejection::new(400, "sha384 mismatch"));
+        }
+
+        self.store.import_object(tmp)?;
+        Ok(())
+    }
+
+    /// Check that `block` follows the current tail of `project` and `branch`
+    ///
+    /// A first tail must follow a block already in the store, unless it starts the chain.
+    fn check_follows(&self, project: &str, branch: &str, block: &Block) -> Result<(), Rejection> {
+        match self.store.read_tail(project, branch)? {
+            Some(current) => {
+                let current = Block::from_unverified(&current);
+                if block.counter <= current.counter {
+                    return Err(Rejection::new(409, "block is older than the current tail"));
+                }
+                if block.counter != current.counter + 1
+                    || block.previous_signature != current.signature
+                {
+                    return Err(Rejection::new(
+                        409,
+                        "block does not follow the current tail",
+                    ));
+                }
+            }
+            None => {
+                if block.counter != 0 && !self.store.block_path(&block.previous_signature).is_file()
+                {
+                    return Err(Rejection::new(409, "previous block not uploaded"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the manifest referenced by `block` and all of its files are in the store
+    fn check_complete(&self, block: &Block) -> Result<(), Rejection> {
+        let file = self
+            .store
+            .open_object(&block.digest)
+            .map_err(|_| Rejection::new(409, "manifest not uploaded"))?;
+        let manifest: Manifest =
+            serde_json::from_reader(file).map_err(|err| Rejection::new(400, err_str(err)))?;
+
+        for (name, digest) in manifest.files.iter() {
+            if !self.store.contains(digest) {
+                return Err(Rejection::new(409, format!("{} not uploaded", name)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn upload(&self, request: &mut Request) -> Result<(), Rejection> {
+        let upload = self
+            .upload_opt
+            .as_ref()
+            .ok_or_else(|| Rejection::new(405, "uploads not enabled"))?;
+
+        let expected = format!("Bearer {}", upload.token);
+        let authorized = request
+            .headers()
+            .iter()
+            .filter(|h| h.field.equiv("Authorization"))
+            .any(|h| constant_time_eq(h.value.as_str().as_bytes(), expected.as_bytes()));
+        if !authorized {
+            return Err(Rejection::new(401, "invalid token"));
+        }
+
+        let (relpath, _immutable) =
+            resolve(request.url()).ok_or_else(|| Rejection::new(404, "not found"))?;
+        let parts: Vec<String> = relpath
+            .iter()
+            .map(|part| part.to_string_lossy().into_owned())
+            .collect();
+
+        match parts
+            .iter()
+            .map(|part| part.as_str())
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            ["object", digest] => {
+                let key =
+                    object_key(digest).ok_or_else(|| Rejection::new(400, "invalid digest"))?;
+                if self.store.contains(&key) {
+                    return Ok(());
+                }
+
+                if request
+                    .body_length()
+                    .is_some_and(|length| length as u64 > self.max_upload_size)
+                {
+                    return Err(Rejection::new(413, "object is too large"));
+                }
+
+                let tmp = self.store.temp_path();
+                fs::create_dir_all(tmp.parent().unwrap())?;
+                let result = self.receive_object(request, &tmp, &key);
+                if result.is_err() {
+                    let _ = fs::remove_file(&tmp);
+                }
+                result
+            }
+            ["block", signature] => {
+                let (block, verified) = Server::read_block(request, &upload.key)?;
+                if b32dec(signature).as_deref() != Some(&verified.signature.0[..]) {
+                    return Err(Rejection::new(400, "signature mismatch"));
+                }
+
+                self.store.write_block(&block)?;
+                Ok(())
+            }
+            ["tail", project, branch] => {
+                let (block, verified) = Server::read_block(request, &upload.key)?;
+                self.check_follows(project, branch, &verified)?;
+                self.check_complete(&verified)?;
+
+                self.store.write_tail(project, branch, &block)?;
+
+                let event = TailEvent::new(project, branch, &verified);
+                for webhook in self.webhooks.iter() {
+                    webhook.spawn(event.clone());
+                }
+
+                Ok(())
+            }
+            ["cas", "index.json"] => {
+                let index = serde_json::from_slice(&Server::read_index(request)?)
+                    .map_err(|err| Rejection::new(400, err_str(err)))?;
+                self.store.write_cas_index(&index)?;
+                Ok(())
+            }
+            ["torrent", "index.json"] => {
+                let index = serde_json::from_slice(&Server::read_index(request)?)
+                    .map_err(|err| Rejection::new(400, err_str(err)))?;
+                self.store.write_torrent_index(&index)?;
+                Ok(())
+            }
+            ["zsync", digest] => {
+                object_key(digest).ok_or_else(|| Rejection::new(400, "invalid digest"))?;
+                let signature = Server::read_index(request)?;
+                DeltaSignature::from_bytes(&signature).map_err(|err| Rejection::new(400, err))?;
+                self.store.write_signature(digest, &signature)?;
+                Ok(())
+            }
+            ["torrent", name] => {
+                let digest = name
+                    .strip_suffix(".torrent")
+                    .filter(|digest| object_key(digest).is_some())
+                    .ok_or_else(|| Rejection::new(400, "invalid torrent name"))?;
+                self.store
+                    .write_torrent(digest, &Server::read_index(request)?)?;
+                Ok(())
+            }
+            _ => Err(Rejection::new(404, "not found")),
+        }
+    }
+}
+
+pub fn serve(args: ServeArguments) -> Result<(), String> {
+    // Without uploads, the server never writes to the store
+    let store = match args.key_opt {
+        Some(_) => {
+            Store::open(args.store_path).map(|store| store.with_permissions(args.permissions))
+        }
+        None => Store::open_read_only(args.store_path),
+    }
+    .map_err(err_str)?;
+    let mut server = Server::new(store, args.address)?;
+    if let Some(key) = args.key_opt {
+        let token = args
+            .token_opt
+            .ok_or_else(|| "uploads require a token".to_string())?;
+        server.allow_upload(key, token)?;
+    }
+    if let Some(max_upload_size) = args.max_upload_size_opt {
+        server.limit_uploads(max_upload_size);
+    }
+    for webhook in args.webhooks {
+        server.add_webhook(Webhook::new(webhook)?);
+    }
+    match server.address() {
+        Some(address) => println!(
+            "buildchain: serving {} on http://{}",
+            args.store_path, address
+        ),
+        None => println!(
+            "buildchain: serving {} on {}",
+            args.store_path, args.address
+        ),
+    }
+    server.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use super::{resolve, Server};
+    use crate::block::tests::signed_block;
+    use crate::delta::DeltaSignature;
+    use crate::id::b32enc;
+    use crate::{BlockSig, Downloader, Manifest, ObjectId, ProbeStatus, Publisher, Sha384, Store};
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("/object/ABC"),
+            Some((PathBuf::from("object/ABC"), true))
+        );
+        assert_eq!(
+            resolve("/tail/default/master?x=1"),
+            Some((PathBuf::from("tail/default/master"), false))
+        );
+        assert_eq!(
+            resolve("/tail/index.json"),
+            Some((PathBuf::from("tail/index.json"), false))
+        );
+        assert_eq!(
+            resolve("/attestation/ABC/KEY"),
+            Some((PathBuf::from("attestation/ABC/KEY"), false))
+        );
+        assert_eq!(
+            resolve("/annotation/ABC"),
+            Some((PathBuf::from("annotation/ABC"), false))
+        );
+        assert_eq!(
+            resolve("/torrent/ABC.torrent"),
+            Some((PathBuf::from("torrent/ABC.torrent"), true))
+        );
+        assert_eq!(
+            resolve("/torrent/index.json"),
+            Some((PathBuf::from("torrent/index.json"), false))
+        );
+        assert_eq!(
+            resolve("/zsync/ABC"),
+            Some((PathBuf::from("zsync/ABC"), true))
+        );
+        assert_eq!(
+            resolve("/cas/index.json"),
+            Some((PathBuf::from("cas/index.json"), false))
+        );
+        let key = ObjectId([0xcd; 48]);
+        assert_eq!(
+            resolve(&format!("/object/{}", key.to_hex())),
+            Some((PathBuf::from(format!("object/{}", key)), true))
+        );
+        let sig = BlockSig([0xef; 64]);
+        assert_eq!(
+            resolve(&format!("/block/{}", sig.to_hex())),
+            Some((PathBuf::from(format!("block/{}", sig)), true))
+        );
+        assert_eq!(
+            resolve("/namespace/firmware/tail/default/master"),
+            Some((
+                PathBuf::from("namespace/firmware/tail/default/master"),
+                false
+            ))
+        );
+        assert_eq!(
+            resolve(&format!("/namespace/firmware/object/{}", key.to_hex())),
+            Some((
+                PathBuf::from(format!("namespace/firmware/object/{}", key)),
+                true
+            ))
+        );
+        assert_eq!(resolve("/namespace/firmware"), None);
+        assert_eq!(resolve("/namespace/a/namespace/b/tail/index.json"), None);
+        assert_eq!(resolve("/object/../tmp"), None);
+        assert_eq!(resolve("/tail/../../etc"), None);
+        assert_eq!(resolve("/object//ABC"), None);
+        assert_eq!(resolve("/manifest.json"), None);
+        assert_eq!(resolve("/"), None);
+    }
+
+    #[test]
+    fn test_serve_object() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let key = store.write_object(b"served object").unwrap();
+
+        let server = Arc::new(Server::new(Store::new(&temp_dir), "127.0.0.1:0").unwrap());
+        let url = format!("http://{}/", server.address().unwrap());
+        {
+            let server = server.clone();
+            thread::spawn(move || server.run());
+        }
+
+        let dl = Downloader::new(
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            &url,
+            "default",
+            "master",
+            None,
+        )
+        .unwrap();
+        assert_eq!(dl.object(&key).unwrap(), b"served object");
+        assert!(dl.object(&ObjectId([0; 48])).is_err());
+    }
+
+    #[test]
+    fn test_stalled_upload() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let key = store.write_object(b"served object").unwrap();
+
+        let mut server = Server::new(Store::new(&temp_dir), "127.0.0.1:0").unwrap();
+        server
+            .allow_upload(
+                "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+                "secret",
+            )
+            .unwrap();
+        let server = Arc::new(server);
+        let address = server.address().unwrap();
+        {
+            let server = server.clone();
+            thread::spawn(move || server.run());
+        }
+
+        // An upload that stops sending its body holds one worker, but not the others
+        let mut stalled = TcpStream::connect(address).unwrap();
+        write!(
+            stalled,
+            "PUT /object/{} HTTP/1.1\r\nHost: {}\r\nAuthorization: Bearer secret\r\n\
+             Content-Length: 100\r\n\r\npartial",
+            ObjectId([0; 48]),
+            address
+        )
+        .unwrap();
+        stalled.flush().unwrap();
+
+        let dl = Downloader::new(
+            "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+            &format!("http://{}/", address),
+            "default",
+            "master",
+            None,
+        )
+        .unwrap();
+        assert_eq!(dl.object(&key).unwrap(), b"served object");
+        drop(stalled);
+    }
+
+    #[test]
+    fn test_probe() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let manifest_key = store
+            .write_object(&serde_json::to_vec(&Manifest::default()).unwrap())
+            .unwrap();
+        let (public_key, block) = signed_block(1, &[0; 64], 0, &manifest_key);
+        store.write_tail("default", "master", &block).unwrap();
+
+        let server = Arc::new(Server::new(Store::new(&temp_dir), "127.0.0.1:0").unwrap());
+        let url = format!("http://{}/", server.address().unwrap());
+        {
+            let server = server.clone();
+            thread::spawn(move || server.run());
+        }
+
+        // Lengths match, but range requests are answered with the whole file
+        let dl = Downloader::new(&b32enc(&public_key), &url, "default", "master", None).unwrap();
+        let checks = dl.probe();
+        assert!(checks
+            .iter()
+            .all(|check| check.status != ProbeStatus::Failed));
+        let warnings: Vec<_> = checks
+            .iter()
+            .filter(|check| check.status == ProbeStatus::Warning)
+            .collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].message.starts_with("range requests"));
+
+        let dl = Downloader::new(&b32enc(&public_key), &url, "default", "other", None).unwrap();
+        let checks = dl.probe();
+        assert_eq!(checks.last().unwrap().path, "tail/default/other");
+        assert_eq!(checks.last().unwrap().status, ProbeStatus::Failed);
+    }
+
+    #[test]
+    fn test_publish() {
+        let source_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let source = Store::new(&source_dir);
+
+        let file_key = source.write_object(b"artifact").unwrap();
+        let manifest = Manifest {
+            time: 0,
+            files: [("artifact".to_string(), file_key)].into(),
+            ..Default::default()
+        };
+        let manifest_key = source
+            .write_object(&serde_json::to_vec(&manifest).unwrap())
+            .unwrap();
+        let (_, genesis) = signed_block(1, &[0; 64], 0, &manifest_key);
+        source.write_block(&genesis).unwrap();
+        let mut previous = [0; 64];
+        previous.copy_from_slice(&genesis[..64]);
+        let (public_key, block) = signed_block(1, &previous, 1, &manifest_key);
+        source.write_tail("default", "master", &block).unwrap();
+        source
+            .add_cas_index(&format!("added QmArtifact object/{}", file_key))
+            .unwrap();
+        source.write_torrents(0, &[]).unwrap();
+        source.write_signatures(0).unwrap();
+        let key = b32enc(&public_key);
+
+        let mirror_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let mut server = Server::new(Store::new(&mirror_dir), "127.0.0.1:0").unwrap();
+        server.allow_upload(&key, "secret").unwrap();
+        let server = Arc::new(server);
+        let url = format!("http://{}/", server.address().unwrap());
+        {
+            let server = server.clone();
+            thread::spawn(move || server.run());
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let wrong = Publisher::new(&url, "wrong", None).unwrap();
+        assert!(runtime.block_on(wrong.publish(&source)).is_err());
+
+        let publisher = Publisher::new(&url, "secret", None).unwrap();
+        runtime.block_on(publisher.publish(&source)).unwrap();
+
+        let dl = Downloader::new(&key, &url, "default", "master", None).unwrap();
+        let tail = dl.tail().unwrap();
+        assert_eq!(tail.counter, 1);
+        assert_eq!(
+            dl.object(&tail.digest).unwrap(),
+            serde_json::to_vec(&manifest).unwrap()
+        );
+
+        assert_eq!(
+            Store::new(&mirror_dir).read_cas_index().unwrap(),
+            source.read_cas_index().unwrap()
+        );
+        assert_eq!(
+            Store::new(&mirror_dir).read_torrent_index().unwrap(),
+            source.read_torrent_index().unwrap()
+        );
+        let signature = mirror_dir.path().join("zsync").join(file_key.to_string());
+        assert!(signature.is_file());
+
+        // Publishing the same block again is refused, as it is not newer than the tail
+        assert!(runtime.block_on(publisher.publish(&source)).is_err());
+
+        // So are blocks that skip counters or fork from the tail
+        let client = reqwest::Client::new();
+        let put = |url: String, body: Vec<u8>| {
+            runtime
+                .block_on(client.put(url).bearer_auth("secret").body(body).send())
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+        let put_tail =
+            |block: &[u8; 400]| put(format!("{}tail/default/master", url), block.to_vec());
+        let (_, skipped) = signed_block(1, &block[..64].try_into().unwrap(), 3, &manifest_key);
+        assert_eq!(put_tail(&skipped), 409);
+        let (_, fork) = signed_block(1, &previous, 2, &manifest_key);
+        assert_eq!(put_tail(&fork), 409);
+        let (_, next) = signed_block(1, &block[..64].try_into().unwrap(), 2, &manifest_key);
+        assert_eq!(put_tail(&next), 201);
+
+        // Signatures must be named by an object digest
+        let empty = DeltaSignature::new(0, &[][..]).unwrap().to_bytes();
+        assert_eq!(put(format!("{}zsync/invalid", url), empty), 400);
+
+        // Objects over the upload limit are refused, and leave nothing behind
+        let mut server = Server::new(Store::new(&mirror_dir), "127.0.0.1:0").unwrap();
+        server.allow_upload(&key, "secret").unwrap();
+        server.limit_uploads(4);
+        let server = Arc::new(server);
+        let limited_url = format!("http://{}/", server.address().unwrap());
+        {
+            let server = server.clone();
+            thread::spawn(move || server.run());
+        }
+        let large = Sha384::new(&b"too large"[..]).unwrap().to_id();
+        assert_eq!(
+            put(
+                format!("{}object/{}", limited_url, large),
+                b"too large".to_vec()
+            ),
+            413
+        );
+        assert!(!Store::new(&mirror_dir).contains(&large));
+        let tmp = mirror_dir.path().join("tmp");
+        assert!(!tmp.exists() || fs::read_dir(tmp).unwrap().next().is_none());
+    }
+}
@@ -1,10 +1,53 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
+#[cfg(any(feature = "build", feature = "download"))]
+use memmap2::{Advice, Mmap};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{self, Digest};
+use std::fs::File;
 use std::io::{self, Read};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
 
-use crate::store::{b32dec, b32enc};
+use crate::id::{b32dec, b32enc};
+use crate::ObjectId;
+
+/// The size of reads while hashing
+pub(crate) const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The number of buffers read ahead of hashing by [`Sha384::from_path`]
+const READAHEAD: usize = 2;
+
+/// Files at least this large are hashed from a memory map
+const MMAP_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The sha384 of `file` hashed from a memory map, or `None` if it is too small to benefit or
+/// cannot be mapped, in which case it should be read instead
+pub(crate) fn mmap_sha384(file: &File) -> Option<[u8; 48]> {
+    if file.metadata().ok()?.len() < MMAP_SIZE {
+        return None;
+    }
+    mmap_digest(file)
+}
+
+#[cfg(any(feature = "build", feature = "download"))]
+fn mmap_digest(file: &File) -> Option<[u8; 48]> {
+    // SAFETY: objects are made read-only before they are hashed, and artifacts are not modified
+    // once built. A file truncated while mapped may fault, as it would with any mmap reader.
+    let mmap = unsafe { Mmap::map(file) }.ok()?;
+    // The advice is only a hint, so failing to give it does not matter
+    let _ = mmap.advise(Advice::Sequential);
+
+    let mut key = [0u8; 48];
+    key.copy_from_slice(sha2::Sha384::digest(&mmap[..]).as_slice());
+    Some(key)
+}
+
+#[cfg(not(any(feature = "build", feature = "download")))]
+fn mmap_digest(_file: &File) -> Option<[u8; 48]> {
+    None
+}
 
 /// Deserializes a lowercase hex string to a `Vec<u8>`.
 fn from_base32<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
@@ -25,6 +68,8 @@ pub struct Sha384(#[serde(deserialize_with = "from_base32", serialize_with = "to
 impl Sha384 {
     /// Create a new Sha384 by reading the provided input
     ///
+    /// Use [`Sha384::from_path`] for files, which maps large files into memory.
+    ///
     /// # Arguments
     ///
     /// * `input` - A `std::io::Read` object that will be used as input to the Sha384 algorithm
@@ -48,8 +93,8 @@ impl Sha384 {
     pub fn new<R: Read>(mut input: R) -> io::Result<Sha384> {
         let mut hasher = sha2::Sha384::default();
 
+        let mut data = vec![0; BUFFER_SIZE];
         loop {
-            let mut data = [0; 4096];
             let count = input.read(&mut data)?;
             if count == 0 {
                 break;
@@ -61,6 +106,55 @@ impl Sha384 {
         Ok(Sha384(hasher.finalize().as_slice().to_vec()))
     }
 
+    /// Create a new Sha384 of the file at `path`
+    ///
+    /// Large files are hashed from a memory map. Otherwise the file is read ahead on another
+    /// thread while the previous chunk is hashed, so reading and hashing large files overlap.
+    ///
+    /// # Errors
+    ///
+    /// Errors that are encountered while reading will be returned
+    pub fn from_path<P: AsRef<Path>>(path: P) -> io::Result<Sha384> {
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() <= BUFFER_SIZE as u64 {
+            return Sha384::new(file);
+        }
+        if let Some(key) = mmap_sha384(&file) {
+            return Ok(Sha384(key.to_vec()));
+        }
+
+        // Buffers are passed back to the reader for reuse, so only a few are allocated
+        let (full_tx, full_rx) = mpsc::sync_channel::<io::Result<(Vec<u8>, usize)>>(READAHEAD);
+        let (empty_tx, empty_rx) = mpsc::channel::<Vec<u8>>();
+        for _ in 0..=READAHEAD {
+            empty_tx.send(vec![0; BUFFER_SIZE]).unwrap();
+        }
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                while let Ok(mut data) = empty_rx.recv() {
+                    let result = file.read(&mut data).map(|count| (data, count));
+                    let done = !matches!(result, Ok((_, count)) if count > 0);
+                    if full_tx.send(result).is_err() || done {
+                        break;
+                    }
+                }
+            });
+
+            let mut hasher = sha2::Sha384::default();
+            loop {
+                let (data, count) = full_rx.recv().unwrap()?;
+                if count == 0 {
+                    break;
+                }
+                hasher.update(&data[..count]);
+                // The reader may have finished, in which case the buffer is not needed
+                let _ = empty_tx.send(data);
+            }
+            Ok(Sha384(hasher.finalize().as_slice().to_vec()))
+        })
+    }
+
     pub fn to_base32(&self) -> String {
         let key = {
             let mut key = [0u8; 48];
@@ -69,11 +163,20 @@ impl Sha384 {
         };
         b32enc(&key)
     }
+
+    /// The digest as an [`ObjectId`]
+    pub fn to_id(&self) -> ObjectId {
+        ObjectId::try_from(self.0.as_slice()).unwrap()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Sha384;
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{mmap_digest, mmap_sha384, Sha384, BUFFER_SIZE};
 
     #[test]
     fn test_match() {
@@ -85,6 +188,29 @@ mod tests {
         assert_eq!(sha_a, sha_b);
     }
 
+    #[test]
+    fn test_from_path() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        for len in [0, 7, BUFFER_SIZE, 3 * BUFFER_SIZE + 5] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let path = temp_dir.path().join(len.to_string());
+            fs::write(&path, &data).unwrap();
+            assert_eq!(
+                Sha384::from_path(&path).unwrap(),
+                Sha384::new(data.as_slice()).unwrap()
+            );
+        }
+        assert!(Sha384::from_path(temp_dir.path().join("missing")).is_err());
+
+        // Small files are read rather than mapped
+        let path = temp_dir.path().join(BUFFER_SIZE.to_string());
+        let file = fs::File::open(&path).unwrap();
+        assert!(mmap_sha384(&file).is_none());
+        let key = mmap_digest(&file).unwrap();
+        assert_eq!(Sha384(key.to_vec()), Sha384::from_path(&path).unwrap());
+        temp_dir.close().unwrap();
+    }
+
     #[test]
     fn test_mismatch() {
         let a = "Input A";
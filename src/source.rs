@@ -1,10 +1,51 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha384};
+use std::fs;
 use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::{Command, Stdio};
 
+use crate::archive::VCS_NAMES;
+use crate::id::b32enc;
+use crate::SourceState;
+
+/// Hash the entries of `dir` into `hasher`, in name order, with names relative to `base`
+fn hash_dir(hasher: &mut Sha384, base: &Path, dir: &Path) -> io::Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry_res| entry_res.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    for path in paths {
+        let name = path.file_name().unwrap();
+        if name.to_str().is_some_and(|name| VCS_NAMES.contains(&name)) {
+            continue;
+        }
+
+        let relative = path.strip_prefix(base).unwrap();
+        hasher.update(relative.as_os_str().as_bytes());
+        hasher.update([0]);
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.file_type().is_symlink() {
+            hasher.update(b"l");
+            hasher.update(fs::read_link(&path)?.as_os_str().as_bytes());
+        } else if metadata.is_dir() {
+            hasher.update(b"d");
+            hash_dir(hasher, base, &path)?;
+        } else {
+            let executable = metadata.permissions().mode() & 0o111 != 0;
+            hasher.update(if executable { b"x" } else { b"f" });
+            hasher.update(crate::Sha384::from_path(&path)?.to_base32());
+        }
+        hasher.update([0]);
+    }
+    Ok(())
+}
+
 /// A source code repository
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct Source {
@@ -147,4 +188,82 @@ impl Source {
             )),
         }
     }
+
+    /// Describe the source downloaded to `directory`, built with the configuration `config`
+    pub fn state<P: AsRef<Path>>(&self, directory: P, config: &[u8]) -> io::Result<SourceState> {
+        let directory = directory.as_ref();
+
+        let revision = if self.kind == "git" {
+            let output = Command::new("git")
+                .arg("-C")
+                .arg(directory)
+                .arg("rev-parse")
+                .arg("HEAD")
+                .stdout(Stdio::piped())
+                .spawn()?
+                .wait_with_output()?;
+
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "Git rev-parse error: {}",
+                    output.status
+                )));
+            }
+
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        } else {
+            None
+        };
+
+        let mut hasher = Sha384::new();
+        hash_dir(&mut hasher, directory, directory)?;
+
+        Ok(SourceState {
+            revision,
+            tree: b32enc(&hasher.finalize()),
+            config: b32enc(&Sha384::digest(config)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::Source;
+
+    #[test]
+    fn test_state() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let dir = temp_dir.path();
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main;").unwrap();
+        fs::write(dir.join(".git/HEAD"), "ref").unwrap();
+
+        let source = Source {
+            kind: "dir".to_string(),
+            url: ".".to_string(),
+        };
+        let state = source.state(dir, b"{}").unwrap();
+        assert_eq!(state.revision, None);
+        assert!(state.is_unchanged(&source.state(dir, b"{}").unwrap()));
+        assert!(!state.is_unchanged(&source.state(dir, b"{ }").unwrap()));
+
+        // Version control data is ignored
+        fs::write(dir.join(".git/HEAD"), "other").unwrap();
+        assert!(state.is_unchanged(&source.state(dir, b"{}").unwrap()));
+
+        fs::set_permissions(dir.join("src/main.c"), fs::Permissions::from_mode(0o755)).unwrap();
+        let executable = source.state(dir, b"{}").unwrap();
+        assert!(!state.is_unchanged(&executable));
+
+        fs::rename(dir.join("src/main.c"), dir.join("src/lib.c")).unwrap();
+        assert!(!executable.is_unchanged(&source.state(dir, b"{}").unwrap()));
+
+        temp_dir.close().unwrap();
+    }
 }
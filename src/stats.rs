@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::Serialize;
+
+use crate::format::print_json;
+use crate::{BlockSig, BuildRecord, Downloader, DownloaderBuilder, Error, Format, Manifest};
+
+pub struct StatsArguments<'a> {
+    pub key: &'a str,
+    pub url: &'a str,
+    pub project: &'a str,
+    pub branch: &'a str,
+    /// The number of builds to analyze back from the tail
+    pub limit: usize,
+    pub format: Format,
+}
+
+/// Statistics of one build in the history of a branch, from its manifest and build record
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BuildStats {
+    pub branch: String,
+    pub counter: u64,
+    pub timestamp: u64,
+    /// The signature of the block
+    pub signature: BlockSig,
+    pub files: usize,
+    /// Files added, changed, and removed since the previous build, if it was analyzed
+    pub added: Option<usize>,
+    pub changed: Option<usize>,
+    pub removed: Option<usize>,
+    /// The total duration of the recorded stages in seconds, if the build stored a record
+    pub duration: Option<f64>,
+    /// The total size of the artifacts in bytes, if the build stored a record
+    pub artifacts_size: Option<u64>,
+    /// The change in artifact size since the previous build, if both stored records
+    pub size_change: Option<i64>,
+}
+
+/// Statistics of up to `limit` builds back from the tail of `dl`, oldest first
+///
+/// One more block is read than is reported, so that the oldest build has file churn.
+pub fn build_stats(dl: &Downloader, limit: usize) -> Result<Vec<BuildStats>, Error> {
+    let mut history = dl.history(limit.saturating_add(1))?;
+    history.reverse();
+
+    let mut stats: Vec<BuildStats> = Vec::new();
+    let mut previous: Option<(Manifest, Option<u64>)> = None;
+    for entry in history {
+        let manifest_json = dl.object(&entry.block.digest)?;
+        let manifest = serde_json::from_slice::<Manifest>(&manifest_json)
+            .map_err(|err| Error::Verify(format!("manifest: {}", err)))?;
+        let record_opt = match &manifest.report {
+            Some(digest) => Some(
+                serde_json::from_slice::<BuildRecord>(&dl.object(digest)?)
+                    .map_err(|err| Error::Verify(format!("build record: {}", err)))?,
+            ),
+            None => None,
+        };
+        let artifacts_size = record_opt.as_ref().and_then(|record| record.artifacts_size);
+
+        let diff_opt = previous.as_ref().map(|(old, _)| manifest.diff(old));
+        let previous_size = previous.as_ref().and_then(|(_, size)| *size);
+        stats.push(BuildStats {
+            branch: entry.branch,
+            counter: entry.block.counter,
+            timestamp: entry.block.timestamp,
+            signature: entry.block.signature,
+            files: manifest.files.len(),
+            added: diff_opt.as_ref().map(|diff| diff.added.len()),
+            changed: diff_opt.as_ref().map(|diff| diff.changed.len()),
+            removed: diff_opt.as_ref().map(|diff| diff.removed.len()),
+            duration: record_opt
+                .as_ref()
+                .map(|record| record.stages.values().sum()),
+            artifacts_size,
+            size_change: artifacts_size
+                .zip(previous_size)
+                .map(|(size, previous)| size as i64 - previous as i64),
+        });
+        previous = Some((manifest, artifacts_size));
+    }
+
+    if stats.len() > limit {
+        stats.remove(0);
+    }
+    Ok(stats)
+}
+
+/// Format an optional value for the stats table
+fn cell<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+/// Print the build duration, artifact size, and file churn of the history of a branch
+pub fn stats(args: StatsArguments) -> Result<(), Error> {
+    let dl = DownloaderBuilder::new(args.key, args.url)
+        .project(args.project)
+        .branch(args.branch)
+        .build_blocking()?;
+    let stats = build_stats(&dl, args.limit)?;
+
+    match args.format {
+        Format::Text => {
+            println!(
+                "{:>8} {:>12} {:>10} {:>14} {:>12} {:>7} {:>7} {:>7} {:>7}",
+                "COUNTER",
+                "TIMESTAMP",
+                "DURATION",
+                "SIZE",
+                "SIZE CHANGE",
+                "FILES",
+                "ADDED",
+                "CHANGED",
+                "REMOVED"
+            );
+            for build in stats.iter() {
+                println!(
+                    "{:>8} {:>12} {:>10} {:>14} {:>12} {:>7} {:>7} {:>7} {:>7}",
+                    build.counter,
+                    build.timestamp,
+                    cell(build.duration.map(|duration| format!("{:.1}s", duration))),
+                    cell(build.artifacts_size),
+                    cell(build.size_change.map(|change| format!("{:+}", change))),
+                    build.files,
+                    cell(build.added),
+                    cell(build.changed),
+                    cell(build.removed)
+                );
+            }
+        }
+        Format::Json => print_json(&stats)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::build_stats;
+    use crate::block::tests::publish_chain;
+    use crate::{BuildRecord, Downloader, Manifest, MemoryTransport, Sha384};
+
+    #[test]
+    fn test_build_stats() {
+        let transport = MemoryTransport::new();
+        let insert = |data: &[u8]| {
+            let digest = Sha384::new(data).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), data);
+            digest
+        };
+
+        let manifests: Vec<Manifest> = (0..3u64)
+            .map(|counter| {
+                let mut manifest = Manifest::default();
+                for name in 0..=counter {
+                    let digest = insert(format!("{} {}", name, counter.min(1)).as_bytes());
+                    manifest.files.insert(name.to_string(), digest);
+                }
+                // The first build did not store a record
+                if counter > 0 {
+                    let record = BuildRecord {
+                        stages: [("build".to_string(), 2.0), ("import".to_string(), 0.5)].into(),
+                        artifacts_size: Some(100 * counter),
+                        ..Default::default()
+                    };
+                    manifest.report = Some(insert(&serde_json::to_vec(&record).unwrap()));
+                }
+                manifest
+            })
+            .collect();
+        let key = publish_chain(&transport, &manifests);
+
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+
+        let stats = build_stats(&dl, 10).unwrap();
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[0].counter, 0);
+        assert_eq!(stats[0].added, None);
+        assert_eq!(stats[0].duration, None);
+        // The second build changed file 0 and added file 1
+        assert_eq!(stats[1].added, Some(1));
+        assert_eq!(stats[1].changed, Some(1));
+        assert_eq!(stats[1].duration, Some(2.5));
+        assert_eq!(stats[1].size_change, None);
+        assert_eq!(stats[2].added, Some(1));
+        assert_eq!(stats[2].changed, Some(0));
+        assert_eq!(stats[2].artifacts_size, Some(200));
+        assert_eq!(stats[2].size_change, Some(100));
+
+        // Churn of the oldest reported build is relative to the build before it
+        let stats = build_stats(&dl, 2).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].counter, 1);
+        assert_eq!(stats[0].added, Some(1));
+    }
+}
@@ -1,47 +1,78 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use std::collections::BTreeMap;
-use std::fs::{create_dir, read_dir, remove_dir, rename, File, OpenOptions};
+#[cfg(feature = "build")]
+use std::fs::hard_link;
+use std::fs::{copy, create_dir, create_dir_all, read_dir, remove_dir, rename, File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 
-use base32::{self, Alphabet};
-use rand::rngs::OsRng;
-use rand::RngCore;
+#[cfg(feature = "build")]
+use rayon::prelude::*;
 use sha2::{Digest, Sha384};
 
-use crate::Manifest;
-
-const B32_ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+use crate::block_index::BlockIndex;
+use crate::id::b32enc;
+#[cfg(feature = "build")]
+use crate::manifest::{artifact_name, check_unique, executable_mode};
+use crate::sha384::{mmap_sha384, BUFFER_SIZE};
+use crate::verify::{DIGEST, PUBLIC_KEY};
+use crate::{BlockSig, Error, Manifest, ObjectId, OsRng, Rng};
+#[cfg(feature = "download")]
+use crate::{DeltaSignature, Torrent};
+
+/// Decode a base32 object digest into an object key
+pub(crate) fn object_key(digest: &str) -> Option<ObjectId> {
+    digest.parse().ok()
+}
 
-pub fn b32enc(bin: &[u8]) -> String {
-    base32::encode(B32_ALPHABET, bin)
+fn block_relpath(sig: &BlockSig) -> PathBuf {
+    PathBuf::from("block").join(sig.to_string())
 }
 
-pub fn b32dec(txt: &str) -> Option<Vec<u8>> {
-    base32::decode(B32_ALPHABET, txt)
+pub(crate) fn object_relpath(key: &ObjectId) -> PathBuf {
+    PathBuf::from("object").join(key.to_string())
 }
 
-fn block_relpath(sig: &[u8; 64]) -> PathBuf {
-    PathBuf::from("block").join(b32enc(sig))
+/* attestation/B32DIGEST/B32KEY, a block signed by a rebuilder of the build with B32DIGEST */
+fn attestation_relpath(key: &ObjectId, public_key: &[u8; 32]) -> PathBuf {
+    PathBuf::from("attestation")
+        .join(key.to_string())
+        .join(b32enc(public_key))
 }
 
-fn object_relpath(key: &[u8; 48]) -> PathBuf {
-    PathBuf::from("object").join(b32enc(key))
+/* annotation/B32SIGNATURE, a block that refers to the annotation of block B32SIGNATURE */
+fn annotation_relpath(sig: &BlockSig) -> PathBuf {
+    PathBuf::from("annotation").join(sig.to_string())
 }
 
 /* tail/PROJECT/BRANCH --> ../../block/B32SIGNATURE */
-fn tail_to_block(sig: &[u8; 64]) -> PathBuf {
+pub(crate) fn tail_to_block(sig: &BlockSig) -> PathBuf {
     PathBuf::from("../..").join(block_relpath(sig))
 }
 
-pub fn random_id() -> String {
+pub fn random_id(rng: &dyn Rng) -> String {
     let mut key = [0u8; 15];
-    OsRng.fill_bytes(&mut key);
+    rng.fill_bytes(&mut key);
     b32enc(&key)
 }
 
+/// Check that `name` can be used as a namespace, see [`Store::namespace`]
+fn check_namespace(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if !valid {
+        return Err(Error::Config(format!("invalid namespace {:?}", name)));
+    }
+    Ok(())
+}
+
 fn create_dir_if_needed<P: AsRef<Path>>(path: P) -> io::Result<()> {
     if path.as_ref().is_dir() {
         return Ok(());
@@ -49,46 +80,295 @@ fn create_dir_if_needed<P: AsRef<Path>>(path: P) -> io::Result<()> {
     create_dir(path.as_ref())
 }
 
+/// Clone the extents of `src` into `dst`, on filesystems that share them such as btrfs and XFS
+#[cfg(feature = "build")]
+fn reflink(src: &File, dst: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // FICLONE from linux/fs.h, which older versions of libc do not define
+    const FICLONE: u32 = 0x4004_9409;
+    // SAFETY: both descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
 fn to_canonical<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<()> {
     let parent = dst.as_ref().parent().unwrap();
     create_dir_if_needed(parent)?;
     rename(src.as_ref(), dst.as_ref())
 }
 
+/// How [`Store::import_artifacts`] moves artifacts into the store
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ImportMode {
+    /// Rename each artifact into the store, leaving a symlink to the object in its place
+    #[default]
+    Rename,
+    /// Hard link each object to its artifact, which stays a regular file
+    ///
+    /// The artifact and object share their contents, so the artifact is made read-only.
+    HardLink,
+    /// Clone the contents of each artifact into its object, which stays a regular file
+    ///
+    /// Filesystems such as btrfs and XFS share the contents until either file is changed, on
+    /// other filesystems the artifact is copied.
+    Reflink,
+}
+
+/// The permissions of the files a [`Store`] writes
+///
+/// Objects, blocks, and attestations are never modified once written, so they are not
+/// writable by anyone. Directories and indexes are created with the umask of the process.
+///
+/// To share a store between services, such as a builder that writes it and a server that
+/// reads it, give the base directory a shared group and the setgid bit, for example with
+/// `chgrp buildchain store && chmod 2770 store`, so the directories created in it inherit
+/// the group, and write it with [`StorePermissions::Group`]. Services that only read the store
+/// should open it with [`Store::open_read_only`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum StorePermissions {
+    /// Files are only readable by their owner, `0o400`
+    #[default]
+    Owner,
+    /// Files are readable by their owner and group, `0o440`
+    Group,
+}
+
+impl StorePermissions {
+    /// The mode of objects, blocks, and attestations
+    pub fn file_mode(self) -> u32 {
+        match self {
+            StorePermissions::Owner => 0o400,
+            StorePermissions::Group => 0o440,
+        }
+    }
+}
+
+impl FromStr for StorePermissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StorePermissions, String> {
+        match s {
+            "owner" => Ok(StorePermissions::Owner),
+            "group" => Ok(StorePermissions::Group),
+            _ => Err(format!("unknown store permissions: {}", s)),
+        }
+    }
+}
+
 pub struct Store {
     basedir: PathBuf,
+    rng: Arc<dyn Rng>,
+    import_mode: ImportMode,
+    permissions: StorePermissions,
+    read_only: bool,
+    indexed: bool,
 }
 
 impl Store {
     pub fn new<P: AsRef<Path>>(basedir: P) -> Store {
+        Store::with_rng(basedir, Arc::new(OsRng))
+    }
+
+    /// Open a store that names temporary files using `rng`
+    pub fn with_rng<P: AsRef<Path>>(basedir: P, rng: Arc<dyn Rng>) -> Store {
         Store {
             basedir: PathBuf::from(basedir.as_ref()),
+            rng,
+            import_mode: ImportMode::default(),
+            permissions: StorePermissions::default(),
+            read_only: false,
+            indexed: true,
+        }
+    }
+
+    /// Import artifacts with `import_mode`, both the artifact directory and the store must be
+    /// on the same filesystem unless it is [`ImportMode::Reflink`]
+    pub fn with_import_mode(mut self, import_mode: ImportMode) -> Store {
+        self.import_mode = import_mode;
+        self
+    }
+
+    /// Write files with `permissions`, see [`StorePermissions`]
+    pub fn with_permissions(mut self, permissions: StorePermissions) -> Store {
+        self.permissions = permissions;
+        self
+    }
+
+    /// Whether [`Store::write_tail`] keeps the [`BlockIndex`] and `tail/index.json` up to date
+    ///
+    /// Stores that are served or published need both, but caches of clients do not.
+    pub fn with_indexes(mut self, indexed: bool) -> Store {
+        self.indexed = indexed;
+        self
+    }
+
+    /// Open an existing store, failing with [`io::ErrorKind::NotFound`] if `basedir` is not a
+    /// directory
+    pub fn open<P: AsRef<Path>>(basedir: P) -> Result<Store, Error> {
+        let basedir = basedir.as_ref();
+        if !basedir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("store {} is not a directory", basedir.display()),
+            )
+            .into());
         }
+        Ok(Store::new(basedir))
+    }
+
+    /// Open an existing store like [`Store::open`], refusing every write to it
+    ///
+    /// Methods that would write fail with [`io::ErrorKind::PermissionDenied`] before touching
+    /// the filesystem, so servers and verifiers cannot change a store they share.
+    pub fn open_read_only<P: AsRef<Path>>(basedir: P) -> Result<Store, Error> {
+        let mut store = Store::open(basedir)?;
+        store.read_only = true;
+        Ok(store)
+    }
+
+    /// The base directory of this store
+    pub fn path(&self) -> &Path {
+        &self.basedir
+    }
+
+    /// Whether this store was opened with [`Store::open_read_only`]
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
     }
 
-    pub fn remove_tmp_dir(&self) -> io::Result<()> {
+    pub(crate) fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("store {} is read-only", self.basedir.display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Open the namespace `name` of this store, a separate store in `namespace/<name>`
+    ///
+    /// Namespaces have their own objects, blocks, and tails, so unrelated projects can share
+    /// one base directory without sharing objects. The namespace inherits the import mode,
+    /// permissions, and read-only mode of this store. It is created if this store is
+    /// writable, and must already exist otherwise.
+    pub fn namespace(&self, name: &str) -> Result<Store, Error> {
+        check_namespace(name)?;
+        let basedir = self.basedir.join("namespace").join(name);
+        if self.read_only {
+            if !basedir.is_dir() {
+                return Err(Error::NotFound(format!("namespace {} not found", name)));
+            }
+        } else {
+            create_dir_all(&basedir)?;
+        }
+        Ok(Store {
+            basedir,
+            rng: self.rng.clone(),
+            import_mode: self.import_mode,
+            permissions: self.permissions,
+            read_only: self.read_only,
+            indexed: self.indexed,
+        })
+    }
+
+    /// List the namespaces of this store, in order
+    pub fn namespaces(&self) -> Result<Vec<String>, Error> {
+        let entries = match read_dir(self.basedir.join("namespace")) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut namespaces = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if check_namespace(name).is_ok() {
+                    namespaces.push(name.to_string());
+                }
+            }
+        }
+        namespaces.sort();
+        Ok(namespaces)
+    }
+
+    pub fn remove_tmp_dir(&self) -> Result<(), Error> {
+        self.check_writable()?;
         let tmp = self.basedir.join("tmp");
-        remove_dir(tmp)
+        Ok(remove_dir(tmp)?)
     }
 
     pub fn temp_path(&self) -> PathBuf {
-        self.basedir.join("tmp").join(random_id())
+        self.basedir.join("tmp").join(random_id(&*self.rng))
     }
 
-    pub fn object_path(&self, key: &[u8; 48]) -> PathBuf {
+    pub fn object_path(&self, key: &ObjectId) -> PathBuf {
         self.basedir.join(object_relpath(key))
     }
 
-    pub fn block_path(&self, sig: &[u8; 64]) -> PathBuf {
+    /// Whether the object with `key` is in this store
+    pub fn contains(&self, key: &ObjectId) -> bool {
+        self.object_path(key).is_file()
+    }
+
+    /// The size of the object with `key` in bytes, or `None` if it is not in this store
+    pub fn object_size(&self, key: &ObjectId) -> Result<Option<u64>, Error> {
+        match self.object_path(key).metadata() {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The keys of the objects in this store, in no particular order
+    ///
+    /// Files in `object/` that are not named by a digest, such as partial writes, are skipped.
+    pub fn list_objects(&self) -> Result<impl Iterator<Item = Result<ObjectId, Error>>, Error> {
+        let entries_opt = match read_dir(self.basedir.join("object")) {
+            Ok(entries) => Some(entries),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        Ok(entries_opt
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| match entry {
+                Ok(entry) => entry.file_name().to_str().and_then(object_key).map(Ok),
+                Err(err) => Some(Err(err.into())),
+            }))
+    }
+
+    pub fn block_path(&self, sig: &BlockSig) -> PathBuf {
         self.basedir.join(block_relpath(sig))
     }
 
+    pub fn attestation_path(&self, key: &ObjectId, public_key: &[u8; 32]) -> PathBuf {
+        self.basedir.join(attestation_relpath(key, public_key))
+    }
+
+    pub fn annotation_path(&self, sig: &BlockSig) -> PathBuf {
+        self.basedir.join(annotation_relpath(sig))
+    }
+
     fn _write_content(&self, content: &[u8]) -> io::Result<PathBuf> {
+        self.check_writable()?;
         let tmp = self.temp_path();
         create_dir_if_needed(tmp.parent().unwrap())?;
         {
             let mut opt = OpenOptions::new();
-            let opt = opt.create_new(true).write(true).mode(0o400);
+            let opt = opt
+                .create_new(true)
+                .write(true)
+                .mode(self.permissions.file_mode());
             let mut file = opt.open(tmp.as_path())?;
             file.write_all(content)?;
             file.sync_all()?;
@@ -96,69 +376,159 @@ impl Store {
         Ok(tmp)
     }
 
-    pub fn import_object<P: AsRef<Path>>(&self, src: P) -> io::Result<[u8; 48]> {
-        let key = {
-            let mut file = File::open(src.as_ref())?;
-
-            {
-                // Set mode to 0o400
-                let mut perm = file.metadata().unwrap().permissions();
-                perm.set_mode(0o400);
-                file.set_permissions(perm)?;
-                file.sync_all()?;
-            }
+    /// Make `src` read-only and hash it, before it is moved into the store
+    fn hash_object<P: AsRef<Path>>(src: P, mode: u32) -> io::Result<ObjectId> {
+        let mut file = File::open(src.as_ref())?;
 
-            let mut hasher = Sha384::default();
-            let mut buf = [0u8; 4096];
-            loop {
-                let len = file.read(&mut buf)?;
-                if len == 0 {
-                    break;
-                }
-                hasher.update(&buf[..len]);
+        {
+            let mut perm = file.metadata()?.permissions();
+            perm.set_mode(mode);
+            file.set_permissions(perm)?;
+            file.sync_all()?;
+        }
+
+        if let Some(key) = mmap_sha384(&file) {
+            return Ok(ObjectId(key));
+        }
+
+        let mut hasher = Sha384::default();
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
             }
+            hasher.update(&buf[..len]);
+        }
 
-            let mut key = [0u8; 48];
-            key.copy_from_slice(hasher.finalize().as_slice());
-            key
-        };
+        let mut key = [0u8; 48];
+        key.copy_from_slice(hasher.finalize().as_slice());
+        Ok(ObjectId(key))
+    }
+
+    /// Copy `src` into the store as the object `dst`, sharing its contents if possible
+    #[cfg(feature = "build")]
+    fn clone_object(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let tmp = self.temp_path();
+        create_dir_if_needed(tmp.parent().unwrap())?;
+        {
+            let mut input = File::open(src)?;
+            let mut output = OpenOptions::new()
+                .create_new(true)
+                .write(true)
+                .mode(self.permissions.file_mode())
+                .open(&tmp)?;
+            if reflink(&input, &output).is_err() {
+                io::copy(&mut input, &mut output)?;
+            }
+            output.sync_all()?;
+        }
+        rename(tmp, dst)
+    }
 
+    pub fn import_object<P: AsRef<Path>>(&self, src: P) -> Result<ObjectId, Error> {
+        self.check_writable()?;
+        let key = Store::hash_object(src.as_ref(), self.permissions.file_mode())?;
         let dst = self.object_path(&key);
         to_canonical(src, dst)?;
         Ok(key)
     }
 
-    pub fn import_artifacts(&self, time: u64) -> io::Result<Manifest> {
+    #[cfg(feature = "build")]
+    pub fn import_artifacts(&self, time: u64) -> Result<Manifest, Error> {
+        self.import_artifacts_with(time, |_name, _path, _index, _total| Ok(()))
+    }
+
+    /// Import artifacts like [`Store::import_artifacts`], calling `on_object` with the name and
+    /// object path of each artifact as it is moved into the store, in name order, and with its
+    /// index counting from 1 and the number of artifacts, for progress
+    ///
+    /// Entries are hashed as the directory is read, so only the names and digests of the
+    /// artifacts are held in memory, even for tens of thousands of artifacts.
+    #[cfg(feature = "build")]
+    pub fn import_artifacts_with<F>(&self, time: u64, mut on_object: F) -> Result<Manifest, Error>
+    where
+        F: FnMut(&str, &Path, usize, usize) -> io::Result<()>,
+    {
+        self.check_writable()?;
         let artifacts = self.basedir.join("artifacts");
         let mut files = BTreeMap::new();
+        let mut modes = BTreeMap::new();
+        let file_mode = self.permissions.file_mode();
+
+        let mut hashed = read_dir(artifacts.as_path())?
+            .par_bridge()
+            .map(|entry| {
+                let entry = entry?;
+                let name = artifact_name(entry.file_name())?;
+                let mode_opt = executable_mode(&entry.metadata()?);
+                let path = entry.path();
+                let key = Store::hash_object(&path, file_mode)?;
+                Ok((name, path, key, mode_opt))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        hashed.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        check_unique(hashed.iter().map(|(name, ..)| name.as_str()))?;
+
+        // The object directory is created once, rather than checked for every artifact
+        create_dir_if_needed(self.basedir.join("object"))?;
+        let total = hashed.len();
+        for (index, (name, link, key, mode_opt)) in hashed.into_iter().enumerate() {
+            let object = self.object_path(&key);
+            match self.import_mode {
+                ImportMode::Rename => rename(&link, &object)?,
+                // An artifact with the same contents may already have been imported
+                ImportMode::HardLink => match hard_link(&link, &object) {
+                    Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+                        return Err(err.into())
+                    }
+                    _ => (),
+                },
+                ImportMode::Reflink => {
+                    if !object.is_file() {
+                        self.clone_object(&link, &object)?;
+                    }
+                }
+            }
+            on_object(&name, &object, index + 1, total)?;
 
-        let entries = read_dir(artifacts.as_path())?;
-        for entry in entries {
-            let entry = entry?;
-
-            let name = entry
-                .file_name()
-                .into_string()
-                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", err)))?;
-
-            let key = self.import_object(entry.path())?;
+            if let Some(mode) = mode_opt {
+                modes.insert(name.clone(), mode);
+            }
 
-            files.insert(name, b32enc(&key[..]));
+            // The artifact is renamed if its name was normalized
+            let dest = artifacts.join(&name);
+            if self.import_mode == ImportMode::Rename {
+                let target = PathBuf::from("..").join(object_relpath(&key));
+                symlink(target.as_path(), &dest)?;
+            } else if link != dest {
+                rename(&link, &dest)?;
+            }
 
-            let target = PathBuf::from("..").join(object_relpath(&key));
-            let link = entry.path();
-            symlink(target.as_path(), link.as_path())?;
+            files.insert(name, key);
         }
 
-        Ok(Manifest { time, files })
+        Ok(Manifest {
+            time,
+            files,
+            modes,
+            channel: None,
+            fork: None,
+            genesis: None,
+            digests: BTreeMap::new(),
+            report: None,
+            rollback: None,
+            devices: BTreeMap::new(),
+            source: None,
+        })
     }
 
-    pub fn write_object(&self, object: &[u8]) -> io::Result<[u8; 48]> {
+    pub fn write_object(&self, object: &[u8]) -> Result<ObjectId, Error> {
         let key = {
             let mut key = [0u8; 48];
             let digest = Sha384::digest(object);
             key.copy_from_slice(digest.as_slice());
-            key
+            ObjectId(key)
         };
         let tmp = self._write_content(object)?;
         let dst = self.object_path(&key);
@@ -166,23 +536,48 @@ impl Store {
         Ok(key)
     }
 
-    pub fn write_manifest(&self, object: &[u8]) -> io::Result<[u8; 48]> {
+    /// Write `object` and point the link `name` at it, replacing any previous link
+    fn write_linked(&self, name: &str, object: &[u8]) -> Result<ObjectId, Error> {
         let key = self.write_object(object)?;
-        let link = self.basedir.join("manifest.json");
+        let link = self.basedir.join(name);
         let target = object_relpath(&key);
-        symlink(target.as_path(), link.as_path())?;
+        let tmp = self.temp_path();
+        symlink(target.as_path(), tmp.as_path())?;
+        rename(tmp, link)?;
         Ok(key)
     }
 
-    pub fn open_object(&self, key: &[u8; 48]) -> io::Result<File> {
-        File::open(self.object_path(key))
+    /// Write the manifest object and point `manifest.json` at it, replacing any previous link
+    pub fn write_manifest(&self, object: &[u8]) -> Result<ObjectId, Error> {
+        self.write_linked("manifest.json", object)
+    }
+
+    /// Write the build record object and point `report.json` at it, see [`crate::BuildRecord`]
+    pub fn write_record(&self, object: &[u8]) -> Result<ObjectId, Error> {
+        self.write_linked("report.json", object)
+    }
+
+    /// Read the manifest pointed to by `manifest.json`, if there is one
+    pub fn read_manifest(&self) -> Result<Option<Manifest>, Error> {
+        let link = self.basedir.join("manifest.json");
+        if !link.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(link)?;
+        let manifest = serde_json::from_reader(file).map_err(io::Error::from)?;
+        Ok(Some(manifest))
     }
 
-    pub fn write_block(&self, block: &[u8; 400]) -> io::Result<[u8; 64]> {
+    pub fn open_object(&self, key: &ObjectId) -> Result<File, Error> {
+        Ok(File::open(self.object_path(key))?)
+    }
+
+    pub fn write_block(&self, block: &[u8; 400]) -> Result<BlockSig, Error> {
         let sig = {
             let mut sig = [0u8; 64];
             sig.copy_from_slice(&block[0..64]);
-            sig
+            BlockSig(sig)
         };
         let tmp = self._write_content(block)?;
         let dst = self.block_path(&sig);
@@ -190,39 +585,416 @@ impl Store {
         Ok(sig)
     }
 
+    /// Write a block signed by a rebuilder as an attestation of the manifest it refers to,
+    /// replacing any previous attestation by the same key
+    pub fn write_attestation(&self, block: &[u8; 400]) -> Result<(), Error> {
+        let key = ObjectId::try_from(&block[DIGEST]).unwrap();
+        let public_key: [u8; 32] = block[PUBLIC_KEY].try_into().unwrap();
+        let tmp = self._write_content(block)?;
+        let dst = self.attestation_path(&key, &public_key);
+        create_dir_all(dst.parent().unwrap())?;
+        Ok(rename(tmp, dst)?)
+    }
+
+    /// Write a block that refers to the [`crate::Annotation`] of the block with `sig`,
+    /// replacing any previous annotation of it
+    pub fn write_annotation(&self, sig: &BlockSig, block: &[u8; 400]) -> Result<(), Error> {
+        let tmp = self._write_content(block)?;
+        let dst = self.annotation_path(sig);
+        create_dir_all(dst.parent().unwrap())?;
+        Ok(rename(tmp, dst)?)
+    }
+
+    /// Write the block and point the tail of `project` and `branch` at it, replacing any
+    /// previous tail
+    ///
+    /// The blocks of the branch after the previous tail are added to the [`BlockIndex`],
+    /// unless indexes are disabled with [`Store::with_indexes`].
     pub fn write_tail(
         &self,
         project: &str,
         branch: &str,
         block: &[u8; 400],
-    ) -> io::Result<[u8; 64]> {
+    ) -> Result<BlockSig, Error> {
         let sig = self.write_block(block)?;
+        let previous_opt = if self.indexed {
+            self.read_tail(project, branch)?
+                .map(|data| BlockSig::try_from(&data[..64]).unwrap())
+        } else {
+            None
+        };
         let mut pb = self.basedir.join("tail");
         create_dir_if_needed(&pb)?;
         pb.push(project);
         create_dir_if_needed(&pb)?;
         pb.push(branch);
         let target = tail_to_block(&sig);
-        symlink(target.as_path(), pb.as_path())?;
+        let tmp = self.temp_path();
+        symlink(target.as_path(), tmp.as_path())?;
+        rename(tmp, pb)?;
+        if self.indexed {
+            BlockIndex::append(self, project, branch, previous_opt.as_ref(), &sig)?;
+            self.write_tail_index()?;
+        }
         Ok(sig)
     }
 
-    pub fn open_block(&self, sig: &[u8; 64]) -> io::Result<File> {
-        File::open(self.block_path(sig))
+    /// Read the tail block of `project` and `branch`, if there is one
+    pub fn read_tail(&self, project: &str, branch: &str) -> Result<Option<[u8; 400]>, Error> {
+        let path = self.basedir.join("tail").join(project).join(branch);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut block = [0u8; 400];
+        File::open(path)?.read_exact(&mut block)?;
+        Ok(Some(block))
+    }
+
+    /// List the projects and branches that have tails in this store
+    pub fn tail_index(&self) -> Result<BTreeMap<String, Vec<String>>, Error> {
+        let mut index = BTreeMap::new();
+
+        let tail = self.basedir.join("tail");
+        if !tail.is_dir() {
+            return Ok(index);
+        }
+
+        for project_entry in read_dir(tail)? {
+            let project_entry = project_entry?;
+            if !project_entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let project = project_entry
+                .file_name()
+                .into_string()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+
+            let mut branches = Vec::new();
+            for branch_entry in read_dir(project_entry.path())? {
+                let branch = branch_entry?.file_name().into_string().map_err(|err| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err))
+                })?;
+                branches.push(branch);
+            }
+            branches.sort();
+
+            index.insert(project, branches);
+        }
+
+        Ok(index)
+    }
+
+    /// Write `tail/index.json`, listing the projects and branches of this store
+    ///
+    /// The index allows clients to discover tails over HTTP, it is not signed
+    pub fn write_tail_index(&self) -> Result<(), Error> {
+        self.check_writable()?;
+        let index = self.tail_index()?;
+        let json = serde_json::to_vec_pretty(&index).map_err(io::Error::from)?;
+
+        let path = self.basedir.join("tail").join("index.json");
+        let tmp = path.with_extension("json.partial");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&json)?;
+            file.sync_all()?;
+        }
+        Ok(rename(tmp, path)?)
+    }
+
+    /// Read `cas/index.json`, which maps object digests to IPFS content identifiers
+    ///
+    /// Returns an empty index if the store has none, see [`crate::CasTransport`].
+    pub fn read_cas_index(&self) -> Result<BTreeMap<String, String>, Error> {
+        match std::fs::read(self.basedir.join("cas").join("index.json")) {
+            Ok(data) => Ok(serde_json::from_slice(&data).map_err(io::Error::from)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Add the objects published by `ipfs add -r object/` to `cas/index.json`, returning how
+    /// many were added
+    ///
+    /// Each line of `listing` is `added <cid> object/<digest>`, other lines are ignored. The
+    /// objects must be in this store.
+    pub fn add_cas_index(&self, listing: &str) -> Result<usize, Error> {
+        let mut index = self.read_cas_index()?;
+        let mut added = 0;
+        for line in listing.lines() {
+            let mut words = line.split_whitespace();
+            let (cid, digest) = match (words.next(), words.next(), words.next()) {
+                (Some("added"), Some(cid), Some(path)) => match path.strip_prefix("object/") {
+                    Some(digest) => (cid, digest),
+                    None => continue,
+                },
+                _ => continue,
+            };
+
+            let key = object_key(digest)
+                .ok_or_else(|| Error::Config(format!("invalid object digest {}", digest)))?;
+            if !self.contains(&key) {
+                return Err(Error::NotFound(format!("object {} not found", digest)));
+            }
+            if index.insert(digest.to_string(), cid.to_string()).as_deref() != Some(cid) {
+                added += 1;
+            }
+        }
+
+        self.write_cas_index(&index)?;
+        Ok(added)
+    }
+
+    /// Replace `cas/index.json` with `index`
+    pub fn write_cas_index(&self, index: &BTreeMap<String, String>) -> Result<(), Error> {
+        self.check_writable()?;
+        let dir = self.basedir.join("cas");
+        create_dir_all(&dir)?;
+        let path = dir.join("index.json");
+        let tmp = path.with_extension("json.partial");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec_pretty(index).map_err(io::Error::from)?)?;
+            file.sync_all()?;
+        }
+        Ok(rename(tmp, path)?)
+    }
+
+    /// Write delta signatures for objects of at least `min_size` bytes that do not have one,
+    /// returning how many were written
+    ///
+    /// Signatures are written to `zsync/<digest>`, see [`crate::DeltaSignature`].
+    #[cfg(feature = "download")]
+    pub fn write_signatures(&self, min_size: u64) -> Result<usize, Error> {
+        let mut written = 0;
+        let dir = self.basedir.join("zsync");
+        for key in self.list_objects()? {
+            let key = key?;
+            let length = self.object_size(&key)?.unwrap_or(0);
+            let digest = key.to_string();
+            if length < min_size || dir.join(&digest).is_file() {
+                continue;
+            }
+
+            let signature = DeltaSignature::new(length, self.open_object(&key)?)?;
+            self.write_signature(&digest, &signature.to_bytes())?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    /// Write the delta signature of the object with `digest`
+    pub fn write_signature(&self, digest: &str, signature: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        if object_key(digest).is_none() {
+            return Err(Error::Config(format!("invalid object digest {}", digest)));
+        }
+        let dir = self.basedir.join("zsync");
+        create_dir_all(&dir)?;
+        let path = dir.join(digest);
+        let tmp = dir.join(format!(".{}.partial", digest));
+        File::create(&tmp)?.write_all(signature)?;
+        Ok(rename(tmp, path)?)
+    }
+
+    /// Read `torrent/index.json`, which maps object digests to magnet links, see
+    /// [`crate::Torrent`]
+    pub fn read_torrent_index(&self) -> Result<BTreeMap<String, String>, Error> {
+        match std::fs::read(self.basedir.join("torrent").join("index.json")) {
+            Ok(data) => Ok(serde_json::from_slice(&data).map_err(io::Error::from)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Replace `torrent/index.json` with `index`
+    pub fn write_torrent_index(&self, index: &BTreeMap<String, String>) -> Result<(), Error> {
+        self.check_writable()?;
+        let dir = self.basedir.join("torrent");
+        create_dir_all(&dir)?;
+        let path = dir.join("index.json");
+        let tmp = path.with_extension("json.partial");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec_pretty(index).map_err(io::Error::from)?)?;
+            file.sync_all()?;
+        }
+        Ok(rename(tmp, path)?)
+    }
+
+    /// Write the torrent of the object with `digest`
+    pub fn write_torrent(&self, digest: &str, torrent: &[u8]) -> Result<(), Error> {
+        self.check_writable()?;
+        if object_key(digest).is_none() {
+            return Err(Error::Config(format!("invalid object digest {}", digest)));
+        }
+        let dir = self.basedir.join("torrent");
+        create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.torrent", digest));
+        let tmp = path.with_extension("partial");
+        File::create(&tmp)?.write_all(torrent)?;
+        Ok(rename(tmp, path)?)
+    }
+
+    /// Write torrents for objects of at least `min_size` bytes that do not have one, returning
+    /// how many were written
+    ///
+    /// The object directories of `mirrors` are added as web seeds. Torrents are listed in
+    /// `torrent/index.json`.
+    #[cfg(feature = "download")]
+    pub fn write_torrents(&self, min_size: u64, mirrors: &[String]) -> Result<usize, Error> {
+        let webseeds: Vec<String> = mirrors
+            .iter()
+            .map(|url| format!("{}/object/", url.trim_end_matches('/')))
+            .collect();
+        let mut index = self.read_torrent_index()?;
+
+        let mut written = 0;
+        for key in self.list_objects()? {
+            let key = key?;
+            let length = self.object_size(&key)?.unwrap_or(0);
+            let digest = key.to_string();
+            if length < min_size || index.contains_key(&digest) {
+                continue;
+            }
+
+            let torrent = Torrent::new(&digest, length, self.open_object(&key)?, &webseeds)?;
+            self.write_torrent(&digest, &torrent.to_bytes())?;
+            index.insert(digest, torrent.magnet());
+            written += 1;
+        }
+
+        if written > 0 {
+            self.write_torrent_index(&index)?;
+        }
+        Ok(written)
+    }
+
+    pub fn open_block(&self, sig: &BlockSig) -> Result<File, Error> {
+        Ok(File::open(self.block_path(sig))?)
+    }
+
+    /// Copy the objects, blocks, delta signatures, attestations, annotations, torrents, CAS
+    /// index, and tails of this store to `dest` as a static mirror
+    ///
+    /// Tails are written as regular files instead of symlinks, and `tail/index.json` is
+    /// regenerated, so the mirror can be uploaded to hosts that do not support symlinks.
+    /// Objects, blocks, and signatures already in `dest` are not copied again.
+    pub fn export_mirror<P: AsRef<Path>>(&self, dest: P) -> Result<(), Error> {
+        let dest = dest.as_ref();
+
+        for kind in ["object", "block", "zsync"] {
+            let dir = self.basedir.join(kind);
+            let dest_dir = dest.join(kind);
+            create_dir_all(&dest_dir)?;
+            if !dir.is_dir() {
+                continue;
+            }
+
+            for entry in read_dir(dir)? {
+                let entry = entry?;
+                let dest_path = dest_dir.join(entry.file_name());
+                if !dest_path.is_file() {
+                    let tmp = dest_path.with_extension("partial");
+                    copy(entry.path(), &tmp)?;
+                    rename(tmp, dest_path)?;
+                }
+            }
+        }
+
+        let attestation_dir = self.basedir.join("attestation");
+        if attestation_dir.is_dir() {
+            for entry in read_dir(attestation_dir)? {
+                let entry = entry?;
+                let dest_dir = dest.join("attestation").join(entry.file_name());
+                create_dir_all(&dest_dir)?;
+                for attestation in read_dir(entry.path())? {
+                    let attestation = attestation?;
+                    let tmp = dest_dir.join(".partial");
+                    copy(attestation.path(), &tmp)?;
+                    rename(tmp, dest_dir.join(attestation.file_name()))?;
+                }
+            }
+        }
+
+        let annotation_dir = self.basedir.join("annotation");
+        if annotation_dir.is_dir() {
+            let dest_dir = dest.join("annotation");
+            create_dir_all(&dest_dir)?;
+            for entry in read_dir(annotation_dir)? {
+                let entry = entry?;
+                let tmp = dest_dir.join(".partial");
+                copy(entry.path(), &tmp)?;
+                rename(tmp, dest_dir.join(entry.file_name()))?;
+            }
+        }
+
+        let torrent_dir = self.basedir.join("torrent");
+        if torrent_dir.is_dir() {
+            let dest_dir = dest.join("torrent");
+            create_dir_all(&dest_dir)?;
+            for entry in read_dir(torrent_dir)? {
+                let entry = entry?;
+                let tmp = dest_dir.join(".partial");
+                copy(entry.path(), &tmp)?;
+                rename(tmp, dest_dir.join(entry.file_name()))?;
+            }
+        }
+
+        let cas_index = self.basedir.join("cas").join("index.json");
+        if cas_index.is_file() {
+            let dest_dir = dest.join("cas");
+            create_dir_all(&dest_dir)?;
+            let tmp = dest_dir.join(".index.json.partial");
+            copy(cas_index, &tmp)?;
+            rename(tmp, dest_dir.join("index.json"))?;
+        }
+
+        let index = self.tail_index()?;
+        for (project, branches) in index.iter() {
+            let dest_dir = dest.join("tail").join(project);
+            create_dir_all(&dest_dir)?;
+
+            for branch in branches.iter() {
+                let block = self.read_tail(project, branch)?.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("tail/{}/{} not found", project, branch),
+                    )
+                })?;
+
+                let dest_path = dest_dir.join(branch);
+                let tmp = dest_dir.join(format!(".{}.partial", branch));
+                File::create(&tmp)?.write_all(&block)?;
+                rename(tmp, dest_path)?;
+            }
+        }
+
+        let tail_dir = dest.join("tail");
+        create_dir_all(&tail_dir)?;
+        let tmp = tail_dir.join(".index.json.partial");
+        let json = serde_json::to_vec_pretty(&index).map_err(io::Error::from)?;
+        File::create(&tmp)?.write_all(&json)?;
+        Ok(rename(tmp, tail_dir.join("index.json"))?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::fs::{create_dir, File};
-    use std::io::{Read, Write};
+    use std::collections::BTreeMap;
+    use std::fs::{self, create_dir, File};
+    use std::io::{self, Read, Write};
     use std::os::unix::fs::PermissionsExt;
     use std::path::{Path, PathBuf};
+    use std::sync::Arc;
 
     use rand::{rngs::OsRng, RngCore};
     use tempfile::TempDir;
 
-    use super::{tail_to_block, Store};
+    use super::{tail_to_block, ImportMode, Store, StorePermissions};
+    use crate::{BlockSig, Error, ObjectId, SeededRng};
 
     #[test]
     fn test_new() {
@@ -248,15 +1020,115 @@ mod tests {
         assert_ne!(p1.to_str().unwrap()[10..], p2.to_str().unwrap()[10..]);
     }
 
+    #[test]
+    fn test_open() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        assert!(Store::open(temp_dir.path()).is_ok());
+
+        let missing = temp_dir.path().join("missing");
+        match Store::open(&missing) {
+            Err(Error::Store(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            _ => panic!("opened missing store"),
+        }
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let key = Store::new(&temp_dir).write_object(b"shared").unwrap();
+        fs::create_dir(temp_dir.path().join("artifacts")).unwrap();
+        fs::write(temp_dir.path().join("artifacts/a.txt"), b"a").unwrap();
+
+        let store = Store::open_read_only(temp_dir.path()).unwrap();
+        assert!(store.is_read_only());
+        assert!(store.contains(&key));
+
+        let denied = |result: Result<(), Error>| match result {
+            Err(Error::Store(err)) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+            _ => panic!("wrote to read-only store"),
+        };
+        denied(store.write_object(b"other").map(drop));
+        denied(store.write_tail("default", "master", &[0; 400]).map(drop));
+        denied(store.import_artifacts(0).map(drop));
+        denied(store.write_cas_index(&BTreeMap::new()));
+        denied(store.remove_tmp_dir());
+        assert!(temp_dir.path().join("artifacts/a.txt").is_file());
+        assert!(!temp_dir.path().join("tail").exists());
+        assert!(!temp_dir.path().join("cas").exists());
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_permissions() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir).with_permissions(StorePermissions::Group);
+        let key = store.write_object(b"shared").unwrap();
+        let mode = |path: PathBuf| path.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(store.object_path(&key)), 0o440);
+
+        fs::create_dir(temp_dir.path().join("artifacts")).unwrap();
+        fs::write(temp_dir.path().join("artifacts/a.txt"), b"a").unwrap();
+        let manifest = store.import_artifacts(0).unwrap();
+        assert_eq!(mode(store.object_path(&manifest.files["a.txt"])), 0o440);
+
+        assert_eq!("group".parse(), Ok(StorePermissions::Group));
+        assert!("world".parse::<StorePermissions>().is_err());
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        assert_eq!(store.namespaces().unwrap(), Vec::<String>::new());
+
+        let a = store.namespace("a").unwrap();
+        let b = store.namespace("b-2").unwrap();
+        assert_eq!(a.path(), temp_dir.path().join("namespace/a"));
+        let key = a.write_object(b"only in a").unwrap();
+        a.write_tail("default", "master", &[0; 400]).unwrap();
+        assert!(a.contains(&key));
+        assert!(!b.contains(&key));
+        assert!(!store.contains(&key));
+        assert!(b.read_tail("default", "master").unwrap().is_none());
+        assert_eq!(store.namespaces().unwrap(), vec!["a", "b-2"]);
+
+        for name in ["", ".", "..", ".hidden", "a/b", "a b"] {
+            match store.namespace(name) {
+                Err(Error::Config(_)) => (),
+                _ => panic!("accepted namespace {:?}", name),
+            }
+        }
+
+        let read_only = Store::open_read_only(temp_dir.path()).unwrap();
+        assert!(read_only.namespace("a").unwrap().is_read_only());
+        match read_only.namespace("c") {
+            Err(Error::NotFound(_)) => (),
+            _ => panic!("created namespace in read-only store"),
+        }
+        assert!(!temp_dir.path().join("namespace/c").exists());
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_temp_path_seeded() {
+        let a = Store::with_rng(Path::new("/nope"), Arc::new(SeededRng::new(1)));
+        let b = Store::with_rng(Path::new("/nope"), Arc::new(SeededRng::new(1)));
+        assert_eq!(a.temp_path(), b.temp_path());
+        assert_eq!(a.temp_path(), b.temp_path());
+        assert_ne!(a.temp_path(), a.temp_path());
+    }
+
     #[test]
     fn test_object_path() {
         let s = Store::new(Path::new("/p"));
         assert_eq!(
-            s.object_path(&[0u8; 48]).as_path(),
+            s.object_path(&ObjectId([0; 48])).as_path(),
             Path::new("/p/object/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA")
         );
         assert_eq!(
-            s.object_path(&[255u8; 48]).as_path(),
+            s.object_path(&ObjectId([255; 48])).as_path(),
             Path::new("/p/object/77777777777777777777777777777777777777777777777777777777777777777777777777776")
         );
     }
@@ -264,8 +1136,8 @@ mod tests {
     #[test]
     fn test_block_path() {
         let s = Store::new(Path::new("/p"));
-        let sig1 = [0u8; 64];
-        let sig2 = [255u8; 64];
+        let sig1 = BlockSig([0; 64]);
+        let sig2 = BlockSig([255; 64]);
         assert_eq!(
             s.block_path(&sig1).as_path(),
             Path::new("/p/block/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA")
@@ -276,6 +1148,73 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_import_artifacts_progress() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        let artifacts = temp_dir.path().join("artifacts");
+        create_dir(&artifacts).unwrap();
+        // Enough artifacts for a package repository, with some sharing contents
+        for index in 0..2000 {
+            fs::write(
+                artifacts.join(format!("pkg{:04}.deb", index)),
+                format!("package {}", index % 1500),
+            )
+            .unwrap();
+        }
+
+        let mut progress = Vec::new();
+        let manifest = store
+            .import_artifacts_with(0, |name, object, index, total| {
+                assert!(object.is_file());
+                progress.push((name.to_string(), index, total));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(manifest.files.len(), 2000);
+        assert_eq!(progress.len(), 2000);
+        assert_eq!(progress[0], ("pkg0000.deb".to_string(), 1, 2000));
+        assert_eq!(progress[1999], ("pkg1999.deb".to_string(), 2000, 2000));
+        assert_eq!(manifest.files["pkg0001.deb"], manifest.files["pkg1501.deb"]);
+        assert_eq!(
+            fs::read(artifacts.join("pkg1501.deb")).unwrap(),
+            b"package 1"
+        );
+    }
+
+    #[test]
+    fn test_import_modes() {
+        use std::os::unix::fs::MetadataExt;
+
+        for mode in [ImportMode::HardLink, ImportMode::Reflink] {
+            let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+            let store = Store::new(&temp_dir).with_import_mode(mode);
+            let artifacts = temp_dir.path().join("artifacts");
+            create_dir(&artifacts).unwrap();
+            fs::write(artifacts.join("a.bin"), b"same").unwrap();
+            fs::write(artifacts.join("b.bin"), b"same").unwrap();
+            fs::write(artifacts.join("cafe\u{301}.bin"), b"other").unwrap();
+
+            let manifest = store.import_artifacts(0).unwrap();
+            assert_eq!(manifest.files.len(), 3);
+            for (name, key) in manifest.files.iter() {
+                // Artifacts stay regular files, under their normalized names
+                let artifact = fs::symlink_metadata(artifacts.join(name)).unwrap();
+                assert!(artifact.is_file(), "{:?} {}", mode, name);
+                assert_eq!(
+                    fs::read(artifacts.join(name)).unwrap(),
+                    fs::read(store.object_path(key)).unwrap()
+                );
+                let object = fs::metadata(store.object_path(key)).unwrap();
+                assert_eq!(object.permissions().mode() & 0o777, 0o400);
+                if mode == ImportMode::HardLink && name != "b.bin" {
+                    assert_eq!(artifact.ino(), object.ino());
+                }
+            }
+            assert!(!artifacts.join("cafe\u{301}.bin").exists());
+        }
+    }
+
     #[test]
     fn test_import_object() {
         let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
@@ -298,7 +1237,7 @@ mod tests {
             file.write_all(&content).unwrap();
         }
 
-        let key: [u8; 48] = store.import_object(example.as_path()).unwrap();
+        let key: ObjectId = store.import_object(example.as_path()).unwrap();
 
         {
             let mut file = store.open_object(&key).unwrap();
@@ -329,7 +1268,7 @@ mod tests {
             OsRng.fill_bytes(&mut content);
             content
         };
-        let key: [u8; 48] = store.write_object(&content).unwrap();
+        let key: ObjectId = store.write_object(&content).unwrap();
 
         {
             let mut file = store.open_object(&key).unwrap();
@@ -352,6 +1291,31 @@ mod tests {
         temp_dir.close().unwrap();
     }
 
+    #[test]
+    fn test_list_objects() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+        assert_eq!(store.list_objects().unwrap().count(), 0);
+
+        let first = store.write_object(b"first").unwrap();
+        let second = store.write_object(b"second object").unwrap();
+        File::create(temp_dir.path().join("object").join("partial")).unwrap();
+
+        assert!(store.contains(&first));
+        assert_eq!(store.object_size(&second).unwrap(), Some(13));
+        let missing = ObjectId([0; 48]);
+        assert!(!store.contains(&missing));
+        assert_eq!(store.object_size(&missing).unwrap(), None);
+
+        let mut keys: Vec<ObjectId> = store.list_objects().unwrap().map(Result::unwrap).collect();
+        keys.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        temp_dir.close().unwrap();
+    }
+
     #[test]
     fn test_write_block() {
         let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
@@ -363,7 +1327,7 @@ mod tests {
             block
         };
 
-        let sig: [u8; 64] = store.write_block(&block).unwrap();
+        let sig: BlockSig = store.write_block(&block).unwrap();
         assert_eq!(sig.to_vec(), block[0..64].to_vec());
 
         {
@@ -400,6 +1364,8 @@ mod tests {
 
         let sig = store.write_tail("stuff", "junk", &block).unwrap();
         assert_eq!(sig.to_vec(), block[0..64].to_vec());
+        assert_eq!(store.read_tail("stuff", "junk").unwrap(), Some(block));
+        assert_eq!(store.read_tail("stuff", "nope").unwrap(), None);
 
         {
             let mut file = store.open_block(&sig).unwrap();
@@ -429,4 +1395,140 @@ mod tests {
 
         temp_dir.close().unwrap();
     }
+
+    #[test]
+    fn test_export_mirror() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path().join("store"));
+        create_dir(temp_dir.path().join("store")).unwrap();
+
+        let key = store.write_object(b"object").unwrap();
+        let mut block = [0u8; 400];
+        OsRng.fill_bytes(&mut block);
+        let sig = store.write_tail("stuff", "junk", &block).unwrap();
+
+        let mirror = temp_dir.path().join("mirror");
+        store.export_mirror(&mirror).unwrap();
+        // Exporting again over an existing mirror succeeds
+        store.export_mirror(&mirror).unwrap();
+
+        let mirror_store = Store::new(&mirror);
+        assert!(mirror_store.object_path(&key).is_file());
+        assert!(mirror_store.block_path(&sig).is_file());
+
+        let tail = mirror.join("tail").join("stuff").join("junk");
+        assert!(!tail.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(
+            mirror_store.read_tail("stuff", "junk").unwrap(),
+            Some(block)
+        );
+        assert_eq!(
+            mirror_store.tail_index().unwrap(),
+            store.tail_index().unwrap()
+        );
+        assert!(mirror.join("tail").join("index.json").is_file());
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_cas_index() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path());
+        assert!(store.read_cas_index().unwrap().is_empty());
+
+        let digest = store.write_object(b"object").unwrap().to_string();
+        let listing = format!(
+            "added QmObject object/{}\nadded QmDir object\n 6 B / 6 B 100%\n",
+            digest
+        );
+        assert_eq!(store.add_cas_index(&listing).unwrap(), 1);
+        assert_eq!(store.add_cas_index(&listing).unwrap(), 0);
+        assert_eq!(
+            store.read_cas_index().unwrap(),
+            [(digest.clone(), "QmObject".to_string())].into()
+        );
+
+        // Objects must be in the store
+        let missing = digest.replace(&digest[..4], "AAAA");
+        assert!(matches!(
+            store.add_cas_index(&format!("added QmMissing object/{}", missing)),
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            store.add_cas_index("added QmInvalid object/invalid"),
+            Err(Error::Config(_))
+        ));
+
+        let mirror = temp_dir.path().join("mirror");
+        store.export_mirror(&mirror).unwrap();
+        assert_eq!(
+            Store::new(&mirror).read_cas_index().unwrap(),
+            store.read_cas_index().unwrap()
+        );
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_torrents() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(temp_dir.path().join("store"));
+        create_dir(temp_dir.path().join("store")).unwrap();
+        let small = store.write_object(b"small").unwrap().to_string();
+        let large = store.write_object(b"large object").unwrap().to_string();
+
+        let mirrors = ["https://example.com/".to_string()];
+        assert_eq!(store.write_torrents(8, &mirrors).unwrap(), 1);
+        assert_eq!(store.write_torrents(8, &mirrors).unwrap(), 0);
+        let index = store.read_torrent_index().unwrap();
+        assert!(!index.contains_key(&small));
+        assert!(index[&large].starts_with("magnet:?xt=urn:btih:"));
+
+        let torrent = std::fs::read(
+            store
+                .path()
+                .join("torrent")
+                .join(format!("{}.torrent", large)),
+        )
+        .unwrap();
+        assert!(String::from_utf8_lossy(&torrent).contains("https://example.com/object/"));
+
+        let mirror = temp_dir.path().join("mirror");
+        store.export_mirror(&mirror).unwrap();
+        assert_eq!(Store::new(&mirror).read_torrent_index().unwrap(), index);
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_tail_index() {
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let store = Store::new(&temp_dir);
+
+        assert!(store.tail_index().unwrap().is_empty());
+
+        for (project, branch) in [("stuff", "junk"), ("stuff", "cruft"), ("other", "junk")] {
+            let mut block = [0u8; 400];
+            OsRng.fill_bytes(&mut block);
+            store.write_tail(project, branch, &block).unwrap();
+        }
+
+        let index = store.tail_index().unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index["stuff"], vec!["cruft", "junk"]);
+        assert_eq!(index["other"], vec!["junk"]);
+
+        let mut json = String::new();
+        File::open(temp_dir.path().join("tail").join("index.json"))
+            .unwrap()
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<BTreeMap<_, _>>(&json).unwrap(),
+            index
+        );
+
+        temp_dir.close().unwrap();
+    }
 }
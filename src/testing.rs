@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Fixtures for end-to-end tests of clients
+//!
+//! A [`TestStore`] signs builds into a temporary store with a [`KeyPair`], and
+//! [`TestStore::serve`] serves it over HTTP from the same process, so updaters can be tested
+//! against a real mirror without any external infrastructure.
+//!
+//! ```
+//! use buildchain::testing::{KeyPair, TestStore};
+//!
+//! let store = TestStore::new(KeyPair::from_seed(&[1; 32])).unwrap();
+//! store.publish("default", "master", 0, &[("update.bin", b"update")]).unwrap();
+//!
+//! let mirror = store.serve().unwrap();
+//! let downloader = mirror.downloader("default", "master").unwrap();
+//! assert_eq!(downloader.tail().unwrap().counter, 0);
+//! ```
+
+use std::collections::BTreeMap;
+use std::io;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use sodalite::{sign_attached, sign_keypair_seed};
+use tempfile::TempDir;
+
+use crate::block::PackedBlock;
+use crate::id::b32enc;
+use crate::verify::PublicKey;
+use crate::{Block, Clock, Downloader, Error, Manifest, Rng, Server, Store, SystemClock};
+
+/// A signing key pair
+#[derive(Clone)]
+pub struct KeyPair {
+    public_key: [u8; 32],
+    secret_key: [u8; 64],
+}
+
+impl KeyPair {
+    /// Derive a key pair from `seed`, so fixtures are the same on every run
+    pub fn from_seed(seed: &[u8; 32]) -> KeyPair {
+        let mut public_key = [0u8; 32];
+        let mut secret_key = [0u8; 64];
+        sign_keypair_seed(&mut public_key, &mut secret_key, seed);
+        KeyPair {
+            public_key,
+            secret_key,
+        }
+    }
+
+    /// Generate a key pair from the random bytes of `rng`
+    pub fn generate(rng: &dyn Rng) -> KeyPair {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        KeyPair::from_seed(&seed)
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    /// The public key in base32, as passed to [`Downloader::new`]
+    pub fn public_key_base32(&self) -> String {
+        b32enc(&self.public_key)
+    }
+
+    /// Sign a block following `previous`, referring to the manifest with sha384 `digest`
+    pub fn sign_block(
+        &self,
+        previous: &[u8; 64],
+        counter: u64,
+        timestamp: u64,
+        digest: &[u8; 48],
+    ) -> [u8; 400] {
+        let mut message = [0u8; 336];
+        message[..32].copy_from_slice(&self.public_key);
+        message[32..96].copy_from_slice(previous);
+        message[96..104].copy_from_slice(&counter.to_le_bytes());
+        message[104..112].copy_from_slice(&timestamp.to_le_bytes());
+        message[288..].copy_from_slice(digest);
+
+        let mut block = [0u8; 400];
+        sign_attached(&mut block, &message, &self.secret_key);
+        block
+    }
+}
+
+/// A store in a temporary directory, removed when dropped
+pub struct TestStore {
+    // Dropped after the store
+    temp_dir: TempDir,
+    store: Store,
+    key: KeyPair,
+    clock: Arc<dyn Clock>,
+}
+
+impl TestStore {
+    /// Create an empty store, signing blocks with `key`
+    pub fn new(key: KeyPair) -> Result<TestStore, Error> {
+        let temp_dir = TempDir::with_prefix("buildchain-test.")?;
+        let store = Store::new(&temp_dir);
+        Ok(TestStore {
+            temp_dir,
+            store,
+            key,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Timestamp blocks with `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> TestStore {
+        self.clock = clock;
+        self
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub fn path(&self) -> &Path {
+        self.temp_dir.path()
+    }
+
+    pub fn key(&self) -> &KeyPair {
+        &self.key
+    }
+
+    /// Publish a build of `files` with the manifest `time`, as the next tail of `project`
+    /// and `branch`
+    pub fn publish(
+        &self,
+        project: &str,
+        branch: &str,
+        time: u64,
+        files: &[(&str, &[u8])],
+    ) -> Result<Block, Error> {
+        let mut manifest = Manifest {
+            time,
+            files: BTreeMap::new(),
+            modes: BTreeMap::new(),
+            channel: None,
+            fork: None,
+            genesis: None,
+            digests: BTreeMap::new(),
+            report: None,
+            rollback: None,
+            devices: BTreeMap::new(),
+            source: None,
+        };
+        for (name, data) in files.iter() {
+            let key = self.store.write_object(data)?;
+            manifest.files.insert(name.to_string(), key);
+        }
+        let manifest_bytes = serde_json::to_vec_pretty(&manifest).map_err(io::Error::from)?;
+        let digest = self.store.write_object(&manifest_bytes)?;
+
+        let (previous, counter) = match self.store.read_tail(project, branch)? {
+            Some(tail) => {
+                let mut previous = [0u8; 64];
+                previous.copy_from_slice(&tail[..64]);
+                let tail = self.verify(&tail)?;
+                (previous, tail.counter + 1)
+            }
+            None => ([0u8; 64], 0),
+        };
+
+        let block = self
+            .key
+            .sign_block(&previous, counter, self.clock.now(), &digest);
+        self.store.write_tail(project, branch, &block)?;
+        self.verify(&block)
+    }
+
+    fn verify(&self, data: &[u8; 400]) -> Result<Block, Error> {
+        PackedBlock::from_bytes(data)
+            .map_err(Error::Verify)?
+            .verify(&PublicKey::from(self.key.public_key))
+            .map_err(|err| Error::Verify(err.to_string()))
+    }
+
+    /// Serve this store over HTTP on a local port until the returned mirror is dropped
+    pub fn serve(&self) -> Result<MockMirror<'_>, Error> {
+        let server = Server::new(Store::new(self.path()), "127.0.0.1:0").map_err(Error::Http)?;
+        let address = server
+            .address()
+            .ok_or_else(|| Error::Http("server has no address".to_string()))?;
+
+        let server = Arc::new(server);
+        let thread = {
+            let server = server.clone();
+            thread::spawn(move || server.run())
+        };
+
+        Ok(MockMirror {
+            server,
+            thread_opt: Some(thread),
+            url: format!("http://{}/", address),
+            key: self.key.public_key_base32(),
+            _store: PhantomData,
+        })
+    }
+}
+
+/// A [`TestStore`] served over HTTP, see [`TestStore::serve`]
+pub struct MockMirror<'a> {
+    server: Arc<Server>,
+    thread_opt: Option<JoinHandle<Result<(), String>>>,
+    url: String,
+    key: String,
+    _store: PhantomData<&'a TestStore>,
+}
+
+impl MockMirror<'_> {
+    /// The URL of the mirror
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Create a [`Downloader`] for `project` and `branch` that trusts the store's key
+    pub fn downloader(&self, project: &str, branch: &str) -> Result<Downloader, Error> {
+        Downloader::new(&self.key, &self.url, project, branch, None)
+    }
+}
+
+impl Drop for MockMirror<'_> {
+    fn drop(&mut self) {
+        self.server.stop();
+        if let Some(thread) = self.thread_opt.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{KeyPair, TestStore};
+    use crate::{FixedClock, SeededRng};
+
+    #[test]
+    fn test_mock_mirror() {
+        let key = KeyPair::generate(&SeededRng::new(1));
+        let store = TestStore::new(key)
+            .unwrap()
+            .with_clock(Arc::new(FixedClock(1_500_000_000)));
+
+        let first = store
+            .publish("default", "master", 1, &[("a.bin", b"first")])
+            .unwrap();
+        let second = store
+            .publish("default", "master", 2, &[("a.bin", b"second")])
+            .unwrap();
+        assert_eq!(first.counter, 0);
+        assert_eq!(second.counter, 1);
+        assert_eq!(second.timestamp, 1_500_000_000);
+        assert_eq!(second.previous_signature, first.signature);
+
+        let mirror = store.serve().unwrap();
+        let downloader = mirror.downloader("default", "master").unwrap();
+        let tail = downloader.tail().unwrap();
+        assert_eq!(tail.signature, second.signature);
+
+        let manifest = downloader.object(&tail.digest).unwrap();
+        assert!(String::from_utf8(manifest).unwrap().contains("\"time\": 2"));
+
+        let other = KeyPair::from_seed(&[2; 32]);
+        let wrong = crate::Downloader::new(
+            &other.public_key_base32(),
+            mirror.url(),
+            "default",
+            "master",
+            None,
+        )
+        .unwrap();
+        assert!(wrong.tail().is_err());
+    }
+}
@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! BitTorrent metadata for large objects, so clients can share downloads with each other
+//!
+//! Torrents are written to `torrent/<digest>.torrent` in a store, with the object digest as the
+//! name of the single file, and listed in `torrent/index.json` by digest. Mirrors are added as
+//! web seeds, so a torrent can always be completed from a mirror even with no other peers.
+
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+
+/// The default minimum size of objects to create torrents for, 64 MiB
+pub const DEFAULT_TORRENT_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The smallest piece length, pieces are doubled from here up to [`MAX_PIECE_LENGTH`] until
+/// there are at most [`TARGET_PIECES`]
+const MIN_PIECE_LENGTH: u64 = 256 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+const TARGET_PIECES: u64 = 2048;
+
+/// A bencoded value, dictionaries are sorted by key as required
+enum Bencode {
+    Int(u64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<&'static str, Bencode>),
+}
+
+impl Bencode {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(value) => out.extend_from_slice(format!("i{}e", value).as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Bencode::List(values) => {
+                out.push(b'l');
+                for value in values.iter() {
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+            Bencode::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries.iter() {
+                    Bencode::Bytes(key.as_bytes().to_vec()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+/// The metadata of a single file torrent
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Torrent {
+    /// The name of the file, which is the digest of the object
+    pub name: String,
+    pub length: u64,
+    pub piece_length: u64,
+    /// The SHA-1 of each piece
+    pub pieces: Vec<[u8; 20]>,
+    /// URLs of directories containing the file, such as `https://example.com/object/`
+    pub webseeds: Vec<String>,
+}
+
+impl Torrent {
+    /// Hash the `length` bytes of `reader` into a torrent for the file `name`
+    pub fn new<R: Read>(
+        name: &str,
+        length: u64,
+        mut reader: R,
+        webseeds: &[String],
+    ) -> io::Result<Torrent> {
+        let mut piece_length = MIN_PIECE_LENGTH;
+        while length / piece_length > TARGET_PIECES && piece_length < MAX_PIECE_LENGTH {
+            piece_length *= 2;
+        }
+
+        let mut pieces = Vec::new();
+        let mut buffer = vec![0; piece_length as usize];
+        let mut remaining = length;
+        while remaining > 0 {
+            let count = remaining.min(piece_length) as usize;
+            reader.read_exact(&mut buffer[..count])?;
+            pieces.push(Sha1::digest(&buffer[..count]).into());
+            remaining -= count as u64;
+        }
+
+        Ok(Torrent {
+            name: name.to_string(),
+            length,
+            piece_length,
+            pieces,
+            webseeds: webseeds.to_vec(),
+        })
+    }
+
+    fn info(&self) -> Bencode {
+        Bencode::Dict(
+            [
+                ("length", Bencode::Int(self.length)),
+                ("name", Bencode::Bytes(self.name.as_bytes().to_vec())),
+                ("piece length", Bencode::Int(self.piece_length)),
+                ("pieces", Bencode::Bytes(self.pieces.concat())),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    /// The hex info hash, which identifies the torrent to peers
+    pub fn info_hash(&self) -> String {
+        let mut info = Vec::new();
+        self.info().encode(&mut info);
+        Sha1::digest(&info)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// A magnet link for the torrent
+    pub fn magnet(&self) -> String {
+        format!("magnet:?xt=urn:btih:{}&dn={}", self.info_hash(), self.name)
+    }
+
+    /// The contents of the `.torrent` file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut entries = BTreeMap::new();
+        entries.insert("info", self.info());
+        if !self.webseeds.is_empty() {
+            entries.insert(
+                "url-list",
+                Bencode::List(
+                    self.webseeds
+                        .iter()
+                        .map(|url| Bencode::Bytes(url.as_bytes().to_vec()))
+                        .collect(),
+                ),
+            );
+        }
+
+        let mut data = Vec::new();
+        Bencode::Dict(entries).encode(&mut data);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Torrent, MIN_PIECE_LENGTH};
+
+    #[test]
+    fn test_torrent() {
+        let torrent = Torrent::new(
+            "NAME",
+            5,
+            &b"hello"[..],
+            &["https://example.com/object/".to_string()],
+        )
+        .unwrap();
+        assert_eq!(torrent.piece_length, MIN_PIECE_LENGTH);
+        assert_eq!(torrent.pieces.len(), 1);
+
+        let mut expected =
+            b"d4:infod6:lengthi5e4:name4:NAME12:piece lengthi262144e6:pieces20:".to_vec();
+        expected.extend_from_slice(&torrent.pieces[0]);
+        expected.extend_from_slice(b"e8:url-listl27:https://example.com/object/ee");
+        assert_eq!(torrent.to_bytes(), expected);
+        // The SHA-1 of "hello"
+        assert_eq!(torrent.pieces[0][..4], [0xaa, 0xf4, 0xc6, 0x1d]);
+        assert_eq!(torrent.info_hash().len(), 40);
+        assert!(torrent.magnet().starts_with("magnet:?xt=urn:btih:"));
+
+        // The last piece may be short
+        let length = MIN_PIECE_LENGTH + 1;
+        let torrent = Torrent::new("NAME", length, std::io::repeat(0), &[]).unwrap();
+        assert_eq!(torrent.pieces.len(), 2);
+        assert!(!String::from_utf8_lossy(&torrent.to_bytes()).contains("url-list"));
+
+        // Data shorter than the length fails
+        assert!(Torrent::new("NAME", 6, &b"hello"[..], &[]).is_err());
+    }
+}
@@ -0,0 +1,634 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::header::{
+    HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_LENGTH, ETAG, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, RANGE,
+};
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::{err_str, Auth, Error, Sha384, Store};
+
+/// The future returned by [`Transport`] methods
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send + 'a>>;
+
+/// Values from a previous response, used to make conditional requests
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Validators {
+    /// The `ETag` of the previous response
+    pub etag: Option<String>,
+    /// The `Last-Modified` time of the previous response
+    pub last_modified: Option<String>,
+}
+
+/// The result of a conditional request
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Fetched {
+    /// The file has not changed since the previous response
+    NotModified,
+    /// The file has changed, with its new contents and validators
+    Modified(Vec<u8>, Validators),
+}
+
+/// What a mirror reports about a file without sending it, see [`Transport::head`]
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct FileInfo {
+    /// The length of the file, if the mirror reports it
+    pub length: Option<u64>,
+    /// Whether the mirror can send part of the file, see [`Transport::get_range`]
+    pub accept_ranges: bool,
+}
+
+/// Fetches files from a buildchain mirror
+///
+/// Paths are relative to the root of the mirror, such as `tail/<project>/<branch>` and
+/// `object/<digest>`. Transports only move bytes, all verification is done by the
+/// [`crate::r#async::Downloader`].
+pub trait Transport: Send + Sync {
+    /// Get the file at `path`
+    fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>>;
+
+    /// Get the file at `path`, unless it has not changed since the response with `validators`
+    ///
+    /// The default implementation always gets the file.
+    fn get_conditional<'a>(
+        &'a self,
+        path: &'a str,
+        _validators: &'a Validators,
+    ) -> TransportFuture<'a, Fetched> {
+        Box::pin(async move {
+            let data = self.get(path).await?;
+            Ok(Fetched::Modified(data, Validators::default()))
+        })
+    }
+
+    /// Get the bytes in `range` of the file at `path`
+    ///
+    /// The default implementation gets the whole file.
+    fn get_range<'a>(&'a self, path: &'a str, range: Range<u64>) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let data = self.get(path).await?;
+            slice_range(path, data, range)
+        })
+    }
+
+    /// Get the length of the file at `path`, and whether it supports range requests
+    ///
+    /// The default implementation gets the whole file, and reports no range support, as the
+    /// default [`Transport::get_range`] also gets the whole file.
+    fn head<'a>(&'a self, path: &'a str) -> TransportFuture<'a, FileInfo> {
+        Box::pin(async move {
+            let data = self.get(path).await?;
+            Ok(FileInfo {
+                length: Some(data.len() as u64),
+                accept_ranges: false,
+            })
+        })
+    }
+
+    /// List the projects and branches on the mirror
+    ///
+    /// The default implementation reads `tail/index.json`.
+    fn index(&self) -> TransportFuture<'_, BTreeMap<String, Vec<String>>> {
+        Box::pin(async move {
+            let data = self.get("tail/index.json").await?;
+            serde_json::from_slice(&data).map_err(|err| Error::Http(err_str(err)))
+        })
+    }
+}
+
+/// A mirror served over HTTP(S)
+///
+/// Compressed responses are decoded by the client, see [`crate::DownloaderBuilder::compression`].
+pub struct HttpTransport {
+    url: reqwest::Url,
+    client: reqwest::Client,
+    auth_opt: Option<Auth>,
+    read_timeout_opt: Option<Duration>,
+}
+
+impl HttpTransport {
+    /// Create a transport for the mirror at `url`, sending `auth` with every request if provided
+    pub fn new(
+        url: reqwest::Url,
+        client: reqwest::Client,
+        auth_opt: Option<Auth>,
+    ) -> HttpTransport {
+        HttpTransport {
+            url,
+            client,
+            auth_opt,
+            read_timeout_opt: None,
+        }
+    }
+
+    /// Fail requests when no data is received for `timeout_opt`
+    pub fn read_timeout(mut self, timeout_opt: Option<Duration>) -> HttpTransport {
+        self.read_timeout_opt = timeout_opt;
+        self
+    }
+
+    /// Wait for `future`, failing if the read timeout passes first
+    async fn timed<T, F>(&self, path: &str, future: F) -> Result<T, Error>
+    where
+        F: Future<Output = reqwest::Result<T>>,
+    {
+        match self.read_timeout_opt {
+            Some(timeout) => tokio::time::timeout(timeout, future)
+                .await
+                .map_err(|_| Error::Http(format!("timed out downloading {}", path)))?
+                .map_err(http_err),
+            None => future.await.map_err(http_err),
+        }
+    }
+
+    /// Read the body of `response` a chunk at a time, so that stalls are detected
+    async fn body(&self, path: &str, mut response: reqwest::Response) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.timed(path, response.chunk()).await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self
+            .url
+            .join(path)
+            .map_err(|err| Error::Config(err_str(err)))?;
+        let request = self.client.request(method, url);
+        Ok(match &self.auth_opt {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic { username, password }) => {
+                request.basic_auth(username, password.as_ref())
+            }
+            None => request,
+        })
+    }
+}
+
+/// Take `range` from the whole file at `path`
+fn slice_range(path: &str, data: Vec<u8>, range: Range<u64>) -> Result<Vec<u8>, Error> {
+    data.get(range.start as usize..range.end as usize)
+        .map(|data| data.to_vec())
+        .ok_or_else(|| Error::Http(format!("{} has no bytes {:?}", path, range)))
+}
+
+fn http_err(err: reqwest::Error) -> Error {
+    Error::Http(err_str(err))
+}
+
+/// Fail unless `status` is a success, distinguishing missing files from other errors
+fn status_err(path: &str, status: StatusCode) -> Result<(), Error> {
+    let message = format!("failed to download {}: {:?}", path, status);
+    if status == StatusCode::NOT_FOUND {
+        Err(Error::NotFound(message))
+    } else if !status.is_success() {
+        Err(Error::Http(message))
+    } else {
+        Ok(())
+    }
+}
+
+impl Transport for HttpTransport {
+    fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let response = self
+                .timed(path, self.request(Method::GET, path)?.send())
+                .await?;
+            status_err(path, response.status())?;
+
+            self.body(path, response).await
+        })
+    }
+
+    fn get_range<'a>(&'a self, path: &'a str, range: Range<u64>) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            if range.is_empty() {
+                return Ok(Vec::new());
+            }
+            // Compression is not negotiated for range requests, so ranges are of the file as is
+            let request = self
+                .request(Method::GET, path)?
+                .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+            let response = self.timed(path, request.send()).await?;
+            status_err(path, response.status())?;
+
+            // Servers that do not support ranges send the whole file
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let data = self.body(path, response).await?;
+            if partial {
+                if data.len() as u64 != range.end - range.start {
+                    return Err(Error::Http(format!(
+                        "{} sent {} bytes for {:?}",
+                        path,
+                        data.len(),
+                        range
+                    )));
+                }
+                Ok(data)
+            } else {
+                slice_range(path, data, range)
+            }
+        })
+    }
+
+    fn head<'a>(&'a self, path: &'a str) -> TransportFuture<'a, FileInfo> {
+        Box::pin(async move {
+            // Compressed responses report the compressed length, so ask for the file as is
+            let request = self
+                .request(Method::HEAD, path)?
+                .header(ACCEPT_ENCODING, "identity");
+            let response = self.timed(path, request.send()).await?;
+            status_err(path, response.status())?;
+
+            // The body of a HEAD response is empty, so the header is read directly
+            let headers = response.headers();
+            let length = headers
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok());
+            let accept_ranges = headers
+                .get_all(ACCEPT_RANGES)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .any(|value| value.split(',').any(|unit| unit.trim() == "bytes"));
+            Ok(FileInfo {
+                length,
+                accept_ranges,
+            })
+        })
+    }
+
+    fn get_conditional<'a>(
+        &'a self,
+        path: &'a str,
+        validators: &'a Validators,
+    ) -> TransportFuture<'a, Fetched> {
+        Box::pin(async move {
+            let mut request = self.request(Method::GET, path)?;
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+
+            let response = self.timed(path, request.send()).await?;
+            if response.status() == StatusCode::NOT_MODIFIED {
+                return Ok(Fetched::NotModified);
+            }
+            status_err(path, response.status())?;
+
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value: &HeaderValue| value.to_str().ok())
+                    .map(|value| value.to_string())
+            };
+            let validators = Validators {
+                etag: header(ETAG),
+                last_modified: header(LAST_MODIFIED),
+            };
+
+            let data = self.body(path, response).await?;
+            Ok(Fetched::Modified(data, validators))
+        })
+    }
+}
+
+/// A mirror on the local filesystem, such as a USB stick
+pub struct LocalTransport {
+    dir: PathBuf,
+}
+
+impl LocalTransport {
+    /// Create a transport for the mirror in `dir`
+    pub fn new<P: AsRef<Path>>(dir: P) -> LocalTransport {
+        LocalTransport {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+fn local_err(path: &str, err: io::Error) -> Error {
+    let message = format!("failed to read {}: {}", path, err);
+    if err.kind() == io::ErrorKind::NotFound {
+        Error::NotFound(message)
+    } else {
+        Error::Store(io::Error::new(err.kind(), message))
+    }
+}
+
+impl Transport for LocalTransport {
+    fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            tokio::fs::read(self.dir.join(path))
+                .await
+                .map_err(|err| local_err(path, err))
+        })
+    }
+
+    fn get_range<'a>(&'a self, path: &'a str, range: Range<u64>) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let read = async {
+                let mut file = tokio::fs::File::open(self.dir.join(path)).await?;
+                file.seek(io::SeekFrom::Start(range.start)).await?;
+                let mut data = vec![0; (range.end - range.start) as usize];
+                file.read_exact(&mut data).await?;
+                Ok(data)
+            };
+            read.await.map_err(|err| local_err(path, err))
+        })
+    }
+
+    fn head<'a>(&'a self, path: &'a str) -> TransportFuture<'a, FileInfo> {
+        Box::pin(async move {
+            let metadata = tokio::fs::metadata(self.dir.join(path))
+                .await
+                .map_err(|err| local_err(path, err))?;
+            Ok(FileInfo {
+                length: Some(metadata.len()),
+                accept_ranges: true,
+            })
+        })
+    }
+
+    fn index(&self) -> TransportFuture<'_, BTreeMap<String, Vec<String>>> {
+        Box::pin(async move { Store::new(&self.dir).tail_index() })
+    }
+}
+
+/// A mirror kept in memory, for tests
+#[derive(Default)]
+pub struct MemoryTransport {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    /// Create an empty mirror
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    /// Add or replace the file at `path`
+    pub fn insert(&self, path: &str, data: &[u8]) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), data.to_vec());
+    }
+
+    /// Remove the file at `path`
+    pub fn remove(&self, path: &str) {
+        self.files.lock().unwrap().remove(path);
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>> {
+        let result = self
+            .files
+            .lock()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("{} not found", path)));
+        Box::pin(async move { result })
+    }
+}
+
+/// The path of the index of content identifiers on a mirror, see [`CasTransport`]
+pub const CAS_INDEX_PATH: &str = "cas/index.json";
+
+/// The path of the index of torrents on a mirror, see [`TorrentTransport`]
+pub const TORRENT_INDEX_PATH: &str = "torrent/index.json";
+
+/// The default command used by [`TorrentTransport`], `{dir}` and `{torrent}` are replaced by
+/// the download directory and the path of the torrent
+pub const DEFAULT_TORRENT_COMMAND: &[&str] =
+    &["aria2c", "--seed-time=0", "--dir={dir}", "{torrent}"];
+
+/// An unsigned index on a mirror mapping object digests to other names, read once
+struct MirrorIndex {
+    path: &'static str,
+    entries: Mutex<Option<BTreeMap<String, String>>>,
+}
+
+impl MirrorIndex {
+    fn new(path: &'static str) -> MirrorIndex {
+        MirrorIndex {
+            path,
+            entries: Mutex::new(None),
+        }
+    }
+
+    /// Look up the object with `digest`, reading the index from `mirror` the first time
+    async fn get(&self, mirror: &dyn Transport, digest: &str) -> Result<Option<String>, Error> {
+        if let Some(entries) = &*self.entries.lock().unwrap() {
+            return Ok(entries.get(digest).cloned());
+        }
+
+        let entries: BTreeMap<String, String> = match mirror.get(self.path).await {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|err| Error::Verify(format!("{}: {}", self.path, err_str(err))))?,
+            Err(Error::NotFound(_)) => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        let value_opt = entries.get(digest).cloned();
+        *self.entries.lock().unwrap() = Some(entries);
+        Ok(value_opt)
+    }
+}
+
+/// Fail unless `data` is the object with `digest`, which was fetched from `source`
+fn check_object(source: &str, digest: &str, data: &[u8]) -> Result<(), Error> {
+    if Sha384::new(data)?.to_base32() != digest {
+        return Err(Error::Verify(format!(
+            "{} does not match object {}",
+            source, digest
+        )));
+    }
+    Ok(())
+}
+
+/// A mirror whose objects are also published to IPFS or another content-addressed store
+///
+/// The index at [`CAS_INDEX_PATH`] on the mirror maps object digests to content identifiers,
+/// and objects in it are fetched from the gateway as `ipfs/<cid>`. Objects that are not in the
+/// index, or that the gateway fails to serve correctly, are fetched from the mirror. Neither
+/// the index nor the gateway have to be trusted, since objects are verified by their digests.
+pub struct CasTransport {
+    mirror: Box<dyn Transport>,
+    gateway: Box<dyn Transport>,
+    index: MirrorIndex,
+}
+
+impl CasTransport {
+    /// Fetch objects of `mirror` from `gateway` when they are published there
+    pub fn new(mirror: Box<dyn Transport>, gateway: Box<dyn Transport>) -> CasTransport {
+        CasTransport {
+            mirror,
+            gateway,
+            index: MirrorIndex::new(CAS_INDEX_PATH),
+        }
+    }
+
+    async fn get_from_gateway(&self, digest: &str) -> Result<Option<Vec<u8>>, Error> {
+        let cid = match self.index.get(self.mirror.as_ref(), digest).await? {
+            Some(cid) => cid,
+            None => return Ok(None),
+        };
+        let path = format!("ipfs/{}", cid);
+        let data = self.gateway.get(&path).await?;
+        check_object(&path, digest, &data)?;
+        Ok(Some(data))
+    }
+}
+
+impl Transport for CasTransport {
+    fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            if let Some(digest) = path.strip_prefix("object/") {
+                if let Ok(Some(data)) = self.get_from_gateway(digest).await {
+                    return Ok(data);
+                }
+            }
+            self.mirror.get(path).await
+        })
+    }
+
+    fn get_range<'a>(&'a self, path: &'a str, range: Range<u64>) -> TransportFuture<'a, Vec<u8>> {
+        self.mirror.get_range(path, range)
+    }
+
+    fn get_conditional<'a>(
+        &'a self,
+        path: &'a str,
+        validators: &'a Validators,
+    ) -> TransportFuture<'a, Fetched> {
+        self.mirror.get_conditional(path, validators)
+    }
+
+    fn head<'a>(&'a self, path: &'a str) -> TransportFuture<'a, FileInfo> {
+        self.mirror.head(path)
+    }
+
+    fn index(&self) -> TransportFuture<'_, BTreeMap<String, Vec<String>>> {
+        self.mirror.index()
+    }
+}
+
+/// A mirror that publishes torrents of large objects, which are downloaded from peers
+///
+/// Objects listed in the index at [`TORRENT_INDEX_PATH`] are downloaded by running a
+/// BitTorrent client on their torrent, see [`DEFAULT_TORRENT_COMMAND`]. Torrents list the
+/// mirror as a web seed, so the client can finish without peers. Objects that are not listed,
+/// or that the client fails to download correctly, are fetched from the mirror.
+pub struct TorrentTransport {
+    mirror: Box<dyn Transport>,
+    command: Vec<String>,
+    index: MirrorIndex,
+}
+
+impl TorrentTransport {
+    /// Download torrents of `mirror` with `command`, see [`DEFAULT_TORRENT_COMMAND`]
+    pub fn new(mirror: Box<dyn Transport>, command: Vec<String>) -> TorrentTransport {
+        TorrentTransport {
+            mirror,
+            command,
+            index: MirrorIndex::new(TORRENT_INDEX_PATH),
+        }
+    }
+
+    async fn get_from_peers(&self, digest: &str) -> Result<Option<Vec<u8>>, Error> {
+        if self
+            .index
+            .get(self.mirror.as_ref(), digest)
+            .await?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let path = format!("torrent/{}.torrent", digest);
+        let torrent = self.mirror.get(&path).await?;
+
+        let temp_dir = tempfile::TempDir::with_prefix("buildchain-torrent.")?;
+        let torrent_path = temp_dir.path().join("object.torrent");
+        tokio::fs::write(&torrent_path, torrent).await?;
+        let args: Vec<String> = self
+            .command
+            .iter()
+            .map(|arg| {
+                arg.replace("{dir}", &temp_dir.path().to_string_lossy())
+                    .replace("{torrent}", &torrent_path.to_string_lossy())
+            })
+            .collect();
+        let (program, args) = args
+            .split_first()
+            .ok_or_else(|| Error::Config("empty torrent command".to_string()))?;
+
+        // The output of the client goes to stderr, as stdout may be used for artifacts
+        let mut command = process::Command::new(program);
+        command.args(args).stdout(io::stderr());
+        let status = tokio::task::spawn_blocking(move || command.status())
+            .await
+            .map_err(|err| Error::Exec(io::Error::other(err)))?
+            .map_err(Error::Exec)?;
+        if !status.success() {
+            return Err(Error::Exec(io::Error::other(format!(
+                "{} exited with {}",
+                program, status
+            ))));
+        }
+
+        let data = tokio::fs::read(temp_dir.path().join(digest)).await?;
+        check_object(&path, digest, &data)?;
+        Ok(Some(data))
+    }
+}
+
+impl Transport for TorrentTransport {
+    fn get<'a>(&'a self, path: &'a str) -> TransportFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            if let Some(digest) = path.strip_prefix("object/") {
+                if let Ok(Some(data)) = self.get_from_peers(digest).await {
+                    return Ok(data);
+                }
+            }
+            self.mirror.get(path).await
+        })
+    }
+
+    fn get_range<'a>(&'a self, path: &'a str, range: Range<u64>) -> TransportFuture<'a, Vec<u8>> {
+        self.mirror.get_range(path, range)
+    }
+
+    fn get_conditional<'a>(
+        &'a self,
+        path: &'a str,
+        validators: &'a Validators,
+    ) -> TransportFuture<'a, Fetched> {
+        self.mirror.get_conditional(path, validators)
+    }
+
+    fn head<'a>(&'a self, path: &'a str) -> TransportFuture<'a, FileInfo> {
+        self.mirror.head(path)
+    }
+
+    fn index(&self) -> TransportFuture<'_, BTreeMap<String, Vec<String>>> {
+        self.mirror.index()
+    }
+}
@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A high level update API, for applications that install builds from a mirror
+//!
+//! An update is found with [`Updater::check`], its files are selected with [`Updater::plan`],
+//! and they are downloaded with [`Updater::fetch`], which verifies them and returns the paths
+//! to hand off to the installer. [`Updater::apply`] hands them to an [`Installer`] instead.
+//! All verification of tails, manifests, and objects is done by the [`Downloader`].
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::install::install_artifact;
+use crate::{
+    Block, Downloader, Error, InstallArtifact, InstallOutcome, Installer, Manifest, ObjectId,
+};
+
+/// A build newer than the installed one, found by [`Updater::check`]
+#[derive(Clone, Debug, Serialize)]
+pub struct Update {
+    pub block: Block,
+    pub manifest: Manifest,
+}
+
+/// The files of an [`Update`] to download, created by [`Updater::plan`]
+#[derive(Clone, Debug, Serialize)]
+pub struct UpdatePlan {
+    pub block: Block,
+    pub manifest: Manifest,
+    /// The selected files and their digests
+    pub files: BTreeMap<String, ObjectId>,
+}
+
+/// Checks for, downloads, and verifies updates, staging them in a directory
+pub struct Updater {
+    dl: Downloader,
+    dir: PathBuf,
+    device_opt: Option<(String, Option<String>)>,
+}
+
+impl Updater {
+    /// Update from the mirror of `dl`, staging files in `dir`
+    pub fn new<P: AsRef<Path>>(dl: Downloader, dir: P) -> Updater {
+        Updater {
+            dl,
+            dir: dir.as_ref().to_path_buf(),
+            device_opt: None,
+        }
+    }
+
+    /// Only offer builds rolled out to the device with `seed`, see [`crate::Channel`]
+    pub fn device(mut self, seed: &str, cohort_opt: Option<&str>) -> Updater {
+        self.device_opt = Some((seed.to_string(), cohort_opt.map(str::to_string)));
+        self
+    }
+
+    /// The Downloader used for updates
+    pub fn downloader(&self) -> &Downloader {
+        &self.dl
+    }
+
+    /// Check for a build newer than the installed build with `installed_counter`
+    ///
+    /// Returns `None` if there is no newer build, or if nothing is installed and there is no
+    /// build.
+    pub fn check(&self, installed_counter: Option<u64>) -> Result<Option<Update>, Error> {
+        let block = match &self.device_opt {
+            Some((seed, cohort_opt)) => self
+                .dl
+                .tail_for_device(seed.as_bytes(), cohort_opt.as_deref())?,
+            None => self.dl.tail()?,
+        };
+        if installed_counter.is_some_and(|counter| block.counter <= counter) {
+            return Ok(None);
+        }
+
+        let manifest_json = self.dl.object(&block.digest)?;
+        let manifest = Manifest::from_signed(&manifest_json, &block).map_err(Error::Verify)?;
+        Ok(Some(Update { block, manifest }))
+    }
+
+    /// Select the `files` of `update` to download, or every file if `files` is empty
+    pub fn plan(&self, update: &Update, files: &[&str]) -> Result<UpdatePlan, Error> {
+        let mut selected = BTreeMap::new();
+        for (name, digest) in update.manifest.files.iter() {
+            if files.is_empty() || files.contains(&name.as_str()) {
+                if name.is_empty() || name.split('/').any(|part| part == ".." || part.is_empty()) {
+                    return Err(Error::Verify(format!("invalid artifact name {}", name)));
+                }
+                selected.insert(name.clone(), *digest);
+            }
+        }
+        for file in files.iter() {
+            if !selected.contains_key(*file) {
+                return Err(Error::NotFound(format!(
+                    "{} not found in build {}",
+                    file, update.block.counter
+                )));
+            }
+        }
+
+        Ok(UpdatePlan {
+            block: update.block.clone(),
+            manifest: update.manifest.clone(),
+            files: selected,
+        })
+    }
+
+    /// The directory files of the build of `plan` are staged in
+    pub fn staging_dir(&self, plan: &UpdatePlan) -> PathBuf {
+        self.dir.join(plan.block.counter.to_string())
+    }
+
+    /// Download the files of `plan` into the staging directory, returning their verified paths
+    ///
+    /// Files already staged and verified are not downloaded again, so an interrupted fetch can
+    /// be resumed by calling it again. The plan is checked against the policy of the
+    /// Downloader first, see [`Downloader::set_policy`].
+    pub fn fetch(&self, plan: &UpdatePlan) -> Result<Vec<PathBuf>, Error> {
+        let names: Vec<&str> = plan.files.keys().map(String::as_str).collect();
+        self.dl.check_policy(&plan.block, &plan.manifest, &names)?;
+
+        let dir = self.staging_dir(plan);
+        for (name, digest) in plan.files.iter() {
+            let path = dir.join(name);
+            if self.verify_file(plan, name, &path).is_ok() {
+                continue;
+            }
+
+            let data = self.dl.object(digest)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut tmp = OsString::from(path.as_os_str());
+            tmp.push(".partial");
+            {
+                let mut file = fs::File::create(&tmp)?;
+                file.write_all(&data)?;
+                if let Some(mode) = plan.manifest.modes.get(name) {
+                    file.set_permissions(fs::Permissions::from_mode(*mode))?;
+                }
+                file.sync_all()?;
+            }
+            fs::rename(tmp, path)?;
+        }
+        self.verify(plan)
+    }
+
+    fn verify_file(&self, plan: &UpdatePlan, name: &str, path: &Path) -> Result<(), Error> {
+        let data = fs::read(path)
+            .map_err(|err| Error::NotFound(format!("{} is not staged: {}", name, err)))?;
+        plan.manifest
+            .verify_file(name, &data)
+            .map_err(Error::Verify)
+    }
+
+    /// Hand the staged files of `plan` to `installer`, after verifying them again
+    ///
+    /// With `dry_run`, the installer only describes what it would do. Files are installed in
+    /// the order of their names, and installation stops at the first failure.
+    pub fn apply(
+        &self,
+        plan: &UpdatePlan,
+        installer: &dyn Installer,
+        dry_run: bool,
+    ) -> Result<Vec<InstallOutcome>, Error> {
+        let paths = self.verify(plan)?;
+        let mut outcomes = Vec::new();
+        for ((name, digest), path) in plan.files.iter().zip(paths.iter()) {
+            let artifact = InstallArtifact {
+                name,
+                path,
+                digest,
+                block: &plan.block,
+            };
+            let outcome = install_artifact(installer, &artifact, dry_run)
+                .map_err(|reason| Error::Exec(io::Error::other(reason)))?;
+            outcomes.push(outcome);
+        }
+        Ok(outcomes)
+    }
+
+    /// Verify the staged files of `plan` again, returning their paths
+    ///
+    /// Installers should call this before using files staged by an earlier process.
+    pub fn verify(&self, plan: &UpdatePlan) -> Result<Vec<PathBuf>, Error> {
+        let dir = self.staging_dir(plan);
+        let mut paths = Vec::new();
+        for name in plan.files.keys() {
+            let path = dir.join(name);
+            self.verify_file(plan, name, &path)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::Updater;
+    use crate::block::tests::publish_chain;
+    use crate::{
+        Block, CommandInstaller, Downloader, Error, Manifest, MemoryTransport, Policy, PolicyFile,
+        Sha384,
+    };
+
+    /// Publish a build with two files at counter 5, after empty builds, returning the key and
+    /// the mirror
+    fn mirror() -> (String, MemoryTransport) {
+        let transport = MemoryTransport::new();
+        let mut manifest = Manifest::default();
+        for (name, data) in [("a.bin", "a"), ("dir/b.bin", "b")] {
+            let digest = Sha384::new(data.as_bytes()).unwrap().to_id();
+            transport.insert(&format!("object/{}", digest), data.as_bytes());
+            manifest.files.insert(name.to_string(), digest);
+        }
+        let mut manifests = vec![Manifest::default(); 5];
+        manifests.push(manifest);
+        let key = publish_chain(&transport, &manifests);
+        (key, transport)
+    }
+
+    #[test]
+    fn test_updater() {
+        let (key, transport) = mirror();
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        let updater = Updater::new(dl, temp_dir.path());
+
+        assert!(updater.check(Some(5)).unwrap().is_none());
+        let update = updater.check(Some(4)).unwrap().unwrap();
+        assert_eq!(update.block.counter, 5);
+
+        assert!(matches!(
+            updater.plan(&update, &["missing"]),
+            Err(Error::NotFound(_))
+        ));
+        let plan = updater.plan(&update, &["dir/b.bin"]).unwrap();
+        assert_eq!(plan.files.len(), 1);
+
+        let paths = updater.fetch(&plan).unwrap();
+        assert_eq!(paths, [temp_dir.path().join("5/dir/b.bin")]);
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"b");
+        assert_eq!(updater.verify(&plan).unwrap(), paths);
+
+        // Staged files that were changed fail verification, and are fetched again
+        fs::write(&paths[0], "changed").unwrap();
+        assert!(matches!(updater.verify(&plan), Err(Error::Verify(_))));
+        updater.fetch(&plan).unwrap();
+        assert_eq!(fs::read(&paths[0]).unwrap(), b"b");
+
+        temp_dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_apply() {
+        let (key, transport) = mirror();
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        let updater = Updater::new(dl, temp_dir.path().join("staging"));
+        let update = updater.check(None).unwrap().unwrap();
+        let plan = updater.plan(&update, &[]).unwrap();
+        updater.fetch(&plan).unwrap();
+
+        let log = temp_dir.path().join("installed");
+        let installer = CommandInstaller::new(&format!("echo \"$2\" >> {}", log.display()));
+        let outcomes = updater.apply(&plan, &installer, true).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|outcome| outcome.dry_run));
+        assert!(!log.exists());
+
+        updater.apply(&plan, &installer, false).unwrap();
+        assert_eq!(fs::read_to_string(&log).unwrap(), "a.bin\ndir/b.bin\n");
+
+        // Staged files are verified again before they are installed
+        fs::write(updater.staging_dir(&plan).join("a.bin"), "changed").unwrap();
+        assert!(matches!(
+            updater.apply(&plan, &installer, false),
+            Err(Error::Verify(_))
+        ));
+        assert!(matches!(
+            updater.apply(&plan, &CommandInstaller::new("exit 1"), true),
+            Err(Error::Verify(_))
+        ));
+
+        temp_dir.close().unwrap();
+    }
+
+    /// Refuses files in directories, and files that are not one byte long
+    struct TopLevelPolicy;
+
+    impl Policy for TopLevelPolicy {
+        fn check_file(
+            &self,
+            _block: &Block,
+            _manifest: &Manifest,
+            file: &PolicyFile,
+        ) -> Result<(), String> {
+            if file.name.contains('/') {
+                return Err("files in directories are not allowed".to_string());
+            }
+            if file.length != Some(1) {
+                return Err(format!("unexpected length {:?}", file.length));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let (key, transport) = mirror();
+        let temp_dir = TempDir::with_prefix("buildchain-test.").unwrap();
+        let mut dl =
+            Downloader::from_transport(&key, "default", "master", Box::new(transport)).unwrap();
+        dl.set_policy(Box::new(TopLevelPolicy));
+        let updater = Updater::new(dl, temp_dir.path());
+
+        // Nothing is downloaded if any file is refused
+        let update = updater.check(None).unwrap().unwrap();
+        let plan = updater.plan(&update, &[]).unwrap();
+        assert!(matches!(
+            updater.fetch(&plan),
+            Err(Error::Denied(message)) if message.starts_with("dir/b.bin denied")
+        ));
+        assert!(!updater.staging_dir(&plan).exists());
+
+        let plan = updater.plan(&update, &["a.bin"]).unwrap();
+        assert_eq!(updater.fetch(&plan).unwrap().len(), 1);
+
+        temp_dir.close().unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! Verification of blocks and objects, without network, filesystem, or process access
+//!
+//! Verifying a block or object only depends on the signature and hash implementations, and
+//! does not allocate, so it is suitable for early boot and recovery environments. Only
+//! [`VerifiedBlock::to_block`] uses the rest of the crate, to convert to a [`Block`].
+
+use core::array::TryFromSliceError;
+use core::fmt;
+use core::hint::black_box;
+use core::ops::Range;
+
+use sha2::{Digest, Sha384};
+use sodalite::sign_attached_open;
+
+use crate::Block;
+
+/// The size of a signed block
+pub const BLOCK_SIZE: usize = 400;
+
+// The fields of a block, integers are little-endian
+pub(crate) const SIGNATURE: Range<usize> = 0..64;
+pub(crate) const PUBLIC_KEY: Range<usize> = 64..96;
+pub(crate) const PREVIOUS_SIGNATURE: Range<usize> = 96..160;
+pub(crate) const COUNTER: Range<usize> = 160..168;
+pub(crate) const TIMESTAMP: Range<usize> = 168..176;
+pub(crate) const DIGEST: Range<usize> = 352..BLOCK_SIZE;
+
+/// The reasons verification can fail
+#[derive(Clone, Copy, Debug, Eq, PartialEq, thiserror::Error)]
+pub enum VerifyError {
+    #[error("public key mismatch")]
+    PublicKeyMismatch,
+    #[error("signature invalid")]
+    SignatureInvalid,
+    #[error("message data invalid")]
+    MessageInvalid,
+    #[error("sha384 mismatch")]
+    DigestMismatch,
+}
+
+/// Compare `a` and `b` in time that depends only on their lengths, not their contents
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a
+        .iter()
+        .zip(b.iter())
+        .fold(0u8, |difference, (x, y)| black_box(difference | (x ^ y)));
+    difference == 0
+}
+
+/// The public key of a signer
+///
+/// Keys can only be created with the right length, and compare in constant time.
+#[derive(Clone, Copy)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// True if `data` is this key, compared in constant time
+    pub fn matches(&self, data: &[u8]) -> bool {
+        constant_time_eq(&self.0, data)
+    }
+}
+
+impl From<[u8; 32]> for PublicKey {
+    fn from(key: [u8; 32]) -> PublicKey {
+        PublicKey(key)
+    }
+}
+
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = TryFromSliceError;
+
+    fn try_from(key: &[u8]) -> Result<PublicKey, TryFromSliceError> {
+        key.try_into().map(PublicKey)
+    }
+}
+
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &PublicKey) -> bool {
+        self.matches(&other.0)
+    }
+}
+
+impl Eq for PublicKey {}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({:02x?})", self.0)
+    }
+}
+
+/// A block whose signature has been verified, borrowing the signed bytes
+#[derive(Clone, Copy, Debug)]
+pub struct VerifiedBlock<'a> {
+    data: &'a [u8; BLOCK_SIZE],
+}
+
+/// Decode the little-endian integer in `field` of a block
+pub(crate) fn u64_le(data: &[u8; BLOCK_SIZE], field: Range<usize>) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[field]);
+    u64::from_le_bytes(bytes)
+}
+
+impl<'a> VerifiedBlock<'a> {
+    /// The signature of this block
+    pub fn signature(&self) -> &'a [u8] {
+        &self.data[SIGNATURE]
+    }
+
+    /// The public key that signed this block
+    pub fn public_key(&self) -> &'a [u8] {
+        &self.data[PUBLIC_KEY]
+    }
+
+    /// The signature of the previous block in the chain
+    pub fn previous_signature(&self) -> &'a [u8] {
+        &self.data[PREVIOUS_SIGNATURE]
+    }
+
+    /// The position of this block in the chain
+    pub fn counter(&self) -> u64 {
+        u64_le(self.data, COUNTER)
+    }
+
+    /// The time this block was signed
+    pub fn timestamp(&self) -> u64 {
+        u64_le(self.data, TIMESTAMP)
+    }
+
+    /// The sha384 of the manifest this block refers to
+    pub fn digest(&self) -> &'a [u8; 48] {
+        self.data[DIGEST].try_into().unwrap()
+    }
+
+    /// Convert to a [`Block`], with base32 encoded fields
+    pub fn to_block(&self) -> Block {
+        Block::from_unverified(self.data)
+    }
+}
+
+/// Verify that `data` is a block signed by `key`
+pub fn verify_block<'a>(
+    data: &'a [u8; BLOCK_SIZE],
+    key: &[u8; 32],
+) -> Result<VerifiedBlock<'a>, VerifyError> {
+    if !constant_time_eq(&data[PUBLIC_KEY], key) {
+        return Err(VerifyError::PublicKeyMismatch);
+    }
+
+    let mut m = [0u8; BLOCK_SIZE];
+    let count =
+        sign_attached_open(&mut m, data, key).map_err(|()| VerifyError::SignatureInvalid)?;
+
+    // Check that message matches signed message after skipping the signature
+    if m[..count] != data[PUBLIC_KEY.start..] {
+        return Err(VerifyError::MessageInvalid);
+    }
+
+    Ok(VerifiedBlock { data })
+}
+
+/// Verifies an object incrementally, so that large objects do not need to be in memory
+#[derive(Clone, Default)]
+pub struct ObjectVerifier {
+    hasher: Sha384,
+}
+
+impl ObjectVerifier {
+    pub fn new() -> ObjectVerifier {
+        ObjectVerifier::default()
+    }
+
+    /// Hash the next part of the object
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    /// Check that the object hashed so far has the sha384 `digest`
+    pub fn verify(self, digest: &[u8; 48]) -> Result<(), VerifyError> {
+        if self.hasher.finalize().as_slice() == digest {
+            Ok(())
+        } else {
+            Err(VerifyError::DigestMismatch)
+        }
+    }
+}
+
+/// Verify that `data` has the sha384 `digest`
+pub fn verify_object(data: &[u8], digest: &[u8; 48]) -> Result<(), VerifyError> {
+    let mut verifier = ObjectVerifier::new();
+    verifier.update(data);
+    verifier.verify(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha384};
+
+    use super::{verify_block, verify_object, ObjectVerifier, PublicKey, VerifyError};
+    use crate::block::tests::signed_block;
+
+    #[test]
+    fn test_verify_block() {
+        let (key, block) = signed_block(1, &[2; 64], 3, &[4; 48]);
+
+        let verified = verify_block(&block, &key).unwrap();
+        assert_eq!(verified.public_key(), key);
+        assert_eq!(verified.signature(), &block[..64]);
+        assert_eq!(verified.previous_signature(), [2; 64]);
+        assert_eq!(verified.counter(), 3);
+        assert_eq!(verified.timestamp(), 1_500_000_003);
+        assert_eq!(verified.digest(), &[4; 48]);
+        assert_eq!(verified.to_block().counter, 3);
+
+        let (other_key, _block) = signed_block(2, &[0; 64], 0, &[0; 48]);
+        assert_eq!(
+            verify_block(&block, &other_key).unwrap_err(),
+            VerifyError::PublicKeyMismatch
+        );
+
+        let mut tampered = block;
+        tampered[200] ^= 1;
+        assert_eq!(
+            verify_block(&tampered, &key).unwrap_err(),
+            VerifyError::SignatureInvalid
+        );
+    }
+
+    #[test]
+    fn test_verify_object() {
+        let digest: [u8; 48] = Sha384::digest(b"object").into();
+        assert_eq!(verify_object(b"object", &digest), Ok(()));
+        assert_eq!(
+            verify_object(b"tampered", &digest),
+            Err(VerifyError::DigestMismatch)
+        );
+
+        let mut verifier = ObjectVerifier::new();
+        verifier.update(b"obj");
+        verifier.update(b"ect");
+        assert_eq!(verifier.verify(&digest), Ok(()));
+    }
+
+    #[test]
+    fn test_public_key() {
+        let key = PublicKey::from([1; 32]);
+        assert!(key.matches(&[1; 32]));
+        assert!(!key.matches(&[1; 31]));
+        assert!(!key.matches(&[[1; 31].as_slice(), &[2]].concat()));
+        assert_eq!(PublicKey::try_from(&[1u8; 32][..]).unwrap(), key);
+        assert_ne!(PublicKey::from([2; 32]), key);
+        assert!(PublicKey::try_from(&[1u8; 33][..]).is_err());
+        assert!(PublicKey::try_from(&[][..]).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: GPL-3.0-only
+
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use tokio::runtime;
+
+use crate::{err_str, Block, BlockSig, ObjectId};
+
+/// Sent to webhooks when a server accepts a new tail
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TailEvent {
+    pub project: String,
+    pub branch: String,
+    pub counter: u64,
+    pub timestamp: u64,
+    /// The signature of the new tail block
+    pub signature: BlockSig,
+    /// The digest of the manifest referenced by the new tail block
+    pub digest: ObjectId,
+}
+
+impl TailEvent {
+    pub fn new(project: &str, branch: &str, block: &Block) -> TailEvent {
+        TailEvent {
+            project: project.to_string(),
+            branch: branch.to_string(),
+            counter: block.counter,
+            timestamp: block.timestamp,
+            signature: block.signature,
+            digest: block.digest,
+        }
+    }
+}
+
+/// How long to wait to connect to a webhook
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a webhook to respond
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of events that can wait to be sent before new events are dropped
+const QUEUE_SIZE: usize = 64;
+
+/// A URL that is sent an HTTP POST with a JSON [`TailEvent`] for each new tail
+///
+/// Events passed to [`Webhook::spawn`] are sent in order by one worker thread per webhook, so a
+/// slow or unreachable webhook delays only its own events.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    url: reqwest::Url,
+    client: reqwest::Client,
+    queue: SyncSender<TailEvent>,
+}
+
+/// Post `event` to `url`, waiting for the response
+async fn post(
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    event: &TailEvent,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(event).map_err(err_str)?;
+
+    let response = client
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(err_str)?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("{} responded {:?}", url, response.status()))
+    }
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Result<Webhook, String> {
+        let url = reqwest::Url::parse(url).map_err(err_str)?;
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(err_str)?;
+
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(err_str)?;
+        let (queue, events) = mpsc::sync_channel::<TailEvent>(QUEUE_SIZE);
+        {
+            let url = url.clone();
+            let client = client.clone();
+            thread::Builder::new()
+                .name("buildchain-webhook".to_string())
+                .spawn(move || {
+                    for event in events {
+                        if let Err(err) = runtime.block_on(post(&client, &url, &event)) {
+                            eprintln!("buildchain: webhook: {}", err);
+                        }
+                    }
+                })
+                .map_err(err_str)?;
+        }
+
+        Ok(Webhook { url, client, queue })
+    }
+
+    /// Send `event` to the webhook, waiting for the response
+    pub async fn send(&self, event: &TailEvent) -> Result<(), String> {
+        post(&self.client, &self.url, event).await
+    }
+
+    /// Queue `event` to be sent in the background, logging failures
+    ///
+    /// If the webhook is too far behind, the event is dropped instead of waiting.
+    pub fn spawn(&self, event: TailEvent) {
+        if let Err(err) = self.queue.try_send(event) {
+            let reason = match err {
+                TrySendError::Full(_) => "too many events waiting",
+                TrySendError::Disconnected(_) => "worker stopped",
+            };
+            eprintln!(
+                "buildchain: webhook: {}: dropped event, {}",
+                self.url, reason
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{TailEvent, Webhook};
+    use crate::{BlockSig, ObjectId};
+
+    #[test]
+    fn test_send() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", server.server_addr().to_ip().unwrap());
+
+        let event = TailEvent {
+            project: "stuff".to_string(),
+            branch: "junk".to_string(),
+            counter: 3,
+            timestamp: 1_500_000_000,
+            signature: BlockSig([1; 64]),
+            digest: ObjectId([2; 48]),
+        };
+
+        let receiver = thread::spawn(move || {
+            let mut request = server.recv().unwrap();
+            assert_eq!(*request.method(), tiny_http::Method::Post);
+            assert_eq!(request.url(), "/hook");
+
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            request.respond(tiny_http::Response::empty(204)).unwrap();
+            serde_json::from_str::<TailEvent>(&body).unwrap()
+        });
+
+        let webhook = Webhook::new(&url).unwrap();
+        webhook.spawn(event.clone());
+        assert_eq!(receiver.join().unwrap(), event);
+    }
+}